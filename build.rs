@@ -0,0 +1,43 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    #[cfg(feature = "grpc")]
+    grpc();
+}
+
+/// Generates the server side of the `zos.Image` service described in
+/// proto/zos.proto, for the messages defined in src/grpc.rs.
+#[cfg(feature = "grpc")]
+fn grpc() {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    let methods = [
+        ("list_dir", "ListDir", "PathRequest", "ListDirReply"),
+        ("read_file", "ReadFile", "PathRequest", "FileData"),
+        ("write_file", "WriteFile", "FileData", "Empty"),
+        ("remove", "Remove", "PathRequest", "Empty"),
+        ("mkdir", "Mkdir", "PathRequest", "Empty"),
+        ("move_file", "Move", "MoveRequest", "Empty"),
+        ("check", "Check", "CheckRequest", "CheckReply"),
+    ];
+
+    let service = methods
+        .into_iter()
+        .fold(
+            Service::builder().name("Image").package("zos"),
+            |service, (name, route, input, output)| {
+                service.method(
+                    Method::builder()
+                        .name(name)
+                        .route_name(route)
+                        .input_type(format!("crate::grpc::{input}"))
+                        .output_type(format!("crate::grpc::{output}"))
+                        .codec_path("tonic::codec::ProstCodec")
+                        .build(),
+                )
+            },
+        )
+        .build();
+
+    Builder::new().build_client(false).compile(&[service]);
+}
@@ -0,0 +1,108 @@
+//! Inspects images in a web page, with the image kept in memory and the page
+//! saving it to IndexedDB. Build it with
+//!
+//! ```text
+//! cargo build --release --example web --target wasm32-unknown-unknown
+//! wasm-bindgen --target web --out-dir examples/web/pkg \
+//!     target/wasm32-unknown-unknown/release/examples/web.wasm
+//! ```
+//!
+//! and serve `examples/web` over HTTP.
+#![cfg(target_arch = "wasm32")]
+
+use wasm_bindgen::prelude::*;
+use zos_rs::{
+    fat::{check::CheckOptions, disk::Memory, FATError, FAT},
+    json,
+};
+
+fn error(error: FATError) -> JsError {
+    JsError::new(&format!("{error:?}"))
+}
+
+/// `path` as the image's own operations know it.
+fn image_path(path: &str) -> String {
+    let parts = path
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect::<Vec<_>>();
+    if parts.is_empty() {
+        ".".to_string()
+    } else {
+        parts.join("/")
+    }
+}
+
+#[wasm_bindgen]
+pub struct Image {
+    fat: FAT,
+}
+
+#[wasm_bindgen]
+impl Image {
+    /// Opens an image from its bytes, such as a file the user picked or one
+    /// saved in IndexedDB earlier.
+    #[wasm_bindgen(constructor)]
+    pub fn new(bytes: Vec<u8>) -> Result<Image, JsError> {
+        let fat = FAT::with_backend(Box::new(Memory::new(bytes)))?;
+        if fat.header().is_none() {
+            return Err(JsError::new("the image is not formatted"));
+        }
+        if fat.is_locked() {
+            return Err(JsError::new("encrypted images are not supported"));
+        }
+        Ok(Self { fat })
+    }
+
+    pub fn label(&self) -> String {
+        self.fat.label().unwrap_or_default().to_string()
+    }
+
+    /// The directory at `path` as a JSON array of `{name, dir, size}`.
+    pub fn ls(&mut self, path: &str) -> Result<String, JsError> {
+        let entries = self
+            .fat
+            .read_dir(&image_path(path))
+            .map_err(error)?
+            .iter()
+            .filter(|entry| entry.name() != "." && entry.name() != "..")
+            .map(|entry| {
+                format!(
+                    "{{\"name\":{},\"dir\":{},\"size\":{}}}",
+                    json::string(entry.name()),
+                    FAT::filter_mkdir(entry),
+                    entry.size()
+                )
+            })
+            .collect::<Vec<_>>();
+        Ok(format!("[{}]", entries.join(",")))
+    }
+
+    pub fn cat(&mut self, path: &str) -> Result<Vec<u8>, JsError> {
+        let mut data = vec![];
+        self.fat.cat(&image_path(path), &mut data).map_err(error)?;
+        Ok(data)
+    }
+
+    /// Findings of a read-only `check` as a JSON array.
+    pub fn check(&mut self) -> Result<String, JsError> {
+        let report = self
+            .fat
+            .check(CheckOptions {
+                repair: false,
+                use_mirror: false,
+            })
+            .map_err(error)?;
+        let findings = report
+            .findings
+            .iter()
+            .map(|finding| finding.to_json())
+            .collect::<Vec<_>>();
+        Ok(format!("[{}]", findings.join(",")))
+    }
+
+    /// The image as it is now, to be saved again.
+    pub fn bytes(&mut self) -> Result<Vec<u8>, JsError> {
+        self.fat.image_bytes().map_err(error)
+    }
+}
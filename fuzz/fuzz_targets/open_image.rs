@@ -0,0 +1,40 @@
+#![no_main]
+
+use std::io;
+
+use libfuzzer_sys::fuzz_target;
+use zos_rs::fat::{disk::Memory, FAT};
+
+/// Lists and reads everything below `path`, ignoring the errors damaged
+/// images are expected to give.
+fn walk(fat: &mut FAT, path: &str, depth: usize) {
+    let Ok(entries) = fat.read_dir(path) else {
+        return;
+    };
+
+    for entry in entries {
+        if entry.name() == "." || entry.name() == ".." || entry.name().is_empty() {
+            continue;
+        }
+        let child = if path == "." {
+            entry.name().to_string()
+        } else {
+            format!("{path}/{}", entry.name())
+        };
+
+        // a directory that contains itself must not recurse forever
+        if FAT::filter_mkdir(&entry) && depth < 8 {
+            walk(fat, &child, depth + 1);
+        } else {
+            let _ = fat.cat(&child, io::sink());
+        }
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(mut fat) = FAT::open_untrusted(Box::new(Memory::new(data.to_vec()))) else {
+        return;
+    };
+    walk(&mut fat, ".", 0);
+    let _ = fat.verify_invariants();
+});
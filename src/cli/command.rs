@@ -1,15 +1,32 @@
 use std::{
+    collections::BTreeMap,
     fmt::Display,
     fs::{self, read_to_string, File},
+    io::{self, BufWriter, Write},
+    path::Path,
 };
 
 use crate::{
-    fat::{dirent::Flags, FATError},
+    fat::{
+        alloc::AllocStrategy,
+        batch::FsOp,
+        check::{CheckOptions, Finding},
+        diff::{compare, diff, hash, Fingerprint},
+        dirent::{Entry, Flags},
+        disk,
+        header::{FormatOptions, VERSION},
+        meta,
+        raw::Block,
+        Corruption, FATError, FAT, ROOT,
+    },
+    json::{self, Value},
+    lock::Lock,
+    read_passphrase,
     units::Unit,
     Application,
 };
 
-use super::get;
+use super::{copy_ends, execute, get, split_alias};
 
 #[derive(Debug, Clone)]
 pub enum CommandError {
@@ -18,6 +35,26 @@ pub enum CommandError {
     Exist,
     NotEmpty,
     CannotCreateFile,
+    InvalidLabel,
+    NoChecksums,
+    InvalidOption,
+    NoReflinks,
+    Corrupted,
+    /// The image is a real FAT volume, which can only be read.
+    ReadOnly,
+    /// No image is open, see `open`.
+    NoImage,
+    NotFormatted,
+    /// A destructive command was not confirmed.
+    Cancelled,
+    /// The new name is empty, only dots, reserved or holds a control character.
+    InvalidName,
+    /// The entry is `.`, `..` or a system entry.
+    Protected,
+    /// The entry's owner and mode do not allow it, see `chmod`.
+    PermissionDenied,
+    /// Another process has the image open.
+    InUse,
 }
 
 impl Display for CommandError {
@@ -31,15 +68,28 @@ impl Display for CommandError {
                 Self::Exist => "EXIST",
                 Self::NotEmpty => "NOT EMPTY",
                 Self::CannotCreateFile => "CANNOT CREATE FILE",
+                Self::InvalidLabel => "INVALID LABEL",
+                Self::NoChecksums => "NO CHECKSUMS",
+                Self::InvalidOption => "INVALID OPTION",
+                Self::NoReflinks => "NO REFLINKS",
+                Self::Corrupted => "CORRUPTED",
+                Self::ReadOnly => "READ ONLY",
+                Self::NoImage => "NO IMAGE",
+                Self::NotFormatted => "NOT FORMATTED",
+                Self::Cancelled => "CANCELLED",
+                Self::InvalidName => "INVALID NAME",
+                Self::Protected => "PROTECTED",
+                Self::PermissionDenied => "PERMISSION DENIED",
+                Self::InUse => "IN USE",
             }
         )
     }
 }
 
-fn build_path(current_path: &String, given_path: Option<&String>) -> String {
+fn build_path(current_path: &str, given_path: Option<&String>) -> String {
     if let Some(given_path) = given_path {
-        if given_path.starts_with('/') {
-            given_path[1..].to_string()
+        if let Some(absolute) = given_path.strip_prefix('/') {
+            absolute.to_string()
         } else {
             let len = if given_path.is_empty() {
                 current_path.len() - 1
@@ -53,22 +103,59 @@ fn build_path(current_path: &String, given_path: Option<&String>) -> String {
     }
 }
 
+fn parse_unit(size: &str) -> Option<Unit> {
+    let units = size.trim_start_matches(|c: char| c.is_ascii_digit());
+    let count = size
+        .trim_end_matches(|c: char| c.is_alphabetic())
+        .parse::<usize>()
+        .ok()?;
+
+    Unit::from_str(count, units)
+}
+
+/// A number of bytes, given plain or with a unit.
+fn parse_size(size: &str) -> Option<u64> {
+    size.parse::<u64>()
+        .ok()
+        .or_else(|| parse_unit(size).and_then(|unit| u64::try_from(unit.to_bytes()).ok()))
+}
+
+/// Moves an existing file aside as its next version when versioning is on.
+fn keep_version(application: &mut Application, path: &str) -> Result<bool, CommandError> {
+    application
+        .file_system
+        .keep_version(path)
+        .map_err(|_| CommandError::CannotCreateFile)
+}
+
 pub trait CommandHandler {
     type Error;
 
     fn handle(&self, application: &mut Application) -> Result<(), Self::Error>;
 }
-//     1) Zkopíruje soubor s1 do umístění s2
+//     1) Zkopíruje soubor s1 do umístění s2, nebo soubory s1 s2 ... do existujícího adresáře a1
 // Možný výsledek:
 // OK
 // FILE NOT FOUND (není zdroj)
-// PATH NOT FOUND (neexistuje cílová cesta)
+// PATH NOT FOUND (neexistuje cílová cesta, u více zdrojů není a1 adresář)
+// EXIST (s2 už existuje)
+// NO REFLINKS (obraz nemá tabulku počtu odkazů, viz tune reflink on)
+// INVALID NAME (jméno s2 nelze použít, viz mkdir)
+// PROTECTED (s2 je . , .. nebo systémová položka)
+// PERMISSION DENIED (s1 nelze číst, nebo do cílového adresáře nelze zapisovat)
 // cp s1 s2
-pub struct CopyFile(String, String);
+// cp s1 s2 s3 a1 (když jeden selže, nezkopíruje se nic)
+// cp --reflink s1 s2 (s2 sdílí clustery s s1, dokud se do jednoho z nich nezapíše)
+// cp a1:/s1 a2:/s2 (mezi obrazy připojenými příkazem mount, bez a1: jde o aktuální obraz)
+// Možný výsledek mezi obrazy navíc:
+// CANNOT CREATE FILE (s2 se nevejde)
+// READ ONLY (cílový obraz je skutečný svazek FAT)
+// INVALID OPTION (zdroje jsou na různých obrazech)
+pub struct CopyFile(Vec<String>, String, bool);
 
 impl CopyFile {
-    pub fn new(source: String, destination: String) -> Self {
-        Self(source, destination)
+    pub fn new(sources: Vec<String>, destination: String, reflink: bool) -> Self {
+        Self(sources, destination, reflink)
     }
 }
 
@@ -76,24 +163,198 @@ impl CommandHandler for CopyFile {
     type Error = CommandError;
 
     fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
-        application
-            .file_system
-            .copy(
-                &build_path(&application.current_path, Some(&self.0)),
-                &build_path(&application.current_path, Some(&self.1)),
-            )
-            .map_err(|_| CommandError::FileNotFound)
+        let words: Vec<&str> = std::iter::once("cp")
+            .chain(self.0.iter().map(String::as_str))
+            .chain([self.1.as_str()])
+            .collect();
+        if let Some((from, to)) = copy_ends(application, &words) {
+            if from.is_some() || to.is_some() {
+                return self.copy_across(application, from, to);
+            }
+        }
+
+        let sources = self
+            .0
+            .iter()
+            .map(|source| build_path(&application.current_path, Some(source)))
+            .collect();
+        let destination = build_path(&application.current_path, Some(&self.1));
+
+        for (source, destination) in
+            destinations(&mut application.file_system, sources, destination)?
+        {
+            if application
+                .file_system
+                .find_file(&source, FAT::filter_find_file)
+                .is_err()
+            {
+                return Err(CommandError::FileNotFound);
+            }
+            if self.2
+                && !application
+                    .file_system
+                    .header()
+                    .is_some_and(|header| header.has_refcounts())
+            {
+                return Err(CommandError::NoReflinks);
+            }
+            keep_version(application, &destination)?;
+
+            let result = if self.2 {
+                application.file_system.reflink(&source, &destination)
+            } else {
+                application.file_system.copy(&source, &destination)
+            };
+            result.map_err(|e| match e {
+                FATError::FileExists => CommandError::Exist,
+                FATError::InvalidName => CommandError::InvalidName,
+                FATError::Protected => CommandError::Protected,
+                FATError::PermissionDenied => CommandError::PermissionDenied,
+                _ => CommandError::PathNotFound,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl CopyFile {
+    /// Streams the sources into the destination when they are on different
+    /// images, `from` and `to` being their aliases, `None` for the current
+    /// image.
+    fn copy_across(
+        &self,
+        application: &mut Application,
+        from: Option<&str>,
+        to: Option<&str>,
+    ) -> Result<(), CommandError> {
+        if self.2 {
+            return Err(CommandError::NoReflinks);
+        }
+        let path = |application: &Application, word: &String, alias: Option<&str>| match alias {
+            Some(alias) => word[alias.len() + 1..].trim_start_matches('/').to_string(),
+            None => build_path(&application.current_path, Some(word)),
+        };
+        let mut sources = vec![];
+        for source in &self.0 {
+            if split_alias(application, source).map(|(alias, _)| alias) != from {
+                return Err(CommandError::InvalidOption);
+            }
+            sources.push(path(application, source, from));
+        }
+        let destination = path(application, &self.1, to);
+
+        let mut taken = from.map(|alias| application.mounts.remove_entry(alias).unwrap());
+        let result = match &mut taken {
+            Some((_, source_fs)) => {
+                let target = match to {
+                    Some(alias) => application.mounts.get_mut(alias).unwrap(),
+                    None => &mut application.file_system,
+                };
+                copy_into(source_fs, sources, target, destination)
+            }
+            None => {
+                let target = application.mounts.get_mut(to.unwrap()).unwrap();
+                copy_into(&mut application.file_system, sources, target, destination)
+            }
+        };
+        if let Some((alias, fs)) = taken {
+            application.mounts.insert(alias, fs);
+        }
+        result
+    }
+}
+
+/// Pairs each of `sources` with where it goes: into `destination` when that
+/// is a directory, which it has to be for more than one source, or else to
+/// `destination` itself.
+fn destinations(
+    fs: &mut FAT,
+    sources: Vec<String>,
+    destination: String,
+) -> Result<Vec<(String, String)>, CommandError> {
+    let dir = match destination.trim_end_matches('/') {
+        "" => ROOT,
+        dir => dir,
+    };
+    let resolved = fs.resolve(dir, FAT::filter_mkdir);
+    let is_dir = resolved.is_ok();
+    // a directory on the way to the destination is missing, or is a file
+    let no_parent =
+        matches!(resolved, Err((component, _)) if component + 1 < dir.split('/').count());
+    if no_parent || (!is_dir && (sources.len() > 1 || destination.ends_with('/'))) {
+        return Err(CommandError::PathNotFound);
+    }
+    if !is_dir {
+        return Ok(sources
+            .into_iter()
+            .map(|source| (source, destination.clone()))
+            .collect());
+    }
+
+    Ok(sources
+        .into_iter()
+        .map(|source| {
+            let name = source.rsplit('/').next().unwrap_or(&source);
+            let destination = if dir == "." {
+                name.to_string()
+            } else {
+                format!("{dir}/{name}")
+            };
+            (source, destination)
+        })
+        .collect())
+}
+
+fn copy_into(
+    source_fs: &mut FAT,
+    sources: Vec<String>,
+    target: &mut FAT,
+    destination: String,
+) -> Result<(), CommandError> {
+    if target.foreign().is_some() {
+        return Err(CommandError::ReadOnly);
+    }
+    let pairs = destinations(target, sources, destination)?;
+    if pairs
+        .iter()
+        .any(|(source, _)| source_fs.find_file(source, FAT::filter_find_file).is_err())
+    {
+        return Err(CommandError::FileNotFound);
     }
+
+    target
+        .transaction(|target| {
+            for (source, destination) in &pairs {
+                target.keep_version(destination)?;
+                source_fs.copy_to(source, target, destination)?;
+            }
+            Ok(())
+        })
+        .map_err(|e| match e {
+            FATError::FileExists => CommandError::Exist,
+            FATError::NotEnoughSpace | FATError::FileTooLarge => CommandError::CannotCreateFile,
+            FATError::InvalidName => CommandError::InvalidName,
+            FATError::Protected => CommandError::Protected,
+            FATError::PermissionDenied => CommandError::PermissionDenied,
+            _ => CommandError::PathNotFound,
+        })
 }
-// 2) Přesune soubor s1 do umístění s2, nebo přejmenuje s1 na s2
+// 2) Přesune soubor s1 do umístění s2, nebo přejmenuje s1 na s2, nebo přesune soubory
+//    s1 s2 ... do existujícího adresáře a1
+// mv s1 s2
+// mv s1 s2 s3 a1 (když jeden selže, nepřesune se nic)
 // Možný výsledek:
 // OK
 // FILE NOT FOUND (není zdroj)
-// PATH NOT FOUND (neexistuje cílová cesta)
-pub struct MoveFile(String, String);
+// PATH NOT FOUND (neexistuje cílová cesta, u více zdrojů není a1 adresář)
+// EXIST (cíl už existuje)
+// INVALID NAME (nové jméno nelze použít, viz mkdir)
+// PROTECTED (zdroj nebo cíl je . , .. nebo systémová položka)
+// PERMISSION DENIED (do zdrojového nebo cílového adresáře nelze zapisovat)
+pub struct MoveFile(Vec<String>, String);
 impl MoveFile {
-    pub fn new(source: String, destination: String) -> Self {
-        Self(source, destination)
+    pub fn new(sources: Vec<String>, destination: String) -> Self {
+        Self(sources, destination)
     }
 }
 
@@ -101,36 +362,207 @@ impl CommandHandler for MoveFile {
     type Error = CommandError;
 
     fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
-        application
-            .file_system
-            .move_file(
-                &build_path(&application.current_path, Some(&self.0)),
-                &build_path(&application.current_path, Some(&self.1)),
-            )
-            .map_err(|e| match e {
-                _ => CommandError::FileNotFound,
-            })
+        let sources = self
+            .0
+            .iter()
+            .map(|source| build_path(&application.current_path, Some(source)))
+            .collect();
+        let destination = build_path(&application.current_path, Some(&self.1));
+
+        for (source, destination) in
+            destinations(&mut application.file_system, sources, destination)?
+        {
+            let fs = &mut application.file_system;
+            let result = fs.move_file(&source, &destination);
+            result.map_err(|e| match e {
+                FATError::FileExists => CommandError::Exist,
+                FATError::InvalidName => CommandError::InvalidName,
+                FATError::Protected => CommandError::Protected,
+                FATError::PermissionDenied => CommandError::PermissionDenied,
+                _ if fs.find_file(&source, FAT::filter_find_file).is_err() => {
+                    CommandError::FileNotFound
+                }
+                _ => CommandError::PathNotFound,
+            })?;
+        }
+        Ok(())
     }
 }
-// 3) Smaže soubor s1
-// rm s1
+// 3) Smaže soubor s1 (přesune ho do koše /.trash, odkud ho lze obnovit,
+//    nebo při zapnutém verzování do verze s1;N), případně více souborů najednou
+// rm s1 (v interaktivním režimu se nejdřív zeptá, -f otázku vynechá)
+// rm -f s1
+// rm s1 s2 s3 (u každého vypíše výsledek a pokračuje i po chybě)
+// rm -e s1 s2 s3 (vypisuje stejně, ale při první chybě skončí; co už smazal, zůstane smazané)
 // Možný výsledek:
 // OK
+// s1: OK
+// s2: FILE NOT FOUND
 // FILE NOT FOUND
-pub struct RemoveFile(String);
+// PROTECTED (. , .. nebo systémová položka)
+// PERMISSION DENIED (do adresáře se souborem nelze zapisovat)
+// CANCELLED (na otázku nepřišlo y)
+pub struct RemoveFile {
+    files: Vec<String>,
+    stop_on_error: bool,
+}
 impl RemoveFile {
+    pub fn new(files: Vec<String>, stop_on_error: bool) -> Self {
+        Self {
+            files,
+            stop_on_error,
+        }
+    }
+
+    fn remove(application: &mut Application, file: &String) -> Result<(), CommandError> {
+        let path = build_path(&application.current_path, Some(file));
+
+        if keep_version(application, &path)? {
+            return Ok(());
+        }
+
+        application.file_system.trash(&path).map_err(|e| match e {
+            FATError::Protected => CommandError::Protected,
+            FATError::PermissionDenied => CommandError::PermissionDenied,
+            _ => CommandError::FileNotFound,
+        })
+    }
+}
+
+impl CommandHandler for RemoveFile {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        if let [file] = &self.files[..] {
+            return Self::remove(application, file);
+        }
+
+        // the files removed so far are reported, so the command succeeds and
+        // keeps them removed even when another one fails
+        let mut failed = false;
+        for file in &self.files {
+            match Self::remove(application, file) {
+                Ok(()) => sayln!(application, "{file}: OK"),
+                Err(err) => {
+                    failed = true;
+                    sayln!(application, "{file}: {}", err.to_string().trim_end());
+                    if self.stop_on_error {
+                        break;
+                    }
+                }
+            }
+        }
+        if failed {
+            application.set_status(1);
+        }
+        Ok(())
+    }
+}
+// Vypíše soubory v koši
+// trash list
+// Možný výsledek:
+// 1 /cp/test.txt
+// OK
+pub struct TrashList;
+impl TrashList {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl CommandHandler for TrashList {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let trashed = application
+            .file_system
+            .list_trash()
+            .map_err(|_| CommandError::FileNotFound)?;
+
+        for item in trashed {
+            sayln!(application, "{item}");
+        }
+
+        Ok(())
+    }
+}
+// Trvale smaže všechny soubory v koši
+// trash empty
+// Možný výsledek:
+// 3 file(s) removed
+// OK
+pub struct EmptyTrash;
+impl EmptyTrash {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl CommandHandler for EmptyTrash {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let removed = application
+            .file_system
+            .empty_trash()
+            .map_err(|_| CommandError::FileNotFound)?;
+
+        sayln!(application, "{removed} file(s) removed");
+        Ok(())
+    }
+}
+// Obnoví soubor z koše na původní místo
+// restore 1
+// Možný výsledek:
+// OK
+// FILE NOT FOUND (v koši není)
+// EXIST (na původním místě už soubor je)
+// PATH NOT FOUND (původní adresář už neexistuje)
+pub struct Restore(String);
+impl Restore {
+    pub fn new(id: String) -> Self {
+        Self(id)
+    }
+}
+
+impl CommandHandler for Restore {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let id = self.0.parse().map_err(|_| CommandError::FileNotFound)?;
+        let trashed = application
+            .file_system
+            .list_trash()
+            .map_err(|_| CommandError::FileNotFound)?;
+        if !trashed.iter().any(|item| item.id == id) {
+            return Err(CommandError::FileNotFound);
+        }
+
+        application.file_system.restore(id).map_err(|e| match e {
+            FATError::FileExists => CommandError::Exist,
+            _ => CommandError::PathNotFound,
+        })
+    }
+}
+// Přepíše obsah souboru s1 náhodnými daty a smaže ho
+// shred s1
+// Možný výsledek:
+// OK
+// FILE NOT FOUND
+pub struct Shred(String);
+impl Shred {
     pub fn new(file: String) -> Self {
         Self(file)
     }
 }
 
-impl CommandHandler for RemoveFile {
+impl CommandHandler for Shred {
     type Error = CommandError;
 
     fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
         application
             .file_system
-            .remove_file(&build_path(&application.current_path, Some(&self.0)))
+            .shred(&build_path(&application.current_path, Some(&self.0)))
             .map_err(|_| CommandError::FileNotFound)
     }
 }
@@ -140,6 +572,8 @@ impl CommandHandler for RemoveFile {
 // OK
 // PATH NOT FOUND (neexistuje zadaná cesta)
 // EXIST (nelze založit, již existuje)
+// INVALID NAME (prázdné jméno, jen tečky, rezervované jméno jako CON nebo řídicí znaky)
+// PERMISSION DENIED (do nadřazeného adresáře nelze zapisovat)
 pub struct MakeDirectory(String);
 impl MakeDirectory {
     pub fn new(dirname: String) -> Self {
@@ -155,20 +589,26 @@ impl CommandHandler for MakeDirectory {
 
         application.file_system.mkdir(&path).map_err(|e| match e {
             FATError::FileExists => CommandError::Exist,
+            FATError::InvalidName => CommandError::InvalidName,
+            FATError::PermissionDenied => CommandError::PermissionDenied,
             _ => CommandError::PathNotFound,
         })
     }
 }
-// 5) Smaže prázdný adresář a1
+// 5) Smaže prázdný adresář a1, s -r i se vším, co obsahuje
 // rmdir a1
+// rmdir -r a1 (v interaktivním režimu se nejdřív zeptá, -f otázku vynechá)
 // Možný výsledek:
 // OK
 // FILE NOT FOUND (neexistující adresář)
 // NOT EMPTY (adresář obsahuje podadresáře, nebo soubory)
-pub struct RemoveDirectory(String);
+// PROTECTED (a1 je . nebo ..)
+// PERMISSION DENIED (do nadřazeného adresáře nelze zapisovat)
+// CANCELLED (na otázku nepřišlo y)
+pub struct RemoveDirectory(String, bool);
 impl RemoveDirectory {
-    pub fn new(dirname: String) -> Self {
-        Self(dirname)
+    pub fn new(dirname: String, recursive: bool) -> Self {
+        Self(dirname, recursive)
     }
 }
 
@@ -176,26 +616,123 @@ impl CommandHandler for RemoveDirectory {
     type Error = CommandError;
 
     fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
-        application
-            .file_system
-            .remove_dir(&build_path(&application.current_path, Some(&self.0)))
-            .map_err(|e| match e {
-                FATError::DirNotEmpty => CommandError::NotEmpty,
-                _ => CommandError::FileNotFound,
-            })
+        let path = build_path(&application.current_path, Some(&self.0));
+        let fs = &mut application.file_system;
+        if self.1 {
+            fs.remove_tree(&path)
+        } else {
+            fs.remove_dir(&path)
+        }
+        .map_err(|e| match e {
+            FATError::DirNotEmpty => CommandError::NotEmpty,
+            FATError::Protected => CommandError::Protected,
+            FATError::PermissionDenied => CommandError::PermissionDenied,
+            _ => CommandError::FileNotFound,
+        })
     }
 }
-// 6) Vypíše obsah adresáře a1, bez parametru vypíše obsah aktuálního adresáře
+// 6) Vypíše obsah adresáře a1, bez parametru vypíše obsah aktuálního adresáře; u souboru
+//    vypíše jen soubor, u více cest nejdřív soubory a pak každý adresář pod řádkem a1:
 // ls a1
 // ls
+// ls a1 a2 s1
+// ls -l a1 (u souborů vypíše i velikost a místo zabrané na disku)
+// ls --json a1 (též -j; položky bez . a .. jako JSON pole, s -l i s údaji jako stat;
+//    u více cest objekt s polem pro každou cestu)
 // Možný výsledek:
 // FILE: f1
+// FILE: f1 11975 B (4096 B on disk, compressed)
+// DIR: a2
+// [{"name":"f1","dir":false,"size":11975},{"name":"a2","dir":true,"size":0}]
+// a1:
 // DIR: a2
 // PATH NOT FOUND (neexistující adresář)
-pub struct Listing(Option<String>);
+// a3: FILE NOT FOUND (u více cest, ostatní se vypíší)
+pub struct Listing {
+    paths: Vec<String>,
+    long: bool,
+    json: bool,
+}
 impl Listing {
-    pub fn new(dirname: Option<String>) -> Self {
-        Self(dirname)
+    pub fn new(paths: Vec<String>, long: bool, json: bool) -> Self {
+        Self { paths, long, json }
+    }
+
+    /// What to list for `path`: the entries of a directory, or a file
+    /// itself, and whether it was a directory.
+    fn entries(
+        application: &mut Application,
+        path: Option<&String>,
+    ) -> Result<(Vec<Entry>, bool), CommandError> {
+        let mut path = build_path(&application.current_path, path);
+
+        if path.ends_with("/") || path.is_empty() {
+            path.push('.');
+        }
+        if let Ok(entry) = application
+            .file_system
+            .find_file(&path, FAT::filter_find_file)
+        {
+            return Ok((vec![entry], false));
+        }
+        let entries = application
+            .file_system
+            .read_dir(&path)
+            .map_err(|_| CommandError::FileNotFound)?;
+        Ok((entries, true))
+    }
+
+    fn to_json(
+        &self,
+        application: &mut Application,
+        entries: &[Entry],
+    ) -> Result<String, CommandError> {
+        let mut items = vec![];
+        for entry in entries.iter() {
+            if entry.name() == "." || entry.name() == ".." {
+                continue;
+            }
+            items.push(if self.long {
+                application
+                    .file_system
+                    .stat_entry(entry)
+                    .map_err(|_| CommandError::FileNotFound)?
+                    .to_json()
+            } else {
+                format!(
+                    "{{\"name\":{},\"dir\":{},\"size\":{}}}",
+                    json::string(entry.name()),
+                    FAT::filter_mkdir(entry),
+                    entry.size()
+                )
+            });
+        }
+        Ok(format!("[{}]", items.join(",")))
+    }
+
+    fn print(&self, application: &mut Application, entries: &[Entry]) -> Result<(), CommandError> {
+        for entry in entries.iter() {
+            if FAT::filter_mkdir(entry) {
+                sayln!(application, "DIR: {}", entry.name());
+            } else if self.long {
+                let stat = application
+                    .file_system
+                    .stat_entry(entry)
+                    .map_err(|_| CommandError::FileNotFound)?;
+                let compressed = if stat.compressed { ", compressed" } else { "" };
+                let sparse = if stat.sparse { ", sparse" } else { "" };
+                sayln!(
+                    application,
+                    "FILE: {} {} B ({} B on disk{compressed}{sparse})",
+                    stat.name,
+                    stat.size,
+                    stat.allocated
+                );
+            } else {
+                sayln!(application, "FILE: {}", entry.name());
+            }
+        }
+        Ok(())
     }
 }
 
@@ -203,26 +740,74 @@ impl CommandHandler for Listing {
     type Error = CommandError;
 
     fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
-        let mut path = build_path(&application.current_path, self.0.as_ref());
+        if self.paths.len() < 2 {
+            let (entries, _) = Self::entries(application, self.paths.first())?;
+            if self.json {
+                let items = self.to_json(application, &entries)?;
+                sayln!(application, "{items}");
+                return Ok(());
+            }
+            return self.print(application, &entries);
+        }
 
-        if path.ends_with("/") || path.is_empty() {
-            path.push('.');
+        let mut failed = None;
+        let mut files = vec![];
+        let mut dirs = vec![];
+        for path in &self.paths {
+            match Self::entries(application, Some(path)) {
+                Ok((entries, true)) => dirs.push((path, entries)),
+                Ok((entries, false)) => files.push((path, entries)),
+                Err(err) => {
+                    sayln!(application, "{path}: {}", err.to_string().trim_end());
+                    failed = Some(err);
+                }
+            }
         }
-        application
-            .file_system
-            .listings(&path)
-            .map_err(|_| CommandError::FileNotFound)
+
+        if self.json {
+            let mut fields = vec![];
+            for (path, entries) in files.iter().chain(&dirs) {
+                let items = self.to_json(application, entries)?;
+                fields.push(format!("{}:{items}", json::string(path)));
+            }
+            sayln!(application, "{{{}}}", fields.join(","));
+        } else {
+            for (_, entries) in &files {
+                self.print(application, entries)?;
+            }
+            for (index, (path, entries)) in dirs.iter().enumerate() {
+                if index > 0 || !files.is_empty() {
+                    sayln!(application, "");
+                }
+                sayln!(application, "{path}:");
+                self.print(application, entries)?;
+            }
+        }
+
+        failed.map_or(Ok(()), Err)
     }
 }
-// 7) Vypíše obsah souboru s1
+// 7) Vypíše obsah souboru s1, případně jen zadaný rozsah bajtů (čte jen clustery, které ho drží)
 // cat s1
+// cat s1 --offset 4KB --length 512
 // Možný výsledek:
 // OBSAH
 // FILE NOT FOUND (není zdroj)
-pub struct Concatenate(String);
+// INVALID OPTION (neplatný posun nebo délka)
+// CORRUPTED (při tune verify on nesedí kontrolní součet)
+// PERMISSION DENIED (soubor nelze číst)
+pub struct Concatenate {
+    file: String,
+    offset: Option<String>,
+    length: Option<String>,
+}
 impl Concatenate {
-    pub fn new(dirname: String) -> Self {
-        Self(dirname)
+    pub fn new(file: String, offset: Option<String>, length: Option<String>) -> Self {
+        Self {
+            file,
+            offset,
+            length,
+        }
     }
 }
 
@@ -230,16 +815,27 @@ impl CommandHandler for Concatenate {
     type Error = CommandError;
 
     fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
-        application
-            .file_system
-            .cat(
-                &build_path(&application.current_path, Some(&self.0)),
-                std::io::stdout(),
-            )
-            .map_err(|e| match e {
-                FATError::FileExists => CommandError::Exist,
-                _ => CommandError::PathNotFound,
-            })
+        let path = build_path(&application.current_path, Some(&self.file));
+        let result = if self.offset.is_none() && self.length.is_none() {
+            application.file_system.cat(&path, &mut application.output)
+        } else {
+            let parse = |size: &Option<String>, default| match size {
+                Some(size) => parse_size(size).ok_or(CommandError::InvalidOption),
+                None => Ok(default),
+            };
+            let offset = parse(&self.offset, 0)?;
+            let length = parse(&self.length, u64::MAX)?;
+            application
+                .file_system
+                .read_at(&path, offset, length, &mut application.output)
+        };
+
+        result.map_err(|e| match e {
+            FATError::FileExists => CommandError::Exist,
+            FATError::ChecksumMismatch => CommandError::Corrupted,
+            FATError::PermissionDenied => CommandError::PermissionDenied,
+            _ => CommandError::PathNotFound,
+        })
     }
 }
 // 8) Změní aktuální cestu do adresáře a1
@@ -309,19 +905,26 @@ impl CommandHandler for PrintWorkingDirectory {
     type Error = CommandError;
 
     fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
-        println!("{}", application.current_path);
+        sayln!(application, "{}", application.current_path);
         Ok(())
     }
 }
-// 10) Vypíše informace o souboru/adresáři s1/a1 (v jakých clusterech se nachází)
+// 10) Vypíše informace o souboru/adresáři s1/a1 (v jakých clusterech se nachází, velikost,
+//     příznaky d/h/s/c/p a počet souvislých úseků)
 // info a1/s1
+// info --json a1/s1 (též -j; stejné údaje jako stat --json)
 // Možný výsledek:
 // S1 2,3,4,7,10
+// size: 8000 B, allocated: 10240 B, flags: ---c-, extents: 3 (u řídkého souboru může být
+//     velikost větší)
 // FILE NOT FOUND (není zdroj)
-pub struct PrintInfo(String);
+pub struct PrintInfo {
+    file: String,
+    json: bool,
+}
 impl PrintInfo {
-    pub fn new(file: String) -> Self {
-        Self(file)
+    pub fn new(file: String, json: bool) -> Self {
+        Self { file, json }
     }
 }
 
@@ -329,37 +932,160 @@ impl CommandHandler for PrintInfo {
     type Error = CommandError;
 
     fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
-        application
+        let stat = application
             .file_system
-            .info(&build_path(&application.current_path, Some(&self.0)))
-            .map_err(|_| CommandError::FileNotFound)
+            .stat(&build_path(&application.current_path, Some(&self.file)))
+            .map_err(|_| CommandError::FileNotFound)?;
+
+        if self.json {
+            sayln!(application, "{}", stat.to_json());
+            return Ok(());
+        }
+
+        sayln!(
+            application,
+            "{} {}",
+            stat.name,
+            stat.clusters
+                .iter()
+                .map(|cluster| cluster.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        sayln!(
+            application,
+            "size: {} B, allocated: {} B, flags: {}, extents: {}",
+            stat.size,
+            stat.allocated,
+            stat.letters(),
+            stat.extents().len()
+        );
+        Ok(())
     }
 }
-// 11) Nahraje soubor s1 z pevného disku do umístění s2 ve vašem FS
-// incp s1 s2
+// Vypíše podrobné údaje o souboru/adresáři s1
+// stat a1/s1
+// stat --json a1/s1 (též -j)
 // Možný výsledek:
-// OK
+// name: s1
+// type: file
+// size: 8000 B
+// allocated: 10240 B
+// flags: compressed, sparse (none, když žádné nejsou)
+// start cluster: 2
+// clusters: 5
+// extents: 2-4, 7, 10
+// parent: /a1
+// times: not recorded (položky adresáře časy neukládají)
+// {"name":"s1","dir":false,"size":8000,"allocated":10240,"start":2,"clusters":[2,3,4,7,10],"extents":[{"start":2,"count":3},{"start":7,"count":1},{"start":10,"count":1}],"compressed":false,"sparse":false,"hidden":false,"system":false,"parent":"/a1"}
 // FILE NOT FOUND (není zdroj)
-// PATH NOT FOUND (neexistuje cílová cesta)
-pub struct CopyIn(String, String);
-impl CopyIn {
-    pub fn new(source: String, destination: String) -> Self {
-        Self(source, destination)
+pub struct StatFile {
+    file: String,
+    json: bool,
+}
+impl StatFile {
+    pub fn new(file: String, json: bool) -> Self {
+        Self { file, json }
     }
 }
 
-impl CommandHandler for CopyIn {
+impl CommandHandler for StatFile {
     type Error = CommandError;
 
     fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
-        let file = fs::File::open(&self.0).map_err(|_| CommandError::FileNotFound)?;
-
-        application
+        let path = build_path(&application.current_path, Some(&self.file));
+        let stat = application
             .file_system
-            .new_file(&build_path(&application.current_path, Some(&self.1)), file)
-            .map_err(|e| match e {
-                _ => CommandError::PathNotFound,
-            })
+            .stat(&path)
+            .map_err(|_| CommandError::FileNotFound)?;
+        let parent = match path.rsplit_once('/') {
+            Some((parent, _)) => format!("/{}", parent),
+            None => "/".to_string(),
+        };
+
+        if self.json {
+            let json = stat.to_json();
+            sayln!(
+                application,
+                "{},\"parent\":{}}}",
+                &json[..json.len() - 1],
+                json::string(&parent)
+            );
+            return Ok(());
+        }
+
+        let flags = stat.flags();
+        sayln!(application, "name: {}", stat.name);
+        sayln!(
+            application,
+            "type: {}",
+            if stat.dir { "directory" } else { "file" }
+        );
+        sayln!(application, "size: {} B", stat.size);
+        sayln!(application, "allocated: {} B", stat.allocated);
+        sayln!(
+            application,
+            "flags: {}",
+            if flags.is_empty() {
+                "none".to_string()
+            } else {
+                flags.join(", ")
+            }
+        );
+        match stat.permissions {
+            Some(permissions) => sayln!(application, "owner: {}", permissions),
+            None => sayln!(application, "owner: none"),
+        }
+        sayln!(application, "start cluster: {}", stat.start);
+        sayln!(application, "clusters: {}", stat.clusters.len());
+        sayln!(
+            application,
+            "extents: {}",
+            stat.extents()
+                .iter()
+                .map(|&(first, count)| match count {
+                    1 => first.to_string(),
+                    _ => format!("{}-{}", first, first + count - 1),
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        sayln!(application, "parent: {}", parent);
+        sayln!(application, "times: not recorded");
+        Ok(())
+    }
+}
+// 11) Nahraje soubor s1 z pevného disku do umístění s2 ve vašem FS
+// incp s1 s2
+// Možný výsledek:
+// OK
+// FILE NOT FOUND (není zdroj)
+// PATH NOT FOUND (neexistuje cílová cesta)
+// INVALID NAME (jméno s2 nelze použít, viz mkdir)
+// PERMISSION DENIED (do cílového adresáře nelze zapisovat)
+pub struct CopyIn(String, String);
+impl CopyIn {
+    pub fn new(source: String, destination: String) -> Self {
+        Self(source, destination)
+    }
+}
+
+impl CommandHandler for CopyIn {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let file = fs::File::open(&self.0).map_err(|_| CommandError::FileNotFound)?;
+        let path = build_path(&application.current_path, Some(&self.1));
+        keep_version(application, &path)?;
+
+        application
+            .file_system
+            .new_file(&path, file)
+            .map_err(|e| match e {
+                FATError::InvalidName => CommandError::InvalidName,
+                FATError::PermissionDenied => CommandError::PermissionDenied,
+                _ => CommandError::PathNotFound,
+            })
     }
 }
 // 12) Nahraje soubor s1 z vašeho FS do umístění s2 na pevném disku
@@ -368,6 +1094,8 @@ impl CommandHandler for CopyIn {
 // OK
 // FILE NOT FOUND (není zdroj)
 // PATH NOT FOUND (neexistuje cílová cesta)
+// CORRUPTED (při tune verify on nesedí kontrolní součet)
+// PERMISSION DENIED (s1 nelze číst)
 pub struct CopyOut(String, String);
 impl CopyOut {
     pub fn new(source: String, destination: String) -> Self {
@@ -384,16 +1112,377 @@ impl CommandHandler for CopyOut {
             .write(true)
             .create(true)
             .open(&self.1)
-            .map_err(|_| CommandError::FileNotFound)?;
+            .map_err(|_| CommandError::PathNotFound)?;
 
         application
             .file_system
             .cat(&build_path(&application.current_path, Some(&self.0)), file)
             .map_err(|e| match e {
-                _ => CommandError::PathNotFound,
+                FATError::ChecksumMismatch => CommandError::Corrupted,
+                FATError::PermissionDenied => CommandError::PermissionDenied,
+                _ => CommandError::FileNotFound,
             })
     }
 }
+// Uloží celý strom, nebo adresář či soubor s2, do tar/zip archivu s1 na pevném disku
+// export-tar s1
+// export-tar s1 s2
+// export-zip s1 s2
+// Možný výsledek:
+// OK
+// FILE NOT FOUND (nelze vytvořit archiv)
+// PATH NOT FOUND (neexistuje s2)
+// CORRUPTED (při tune verify on nesedí kontrolní součet)
+pub struct ExportArchive {
+    archive: String,
+    path: Option<String>,
+    zip: bool,
+}
+impl ExportArchive {
+    pub fn new(archive: String, path: Option<String>, zip: bool) -> Self {
+        Self { archive, path, zip }
+    }
+}
+
+impl CommandHandler for ExportArchive {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let path = match &self.path {
+            Some(path) => build_path(&application.current_path, Some(path)),
+            None => String::from("."),
+        };
+        let file =
+            BufWriter::new(File::create(&self.archive).map_err(|_| CommandError::FileNotFound)?);
+
+        let fs = &mut application.file_system;
+        if self.zip {
+            fs.export_zip(&path, file)
+        } else {
+            fs.export_tar(&path, file)
+        }
+        .map_err(|e| match e {
+            FATError::ChecksumMismatch => CommandError::Corrupted,
+            _ => CommandError::PathNotFound,
+        })
+    }
+}
+// Zapíše do s1 na pevném disku skript, který příkazem load vytvoří celý strom znovu v čerstvě
+// naformátovaném obrazu (mkdir, incp, attr, chmod, chown, xattr set); obsah souborů uloží
+// do adresáře s1.data vedle něj, na který odkazují řádky incp
+// export-script s1
+// Možný výsledek:
+// OK
+// FILE NOT FOUND (nelze vytvořit skript nebo data)
+// INVALID NAME (jméno nebo atribut s mezerou, který řádek skriptu neudrží)
+// CORRUPTED (při tune verify on nesedí kontrolní součet)
+pub struct ExportScript(String);
+impl ExportScript {
+    pub fn new(script: String) -> Self {
+        Self(script)
+    }
+}
+
+impl CommandHandler for ExportScript {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        // written once the export succeeded, so a failed one leaves no script
+        let mut script = vec![];
+
+        let data = format!("{}.data", self.0);
+        let create = |name: &str| {
+            let path = Path::new(&data).join(name);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            Ok(BufWriter::new(File::create(path)?))
+        };
+
+        application
+            .file_system
+            .export_script(&mut script, &data, create)
+            .map_err(|e| match e {
+                FATError::InvalidName => CommandError::InvalidName,
+                FATError::ChecksumMismatch => CommandError::Corrupted,
+                _ => CommandError::FileNotFound,
+            })?;
+        fs::write(&self.0, script).map_err(|_| CommandError::FileNotFound)
+    }
+}
+// Vytvoří adresáře a soubory z tar/zip archivu s1 na pevném disku v adresáři a1 (jinak
+// v aktuálním), průběžně vypisuje, kolik z archivu je hotovo; když se nevejde, nevytvoří nic
+// import-tar s1
+// import-tar s1 a1
+// import-zip s1 a1
+// Možný výsledek:
+// OK
+// FILE NOT FOUND (není zdroj)
+// PATH NOT FOUND (neexistuje a1)
+// EXIST (soubor z archivu už existuje)
+// INVALID OPTION (poškozený nebo šifrovaný archiv, příliš dlouhé jméno)
+// CANNOT CREATE FILE (není místo)
+pub struct ImportArchive {
+    archive: String,
+    dest: Option<String>,
+    zip: bool,
+}
+impl ImportArchive {
+    pub fn new(archive: String, dest: Option<String>, zip: bool) -> Self {
+        Self { archive, dest, zip }
+    }
+}
+
+impl CommandHandler for ImportArchive {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let file = File::open(&self.archive).map_err(|_| CommandError::FileNotFound)?;
+        let total = file
+            .metadata()
+            .map_err(|_| CommandError::FileNotFound)?
+            .len()
+            .max(1);
+        let mut dest = build_path(&application.current_path, self.dest.as_ref());
+        if dest.is_empty() {
+            dest.push('.');
+        }
+        let output = &mut application.output;
+        let progress = |path: &str, done: u64| {
+            let _ = writeln!(output, "{:>3}% {path}", done.min(total) * 100 / total);
+        };
+
+        let fs = &mut application.file_system;
+        if self.zip {
+            fs.import_zip(&dest, file, progress)
+        } else {
+            fs.import_tar(&dest, file, progress)
+        }
+        .map_err(|e| match e {
+            FATError::FileExists => CommandError::Exist,
+            FATError::FileNotFound => CommandError::PathNotFound,
+            FATError::CannotRead | FATError::FilenameTooLong => CommandError::InvalidOption,
+            _ => CommandError::CannotCreateFile,
+        })
+    }
+}
+// Přestaví celý strom do nového obrazu s1 na pevném disku, buď jako FAT32, který připojí
+// skutečné operační systémy, nebo zpět do vlastního formátu
+// convert --to fat32 s1
+// convert --to native s1
+// Možný výsledek:
+// OK
+// FILE NOT FOUND (nelze vytvořit s1)
+// INVALID OPTION (neznámý formát, soubor od 4 GB do FAT32)
+// CORRUPTED (při tune verify on nesedí kontrolní součet)
+// CANNOT CREATE FILE (zápis do s1 selhal)
+pub struct Convert {
+    to: String,
+    image: String,
+}
+impl Convert {
+    pub fn new(to: String, image: String) -> Self {
+        Self { to, image }
+    }
+}
+
+impl CommandHandler for Convert {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        if self.to != "fat32" && self.to != "native" {
+            return Err(CommandError::InvalidOption);
+        }
+        let file = File::create(&self.image).map_err(|_| CommandError::FileNotFound)?;
+
+        let fs = &mut application.file_system;
+        if self.to == "fat32" {
+            fs.convert_to_fat32(BufWriter::new(&file))
+                .and_then(|len| file.set_len(len).map_err(|_| FATError::CannotWrite))
+        } else {
+            let mut target =
+                FAT::new(self.image.clone(), None).map_err(|_| CommandError::FileNotFound)?;
+            fs.convert_to_native(&mut target)
+        }
+        .map_err(|e| match e {
+            FATError::FileTooLarge => CommandError::InvalidOption,
+            FATError::ChecksumMismatch => CommandError::Corrupted,
+            _ => CommandError::CannotCreateFile,
+        })
+    }
+}
+// Otevře další obraz s1 pod jménem a1, na jeho soubory se pak odkazuje jako a1:/cesta
+// mount a1 s1
+// Možný výsledek:
+// OK
+// EXIST (jméno a1 už je použité)
+// FILE NOT FOUND (s1 neexistuje nebo ho nelze otevřít)
+// INVALID OPTION (a1 obsahuje : nebo /)
+pub struct Mount {
+    alias: String,
+    image: String,
+}
+impl Mount {
+    pub fn new(alias: String, image: String) -> Self {
+        Self { alias, image }
+    }
+}
+
+impl CommandHandler for Mount {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        if self.alias.contains([':', '/']) {
+            return Err(CommandError::InvalidOption);
+        }
+        if application.mounts.contains_key(&self.alias) {
+            return Err(CommandError::Exist);
+        }
+        if !Path::new(&self.image).is_file() {
+            return Err(CommandError::FileNotFound);
+        }
+
+        let mut fs = FAT::new(self.image.clone(), None).map_err(|_| CommandError::FileNotFound)?;
+        fs.set_user(application.file_system.user());
+        fs.set_deterministic(application.file_system.deterministic());
+        application.mounts.insert(self.alias.clone(), fs);
+        Ok(())
+    }
+}
+
+// Zapíše a zavře obraz připojený pod jménem a1
+// umount a1
+// Možný výsledek:
+// OK
+// PATH NOT FOUND (nic není připojeno jako a1)
+// CANNOT CREATE FILE (obraz nelze zapsat)
+pub struct Unmount(String);
+impl Unmount {
+    pub fn new(alias: String) -> Self {
+        Self(alias)
+    }
+}
+
+impl CommandHandler for Unmount {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let mut fs = application
+            .mounts
+            .remove(&self.0)
+            .ok_or(CommandError::PathNotFound)?;
+        fs.sync().map_err(|_| CommandError::CannotCreateFile)
+    }
+}
+// Zavře aktuální obraz a otevře obraz s1 (neexistující vytvoří, pak je třeba format)
+// open s1
+// Možný výsledek:
+// OK
+// FILE NOT FOUND (s1 nelze otevřít)
+// CORRUPTED (žurnál s1 nelze dokončit)
+// INVALID OPTION (špatné heslo šifrovaného obrazu)
+// CANNOT CREATE FILE (aktuální obraz nelze zapsat)
+// IN USE (s1 má otevřený jiný proces)
+pub struct Open(String);
+impl Open {
+    pub fn new(image: String) -> Self {
+        Self(image)
+    }
+}
+
+impl CommandHandler for Open {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        // reopening the current image keeps its lock
+        let lock = if application.image.as_ref() == Some(&self.0) {
+            None
+        } else {
+            Some(Lock::acquire(&self.0).map_err(|error| match error.kind() {
+                io::ErrorKind::WouldBlock => CommandError::InUse,
+                _ => CommandError::FileNotFound,
+            })?)
+        };
+
+        let mut fs = FAT::new(self.0.clone(), None).map_err(|_| CommandError::FileNotFound)?;
+        let recovered = fs.replay_journal().map_err(|_| CommandError::Corrupted)?;
+        if recovered > 0 {
+            sayln!(
+                application,
+                "recovered {recovered} metadata block(s) from the journal"
+            );
+        }
+        if fs.is_locked() {
+            let passphrase =
+                read_passphrase("Passphrase: ").map_err(|_| CommandError::InvalidOption)?;
+            fs.unlock(&passphrase)
+                .map_err(|_| CommandError::InvalidOption)?;
+        }
+        if fs.is_dirty() {
+            sayln!(
+                application,
+                "warning: the image was not closed cleanly, run `check` to verify it"
+            );
+        }
+
+        application
+            .file_system
+            .sync()
+            .map_err(|_| CommandError::CannotCreateFile)?;
+        fs.set_user(application.file_system.user());
+        fs.set_deterministic(application.file_system.deterministic());
+        if lock.is_some() {
+            application.lock = lock;
+        }
+        if let Some(lock) = &mut application.lock {
+            lock.refresh(&fs)
+                .map_err(|_| CommandError::CannotCreateFile)?;
+        }
+        application.file_system = fs;
+        application.image = Some(self.0.clone());
+        application.current_path = "/".to_string();
+        Ok(())
+    }
+}
+
+// Zapíše a zavře aktuální obraz, další příkazy potřebují open
+// close
+// Možný výsledek:
+// OK
+// NO IMAGE (žádný obraz není otevřený)
+// CANNOT CREATE FILE (obraz nelze zapsat)
+pub struct Close;
+impl Close {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl CommandHandler for Close {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        if application.image.is_none() {
+            return Err(CommandError::NoImage);
+        }
+        application
+            .file_system
+            .sync()
+            .map_err(|_| CommandError::CannotCreateFile)?;
+        // an empty image nothing runs on stands in until the next `open`
+        let user = application.file_system.user();
+        let deterministic = application.file_system.deterministic();
+        application.file_system = FAT::with_backend(Box::new(disk::Memory::default()))
+            .map_err(|_| CommandError::CannotCreateFile)?;
+        application.file_system.set_user(user);
+        application.file_system.set_deterministic(deterministic);
+        application.image = None;
+        application.lock = None;
+        application.current_path = "/".to_string();
+        Ok(())
+    }
+}
+
 // 13) Načte soubor z pevného disku, ve kterém budou jednotlivé příkazy, a začne je sekvenčně
 // vykonávat. Formát je 1 příkaz/1řádek
 // load s1
@@ -412,101 +1501,1973 @@ impl CommandHandler for LoadCommands {
 
     fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
         let string = read_to_string(&self.0).map_err(|_| CommandError::FileNotFound)?;
+        // a script is never stopped to ask
+        let interactive = std::mem::replace(&mut application.interactive, false);
         for line in string.lines() {
             if let Some(cmd) = get(line) {
-                println!("{line}");
-                match cmd.handle(application) {
-                    Ok(_) => println!("OK"),
-                    Err(e) => println!("{e}"),
+                sayln!(application, "{line}");
+                match execute(application, line, cmd.as_ref()) {
+                    Ok(_) => sayln!(application, "OK"),
+                    Err(e) => sayln!(application, "{e}"),
                 }
             } else {
-                println!("invalid command: {line}");
+                sayln!(application, "invalid command: {line}");
             }
         }
+        application.interactive = interactive;
 
         Ok(())
     }
 }
-// 14) Příkaz provede formát souboru, který byl zadán jako parametr při spuštění programu na
-// souborový systém dané velikosti. Pokud už soubor nějaká data obsahoval, budou přemazána.
-// Pokud soubor neexistoval, bude vytvořen.
-// format 600MB
+// Vypíše záznamy o příkazech, které měnily obraz, od nejstaršího
+// history
 // Možný výsledek:
+// 2026-10-16 09:12:44 /> mkdir a1: OK
+// 2026-10-16 09:12:51 /a1/> rm s1: FILE NOT FOUND
 // OK
-// CANNOT CREATE FILE
-pub struct Format(String);
-impl Format {
-    pub fn new(size: String) -> Self {
-        Self(size)
+pub struct History;
+impl History {
+    pub fn new() -> Self {
+        Self
     }
 }
 
-impl CommandHandler for Format {
+impl CommandHandler for History {
     type Error = CommandError;
 
     fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
-        let units = self.0.trim_start_matches(|c: char| c.is_digit(10));
-        let count = self
-            .0
-            .trim_end_matches(|c: char| c.is_alphabetic())
-            .parse::<usize>()
-            .map_err(|_| CommandError::CannotCreateFile)?;
-
-        let capacity = Unit::from_str(count, units).ok_or(CommandError::CannotCreateFile)?;
-        application
+        let records = application
             .file_system
-            .format(capacity)
-            .map_err(|_| CommandError::CannotCreateFile)
+            .history()
+            .map_err(|_| CommandError::FileNotFound)?;
+
+        for record in records {
+            sayln!(application, "{record}");
+        }
+
+        Ok(())
     }
 }
-
-pub struct Bug(String);
-impl Bug {
+// Načte soubor z pevného disku se změnami, jedna na řádek (mkdir, incp, cp, mv, rm, rmdir),
+// a provede je najednou: buď se provedou všechny, nebo žádná
+// batch s1
+// Možný výsledek:
+// OK
+// FILE NOT FOUND (není zdroj nebo soubor pro incp)
+// INVALID OPTION (neznámá změna)
+// EXIST (cíl už existuje, nic se nezměnilo)
+// NOT EMPTY (adresář není prázdný, nic se nezměnilo)
+// PATH NOT FOUND (cesta neexistuje, nic se nezměnilo)
+pub struct Batch(String);
+impl Batch {
     pub fn new(file: String) -> Self {
         Self(file)
     }
 }
 
-impl CommandHandler for Bug {
+impl CommandHandler for Batch {
     type Error = CommandError;
 
     fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
-        application
-            .file_system
-            .bug(&build_path(&application.current_path, Some(&self.0)))
-            .map_err(|_| CommandError::FileNotFound)
+        let string = read_to_string(&self.0).map_err(|_| CommandError::FileNotFound)?;
+        let path = |given: &str| build_path(&application.current_path, Some(&given.to_string()));
+        let mut ops = vec![];
+
+        for line in string.lines() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let op = match words[..] {
+                [] => continue,
+                ["mkdir", dir] => FsOp::Mkdir(path(dir)),
+                ["incp", source, dest] => FsOp::Write {
+                    path: path(dest),
+                    data: fs::read(source).map_err(|_| CommandError::FileNotFound)?,
+                },
+                ["cp", source, dest] => FsOp::Copy {
+                    source: path(source),
+                    dest: path(dest),
+                },
+                ["mv", source, dest] => FsOp::Rename {
+                    source: path(source),
+                    dest: path(dest),
+                },
+                ["rm", file] => FsOp::Remove(path(file)),
+                ["rmdir", dir] => FsOp::RemoveDir(path(dir)),
+                _ => return Err(CommandError::InvalidOption),
+            };
+            ops.push(op);
+        }
+
+        application.file_system.apply(&ops).map_err(|e| match e {
+            FATError::FileExists => CommandError::Exist,
+            FATError::DirNotEmpty => CommandError::NotEmpty,
+            _ => CommandError::PathNotFound,
+        })
     }
 }
+// 14) Příkaz provede formát souboru, který byl zadán jako parametr při spuštění programu na
+// souborový systém dané velikosti. Pokud už soubor nějaká data obsahoval, budou přemazána.
+// Pokud soubor neexistoval, bude vytvořen.
+// format 600MB
+// format 600MB --sector-size 4KB --cluster-size 16KB --fats 2 --label DATA --preallocate
+// format 600MB --compress (nové soubory se ukládají komprimovaně)
+// format 600MB --encrypt (datová oblast se šifruje, heslo se zadá na dalším řádku)
+// format 600MB --dir-index (velké adresáře dostanou hashový index jmen)
+// format -f 600MB (v interaktivním režimu se bez -f nejdřív zeptá)
+// format --label DATA 600MB (volby mohou stát i před velikostí, neznámá volba je chyba)
+// Možný výsledek:
+// OK
+// CANNOT CREATE FILE
+// CANCELLED (na otázku nepřišlo y)
+pub struct Format {
+    size: String,
+    sector_size: Option<String>,
+    cluster_size: Option<String>,
+    fats: Option<String>,
+    label: Option<String>,
+    preallocate: bool,
+    compress: bool,
+    encrypt: bool,
+    dir_index: bool,
+}
 
-pub struct Check;
-impl Check {
-    pub fn new() -> Self {
-        Self
+impl Format {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        size: String,
+        sector_size: Option<String>,
+        cluster_size: Option<String>,
+        fats: Option<String>,
+        label: Option<String>,
+        preallocate: bool,
+        compress: bool,
+        encrypt: bool,
+        dir_index: bool,
+    ) -> Self {
+        Self {
+            size,
+            sector_size,
+            cluster_size,
+            fats,
+            label,
+            preallocate,
+            compress,
+            encrypt,
+            dir_index,
+        }
     }
 }
 
-impl CommandHandler for Check {
+impl CommandHandler for Format {
     type Error = CommandError;
 
     fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let capacity = parse_unit(&self.size).ok_or(CommandError::CannotCreateFile)?;
+        let mut options = FormatOptions::default();
+
+        if let Some(sector_size) = &self.sector_size {
+            options.bytes_per_sector = parse_unit(sector_size)
+                .ok_or(CommandError::CannotCreateFile)?
+                .to_bytes() as u32;
+        }
+
+        if let Some(cluster_size) = &self.cluster_size {
+            options.cluster_size = parse_unit(cluster_size)
+                .ok_or(CommandError::CannotCreateFile)?
+                .to_bytes() as u32;
+        }
+
+        if let Some(fats) = &self.fats {
+            options.fat_count = fats.parse().map_err(|_| CommandError::CannotCreateFile)?;
+        }
+
+        if let Some(label) = &self.label {
+            options.label = label.clone();
+        }
+
+        options.preallocate = self.preallocate;
+        options.compress = self.compress;
+        options.dir_index = self.dir_index;
+
+        if self.encrypt {
+            let passphrase =
+                read_passphrase("New passphrase: ").map_err(|_| CommandError::CannotCreateFile)?;
+            if passphrase.is_empty() {
+                return Err(CommandError::CannotCreateFile);
+            }
+            options.passphrase = Some(passphrase);
+        }
+
         application
             .file_system
-            .check()
-            .map_err(|_| CommandError::FileNotFound)
+            .format(capacity, &options)
+            .map_err(|_| CommandError::CannotCreateFile)
     }
 }
 
-pub struct Exit;
-impl Exit {
-    pub fn new() -> Self {
-        Self
+// Naformátuje obraz (je-li v manifestu "format") a naplní ho podle manifestu s1 ve formátu JSON,
+// vše v uvedeném pořadí, takže stejný manifest dá vždy stejný obraz; chybějící nadřazené adresáře
+// vytvoří, cesty ke zdrojovým souborům jsou relativní k manifestu
+// build s1
+// {"format": {"size": "10MB", "cluster_size": "1KB", "label": "FIXTURE", "dir_index": true},
+//  "directories": ["docs", {"path": "private", "mode": "700", "owner": 5}],
+//  "files": [{"path": "docs/a.txt", "source": "a.txt", "compressed": true, "mode": "600",
+//             "owner": 5, "xattrs": {"mime": "text/plain"}}]}
+// Možný výsledek:
+// OK
+// FILE NOT FOUND (není manifest nebo zdrojový soubor)
+// INVALID OPTION (neplatný manifest)
+// EXIST
+// INVALID NAME
+// CANNOT CREATE FILE (nelze naformátovat, není místo)
+// NOT FORMATTED (manifest bez "format" na nenaformátovaném obrazu)
+pub struct Build(String);
+impl Build {
+    pub fn new(manifest: String) -> Self {
+        Self(manifest)
     }
 }
 
-impl CommandHandler for Exit {
+impl CommandHandler for Build {
     type Error = CommandError;
 
     fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let text = read_to_string(&self.0).map_err(|_| CommandError::FileNotFound)?;
+        let manifest = json::parse(&text).ok_or(CommandError::InvalidOption)?;
+        let base = Path::new(&self.0).parent().unwrap_or(Path::new(""));
+
+        if let Some(format) = manifest.get("format") {
+            if application.image.is_none() {
+                return Err(CommandError::NoImage);
+            }
+            let text = |key| format.get(key).and_then(Value::as_str).map(str::to_string);
+            let flag = |key| format.get(key).and_then(Value::as_bool).unwrap_or(false);
+            Format::new(
+                text("size").ok_or(CommandError::InvalidOption)?,
+                text("sector_size"),
+                text("cluster_size"),
+                format
+                    .get("fats")
+                    .and_then(Value::as_u64)
+                    .map(|fats| fats.to_string()),
+                text("label"),
+                flag("preallocate"),
+                flag("compress"),
+                false,
+                flag("dir_index"),
+            )
+            .handle(application)?;
+        }
+        if application.file_system.header().is_none() {
+            return Err(CommandError::NotFormatted);
+        }
+
+        let list = |key| match manifest.get(key) {
+            Some(Value::Array(items)) => Ok(items.as_slice()),
+            None => Ok(&[][..]),
+            Some(_) => Err(CommandError::InvalidOption),
+        };
+        let fs = &mut application.file_system;
+        let failed = |e| match e {
+            FATError::FileExists => CommandError::Exist,
+            FATError::FileNotFound | FATError::NotADirectory => CommandError::PathNotFound,
+            FATError::InvalidName | FATError::FilenameTooLong => CommandError::InvalidName,
+            FATError::PermissionDenied => CommandError::PermissionDenied,
+            _ => CommandError::CannotCreateFile,
+        };
+
+        for dir in list("directories")? {
+            let path = match dir {
+                Value::String(path) => path.as_str(),
+                _ => dir
+                    .get("path")
+                    .and_then(Value::as_str)
+                    .ok_or(CommandError::InvalidOption)?,
+            };
+            let path = path.trim_matches('/');
+            make_dirs(fs, path).map_err(failed)?;
+            set_manifest_attributes(fs, path, dir)?;
+        }
+
+        for file in list("files")? {
+            let path = file
+                .get("path")
+                .and_then(Value::as_str)
+                .ok_or(CommandError::InvalidOption)?
+                .trim_matches('/');
+            let source = file
+                .get("source")
+                .and_then(Value::as_str)
+                .ok_or(CommandError::InvalidOption)?;
+
+            make_dirs(fs, path.rsplit_once('/').map_or("", |(dir, _)| dir)).map_err(failed)?;
+            let infile = File::open(base.join(source)).map_err(|_| CommandError::FileNotFound)?;
+            fs.new_file(path, infile).map_err(failed)?;
+            if file.get("compressed").and_then(Value::as_bool) == Some(true) {
+                fs.set_compressed(path, true).map_err(failed)?;
+            }
+            set_manifest_attributes(fs, path, file)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Makes the directory `path` and any of its parents that are missing.
+fn make_dirs(fs: &mut FAT, path: &str) -> Result<(), FATError> {
+    let mut prefix = String::new();
+    for component in path.split('/').filter(|component| !component.is_empty()) {
+        if !prefix.is_empty() {
+            prefix.push('/');
+        }
+        prefix.push_str(component);
+        if fs.find_file(&prefix, FAT::filter_mkdir).is_err() {
+            fs.mkdir(&prefix)?;
+        }
+    }
+    Ok(())
+}
+
+/// Applies the `mode`, `owner` and `xattrs` of a manifest item to `path`.
+fn set_manifest_attributes(fs: &mut FAT, path: &str, item: &Value) -> Result<(), CommandError> {
+    let failed = |e| match e {
+        FATError::PermissionDenied => CommandError::PermissionDenied,
+        _ => CommandError::CannotCreateFile,
+    };
+
+    if let Some(mode) = item.get("mode") {
+        let mode = mode
+            .as_str()
+            .and_then(|mode| u16::from_str_radix(mode, 8).ok())
+            .filter(|mode| *mode <= 0o777)
+            .ok_or(CommandError::InvalidOption)?;
+        fs.chmod(path, mode).map_err(failed)?;
+    }
+    if let Some(owner) = item.get("owner") {
+        let owner = owner
+            .as_u64()
+            .and_then(|owner| u16::try_from(owner).ok())
+            .ok_or(CommandError::InvalidOption)?;
+        fs.chown(path, owner).map_err(failed)?;
+    }
+    match item.get("xattrs") {
+        Some(Value::Object(xattrs)) => {
+            for (key, value) in xattrs {
+                let value = value.as_str().ok_or(CommandError::InvalidOption)?;
+                fs.set_xattr(path, key, value).map_err(failed)?;
+            }
+        }
+        Some(_) => return Err(CommandError::InvalidOption),
+        None => {}
+    }
+    Ok(())
+}
+
+// Vypíše, nebo nastaví jmenovku svazku
+// label
+// label DATA
+// Možný výsledek:
+// DATA
+// OK
+// INVALID LABEL
+pub struct Label(Option<String>);
+impl Label {
+    pub fn new(label: Option<String>) -> Self {
+        Self(label)
+    }
+}
+
+impl CommandHandler for Label {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        match &self.0 {
+            Some(label) => application
+                .file_system
+                .set_label(label)
+                .map_err(|_| CommandError::InvalidLabel),
+            None => {
+                sayln!(
+                    application,
+                    "{}",
+                    application.file_system.label().unwrap_or_default()
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+// Vypíše informace o souborovém systému (hlavičku, počet obsazených a volných clusterů),
+// u skutečného FAT12/16/32 svazku jeho typ a geometrii
+// fsinfo
+// Možný výsledek:
+// FAT Info: ...
+// Used clusters: 1
+// Free clusters: 153599
+// FAT32 volume (read-only) ...
+// FILE NOT FOUND (obraz není naformátován)
+pub struct FileSystemInfo;
+impl FileSystemInfo {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl CommandHandler for FileSystemInfo {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        if let Some(volume) = application.file_system.foreign() {
+            say!(application, "{volume}");
+            return Ok(());
+        }
+        let info = application
+            .file_system
+            .header_info()
+            .map_err(|_| CommandError::FileNotFound)?;
+        say!(application, "{info}");
+        Ok(())
+    }
+}
+
+// Vypíše velikost datové oblasti, obsazené a volné místo
+// df
+// df --json (též -j)
+// Možný výsledek:
+// total: 629145600 B, used: 4096 B, free: 629141504 B
+// {"total":629145600,"used":4096,"free":629141504}
+// FILE NOT FOUND (obraz nelze přečíst)
+pub struct DiskFree(bool);
+impl DiskFree {
+    pub fn new(json: bool) -> Self {
+        Self(json)
+    }
+}
+
+impl CommandHandler for DiskFree {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let usage = application
+            .file_system
+            .usage()
+            .map_err(|_| CommandError::FileNotFound)?;
+
+        if self.0 {
+            sayln!(application, "{}", usage.to_json());
+        } else {
+            sayln!(application, "{usage}");
+        }
+        Ok(())
+    }
+}
+
+// Vypíše počty čtení a zápisů sektorů a FAT, zásahů cache a alokovaných a uvolněných clusterů od připojení
+// stats
+// stats --json (též -j)
+// stats --reset
+// Možný výsledek:
+// sector reads: 120
+// sector writes: 8
+// FAT reads: 4
+// FAT writes: 2
+// cache hits: 10
+// cache misses: 2
+// clusters allocated: 1
+// clusters freed: 0
+// {"sector_reads":120,"sector_writes":8,...}
+// OK (po --reset)
+pub struct Stats {
+    reset: bool,
+    json: bool,
+}
+
+impl Stats {
+    pub fn new(reset: bool, json: bool) -> Self {
+        Self { reset, json }
+    }
+}
+
+impl CommandHandler for Stats {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        if self.reset {
+            application.file_system.reset_stats();
+            return Ok(());
+        }
+
+        let stats = application.file_system.stats();
+        if self.json {
+            sayln!(application, "{}", stats.to_json());
+        } else {
+            sayln!(application, "{stats}");
+        }
+        Ok(())
+    }
+}
+
+// Vytvoří, přečte, přepíše a smaže zadaný počet souborů v pomocném adresáři .bench a vypíše propustnost jednotlivých fází
+// bench
+// bench --size 100MB --files 1000
+// Možný výsledek:
+// create:    1000 ops, 104857000 B in 1.183 s: 845.3 ops/s, 84.5 MB/s
+// read:      1000 ops, 104857000 B in 0.047 s: 21461.0 ops/s, 2146.1 MB/s
+// overwrite: 1000 ops, 104857000 B in 3.392 s: 294.8 ops/s, 29.5 MB/s
+// delete:    1000 ops, 104857000 B in 0.067 s: 14862.0 ops/s, 1486.2 MB/s
+// INVALID OPTION (neplatná velikost)
+// EXIST (adresář .bench už existuje)
+// CANNOT CREATE FILE (nedostatek místa nebo příliš mnoho souborů)
+pub struct Bench {
+    size: String,
+    files: u32,
+}
+
+impl Bench {
+    pub fn new(size: String, files: u32) -> Self {
+        Self { size, files }
+    }
+}
+
+impl CommandHandler for Bench {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let size = parse_unit(&self.size).ok_or(CommandError::InvalidOption)?;
+
+        let phases = application
+            .file_system
+            .bench(size.to_bytes() as u64, self.files)
+            .map_err(|err| match err {
+                FATError::FileExists => CommandError::Exist,
+                _ => CommandError::CannotCreateFile,
+            })?;
+
+        for phase in phases {
+            sayln!(application, "{phase}");
+        }
+        Ok(())
+    }
+}
+
+// Zvětší (nebo s --shrink zmenší) obraz na zadanou velikost, data zůstanou zachována
+// resize 1GB
+// resize --shrink 100MB
+// Možný výsledek:
+// OK
+// CANNOT CREATE FILE (neplatná velikost, data se nevejdou, nebo obraz nelze zapsat)
+pub struct Resize(String, bool);
+impl Resize {
+    pub fn new(size: String, shrink: bool) -> Self {
+        Self(size, shrink)
+    }
+}
+
+impl CommandHandler for Resize {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let capacity = parse_unit(&self.0).ok_or(CommandError::CannotCreateFile)?;
+
+        if self.1 {
+            application.file_system.shrink(capacity)
+        } else {
+            application.file_system.resize(capacity)
+        }
+        .map_err(|_| CommandError::CannotCreateFile)
+    }
+}
+
+// Zkopíruje celý obraz do souboru s1 na pevném disku, s --compact vynechá volné clustery
+// clone-image s1
+// clone-image s1 --compact
+// Možný výsledek:
+// OK
+// CANNOT CREATE FILE
+pub struct CloneImage(String, bool);
+impl CloneImage {
+    pub fn new(destination: String, compact: bool) -> Self {
+        Self(destination, compact)
+    }
+}
+
+impl CommandHandler for CloneImage {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        application
+            .file_system
+            .clone_image(&self.0, self.1)
+            .map_err(|_| CommandError::CannotCreateFile)
+    }
+}
+
+// Porovná strom obrazu s1 (otevřeného jen pro čtení) s aktuálním obrazem
+// Soubory a adresáře pouze v s1 označí +, pouze v aktuálním obrazu -, změněné ~
+// diff-image s1
+// Možný výsledek:
+// + /a1/nove.txt
+// - /stare.txt
+// ~ /zmeneny.txt
+// OK
+// FILE NOT FOUND
+pub struct DiffImage(String);
+impl DiffImage {
+    pub fn new(other: String) -> Self {
+        Self(other)
+    }
+}
+
+impl CommandHandler for DiffImage {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let mut other = FAT::open_read_only(&self.0).map_err(|_| CommandError::FileNotFound)?;
+        let differences = diff(&mut application.file_system, &mut other)
+            .map_err(|_| CommandError::PathNotFound)?;
+
+        for difference in differences {
+            sayln!(application, "{difference}");
+        }
+
+        Ok(())
+    }
+}
+
+// Porovná adresář a1 v obrazu s adresářem s1 na pevném disku podle jmen, velikostí a hashů
+// obsahu, např. pro ověření po sync nebo import-tar; cesty jsou relativní k oběma adresářům
+// Pouze v a1 označí -, pouze v s1 +, rozdílné ~
+// diff a1 s1
+// Možný výsledek:
+// + docs/novy.txt
+// - stary.txt
+// ~ docs/zmeneny.txt
+// OK
+// PATH NOT FOUND (neexistuje a1)
+// FILE NOT FOUND (neexistuje nebo nelze číst s1)
+pub struct DiffTree {
+    dir: String,
+    host: String,
+}
+impl DiffTree {
+    pub fn new(dir: String, host: String) -> Self {
+        Self { dir, host }
+    }
+}
+
+impl CommandHandler for DiffTree {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let mut dir = build_path(&application.current_path, Some(&self.dir));
+        if dir.is_empty() {
+            dir.push('.');
+        }
+        let image = application
+            .file_system
+            .fingerprints(&dir)
+            .map_err(|e| match e {
+                FATError::ChecksumMismatch => CommandError::Corrupted,
+                _ => CommandError::PathNotFound,
+            })?;
+
+        let mut host = BTreeMap::new();
+        host_fingerprints(Path::new(&self.host), "", &mut host)
+            .map_err(|_| CommandError::FileNotFound)?;
+
+        for difference in compare(&image, &host) {
+            sayln!(application, "{difference}");
+        }
+        Ok(())
+    }
+}
+
+/// Adds the fingerprints of everything below the host directory `dir` to
+/// `tree`, by path after `prefix`.
+fn host_fingerprints(
+    dir: &Path,
+    prefix: &str,
+    tree: &mut BTreeMap<String, Fingerprint>,
+) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = format!("{prefix}{}", entry.file_name().to_string_lossy());
+        if fs::metadata(entry.path())?.is_dir() {
+            host_fingerprints(&entry.path(), &format!("{name}/"), tree)?;
+            tree.insert(name, None);
+        } else {
+            let contents = fs::read(entry.path())?;
+            tree.insert(name, Some((contents.len() as u64, hash(&contents))));
+        }
+    }
+    Ok(())
+}
+
+// Ověří kontrolní součty FAT a všech použitých clusterů proti tabulce uložené v obrazu
+// verify-image
+// Možný výsledek:
+// cluster 42 is corrupted
+// OK
+// NO CHECKSUMS (obraz nemá tabulku kontrolních součtů, pomůže migrate)
+pub struct VerifyImage;
+impl VerifyImage {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl CommandHandler for VerifyImage {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let has_checksums = application
+            .file_system
+            .header()
+            .is_some_and(|header| header.has_checksums());
+        if !has_checksums {
+            return Err(CommandError::NoChecksums);
+        }
+
+        let mismatches = application
+            .file_system
+            .verify_image()
+            .map_err(|_| CommandError::PathNotFound)?;
+
+        for mismatch in mismatches {
+            sayln!(application, "{mismatch}");
+        }
+
+        Ok(())
+    }
+}
+
+// Přečte všechny datové clustery a volné, které selžou, označí ve FAT jako vadné, aby se už
+// nepřidělily; použité clustery jen vypíše (data zůstanou, řetězce opraví check --repair)
+// badblocks
+// badblocks --write (volné clustery navíc zapíše testovacími vzory, přečte zpět a vrátí
+//                    jim původní obsah)
+// Možný výsledek:
+// cluster 57 marked bad
+// cluster 12 failed, in use
+// 2558 cluster(s) tested: 1 marked bad, 1 failing in use, 0 already bad
+// OK
+// CANNOT CREATE FILE (FAT nelze zapsat)
+pub struct BadBlocks(bool);
+impl BadBlocks {
+    pub fn new(write: bool) -> Self {
+        Self(write)
+    }
+}
+
+impl CommandHandler for BadBlocks {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let report = application
+            .file_system
+            .badblocks(self.0)
+            .map_err(|_| CommandError::CannotCreateFile)?;
+
+        for cluster in &report.marked {
+            sayln!(application, "cluster {cluster} marked bad");
+        }
+        for cluster in &report.in_use {
+            sayln!(application, "cluster {cluster} failed, in use");
+        }
+        sayln!(application, "{report}");
+
+        Ok(())
+    }
+}
+
+// Projde všechny kopie FAT a použité clustery a porovná je s tabulkou kontrolních součtů
+// scrub
+// scrub --repair (poškozené sektory FAT obnoví z neporušené kopie, clustery z jiného clusteru
+//                 se stejným kontrolním součtem)
+// Možný výsledek:
+// FAT sector 0 is corrupted, repaired from FAT copy 1
+// cluster 42 is corrupted
+// 2 FAT sector(s), 120 cluster(s) scrubbed: 2 mismatch(es), 1 repaired
+// OK
+// NO CHECKSUMS
+pub struct Scrub(bool);
+impl Scrub {
+    pub fn new(repair: bool) -> Self {
+        Self(repair)
+    }
+}
+
+impl CommandHandler for Scrub {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let has_checksums = application
+            .file_system
+            .header()
+            .is_some_and(|header| header.has_checksums());
+        if !has_checksums {
+            return Err(CommandError::NoChecksums);
+        }
+
+        let report = application
+            .file_system
+            .scrub(self.0)
+            .map_err(|_| CommandError::PathNotFound)?;
+
+        for scrubbed in &report.found {
+            sayln!(application, "{scrubbed}");
+        }
+        sayln!(application, "{report}");
+
+        Ok(())
+    }
+}
+
+// Vypíše počet souvislých úseků (extentů) každého souboru v a1, bez parametru celého obrazu,
+// a souhrn fragmentace včetně největšího souvislého volného místa
+// frag a1
+// frag
+// Možný výsledek:
+// /a1/s1: 2 extent(s), 5 cluster(s)
+// 1 file(s), 2 extent(s), 100.0% fragmented
+// free: 2500 cluster(s), largest free run: 2400 cluster(s)
+// OK
+// PATH NOT FOUND
+pub struct Fragmentation(Option<String>);
+impl Fragmentation {
+    pub fn new(path: Option<String>) -> Self {
+        Self(path)
+    }
+}
+
+impl CommandHandler for Fragmentation {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let path = build_path(&application.current_path, self.0.as_ref());
+        let report = application
+            .file_system
+            .fragmentation(&path)
+            .map_err(|_| CommandError::PathNotFound)?;
+
+        for file in report.files.iter() {
+            sayln!(application, "{file}");
+        }
+        sayln!(application, "{report}");
+
+        Ok(())
+    }
+}
+
+// Přesune fragmentované soubory do souvislých úseků volných clusterů, adresáře nechá na místě
+// defrag
+// defrag --dry-run (jen vypíše, kolik by se přesunulo)
+// Možný výsledek:
+// 3 file(s), 42 cluster(s) moved, 0 file(s) skipped
+// OK
+pub struct Defragment(bool);
+impl Defragment {
+    pub fn new(dry_run: bool) -> Self {
+        Self(dry_run)
+    }
+}
+
+impl CommandHandler for Defragment {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let report = application
+            .file_system
+            .defrag(self.0)
+            .map_err(|_| CommandError::CannotCreateFile)?;
+
+        sayln!(application, "{report}");
+        Ok(())
+    }
+}
+
+// Sesune položky adresáře a1 k sobě a uvolní clustery, které na konci zůstaly prázdné;
+// smazané položky tím zmizí a nejdou obnovit přes undelete
+// compactdir a1
+// Možný výsledek:
+// 2 cluster(s) freed
+// OK
+// PATH NOT FOUND (neexistující adresář)
+pub struct CompactDirectory(String);
+impl CompactDirectory {
+    pub fn new(dirname: String) -> Self {
+        Self(dirname)
+    }
+}
+
+impl CommandHandler for CompactDirectory {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let freed = application
+            .file_system
+            .compact_dir(&build_path(&application.current_path, Some(&self.0)))
+            .map_err(|_| CommandError::PathNotFound)?;
+
+        sayln!(application, "{freed} cluster(s) freed");
+        Ok(())
+    }
+}
+
+// Najde soubory se stejným obsahem a nechá je sdílet jeden řetězec clusterů
+// dedup
+// Možný výsledek:
+// 2 file(s) deduplicated, 24 cluster(s) reclaimed
+// OK
+// NO REFLINKS (obraz nemá tabulku počtu odkazů, viz tune reflink on)
+pub struct Dedup;
+impl Dedup {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl CommandHandler for Dedup {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let has_refcounts = application
+            .file_system
+            .header()
+            .is_some_and(|header| header.has_refcounts());
+        if !has_refcounts {
+            return Err(CommandError::NoReflinks);
+        }
+
+        let report = application
+            .file_system
+            .dedup()
+            .map_err(|_| CommandError::CannotCreateFile)?;
+
+        sayln!(application, "{report}");
+        Ok(())
+    }
+}
+
+// Vypíše, nebo změní nastavení připojeného obrazu (platí do konce sezení)
+// tune
+// tune alloc first-fit|next-fit|best-fit
+// tune discard on|off (uvolněné clustery přepíše nulami)
+// tune versions 3 (počet uchovaných verzí souboru, 0 vypne verzování)
+// tune reflink on (přidá tabulku počtu odkazů pro cp --reflink, trvale)
+// tune verify on|off (každé čtení ověří proti tabulce kontrolních součtů)
+// tune journal on (přidá žurnál, přes který se atomicky zapisují metadata, trvale)
+// tune dir-index on (velké adresáře dostanou hashový index jmen, trvale)
+// tune deterministic on|off (stejné příkazy dají bajtově stejný obraz: nulové UUID a časy, first-fit, discard)
+// Možný výsledek:
+// alloc: first-fit
+// discard: off
+// versions: 0
+// reflink: on
+// journal: on
+// dir-index: off
+// verify: off
+// deterministic: off
+// OK
+// INVALID OPTION
+pub struct Tune(Option<(String, String)>);
+impl Tune {
+    pub fn new(setting: Option<(String, String)>) -> Self {
+        Self(setting)
+    }
+}
+
+impl CommandHandler for Tune {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        match &self.0 {
+            None => {
+                sayln!(
+                    application,
+                    "alloc: {}",
+                    application.file_system.alloc_strategy()
+                );
+                let discard = if application.file_system.discard() {
+                    "on"
+                } else {
+                    "off"
+                };
+                sayln!(application, "discard: {discard}");
+                sayln!(
+                    application,
+                    "versions: {}",
+                    application.file_system.max_versions()
+                );
+                let reflink = match application.file_system.header() {
+                    Some(header) if header.has_refcounts() => "on",
+                    _ => "off",
+                };
+                sayln!(application, "reflink: {reflink}");
+                let journal = match application.file_system.header() {
+                    Some(header) if header.has_journal() => "on",
+                    _ => "off",
+                };
+                sayln!(application, "journal: {journal}");
+                let dir_index = match application.file_system.header() {
+                    Some(header) if header.has_dir_index() => "on",
+                    _ => "off",
+                };
+                sayln!(application, "dir-index: {dir_index}");
+                let verify = if application.file_system.verify() {
+                    "on"
+                } else {
+                    "off"
+                };
+                sayln!(application, "verify: {verify}");
+                let deterministic = if application.file_system.deterministic() {
+                    "on"
+                } else {
+                    "off"
+                };
+                sayln!(application, "deterministic: {deterministic}");
+                Ok(())
+            }
+            Some((name, value)) if name == "reflink" && value == "on" => application
+                .file_system
+                .enable_reflinks()
+                .map_err(|_| CommandError::CannotCreateFile),
+            Some((name, value)) if name == "journal" && value == "on" => application
+                .file_system
+                .enable_journal()
+                .map_err(|_| CommandError::CannotCreateFile),
+            Some((name, value)) if name == "dir-index" && value == "on" => application
+                .file_system
+                .enable_dir_index()
+                .map_err(|_| CommandError::CannotCreateFile),
+            Some((name, value)) if name == "versions" => {
+                let max_versions = value.parse().map_err(|_| CommandError::InvalidOption)?;
+                application.file_system.set_max_versions(max_versions);
+                Ok(())
+            }
+            Some((name, value)) if name == "discard" => {
+                let discard = match value.as_str() {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(CommandError::InvalidOption),
+                };
+                application.file_system.set_discard(discard);
+                Ok(())
+            }
+            Some((name, value)) if name == "verify" => {
+                let verify = match value.as_str() {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(CommandError::InvalidOption),
+                };
+                application.file_system.set_verify(verify);
+                Ok(())
+            }
+            Some((name, value)) if name == "deterministic" => {
+                let deterministic = match value.as_str() {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(CommandError::InvalidOption),
+                };
+                application.file_system.set_deterministic(deterministic);
+                Ok(())
+            }
+            Some((name, value)) if name == "alloc" => {
+                let strategy =
+                    AllocStrategy::from_name(value).ok_or(CommandError::InvalidOption)?;
+                application.file_system.set_alloc_strategy(strategy);
+                Ok(())
+            }
+            Some(_) => Err(CommandError::InvalidOption),
+        }
+    }
+}
+
+pub enum SnapshotAction {
+    List,
+    Create(String),
+    Restore(String),
+    Delete(String),
+}
+
+// Uloží stav celého FS (FAT a adresáře) pod jménem n, vrátí FS do uloženého
+// stavu, nebo snímek smaže; clustery používané snímkem se znovu nepřidělí
+// snapshot list
+// snapshot create n
+// snapshot restore n
+// snapshot delete n
+// Možný výsledek:
+// OK
+// EXIST (snímek už existuje)
+// FILE NOT FOUND (snímek neexistuje)
+// INVALID OPTION (snímek patří jinému obrazu nebo rozložení)
+pub struct Snapshots(SnapshotAction);
+impl Snapshots {
+    pub fn new(action: SnapshotAction) -> Self {
+        Self(action)
+    }
+}
+
+impl CommandHandler for Snapshots {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let file_system = &mut application.file_system;
+        let result = match &self.0 {
+            SnapshotAction::List => file_system.list_snapshots().map(|names| {
+                for name in names {
+                    sayln!(application, "{name}");
+                }
+            }),
+            SnapshotAction::Create(name) => file_system.create_snapshot(name),
+            SnapshotAction::Restore(name) => {
+                application.current_path = "/".to_string();
+                application.file_system.restore_snapshot(name)
+            }
+            SnapshotAction::Delete(name) => file_system.delete_snapshot(name),
+        };
+
+        result.map_err(|e| match e {
+            FATError::FileExists => CommandError::Exist,
+            FATError::SnapshotMismatch => CommandError::InvalidOption,
+            FATError::FileNotFound => CommandError::FileNotFound,
+            _ => CommandError::CannotCreateFile,
+        })
+    }
+}
+
+// Zapne (+c), nebo vypne (-c) kompresi souboru s1; soubor, který by se
+// kompresí nezmenšil, zůstane nekomprimovaný
+// attr +c s1
+// attr -c s1
+// Možný výsledek:
+// OK
+// FILE NOT FOUND
+// INVALID OPTION
+pub struct Attribute(String, String);
+impl Attribute {
+    pub fn new(attribute: String, file: String) -> Self {
+        Self(attribute, file)
+    }
+}
+
+impl CommandHandler for Attribute {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let compressed = match self.0.as_str() {
+            "+c" => true,
+            "-c" => false,
+            _ => return Err(CommandError::InvalidOption),
+        };
+
+        application
+            .file_system
+            .set_compressed(
+                &build_path(&application.current_path, Some(&self.1)),
+                compressed,
+            )
+            .map_err(|_| CommandError::FileNotFound)
+    }
+}
+
+// Vytvoří soubor s1 zadané velikosti s pseudonáhodným obsahem, se stejným --seed vždy se stejným
+// mkrandom s1 1GB
+// mkrandom s1 4096 --seed 42
+// Možný výsledek:
+// OK
+// EXIST
+// PATH NOT FOUND
+// INVALID OPTION (neplatná velikost nebo seed)
+// CANNOT CREATE FILE (není místo)
+pub struct MakeRandom {
+    file: String,
+    size: String,
+    seed: Option<String>,
+}
+
+impl MakeRandom {
+    pub fn new(file: String, size: String, seed: Option<String>) -> Self {
+        Self { file, size, seed }
+    }
+}
+
+impl CommandHandler for MakeRandom {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let size = parse_size(&self.size).ok_or(CommandError::InvalidOption)?;
+        let seed = match &self.seed {
+            Some(seed) => seed.parse().map_err(|_| CommandError::InvalidOption)?,
+            None => 0,
+        };
+
+        application
+            .file_system
+            .mkrandom(
+                &build_path(&application.current_path, Some(&self.file)),
+                size,
+                seed,
+            )
+            .map_err(|e| match e {
+                FATError::FileExists => CommandError::Exist,
+                FATError::FileNotFound => CommandError::PathNotFound,
+                _ => CommandError::CannotCreateFile,
+            })
+    }
+}
+
+// Zkrátí soubor s1 na danou velikost, nebo ho prodlouží dírou, která nezabírá žádné clustery
+// truncate s1 10MB
+// truncate s1 1500
+// Možný výsledek:
+// OK
+// FILE NOT FOUND
+// INVALID OPTION (neplatná velikost)
+// CANNOT CREATE FILE (není místo, nebo soubor nad 4 GB na obrazu starším než verze 3)
+// PERMISSION DENIED (do souboru nelze zapisovat)
+pub struct Truncate(String, String);
+impl Truncate {
+    pub fn new(file: String, size: String) -> Self {
+        Self(file, size)
+    }
+}
+
+impl CommandHandler for Truncate {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let size = parse_size(&self.1).ok_or(CommandError::InvalidOption)?;
+
+        application
+            .file_system
+            .truncate(&build_path(&application.current_path, Some(&self.0)), size)
+            .map_err(|e| match e {
+                FATError::FileNotFound => CommandError::FileNotFound,
+                FATError::PermissionDenied => CommandError::PermissionDenied,
+                _ => CommandError::CannotCreateFile,
+            })
+    }
+}
+
+// Zapíše text do souboru s1 od daného bajtu; místo mezi koncem souboru a tímto bajtem
+// zůstane dírou, pro kterou se nepřidělí žádné clustery
+// write s1 4096 text
+// Možný výsledek:
+// OK
+// FILE NOT FOUND
+// INVALID OPTION (neplatný posun)
+// CANNOT CREATE FILE (není místo)
+// PERMISSION DENIED (do souboru nelze zapisovat)
+pub struct WriteAt(String, String, String);
+impl WriteAt {
+    pub fn new(file: String, offset: String, text: String) -> Self {
+        Self(file, offset, text)
+    }
+}
+
+impl CommandHandler for WriteAt {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let offset = self
+            .1
+            .parse::<u64>()
+            .map_err(|_| CommandError::InvalidOption)?;
+
+        application
+            .file_system
+            .write_at(
+                &build_path(&application.current_path, Some(&self.0)),
+                offset,
+                self.2.as_bytes(),
+            )
+            .map_err(|e| match e {
+                FATError::FileNotFound => CommandError::FileNotFound,
+                FATError::PermissionDenied => CommandError::PermissionDenied,
+                _ => CommandError::CannotCreateFile,
+            })
+    }
+}
+
+pub enum XattrAction {
+    List,
+    Get(String),
+    Set(String, String),
+    Remove(String),
+}
+
+// Vypíše, přečte, nastaví nebo smaže rozšířený atribut (klíč a krátkou hodnotu) souboru
+// nebo adresáře s1
+// xattr list s1
+// xattr get s1 mime
+// xattr set s1 mime text/plain
+// xattr rm s1 mime
+// Možný výsledek:
+// mime=text/plain
+// OK
+// FILE NOT FOUND (neexistuje soubor nebo atribut)
+// INVALID OPTION (prázdný nebo příliš dlouhý klíč)
+// CANNOT CREATE FILE (atributy se nevejdou do clusteru)
+pub struct Xattr(String, XattrAction);
+impl Xattr {
+    pub fn new(path: String, action: XattrAction) -> Self {
+        Self(path, action)
+    }
+}
+
+impl CommandHandler for Xattr {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let path = build_path(&application.current_path, Some(&self.0));
+        let file_system = &mut application.file_system;
+        let result = match &self.1 {
+            XattrAction::List => file_system.list_xattrs(&path).map(|attributes| {
+                for (key, value) in attributes {
+                    sayln!(application, "{key}={value}");
+                }
+            }),
+            XattrAction::Get(key) => match file_system.get_xattr(&path, key) {
+                Ok(Some(value)) => {
+                    sayln!(application, "{value}");
+                    Ok(())
+                }
+                Ok(None) => Err(FATError::FileNotFound),
+                Err(e) => Err(e),
+            },
+            XattrAction::Set(key, value) => file_system.set_xattr(&path, key, value),
+            XattrAction::Remove(key) => match file_system.remove_xattr(&path, key) {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(FATError::FileNotFound),
+                Err(e) => Err(e),
+            },
+        };
+
+        result.map_err(|e| match e {
+            FATError::FileNotFound => CommandError::FileNotFound,
+            FATError::FilenameTooLong => CommandError::InvalidOption,
+            _ => CommandError::CannotCreateFile,
+        })
+    }
+}
+
+// Předá soubor nebo adresář s1 uživateli 1000; smí jen root (bez --user, nebo --user 0)
+// chown 1000 s1
+// Možný výsledek:
+// OK
+// FILE NOT FOUND
+// INVALID OPTION (číslo uživatele není 0 až 65535)
+// PROTECTED (. , .. nebo systémová položka)
+// PERMISSION DENIED (nejste root)
+pub struct ChangeOwner(String, String);
+impl ChangeOwner {
+    pub fn new(owner: String, path: String) -> Self {
+        Self(owner, path)
+    }
+}
+
+impl CommandHandler for ChangeOwner {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let owner = self
+            .0
+            .parse::<u16>()
+            .map_err(|_| CommandError::InvalidOption)?;
+
+        application
+            .file_system
+            .chown(&build_path(&application.current_path, Some(&self.1)), owner)
+            .map_err(|e| match e {
+                FATError::Protected => CommandError::Protected,
+                FATError::PermissionDenied => CommandError::PermissionDenied,
+                _ => CommandError::FileNotFound,
+            })
+    }
+}
+
+// Nastaví práva souboru nebo adresáře s1 osmičkově jako rwx pro vlastníka, skupinu
+// a ostatní; skupina se nekontroluje. Položka bez vlastníka připadne tomu, kdo práva mění
+// chmod 640 s1
+// Možný výsledek:
+// OK
+// FILE NOT FOUND
+// INVALID OPTION (práva nejsou osmičkové číslo do 777)
+// PROTECTED (. , .. nebo systémová položka)
+// PERMISSION DENIED (nejste vlastník ani root)
+pub struct ChangeMode(String, String);
+impl ChangeMode {
+    pub fn new(mode: String, path: String) -> Self {
+        Self(mode, path)
+    }
+}
+
+impl CommandHandler for ChangeMode {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let mode = u16::from_str_radix(&self.0, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o777)
+            .ok_or(CommandError::InvalidOption)?;
+
+        application
+            .file_system
+            .chmod(&build_path(&application.current_path, Some(&self.1)), mode)
+            .map_err(|e| match e {
+                FATError::Protected => CommandError::Protected,
+                FATError::PermissionDenied => CommandError::PermissionDenied,
+                _ => CommandError::FileNotFound,
+            })
+    }
+}
+
+// Vypíše uchované verze souboru s1
+// versions s1
+// Možný výsledek:
+// 1: 1024 byte(s)
+// 2: 2048 byte(s)
+// OK
+// FILE NOT FOUND
+pub struct Versions(String);
+impl Versions {
+    pub fn new(file: String) -> Self {
+        Self(file)
+    }
+}
+
+impl CommandHandler for Versions {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let versions = application
+            .file_system
+            .versions(&build_path(&application.current_path, Some(&self.0)))
+            .map_err(|_| CommandError::FileNotFound)?;
+
+        for version in versions {
+            sayln!(application, "{version}");
+        }
+
+        Ok(())
+    }
+}
+
+// Nahradí soubor s1 jeho verzí n (současný obsah se uchová jako nová verze)
+// revert s1 n
+// Možný výsledek:
+// OK
+// FILE NOT FOUND (verze neexistuje)
+pub struct Revert(String, String);
+impl Revert {
+    pub fn new(file: String, number: String) -> Self {
+        Self(file, number)
+    }
+}
+
+impl CommandHandler for Revert {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let number = self.1.parse().map_err(|_| CommandError::FileNotFound)?;
+
+        application
+            .file_system
+            .revert(
+                &build_path(&application.current_path, Some(&self.0)),
+                number,
+            )
+            .map_err(|_| CommandError::FileNotFound)
+    }
+}
+
+// Vypíše smazané soubory v adresáři a1, jejichž data jsou stále na disku,
+// nebo obnoví soubor s1
+// undelete a1
+// undelete a1 s1
+// Možný výsledek:
+// s1: 1024 byte(s), 1 cluster(s)
+// OK
+// FILE NOT FOUND (nelze obnovit)
+// EXIST (soubor stejného jména už existuje)
+// PATH NOT FOUND (neexistuje adresář)
+pub struct Undelete(String, Option<String>);
+impl Undelete {
+    pub fn new(dirname: String, filename: Option<String>) -> Self {
+        Self(dirname, filename)
+    }
+}
+
+impl CommandHandler for Undelete {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let mut dir = build_path(&application.current_path, Some(&self.0));
+        if dir.ends_with('/') || dir.is_empty() {
+            dir.push('.');
+        }
+
+        if application
+            .file_system
+            .find_file(&dir, FAT::filter_mkdir)
+            .is_err()
+        {
+            return Err(CommandError::PathNotFound);
+        }
+
+        let Some(name) = &self.1 else {
+            let deleted = application
+                .file_system
+                .deleted_files(&dir)
+                .map_err(|_| CommandError::PathNotFound)?;
+
+            for file in deleted {
+                sayln!(application, "{file}");
+            }
+
+            return Ok(());
+        };
+
+        application
+            .file_system
+            .undelete(&dir, name)
+            .map_err(|e| match e {
+                FATError::FileExists => CommandError::Exist,
+                _ => CommandError::FileNotFound,
+            })
+    }
+}
+// Přepíše nulami všechny volné clustery, ve kterých zůstala data
+// trim
+// wipe-free (totéž, před distribucí obrazu)
+// Možný výsledek:
+// 12 cluster(s) zeroed
+// OK
+pub struct Trim;
+impl Trim {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl CommandHandler for Trim {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let trimmed = application
+            .file_system
+            .trim()
+            .map_err(|_| CommandError::CannotCreateFile)?;
+
+        sayln!(application, "{trimmed} cluster(s) zeroed");
+        Ok(())
+    }
+}
+
+// Převede obraz vytvořený starší verzí programu na aktuální formát
+// migrate
+// Možný výsledek:
+// migrated from version 0 to 1
+// OK
+// CANNOT CREATE FILE (hlavičku nelze zapsat)
+pub struct Migrate;
+impl Migrate {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl CommandHandler for Migrate {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let old_version = application
+            .file_system
+            .migrate()
+            .map_err(|_| CommandError::CannotCreateFile)?;
+
+        if old_version == VERSION {
+            sayln!(application, "already at version {VERSION}");
+        } else {
+            sayln!(
+                application,
+                "migrated from version {old_version} to {VERSION}"
+            );
+        }
+
+        Ok(())
+    }
+}
+
+// Úmyslně poškodí soubor s1, aby bylo co hledat příkazem check
+// bug s1 / bug cycle s1 (poslední cluster odkáže zpět na první)
+// bug bad s1 (prostřední cluster řetězce označí jako vadný)
+// bug orphan s1 (odpojí řetězec od položky adresáře)
+// bug size s1 n (přepíše velikost souboru na n)
+// Možný výsledek:
+// OK
+// FILE NOT FOUND
+pub struct Bug(String, Corruption);
+impl Bug {
+    pub fn new(file: String, corruption: Corruption) -> Self {
+        Self(file, corruption)
+    }
+}
+
+impl CommandHandler for Bug {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        application
+            .file_system
+            .bug(
+                &build_path(&application.current_path, Some(&self.0)),
+                self.1,
+            )
+            .map_err(|_| CommandError::FileNotFound)
+    }
+}
+
+pub enum RawAction {
+    /// Dumps the block, or saves it to the host file.
+    Read(Option<String>),
+    /// Overwrites the start of the block with the host file.
+    Write(String),
+}
+
+// NEBEZPEČNÉ: přečte nebo přepíše sektor či cluster obrazu přímo, bez kontrolních součtů,
+// šifrování a jakékoli kontroly konzistence (ladění a ruční opravy obrazu)
+// raw read sector 0 (vypíše sektor šestnáctkově)
+// raw read cluster 12 s1 (uloží cluster do souboru s1 na disku)
+// raw write cluster 12 s1 (přepíše začátek clusteru obsahem souboru s1 na disku; zbytek zůstane)
+// raw write sector 40 s1 (sektory hlavičky přepsat nelze)
+// Možný výsledek:
+// 00000000  00 02 00 00 08 00 00 00  00 50 00 00 02 00 00 00  |.........P......|
+// OK
+// INVALID OPTION (blok mimo obraz, nebo soubor větší než blok)
+// FILE NOT FOUND (není zdroj na disku)
+// PROTECTED (sektor hlavičky)
+// PERMISSION DENIED (jen root)
+// READ ONLY (skutečný svazek FAT)
+pub struct Raw {
+    action: RawAction,
+    block: Block,
+}
+impl Raw {
+    pub fn new(action: RawAction, block: Block) -> Self {
+        Self { action, block }
+    }
+}
+
+impl CommandHandler for Raw {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let map = |e| match e {
+            FATError::Protected => CommandError::Protected,
+            FATError::PermissionDenied => CommandError::PermissionDenied,
+            _ => CommandError::InvalidOption,
+        };
+
+        match &self.action {
+            RawAction::Read(file) => {
+                let bytes = application.file_system.raw_read(self.block).map_err(map)?;
+                match file {
+                    Some(file) => {
+                        fs::write(file, bytes).map_err(|_| CommandError::CannotCreateFile)
+                    }
+                    None => {
+                        for (line, chunk) in bytes.chunks(16).enumerate() {
+                            let hex: Vec<_> =
+                                chunk.iter().map(|byte| format!("{byte:02x}")).collect();
+                            let text: String = chunk
+                                .iter()
+                                .map(|byte| match byte {
+                                    0x20..=0x7e => *byte as char,
+                                    _ => '.',
+                                })
+                                .collect();
+                            sayln!(
+                                application,
+                                "{:08x}  {:<23}  {:<23}  |{text}|",
+                                line * 16,
+                                hex[..hex.len().min(8)].join(" "),
+                                hex[hex.len().min(8)..].join(" ")
+                            );
+                        }
+                        Ok(())
+                    }
+                }
+            }
+            RawAction::Write(file) => {
+                let data = fs::read(file).map_err(|_| CommandError::FileNotFound)?;
+                application
+                    .file_system
+                    .raw_write(self.block, &data)
+                    .map_err(map)
+            }
+        }
+    }
+}
+
+// Vypíše položky FAT od clusteru start (výchozí 1), nejvýše count (výchozí 256), i s jejich významem
+// dumpfat
+// dumpfat 12 4
+// dumpfat 12 4 --raw (jen hodnoty šestnáctkově, osm na řádek)
+// Možný výsledek:
+// cluster  value     meaning
+//      12  0000000d  chain→13
+//      13  ffffffff  EOF
+//      14  00000000  FREE
+//      15  fffffffe  BAD
+// OK
+// 12: 0000000d ffffffff 00000000 fffffffe
+// OK
+// INVALID OPTION (neplatné číslo, nebo start mimo datovou oblast)
+// CORRUPTED (sektor FAT nelze přečíst)
+pub struct DumpFat {
+    start: Option<String>,
+    count: Option<String>,
+    raw: bool,
+}
+impl DumpFat {
+    pub fn new(start: Option<String>, count: Option<String>, raw: bool) -> Self {
+        Self { start, count, raw }
+    }
+}
+
+impl CommandHandler for DumpFat {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let parse = |number: &Option<String>, default| match number {
+            Some(number) => number.parse().map_err(|_| CommandError::InvalidOption),
+            None => Ok(default),
+        };
+        let start = parse(&self.start, 1)?;
+        let count = parse(&self.count, 256)?;
+
+        let entries = application
+            .file_system
+            .fat_entries(start, count)
+            .map_err(|_| CommandError::Corrupted)?;
+        if entries.is_empty() && count > 0 {
+            return Err(CommandError::InvalidOption);
+        }
+
+        if self.raw {
+            for line in entries.chunks(8) {
+                let values: Vec<_> = line
+                    .iter()
+                    .map(|(_, value)| format!("{value:08x}"))
+                    .collect();
+                sayln!(application, "{}: {}", line[0].0, values.join(" "));
+            }
+            return Ok(());
+        }
+
+        sayln!(application, "cluster  value     meaning");
+        for (cluster, value) in entries {
+            let link = application.file_system.link(value);
+            sayln!(application, "{cluster:>7}  {value:08x}  {link}");
+        }
+        Ok(())
+    }
+}
+
+// Vypíše všechny 32bajtové položky v clusterech adresáře a1, i volné a smazané, s dekódovanými poli
+// dumpdir a1
+// Možný výsledek:
+// 5:0   live     name="." size=0 cluster=5 flags=0x00000007 (occupied, directory, system) xattrs=0
+// 5:2   deleted  name="s1" size=12 cluster=7 flags=0x00000000 () xattrs=0
+// 5:3   free
+// 5:4   garbled  ff fe 41 ...
+// OK
+// PATH NOT FOUND
+pub struct DumpDirectory(String);
+impl DumpDirectory {
+    pub fn new(path: String) -> Self {
+        Self(path)
+    }
+}
+
+impl CommandHandler for DumpDirectory {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let slots = application
+            .file_system
+            .dump_dir(&build_path(&application.current_path, Some(&self.0)))
+            .map_err(|e| match e {
+                FATError::ChecksumMismatch | FATError::CannotRead => CommandError::Corrupted,
+                _ => CommandError::PathNotFound,
+            })?;
+
+        for slot in slots {
+            sayln!(application, "{slot}");
+        }
+        Ok(())
+    }
+}
+
+pub enum MetaAction {
+    Dump,
+    /// Compares the tree with the dump in the host file.
+    Diff(String),
+}
+
+// Vypíše metadata celého stromu (cesty, velikosti, příznaky, řetězce clusterů) jako JSON pole
+// s jednou položkou na řádek, nebo je porovná s dříve uloženým výpisem s1 na pevném disku
+// Pouze v s1 označí -, pouze v aktuálním obrazu +, změněné ~ s tím, co se změnilo
+// meta dump > pred.json
+// meta diff pred.json
+// Možný výsledek:
+// [
+// {"path":"/","size":0,"flags":3,"chain":[1]},
+// {"path":"/s1","size":5,"flags":1,"chain":[7]}
+// ]
+// + /a1
+// ~ /s1: size 5 → 9000, chain 7 → 7,8,9
+// OK
+// FILE NOT FOUND (s1 nelze přečíst)
+// INVALID OPTION (s1 není výpis meta dump)
+pub struct Metadata(MetaAction);
+impl Metadata {
+    pub fn new(action: MetaAction) -> Self {
+        Self(action)
+    }
+}
+
+impl CommandHandler for Metadata {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let entries = application
+            .file_system
+            .meta()
+            .map_err(|_| CommandError::Corrupted)?;
+
+        match &self.0 {
+            MetaAction::Dump => say!(application, "{}", meta::to_json(&entries)),
+            MetaAction::Diff(file) => {
+                let text = read_to_string(file).map_err(|_| CommandError::FileNotFound)?;
+                let old = meta::from_json(&text).ok_or(CommandError::InvalidOption)?;
+                for change in meta::compare_meta(&old, &entries) {
+                    sayln!(application, "{change}");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// check
+// check --use-mirror (obnoví poškozené sektory primární FAT ze zrcadla)
+// check --repair (zkrátí řetězce před vadnými clustery a cykly, vynuluje velikosti adresářů,
+//                 souborům se sdílenými clustery zkopíruje sdílený konec řetězce,
+//                 velikost souboru nesedící s délkou řetězce nastaví podle řetězce,
+//                 nedosažitelné řetězce připojí jako /lost+found/FOUND.000, ...)
+// check --json (též -j; nálezy vypíše jako JSON pole bez stromu adresářů)
+// Po stromu a nálezech vypíše souhrn, v neinteraktivním režimu určí návratový kód programu
+// (0 bez chyb, 1 vše opraveno, 4 zůstaly chyby)
+// Možný výsledek:
+// /a1/s1: chain runs into bad cluster 13
+// repaired: /a1/s1: chain truncated at cluster 12
+// 3 file(s), 2 directories: 1 error(s), 0 warning(s), 1 repaired
+// OK
+// [{"path":"/a1/s1","kind":"bad_cluster","clusters":[13],"message":"...","repaired":true}]
+// OK
+pub struct Check {
+    options: CheckOptions,
+    json: bool,
+}
+impl Check {
+    pub fn new(use_mirror: bool, repair: bool, json: bool) -> Self {
+        Self {
+            options: CheckOptions { repair, use_mirror },
+            json,
+        }
+    }
+}
+
+impl CommandHandler for Check {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        let report = application
+            .file_system
+            .check(self.options)
+            .map_err(|_| CommandError::FileNotFound)?;
+        application.set_status(report.exit_code());
+
+        if self.json {
+            let findings = report
+                .findings
+                .iter()
+                .map(Finding::to_json)
+                .collect::<Vec<_>>();
+            sayln!(application, "[{}]", findings.join(","));
+            return Ok(());
+        }
+
+        for (depth, name) in report.tree.iter() {
+            sayln!(application, "{}{name}", "\t".repeat(*depth));
+        }
+
+        for finding in report.findings.iter() {
+            sayln!(application, "{finding}");
+            if let Some(repair) = &finding.repair {
+                sayln!(application, "repaired: {repair}");
+            }
+        }
+
+        sayln!(application, "{report}");
+        Ok(())
+    }
+}
+
+// Zapíše rozpracované změny a označí obraz jako konzistentní
+// sync
+// Možný výsledek:
+// OK
+// CANNOT CREATE FILE (obraz nelze zapsat)
+pub struct Sync;
+impl Sync {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl CommandHandler for Sync {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        application
+            .file_system
+            .sync()
+            .map_err(|_| CommandError::CannotCreateFile)
+    }
+}
+
+pub struct Exit;
+impl Exit {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl CommandHandler for Exit {
+    type Error = CommandError;
+
+    fn handle(&self, application: &mut Application) -> Result<(), Self::Error> {
+        for fs in application
+            .mounts
+            .values_mut()
+            .chain([&mut application.file_system])
+        {
+            fs.sync().map_err(|_| CommandError::CannotCreateFile)?;
+        }
         application.quit();
         Ok(())
     }
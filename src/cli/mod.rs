@@ -1,31 +1,545 @@
-use self::command::*;
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    mem,
+};
+
+use crate::{
+    fat::{header::Header, history::Record, raw::Block, Corruption},
+    time, Application, Output,
+};
+
+use self::{command::*, pipe::Filter};
+
+/// Prints command output to wherever the application currently sends it.
+macro_rules! say {
+    ($application:expr, $($arg:tt)*) => {{
+        // like `print!`, output that cannot be written is not the command's failure
+        let _ = std::io::Write::write_fmt(&mut $application.output, format_args!($($arg)*));
+    }};
+}
+
+/// Like `say!`, with a newline after the output.
+macro_rules! sayln {
+    ($application:expr, $($arg:tt)*) => {
+        say!($application, "{}\n", format_args!($($arg)*))
+    };
+}
 
 mod command;
+mod pipe;
+
+/// Whether informational output should be JSON rather than text.
+fn json(words: &[&str]) -> bool {
+    words.contains(&"--json") || words.contains(&"-j")
+}
+
+fn option(words: &[&str], name: &str) -> Option<String> {
+    let position = words.iter().position(|word| *word == name)?;
+    words.get(position + 1).map(|s| s.to_string())
+}
+
+/// The capacity `format` is given, wherever it stands among the options;
+/// `None` for an unknown option or anything but a single capacity.
+fn format_size(words: &[&str]) -> Option<String> {
+    const VALUED: [&str; 4] = ["--sector-size", "--cluster-size", "--fats", "--label"];
+    const FLAGS: [&str; 5] = [
+        "-f",
+        "--preallocate",
+        "--compress",
+        "--encrypt",
+        "--dir-index",
+    ];
+
+    let mut size = None;
+    let mut rest = words.iter().skip(1);
+    while let Some(word) = rest.next() {
+        if VALUED.contains(word) {
+            rest.next()?;
+        } else if !FLAGS.contains(word) {
+            if size.is_some() || word.starts_with('-') {
+                return None;
+            }
+            size = Some(word.to_string());
+        }
+    }
+    size
+}
+
+/// Parses and executes a single command line, printing its result.
+///
+/// What the command prints can be passed through filters such as
+/// `| grep error`, see [`Filter`]. A line ending in `> file` or `>> file`
+/// writes it to that host file, replacing or appending to it; the result is
+/// still printed as usual. A command that fails raises the exit status to 1.
+pub fn run(application: &mut Application, line: &str) {
+    let (command, redirect) = match split_redirect(line) {
+        Some((command, file, append)) => (command, Some((file, append))),
+        None => (line, None),
+    };
+    let mut stages = command.split(" | ").map(str::trim);
+    let command = stages.next().unwrap_or_default();
+    let filters: Option<Vec<_>> = stages.map(Filter::parse).collect();
+    let (Some(handler), Some(filters)) = (get(command), filters) else {
+        sayln!(application, "invalid command: {}", line);
+        application.set_status(1);
+        return;
+    };
+
+    let saved = match redirect {
+        Some((file, append)) => {
+            let file = File::options()
+                .create(true)
+                .write(true)
+                .append(append)
+                .truncate(!append)
+                .open(file);
+            match file {
+                Ok(file) => Some(mem::replace(
+                    &mut application.output,
+                    Output::File(BufWriter::new(file)),
+                )),
+                Err(_) => {
+                    sayln!(application, "{}", CommandError::CannotCreateFile);
+                    application.set_status(1);
+                    return;
+                }
+            }
+        }
+        None => None,
+    };
+
+    let mut result = if filters.is_empty() {
+        execute(application, command, handler.as_ref())
+    } else {
+        piped(application, command, handler.as_ref(), &filters)
+    };
+    if let Some(saved) = saved {
+        let mut file = mem::replace(&mut application.output, saved);
+        if file.flush().is_err() && result.is_ok() {
+            result = Err(CommandError::CannotCreateFile);
+        }
+    }
+
+    if let Err(err) = result {
+        sayln!(application, "{}", err);
+        application.set_status(1);
+    } else {
+        sayln!(application, "OK");
+    }
+}
+
+/// Runs the command with its output collected, then passes that through
+/// `filters` in turn and prints what is left.
+fn piped(
+    application: &mut Application,
+    line: &str,
+    handler: &dyn CommandHandler<Error = CommandError>,
+    filters: &[Filter],
+) -> Result<(), CommandError> {
+    let output = mem::replace(&mut application.output, Output::Buffer(vec![]));
+    let result = execute(application, line, handler);
+    let mut data = mem::replace(&mut application.output, output).take();
+
+    for filter in filters {
+        data = filter.apply(&data);
+    }
+    let _ = application.output.write_all(&data);
+    result
+}
+
+/// Splits `command > file` and `command >> file` into the command, the host
+/// file and whether to append to it.
+fn split_redirect(line: &str) -> Option<(&str, &str, bool)> {
+    let (rest, file) = line.trim_end().rsplit_once(char::is_whitespace)?;
+    match rest.trim_end().rsplit_once(char::is_whitespace)? {
+        (command, ">") => Some((command, file, false)),
+        (command, ">>") => Some((command, file, true)),
+        _ => None,
+    }
+}
+
+/// Whether the command can change the image, and so belongs in its history.
+fn mutates(words: &[&str]) -> bool {
+    match words.first() {
+        Some(
+            &("cp" | "mv" | "rm" | "shred" | "undelete" | "attr" | "chown" | "chmod" | "revert"
+            | "restore" | "mkdir" | "rmdir" | "incp" | "batch" | "format" | "resize" | "dedup"
+            | "compactdir" | "trim" | "wipe-free" | "migrate" | "bug" | "truncate" | "write"
+            | "mkrandom" | "import-tar" | "import-zip" | "build" | "badblocks"),
+        ) => true,
+        Some(&"trash") => words.get(1) == Some(&"empty"),
+        Some(&"xattr") => matches!(words.get(1), Some(&("set" | "rm"))),
+        Some(&"raw") => words.get(1) == Some(&"write"),
+        Some(&"snapshot") => words.get(1) != Some(&"list"),
+        Some(&("label" | "tune")) => words.len() > 1,
+        Some(&"defrag") => !words.contains(&"--dry-run"),
+        Some(&("check" | "scrub")) => words.contains(&"--repair"),
+        _ => false,
+    }
+}
+
+/// Whether the command works on a real FAT volume, which is read-only; it
+/// can still be formatted over.
+fn runs_on_foreign(words: &[&str]) -> bool {
+    matches!(
+        words.first(),
+        Some(
+            &("ls"
+                | "cat"
+                | "cd"
+                | "pwd"
+                | "info"
+                | "outcp"
+                | "export-tar"
+                | "export-zip"
+                | "diff"
+                | "fsinfo"
+                | "stats"
+                | "format"
+                | "load"
+                | "convert"
+                | "mount"
+                | "umount"
+                | "open"
+                | "close"
+                | "exit")
+        )
+    )
+}
+
+/// Whether the command can lose data wholesale, and so asks first when typed
+/// at a terminal, unless given `-f`.
+fn destructive(words: &[&str]) -> bool {
+    match words.first() {
+        Some(&("rm" | "format")) => true,
+        Some(&"rmdir") => words.contains(&"-r"),
+        Some(&"raw") => words.get(1) == Some(&"write"),
+        _ => false,
+    }
+}
+
+/// Whether the command works before the image is formatted, or with no
+/// image open at all.
+fn runs_unformatted(words: &[&str]) -> bool {
+    matches!(
+        words.first(),
+        Some(&("format" | "build" | "mount" | "umount" | "load" | "pwd" | "exit"))
+    )
+}
+
+/// Runs `handler` as a transaction, so that the command reaches the image as
+/// a whole or, when it fails, not at all.
+///
+/// Commands that can change the image are recorded in its history, whether
+/// they succeed or not.
+fn execute(
+    application: &mut Application,
+    line: &str,
+    handler: &dyn CommandHandler<Error = CommandError>,
+) -> Result<(), CommandError> {
+    let _span = tracing::info_span!("command", line).entered();
+    let words: Vec<&str> = line.split_whitespace().collect();
+    // they put another image in place of the current one, so there is no
+    // transaction to wrap them in
+    if matches!(words[0], "open" | "close") {
+        return handler.handle(application);
+    }
+
+    let mut aliases: Vec<&str> = words
+        .iter()
+        .filter_map(|word| split_alias(application, word))
+        .map(|(alias, _)| alias)
+        .collect();
+    aliases.sort_unstable();
+    aliases.dedup();
+    // a copy between two images streams from one into the other, anything
+    // else runs on the one image it names
+    let across = copy_ends(application, &words).filter(|(from, to)| from != to);
+    match (&aliases[..], across) {
+        (&[alias], None) => return on_mounted(application, alias, line),
+        (&[_, _, ..], None) => return Err(CommandError::InvalidOption),
+        _ => {}
+    }
+
+    let fs = &application.file_system;
+    if fs.header().is_none() && fs.foreign().is_none() && !runs_unformatted(&words) {
+        return Err(match application.image {
+            Some(_) => CommandError::NotFormatted,
+            None => CommandError::NoImage,
+        });
+    }
+    if application.image.is_none() && words[0] == "format" {
+        return Err(CommandError::NoImage);
+    }
+
+    if application.interactive && destructive(&words) && !words.contains(&"-f") {
+        say!(application, "are you sure? [y/N] ");
+        let _ = application.output.flush();
+        let mut answer = String::new();
+        let _ = io::stdin().read_line(&mut answer);
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            return Err(CommandError::Cancelled);
+        }
+    }
+
+    let here = across.is_none_or(|(_, to)| to.is_none());
+    if here && application.file_system.foreign().is_some() && !runs_on_foreign(&words) {
+        return Err(CommandError::ReadOnly);
+    }
+    application.file_system.begin();
+
+    let result = match handler.handle(application) {
+        Ok(()) => application
+            .file_system
+            .commit()
+            .map_err(|_| CommandError::CannotCreateFile),
+        Err(err) => {
+            application.file_system.rollback();
+            Err(err)
+        }
+    };
+
+    if here && mutates(&words) {
+        let record = Record {
+            timestamp: time::now(),
+            uuid: application.file_system.header().map(Header::uuid),
+            directory: application.current_path.clone(),
+            command: words.join(" "),
+            result: match &result {
+                Ok(()) => "OK".to_string(),
+                Err(err) => err.to_string().trim_end().to_string(),
+            },
+        };
+        // a full image must not turn a command that succeeded into a failure
+        let _ = application.file_system.record(record);
+    }
+
+    // `format` gives the image a new UUID
+    if let Some(lock) = &mut application.lock {
+        let _ = lock.refresh(&application.file_system);
+    }
+
+    tracing::debug!(ok = result.is_ok(), "command finished");
+    result
+}
+
+/// The mounted image a word such as `backup:/docs/a.txt` names, and the
+/// path in it.
+fn split_alias<'a>(application: &Application, word: &'a str) -> Option<(&'a str, &'a str)> {
+    let (alias, path) = word.split_once(':')?;
+    application
+        .mounts
+        .contains_key(alias)
+        .then_some((alias, path))
+}
+
+/// The aliases of the mounted images `cp` copies from, going by its first
+/// source, and into, `None` for the current image.
+fn copy_ends<'a>(
+    application: &Application,
+    words: &[&'a str],
+) -> Option<(Option<&'a str>, Option<&'a str>)> {
+    if words.first() != Some(&"cp") {
+        return None;
+    }
+    let paths: Vec<&str> = words
+        .iter()
+        .skip(1)
+        .filter(|word| !word.starts_with("--"))
+        .copied()
+        .collect();
+    let alias = |word: &'a str| split_alias(application, word).map(|(alias, _)| alias);
+    match paths[..] {
+        [source, .., destination] => Some((alias(source), alias(destination))),
+        _ => None,
+    }
+}
+
+/// Runs `line` on the image mounted as `alias` as if it were the current
+/// one, with `alias:` left off its paths, which start from its root.
+fn on_mounted(application: &mut Application, alias: &str, line: &str) -> Result<(), CommandError> {
+    let prefix = format!("{alias}:");
+    let line = line
+        .split_whitespace()
+        .map(|word| match word.strip_prefix(&prefix) {
+            Some("") => "/",
+            Some(path) => path,
+            None => word,
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    let handler = get(&line).ok_or(CommandError::InvalidOption)?;
+
+    let (alias, mut fs) = application
+        .mounts
+        .remove_entry(alias)
+        .expect("alias is mounted");
+    std::mem::swap(&mut application.file_system, &mut fs);
+    let current_path = std::mem::replace(&mut application.current_path, "/".to_string());
+    let image = application.image.replace(alias.clone());
+
+    let result = execute(application, &line, handler.as_ref());
+
+    application.current_path = current_path;
+    application.image = image;
+    std::mem::swap(&mut application.file_system, &mut fs);
+    application.mounts.insert(alias, fs);
+    result
+}
 
 pub fn get(line: &str) -> Option<Box<dyn CommandHandler<Error = CommandError>>> {
-    if line.len() == 0 {
+    if line.is_empty() {
         return None;
     }
 
     let words: Vec<&str> = line.split_whitespace().collect();
 
-    match *words.get(0)? {
-        "cp" => Some(Box::new(CopyFile::new(
+    match *words.first()? {
+        "cp" => {
+            let mut paths: Vec<String> = words
+                .iter()
+                .skip(1)
+                .filter(|word| !word.starts_with("--"))
+                .map(|word| word.to_string())
+                .collect();
+            let destination = paths.pop()?;
+            if paths.is_empty() {
+                return None;
+            }
+            Some(Box::new(CopyFile::new(
+                paths,
+                destination,
+                words.contains(&"--reflink"),
+            )))
+        }
+        "mv" => {
+            let mut paths: Vec<String> =
+                words.iter().skip(1).map(|word| word.to_string()).collect();
+            let destination = paths.pop()?;
+            if paths.is_empty() {
+                return None;
+            }
+            Some(Box::new(MoveFile::new(paths, destination)))
+        }
+        "rm" => {
+            let files: Vec<String> = words
+                .iter()
+                .skip(1)
+                .filter(|word| !word.starts_with('-'))
+                .map(|word| word.to_string())
+                .collect();
+            if files.is_empty() {
+                return None;
+            }
+            Some(Box::new(RemoveFile::new(files, words.contains(&"-e"))))
+        }
+        "shred" => Some(Box::new(Shred::new(words.get(1)?.to_string()))),
+        "trash" => match *words.get(1)? {
+            "list" => Some(Box::new(TrashList::new())),
+            "empty" => Some(Box::new(EmptyTrash::new())),
+            _ => None,
+        },
+        "undelete" => Some(Box::new(Undelete::new(
+            words.get(1)?.to_string(),
+            words.get(2).map(|s| s.to_string()),
+        ))),
+        "attr" => Some(Box::new(Attribute::new(
+            words.get(1)?.to_string(),
+            words.get(2)?.to_string(),
+        ))),
+        "mkrandom" => Some(Box::new(MakeRandom::new(
+            words.get(1)?.to_string(),
+            words.get(2)?.to_string(),
+            option(&words, "--seed"),
+        ))),
+        "truncate" => Some(Box::new(Truncate::new(
+            words.get(1)?.to_string(),
+            words.get(2)?.to_string(),
+        ))),
+        // the text is the rest of the line, spaces included
+        "write" => Some(Box::new(WriteAt::new(
             words.get(1)?.to_string(),
             words.get(2)?.to_string(),
+            words.get(3..)?.join(" "),
         ))),
-        "mv" => Some(Box::new(MoveFile::new(
+        "xattr" => {
+            let path = words.get(2)?.to_string();
+            Some(Box::new(Xattr::new(
+                path,
+                match *words.get(1)? {
+                    "list" => XattrAction::List,
+                    "get" => XattrAction::Get(words.get(3)?.to_string()),
+                    // the value is the rest of the line, spaces included
+                    "set" => XattrAction::Set(words.get(3)?.to_string(), words[4..].join(" ")),
+                    "rm" => XattrAction::Remove(words.get(3)?.to_string()),
+                    _ => return None,
+                },
+            )))
+        }
+        "chown" => Some(Box::new(ChangeOwner::new(
             words.get(1)?.to_string(),
             words.get(2)?.to_string(),
         ))),
-        "rm" => Some(Box::new(RemoveFile::new(words.get(1)?.to_string()))),
+        "chmod" => Some(Box::new(ChangeMode::new(
+            words.get(1)?.to_string(),
+            words.get(2)?.to_string(),
+        ))),
+        "versions" => Some(Box::new(Versions::new(words.get(1)?.to_string()))),
+        "revert" => Some(Box::new(Revert::new(
+            words.get(1)?.to_string(),
+            words.get(2)?.to_string(),
+        ))),
+        "snapshot" => Some(Box::new(Snapshots::new(match *words.get(1)? {
+            "list" => SnapshotAction::List,
+            "create" => SnapshotAction::Create(words.get(2)?.to_string()),
+            "restore" => SnapshotAction::Restore(words.get(2)?.to_string()),
+            "delete" => SnapshotAction::Delete(words.get(2)?.to_string()),
+            _ => return None,
+        }))),
+        "restore" => Some(Box::new(Restore::new(words.get(1)?.to_string()))),
         "mkdir" => Some(Box::new(MakeDirectory::new(words.get(1)?.to_string()))),
-        "rmdir" => Some(Box::new(RemoveDirectory::new(words.get(1)?.to_string()))),
-        "ls" => Some(Box::new(Listing::new(words.get(1).map(|s| s.to_string())))),
-        "cat" => Some(Box::new(Concatenate::new(words.get(1)?.to_string()))),
+        "rmdir" => Some(Box::new(RemoveDirectory::new(
+            words
+                .iter()
+                .skip(1)
+                .find(|word| !word.starts_with('-'))?
+                .to_string(),
+            words.contains(&"-r"),
+        ))),
+        "ls" => {
+            let long = words.contains(&"-l");
+            let paths = words
+                .iter()
+                .skip(1)
+                .filter(|word| !word.starts_with('-'))
+                .map(|word| word.to_string())
+                .collect();
+            Some(Box::new(Listing::new(paths, long, json(&words))))
+        }
+        "cat" => Some(Box::new(Concatenate::new(
+            words.get(1)?.to_string(),
+            option(&words, "--offset"),
+            option(&words, "--length"),
+        ))),
         "cd" => Some(Box::new(ChangeDirectory::new(words.get(1)?.to_string()))),
         "pwd" => Some(Box::new(PrintWorkingDirectory::new())),
-        "info" => Some(Box::new(PrintInfo::new(words.get(1)?.to_string()))),
+        "info" => Some(Box::new(PrintInfo::new(
+            words
+                .iter()
+                .skip(1)
+                .find(|word| !word.starts_with('-'))?
+                .to_string(),
+            json(&words),
+        ))),
+        "stat" => Some(Box::new(StatFile::new(
+            words
+                .iter()
+                .skip(1)
+                .find(|word| !word.starts_with('-'))?
+                .to_string(),
+            json(&words),
+        ))),
         "incp" => Some(Box::new(CopyIn::new(
             words.get(1)?.to_string(),
             words.get(2)?.to_string(),
@@ -34,10 +548,131 @@ pub fn get(line: &str) -> Option<Box<dyn CommandHandler<Error = CommandError>>>
             words.get(1)?.to_string(),
             words.get(2)?.to_string(),
         ))),
+        "export-script" => Some(Box::new(ExportScript::new(words.get(1)?.to_string()))),
+        "export-tar" | "export-zip" => Some(Box::new(ExportArchive::new(
+            words.get(1)?.to_string(),
+            words.get(2).map(|path| path.to_string()),
+            words[0] == "export-zip",
+        ))),
+        "import-tar" | "import-zip" => Some(Box::new(ImportArchive::new(
+            words.get(1)?.to_string(),
+            words.get(2).map(|dest| dest.to_string()),
+            words[0] == "import-zip",
+        ))),
+        "convert" => match words[1..] {
+            ["--to", to, image] => Some(Box::new(Convert::new(to.to_string(), image.to_string()))),
+            _ => None,
+        },
+        "open" => Some(Box::new(Open::new(words.get(1)?.to_string()))),
+        "close" => Some(Box::new(Close::new())),
+        "mount" => Some(Box::new(Mount::new(
+            words.get(1)?.to_string(),
+            words.get(2)?.to_string(),
+        ))),
+        "umount" => Some(Box::new(Unmount::new(words.get(1)?.to_string()))),
         "load" => Some(Box::new(LoadCommands::new(words.get(1)?.to_string()))),
-        "format" => Some(Box::new(Format::new(words.get(1)?.to_string()))),
-        "bug" => Some(Box::new(Bug::new(words.get(1)?.to_string()))),
-        "check" => Some(Box::new(Check::new())),
+        "history" => Some(Box::new(History::new())),
+        "batch" => Some(Box::new(Batch::new(words.get(1)?.to_string()))),
+        "format" => Some(Box::new(Format::new(
+            format_size(&words)?,
+            option(&words, "--sector-size"),
+            option(&words, "--cluster-size"),
+            option(&words, "--fats"),
+            option(&words, "--label"),
+            words.contains(&"--preallocate"),
+            words.contains(&"--compress"),
+            words.contains(&"--encrypt"),
+            words.contains(&"--dir-index"),
+        ))),
+        "build" => Some(Box::new(Build::new(words.get(1)?.to_string()))),
+        "label" => Some(Box::new(Label::new(words.get(1).map(|s| s.to_string())))),
+        "fsinfo" => Some(Box::new(FileSystemInfo::new())),
+        "df" => Some(Box::new(DiskFree::new(json(&words)))),
+        "bench" => Some(Box::new(Bench::new(
+            option(&words, "--size").unwrap_or("100MB".to_string()),
+            option(&words, "--files")
+                .map_or(Ok(1000), |files| files.parse())
+                .ok()?,
+        ))),
+        "stats" => Some(Box::new(Stats::new(
+            words.contains(&"--reset"),
+            json(&words),
+        ))),
+        "resize" => {
+            let shrink = words.contains(&"--shrink");
+            let size = words.iter().skip(1).find(|word| !word.starts_with("--"))?;
+            Some(Box::new(Resize::new(size.to_string(), shrink)))
+        }
+        "tune" => Some(Box::new(Tune::new(match words.get(1) {
+            Some(name) => Some((name.to_string(), words.get(2)?.to_string())),
+            None => None,
+        }))),
+        "dedup" => Some(Box::new(Dedup::new())),
+        "trim" | "wipe-free" => Some(Box::new(Trim::new())),
+        "defrag" => Some(Box::new(Defragment::new(words.contains(&"--dry-run")))),
+        "compactdir" => Some(Box::new(CompactDirectory::new(words.get(1)?.to_string()))),
+        "frag" => Some(Box::new(Fragmentation::new(
+            words.get(1).map(|s| s.to_string()),
+        ))),
+        "verify-image" => Some(Box::new(VerifyImage::new())),
+        "scrub" => Some(Box::new(Scrub::new(words.contains(&"--repair")))),
+        "badblocks" => Some(Box::new(BadBlocks::new(words.contains(&"--write")))),
+        "diff" => Some(Box::new(DiffTree::new(
+            words.get(1)?.to_string(),
+            words.get(2)?.to_string(),
+        ))),
+        "diff-image" => Some(Box::new(DiffImage::new(words.get(1)?.to_string()))),
+        "clone-image" => {
+            let compact = words.contains(&"--compact");
+            let destination = words.iter().skip(1).find(|word| !word.starts_with("--"))?;
+            Some(Box::new(CloneImage::new(destination.to_string(), compact)))
+        }
+        "migrate" => Some(Box::new(Migrate::new())),
+        "bug" => {
+            let corruption = match *words.get(1)? {
+                "cycle" => Corruption::Cycle,
+                "bad" => Corruption::Bad,
+                "orphan" => Corruption::Orphan,
+                "size" => Corruption::Size(words.get(3)?.parse().ok()?),
+                file => return Some(Box::new(Bug::new(file.to_string(), Corruption::Cycle))),
+            };
+            Some(Box::new(Bug::new(words.get(2)?.to_string(), corruption)))
+        }
+        "raw" => {
+            let block = match *words.get(2)? {
+                "sector" => Block::Sector(words.get(3)?.parse().ok()?),
+                "cluster" => Block::Cluster(words.get(3)?.parse().ok()?),
+                _ => return None,
+            };
+            let action = match *words.get(1)? {
+                "read" => RawAction::Read(words.get(4).map(|file| file.to_string())),
+                "write" => RawAction::Write(words.get(4)?.to_string()),
+                _ => return None,
+            };
+            Some(Box::new(Raw::new(action, block)))
+        }
+        "dumpfat" => {
+            let mut numbers = words.iter().skip(1).filter(|word| !word.starts_with("--"));
+            Some(Box::new(DumpFat::new(
+                numbers.next().map(|word| word.to_string()),
+                numbers.next().map(|word| word.to_string()),
+                words.contains(&"--raw"),
+            )))
+        }
+        "dumpdir" => Some(Box::new(DumpDirectory::new(
+            words.get(1).unwrap_or(&".").to_string(),
+        ))),
+        "meta" => Some(Box::new(Metadata::new(match *words.get(1)? {
+            "dump" => MetaAction::Dump,
+            "diff" => MetaAction::Diff(words.get(2)?.to_string()),
+            _ => return None,
+        }))),
+        "check" => Some(Box::new(Check::new(
+            words.contains(&"--use-mirror"),
+            words.contains(&"--repair"),
+            json(&words),
+        ))),
+        "sync" => Some(Box::new(Sync::new())),
         "exit" => Some(Box::new(Exit::new())),
         _ => None,
     }
@@ -0,0 +1,104 @@
+// Za | lze zapsat příkazy, které zpracují výpis předchozího příkazu
+// cat s1 | grep error (nechá jen řádky obsahující vzor; -v ty ostatní, -i bez ohledu na velikost písmen)
+// cat s1 | wc (spočítá řádky, slova a bajty; -l jen řádky)
+// ls -l | head 5 (nechá prvních n řádků, výchozí je 10)
+// ls | tail (nechá posledních n řádků, výchozí je 10)
+// cat s1 | grep -i error | wc -l > pocet.txt
+// Možný výsledek:
+// 3 12 80
+// OK
+// invalid command (neznámý příkaz za |)
+/// A command that can follow `|`, working on what the one before it printed.
+pub enum Filter {
+    Grep {
+        pattern: String,
+        invert: bool,
+        ignore_case: bool,
+    },
+    Count {
+        lines_only: bool,
+    },
+    Head(usize),
+    Tail(usize),
+}
+
+impl Filter {
+    pub fn parse(stage: &str) -> Option<Self> {
+        let words: Vec<&str> = stage.split_whitespace().collect();
+        let lines = || match words.get(1) {
+            Some(count) => count.parse().ok(),
+            None => Some(10),
+        };
+
+        match *words.first()? {
+            "grep" => Some(Self::Grep {
+                pattern: words
+                    .iter()
+                    .skip(1)
+                    .find(|word| !word.starts_with('-'))?
+                    .to_string(),
+                invert: words.contains(&"-v"),
+                ignore_case: words.contains(&"-i"),
+            }),
+            "wc" => Some(Self::Count {
+                lines_only: words.contains(&"-l"),
+            }),
+            "head" => Some(Self::Head(lines()?)),
+            "tail" => Some(Self::Tail(lines()?)),
+            _ => None,
+        }
+    }
+
+    pub fn apply(&self, input: &[u8]) -> Vec<u8> {
+        let text = String::from_utf8_lossy(input);
+        let lines = text.lines();
+
+        let kept: Vec<&str> = match self {
+            Self::Grep {
+                pattern,
+                invert,
+                ignore_case,
+            } => {
+                let pattern = if *ignore_case {
+                    pattern.to_lowercase()
+                } else {
+                    pattern.clone()
+                };
+                lines
+                    .filter(|line| {
+                        let found = if *ignore_case {
+                            line.to_lowercase().contains(&pattern)
+                        } else {
+                            line.contains(&pattern)
+                        };
+                        found != *invert
+                    })
+                    .collect()
+            }
+            Self::Count { lines_only } => {
+                let count = lines.count();
+                return if *lines_only {
+                    format!("{count}\n")
+                } else {
+                    format!(
+                        "{count} {} {}\n",
+                        text.split_whitespace().count(),
+                        input.len()
+                    )
+                }
+                .into_bytes();
+            }
+            Self::Head(count) => lines.take(*count).collect(),
+            Self::Tail(count) => {
+                let lines: Vec<&str> = lines.collect();
+                lines[lines.len().saturating_sub(*count)..].to_vec()
+            }
+        };
+
+        kept.iter()
+            .flat_map(|line| [line.as_bytes(), b"\n"])
+            .flatten()
+            .copied()
+            .collect()
+    }
+}
@@ -0,0 +1,112 @@
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    time::Instant,
+};
+
+use crate::{cli, metrics::Metrics, Application, Output};
+
+/// Runs command lines sent over the Unix socket at `path`, one client at a
+/// time, until a client sends `exit`.
+///
+/// Each reply is a line with the command's exit status and the length of its
+/// output, followed by the output itself. The working directory carries over
+/// from one client to the next, and paths on the host side of `incp`, `outcp`
+/// and `load` are relative to the daemon.
+///
+/// `metrics` answers with the image's counters and the time commands took,
+/// in the Prometheus text format.
+pub fn serve(application: &mut Application, path: &str) -> io::Result<()> {
+    // a socket left behind by a daemon that was killed would block the bind
+    let _ = fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    application.output = Output::Buffer(vec![]);
+    let mut metrics = Metrics::default();
+
+    for stream in listener.incoming() {
+        // a client that hangs up early only loses its own replies
+        let _ = session(application, &mut metrics, stream?);
+        if !application.running() {
+            break;
+        }
+    }
+
+    application.output = Output::Stdout(io::stdout());
+    fs::remove_file(path)
+}
+
+fn session(
+    application: &mut Application,
+    metrics: &mut Metrics,
+    stream: UnixStream,
+) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        application.status = 0;
+        if line == "metrics" {
+            match metrics.render(&mut application.file_system) {
+                Ok(text) => application.output.write_all(text.as_bytes())?,
+                Err(_) => {
+                    writeln!(application.output, "FILE NOT FOUND")?;
+                    application.set_status(1);
+                }
+            }
+        } else {
+            let start = Instant::now();
+            cli::run(application, line);
+            // only known commands, so that clients cannot add labels at will
+            if cli::get(line).is_some() {
+                let command = line.split_whitespace().next().unwrap_or_default();
+                metrics.record(command, start.elapsed());
+            }
+        }
+        let output = application.output.take();
+        writeln!(writer, "{} {}", application.status, output.len())?;
+        writer.write_all(&output)?;
+
+        if !application.running() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends `commands` to the daemon listening at `path` and prints what they
+/// print. Returns the highest exit status among them.
+pub fn connect(path: &str, commands: impl Iterator<Item = String>) -> io::Result<i32> {
+    let mut writer = UnixStream::connect(path)?;
+    let mut reader = BufReader::new(writer.try_clone()?);
+    let mut stdout = io::stdout();
+    let mut status = 0;
+
+    for command in commands {
+        writeln!(writer, "{command}")?;
+
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let (code, len) = header
+            .trim()
+            .split_once(' ')
+            .and_then(|(code, len)| Some((code.parse::<i32>().ok()?, len.parse().ok()?)))
+            .ok_or(io::ErrorKind::InvalidData)?;
+
+        let mut output = vec![0; len];
+        reader.read_exact(&mut output)?;
+        stdout.write_all(&output)?;
+        stdout.flush()?;
+        status = status.max(code);
+    }
+
+    Ok(status)
+}
@@ -0,0 +1,149 @@
+use std::fmt::Display;
+
+use tracing::{debug, trace};
+
+use super::{fatmanager::FATManager, FATError, FAT};
+
+/// How free clusters are chosen for new chains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AllocStrategy {
+    /// Lowest-numbered free clusters.
+    #[default]
+    First,
+    /// Free clusters after the previous allocation, wrapping around.
+    Next,
+    /// Smallest free run that holds the whole chain, falling back to first-fit.
+    Best,
+}
+
+impl AllocStrategy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "first-fit" => Some(Self::First),
+            "next-fit" => Some(Self::Next),
+            "best-fit" => Some(Self::Best),
+            _ => None,
+        }
+    }
+}
+
+impl Display for AllocStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::First => write!(f, "first-fit"),
+            Self::Next => write!(f, "next-fit"),
+            Self::Best => write!(f, "best-fit"),
+        }
+    }
+}
+
+impl FAT {
+    pub fn alloc_strategy(&self) -> AllocStrategy {
+        self.strategy
+    }
+
+    pub fn set_alloc_strategy(&mut self, strategy: AllocStrategy) {
+        self.strategy = strategy;
+    }
+
+    /// Picks `count` free clusters according to the current strategy.
+    pub(super) fn find_free_clusters(&mut self, count: u32) -> Result<Vec<u32>, FATError> {
+        // clusters held back are among those counted, so the count only ever
+        // overstates what can be handed out
+        if self.free_clusters.is_some_and(|free| free < count) {
+            return Err(FATError::NotEnoughSpace);
+        }
+
+        let clusters = match self.strategy {
+            AllocStrategy::First => self.scan_free(1, count)?,
+            AllocStrategy::Next => {
+                let clusters = self.scan_free(self.next_fit, count)?;
+                self.next_fit = clusters.last().unwrap() + 1;
+                clusters
+            }
+            AllocStrategy::Best => match self.best_run(count)? {
+                Some(start) => (start..start + count).collect(),
+                None => self.scan_free(1, count)?,
+            },
+        };
+
+        self.io.clusters_allocated += count as u64;
+        debug!(count, strategy = %self.strategy, first = clusters[0], "allocated clusters");
+        Ok(clusters)
+    }
+
+    /// Collects `count` free clusters, starting at `start` and wrapping around
+    /// to the beginning of the data region.
+    fn scan_free(&mut self, start: u32, count: u32) -> Result<Vec<u32>, FATError> {
+        let limit = self
+            .header
+            .as_ref()
+            .expect("Image is not formatted!")
+            .data_cluster_count();
+        let start = if (1..=limit).contains(&start) {
+            start
+        } else {
+            1
+        };
+
+        let mut manager = FATManager::new(self.clusters_per_fat_sector());
+        let mut clusters = vec![];
+        let mut misses = 0;
+
+        for step in 0..limit {
+            let cluster = (start - 1 + step) % limit + 1;
+            if !manager.contains_cluster(cluster) {
+                misses += 1;
+            }
+            self.cache_fat(&mut manager, cluster)?;
+
+            if manager
+                .get_cluster_value(cluster)
+                .is_some_and(|value| self.is_allocatable(cluster, value))
+            {
+                clusters.push(cluster);
+                if clusters.len() == count as usize {
+                    trace!(
+                        scanned = step + 1,
+                        fat_sectors = misses,
+                        "found free clusters"
+                    );
+                    return Ok(clusters);
+                }
+            }
+        }
+
+        Err(FATError::NotEnoughSpace)
+    }
+
+    /// Start of the smallest run of free clusters at least `count` long.
+    fn best_run(&mut self, count: u32) -> Result<Option<u32>, FATError> {
+        let fat = self.read_whole_fat()?;
+        let limit = self
+            .header
+            .as_ref()
+            .expect("Image is not formatted!")
+            .data_cluster_count();
+
+        let mut best: Option<(u32, u32)> = None;
+        let mut run_start = 0;
+        let mut run = 0;
+
+        for cluster in 1..=limit + 1 {
+            if cluster <= limit && self.is_allocatable(cluster, fat[cluster as usize]) {
+                if run == 0 {
+                    run_start = cluster;
+                }
+                run += 1;
+                continue;
+            }
+
+            if run >= count && best.is_none_or(|(_, len)| run < len) {
+                best = Some((run_start, run));
+            }
+            run = 0;
+        }
+
+        Ok(best.map(|(start, _)| start))
+    }
+}
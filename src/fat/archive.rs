@@ -0,0 +1,220 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use super::{join, FATError, FAT, ROOT};
+
+/// A directory or file to store in an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    /// Name in the archive, ending with `/` for directories.
+    pub name: String,
+    /// Where the member is in the image.
+    pub path: String,
+    pub size: u64,
+    pub dir: bool,
+}
+
+impl Member {
+    /// Last component of the name, without the trailing `/`.
+    pub fn base_name(&self) -> &str {
+        let name = self.name.trim_end_matches('/');
+        name.rsplit('/').next().unwrap_or(name)
+    }
+}
+
+/// Counts what passes through, so a file that decodes to a size other than
+/// its entry says can be caught before it corrupts the archive.
+pub(super) struct Counted<W> {
+    inner: W,
+    pub written: u64,
+}
+
+impl<W> Counted<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, written: 0 }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// `len` bytes of `inner` from `start` on, seen as a whole file.
+pub(super) struct Section<'a, R> {
+    inner: &'a mut R,
+    start: u64,
+    len: u64,
+    position: u64,
+}
+
+impl<'a, R> Section<'a, R> {
+    pub fn new(inner: &'a mut R, start: u64, len: u64) -> Self {
+        Self {
+            inner,
+            start,
+            len,
+            position: 0,
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for Section<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.len.saturating_sub(self.position).min(buf.len() as u64) as usize;
+        self.inner
+            .seek(SeekFrom::Start(self.start + self.position))?;
+        let n = self.inner.read(&mut buf[..n])?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for Section<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or(io::ErrorKind::InvalidInput)?;
+        Ok(self.position)
+    }
+}
+
+impl FAT {
+    /// Directories and files under `path` in the order an archive stores
+    /// them, parents first, named relative to the parent of `path`, or to the
+    /// root when `path` is the root.
+    pub fn archive_members(&mut self, path: &str) -> Result<Vec<Member>, FATError> {
+        let path = path.trim_end_matches('/').trim_end_matches("/.");
+        let mut members = vec![];
+
+        if path.is_empty() || path == ROOT {
+            self.collect_members(ROOT, "", &mut members)?;
+            return Ok(members);
+        }
+
+        let entry = self.find_file(path, Self::filter_find)?;
+        let name = Self::split_path(path).1;
+        let dir = Self::filter_mkdir(&entry);
+        members.push(Member {
+            name: if dir {
+                format!("{name}/")
+            } else {
+                name.to_string()
+            },
+            path: path.to_string(),
+            size: if dir { 0 } else { entry.size() },
+            dir,
+        });
+        if dir {
+            self.collect_members(path, &format!("{name}/"), &mut members)?;
+        }
+        Ok(members)
+    }
+
+    pub(super) fn collect_members(
+        &mut self,
+        dir: &str,
+        prefix: &str,
+        members: &mut Vec<Member>,
+    ) -> Result<(), FATError> {
+        for entry in self.read_dir(dir)? {
+            let name = entry.name();
+            if name == "." || name == ".." {
+                continue;
+            }
+            let path = join(dir, name);
+
+            if Self::filter_mkdir(&entry) {
+                let name = format!("{prefix}{name}/");
+                members.push(Member {
+                    name: name.clone(),
+                    path: path.clone(),
+                    size: 0,
+                    dir: true,
+                });
+                self.collect_members(&path, &name, members)?;
+            } else {
+                members.push(Member {
+                    name: format!("{prefix}{name}"),
+                    path,
+                    size: entry.size(),
+                    dir: false,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Streams the contents of `member` into `out`, failing when they do not
+    /// come to the size its entry gives.
+    pub(super) fn write_member<W: Write>(
+        &mut self,
+        member: &Member,
+        out: &mut Counted<W>,
+    ) -> Result<(), FATError> {
+        let start = out.written;
+        self.cat(&member.path, &mut *out)?;
+        if out.written - start != member.size {
+            return Err(FATError::CannotRead);
+        }
+        Ok(())
+    }
+
+    /// Where the archive member `name` goes under the directory `dest`,
+    /// making the directories leading to it. `None` for names of nothing,
+    /// such as `./`; names that climb out with `..` are refused.
+    pub(super) fn unpack_path(
+        &mut self,
+        dest: &str,
+        name: &str,
+    ) -> Result<Option<String>, FATError> {
+        let mut components = vec![];
+        for component in name.split('/') {
+            match component {
+                "" | "." => {}
+                ".." => return Err(FATError::CannotRead),
+                component => components.push(component),
+            }
+        }
+        let Some((last, parents)) = components.split_last() else {
+            return Ok(None);
+        };
+
+        let mut path = String::from(dest);
+        for component in parents {
+            path = join(&path, component);
+            self.ensure_dir(&path)?;
+        }
+        Ok(Some(join(&path, last)))
+    }
+
+    /// Makes the directory `path` unless there already is one.
+    pub(super) fn ensure_dir(&mut self, path: &str) -> Result<(), FATError> {
+        match self.find_file(path, Self::filter_find) {
+            Ok(entry) if Self::filter_mkdir(&entry) => Ok(()),
+            Ok(_) => Err(FATError::FileExists),
+            Err(_) => self.mkdir(path),
+        }
+    }
+
+    /// `dest` as a directory to unpack into, which must exist.
+    pub(super) fn unpack_dest<'a>(&mut self, dest: &'a str) -> Result<&'a str, FATError> {
+        let dest = dest.trim_end_matches('/').trim_end_matches("/.");
+        let dest = if dest.is_empty() { "." } else { dest };
+        self.find_file(dest, Self::filter_mkdir)?;
+        Ok(dest)
+    }
+}
@@ -0,0 +1,90 @@
+use std::fmt::Display;
+
+use super::{FATError, FAT};
+
+/// Patterns written to each free cluster by a write test.
+const PATTERNS: [u8; 2] = [0xAA, 0x55];
+
+/// Outcome of a `badblocks` scan.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BadBlocksReport {
+    /// Clusters read, and written as well for a write test.
+    pub tested: u32,
+    /// Free clusters that failed and are now marked bad.
+    pub marked: Vec<u32>,
+    /// Clusters in use that failed; they stay in their chains, see `check`.
+    pub in_use: Vec<u32>,
+    /// Clusters already marked bad, which are not tested again.
+    pub known: u32,
+}
+
+impl Display for BadBlocksReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} cluster(s) tested: {} marked bad, {} failing in use, {} already bad",
+            self.tested,
+            self.marked.len(),
+            self.in_use.len(),
+            self.known
+        )
+    }
+}
+
+impl FAT {
+    /// Reads every data cluster and marks the free ones that fail with the
+    /// BAD value, so they are never allocated.
+    ///
+    /// With `write` set, each free cluster is also written with test
+    /// patterns and read back, then given its old contents again. Clusters in
+    /// use are only read, so no data is put at risk.
+    pub fn badblocks(&mut self, write: bool) -> Result<BadBlocksReport, FATError> {
+        let fat = self.read_whole_fat()?;
+        let last = self
+            .header
+            .as_ref()
+            .expect("Image is not formatted!")
+            .data_cluster_count();
+        let mut report = BadBlocksReport::default();
+
+        for cluster in 1..=last {
+            let value = fat[cluster as usize];
+            if value == Self::mark_bad_cluster() {
+                report.known += 1;
+                continue;
+            }
+            report.tested += 1;
+
+            let free = self.is_allocatable(cluster, value);
+            let sound = match self.read_raw_cluster(cluster) {
+                Ok(bytes) if write && free => self.write_test(cluster, &bytes),
+                Ok(_) => true,
+                Err(_) => false,
+            };
+            if sound {
+                continue;
+            }
+
+            if free {
+                self.set_cluster_value(cluster, Self::mark_bad_cluster())?;
+                report.marked.push(cluster);
+            } else {
+                report.in_use.push(cluster);
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Whether `cluster` keeps each test pattern, putting `old` back after.
+    fn write_test(&mut self, cluster: u32, old: &[u8]) -> bool {
+        let sound = PATTERNS.iter().all(|pattern| {
+            let bytes = vec![*pattern; old.len()];
+            self.write_raw_cluster(cluster, &bytes).is_ok()
+                && self
+                    .read_raw_cluster(cluster)
+                    .is_ok_and(|read| read == bytes)
+        });
+        sound && self.write_raw_cluster(cluster, old).is_ok()
+    }
+}
@@ -0,0 +1,49 @@
+use std::io::Cursor;
+
+use super::{FATError, FAT};
+
+/// A single change applied by [`FAT::apply`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsOp {
+    Mkdir(String),
+    /// Creates the file with `data`, replacing the file already at `path`.
+    Write {
+        path: String,
+        data: Vec<u8>,
+    },
+    Copy {
+        source: String,
+        dest: String,
+    },
+    /// Moves a file, failing if `dest` exists.
+    Rename {
+        source: String,
+        dest: String,
+    },
+    Remove(String),
+    RemoveDir(String),
+}
+
+impl FAT {
+    /// Applies `ops` in order as a single transaction: either all of them take
+    /// effect or, as soon as one fails, none do.
+    pub fn apply(&mut self, ops: &[FsOp]) -> Result<(), FATError> {
+        self.transaction(|fs| ops.iter().try_for_each(|op| fs.apply_op(op)))
+    }
+
+    fn apply_op(&mut self, op: &FsOp) -> Result<(), FATError> {
+        match op {
+            FsOp::Mkdir(path) => self.mkdir(path),
+            FsOp::Write { path, data } => {
+                if self.find_file(path, Self::filter_find_file).is_ok() {
+                    self.remove_file(path)?;
+                }
+                self.new_file(path, Cursor::new(data))
+            }
+            FsOp::Copy { source, dest } => self.copy(source, dest),
+            FsOp::Rename { source, dest } => self.move_file(source, dest),
+            FsOp::Remove(path) => self.remove_file(path),
+            FsOp::RemoveDir(path) => self.remove_dir(path),
+        }
+    }
+}
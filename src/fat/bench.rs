@@ -0,0 +1,118 @@
+use std::{
+    fmt::Display,
+    io::{self, Cursor},
+    time::{Duration, Instant},
+};
+
+use super::{FATError, FAT};
+
+/// Directory `bench` works in; it must not exist beforehand and is removed
+/// afterwards.
+pub const SCRATCH: &str = ".bench";
+
+/// How one kind of operation fared in a benchmark run.
+#[derive(Debug, Clone)]
+pub struct Phase {
+    pub name: &'static str,
+    pub ops: u64,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl Phase {
+    pub fn ops_per_sec(&self) -> f64 {
+        self.ops as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Throughput in MiB per second.
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / (1 << 20) as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:<10} {} ops, {} B in {:.3} s: {:.1} ops/s, {:.1} MB/s",
+            format!("{}:", self.name),
+            self.ops,
+            self.bytes,
+            self.elapsed.as_secs_f64(),
+            self.ops_per_sec(),
+            self.throughput()
+        )
+    }
+}
+
+/// Content of file `index`, different for every pass over it.
+fn pattern(index: u32, pass: u8, len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|offset| (offset as u32).wrapping_mul(31).wrapping_add(index) as u8 ^ pass)
+        .collect()
+}
+
+impl FAT {
+    /// Creates `files` files sharing `size` bytes in [`SCRATCH`], reads them
+    /// back, replaces them with new content and deletes them, timing each pass.
+    ///
+    /// A directory only takes one cluster of entries, so the files are spread
+    /// over subdirectories, which are made before the timing starts.
+    pub fn bench(&mut self, size: u64, files: u32) -> Result<Vec<Phase>, FATError> {
+        let files = files.max(1);
+        let len = (size / files as u64) as usize;
+        // every directory also holds `.` and `..`
+        let per_dir = self.cluster_size() as u32 / 32 - 2;
+        let dirs = files.div_ceil(per_dir);
+        if dirs > per_dir {
+            return Err(FATError::NotEnoughSpace);
+        }
+        let path = |index: u32| format!("{SCRATCH}/{}/{}", index / per_dir, index % per_dir);
+
+        self.mkdir(SCRATCH)?;
+        for dir in 0..dirs {
+            self.mkdir(&format!("{SCRATCH}/{dir}"))?;
+        }
+        let mut phases = vec![];
+
+        let start = Instant::now();
+        for index in 0..files {
+            self.new_file(&path(index), Cursor::new(pattern(index, 0, len)))?;
+        }
+        phases.push(("create", start.elapsed()));
+
+        let start = Instant::now();
+        for index in 0..files {
+            self.cat(&path(index), io::sink())?;
+        }
+        phases.push(("read", start.elapsed()));
+
+        let start = Instant::now();
+        for index in 0..files {
+            self.remove_file(&path(index))?;
+            self.new_file(&path(index), Cursor::new(pattern(index, 0xFF, len)))?;
+        }
+        phases.push(("overwrite", start.elapsed()));
+
+        let start = Instant::now();
+        for index in 0..files {
+            self.remove_file(&path(index))?;
+        }
+        phases.push(("delete", start.elapsed()));
+
+        for dir in 0..dirs {
+            self.remove_dir(&format!("{SCRATCH}/{dir}"))?;
+        }
+        self.remove_dir(SCRATCH)?;
+
+        Ok(phases
+            .into_iter()
+            .map(|(name, elapsed)| Phase {
+                name,
+                ops: files as u64,
+                bytes: files as u64 * len as u64,
+                elapsed,
+            })
+            .collect())
+    }
+}
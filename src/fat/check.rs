@@ -0,0 +1,720 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+};
+
+use crate::json;
+
+use super::{
+    dirent::{Entry, Flags},
+    FATError, FAT,
+};
+
+/// Directory that `check --repair` attaches unreachable chains to.
+const LOST_FOUND: &str = "lost+found";
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CheckOptions {
+    /// Fix the problems found instead of only reporting them.
+    pub repair: bool,
+    /// Restore FAT sectors that differ from the mirror.
+    pub use_mirror: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Problem {
+    FatMirror,
+    BadCluster,
+    Cycle,
+    DirectorySize,
+    CrossLink,
+    FileSize,
+    Orphan,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// File contents are lost or at risk.
+    Error,
+    /// Space is wasted or metadata is off, but no data is at risk.
+    Warning,
+}
+
+impl Problem {
+    pub fn severity(&self) -> Severity {
+        match self {
+            Self::BadCluster | Self::Cycle | Self::CrossLink | Self::FileSize => Severity::Error,
+            Self::FatMirror | Self::DirectorySize | Self::Orphan => Severity::Warning,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::FatMirror => "fat_mirror",
+            Self::BadCluster => "bad_cluster",
+            Self::Cycle => "cycle",
+            Self::DirectorySize => "directory_size",
+            Self::CrossLink => "cross_link",
+            Self::FileSize => "file_size",
+            Self::Orphan => "orphan",
+        }
+    }
+}
+
+/// A fix applied by `check`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Repair {
+    /// The primary copy of a FAT sector was overwritten with the mirror.
+    FatSector { sector: u32 },
+    /// The chain pointed at a bad cluster and now ends at `cluster`.
+    TruncatedChain { path: String, cluster: u32 },
+    /// The chain looped back on itself and now ends at `cluster`.
+    BrokenCycle { path: String, cluster: u32 },
+    /// A directory entry carried a nonzero size.
+    DirectorySize { path: String },
+    /// The chain shared clusters with another entry from `cluster` on and now
+    /// owns a private copy of that tail.
+    CrossLink { path: String, cluster: u32 },
+    /// The file size did not match the length of its chain.
+    FileSize { path: String, old: u64, new: u64 },
+    /// An unreachable chain of `clusters` clusters was attached as `path`.
+    Orphan { path: String, clusters: usize },
+}
+
+impl Display for Repair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::FatSector { sector } => write!(f, "FAT sector {sector} restored from mirror"),
+            Self::TruncatedChain { path, cluster } => {
+                write!(f, "{path}: chain truncated at cluster {cluster}")
+            }
+            Self::BrokenCycle { path, cluster } => {
+                write!(f, "{path}: cycle broken at cluster {cluster}")
+            }
+            Self::DirectorySize { path } => write!(f, "{path}: directory size reset to 0"),
+            Self::CrossLink { path, cluster } => {
+                write!(f, "{path}: shared tail from cluster {cluster} duplicated")
+            }
+            Self::FileSize { path, old, new } => {
+                write!(f, "{path}: size changed from {old} to {new}")
+            }
+            Self::Orphan { path, clusters } => {
+                write!(f, "{path}: recovered {clusters} orphan cluster(s)")
+            }
+        }
+    }
+}
+
+/// A problem found by `check`.
+#[derive(Debug, Clone)]
+pub struct Finding {
+    /// Path of the affected entry, empty when no entry owns the problem.
+    pub path: String,
+    pub problem: Problem,
+    /// Clusters the problem was found at.
+    pub clusters: Vec<u32>,
+    pub message: String,
+    /// The fix applied, if any.
+    pub repair: Option<Repair>,
+}
+
+impl Finding {
+    fn new(path: &str, problem: Problem, clusters: Vec<u32>, message: String) -> Self {
+        Self {
+            path: path.to_string(),
+            problem,
+            clusters,
+            message,
+            repair: None,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"path\":{},\"kind\":{},\"clusters\":[{}],\"message\":{},\"repaired\":{}}}",
+            json::string(&self.path),
+            json::string(self.problem.as_str()),
+            self.clusters
+                .iter()
+                .map(|cluster| cluster.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+            json::string(&self.message),
+            self.repair.is_some()
+        )
+    }
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+/// Outcome of a `check` run.
+#[derive(Debug, Clone, Default)]
+pub struct CheckReport {
+    /// Name and depth of every entry visited, in tree order.
+    pub tree: Vec<(usize, String)>,
+    pub files: usize,
+    pub directories: usize,
+    pub findings: Vec<Finding>,
+}
+
+impl CheckReport {
+    fn count(&self, severity: Severity) -> usize {
+        self.findings
+            .iter()
+            .filter(|finding| finding.problem.severity() == severity)
+            .count()
+    }
+
+    pub fn errors(&self) -> usize {
+        self.count(Severity::Error)
+    }
+
+    pub fn warnings(&self) -> usize {
+        self.count(Severity::Warning)
+    }
+
+    pub fn repairs(&self) -> usize {
+        self.findings
+            .iter()
+            .filter(|finding| finding.repair.is_some())
+            .count()
+    }
+
+    /// Process exit status in the style of fsck: 0 when the image is clean,
+    /// 1 when problems were found and repaired, 4 when errors remain.
+    pub fn exit_code(&self) -> i32 {
+        let unrepaired = self.findings.iter().any(|finding| {
+            finding.problem.severity() == Severity::Error && finding.repair.is_none()
+        });
+
+        if unrepaired {
+            4
+        } else if self.repairs() > 0 {
+            1
+        } else {
+            0
+        }
+    }
+}
+
+impl Display for CheckReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} file(s), {} directories: {} error(s), {} warning(s), {} repaired",
+            self.files,
+            self.directories,
+            self.errors(),
+            self.warnings(),
+            self.repairs()
+        )
+    }
+}
+
+/// A directory entry found while scanning the tree, with the slot it lives
+/// in and the clusters its chain visits.
+pub(super) struct Located {
+    pub(super) path: String,
+    pub(super) entry: Entry,
+    pub(super) dir_cluster: u32,
+    pub(super) index: usize,
+    pub(super) chain: Vec<u32>,
+}
+
+impl FAT {
+    fn check_entry(
+        &mut self,
+        entry: &Entry,
+        path: &str,
+        tabs: usize,
+        options: CheckOptions,
+        report: &mut CheckReport,
+    ) -> Result<(), FATError> {
+        let mut cluster = entry.cluster();
+        report.tree.push((tabs, entry.name().to_string()));
+        if Self::filter_mkdir(entry) {
+            report.directories += 1;
+        } else {
+            report.files += 1;
+        }
+
+        let mut visited = HashSet::new();
+        let mut chain = vec![];
+
+        while cluster != Self::mark_read_done() {
+            if visited.contains(&cluster) {
+                let last = *chain.last().unwrap();
+                let mut finding = Finding::new(
+                    path,
+                    Problem::Cycle,
+                    vec![last],
+                    format!("chain loops back from cluster {last} to {cluster}"),
+                );
+
+                if options.repair {
+                    self.set_cluster_value(last, Self::mark_read_done())?;
+                    finding.repair = Some(Repair::BrokenCycle {
+                        path: path.to_string(),
+                        cluster: last,
+                    });
+                }
+
+                report.findings.push(finding);
+                return Ok(());
+            }
+
+            visited.insert(cluster);
+            chain.push(cluster);
+
+            if entry.flags() & Flags::Directory as u32 == Flags::Directory as u32 {
+                let mut entries = self.read_cluster_entries(cluster)?;
+                for index in 0..entries.len() {
+                    let dirent = entries[index].clone();
+                    if dirent.flags() & Flags::Occupied as u32 != Flags::Occupied as u32
+                        || dirent.name() == "."
+                        || dirent.name() == ".."
+                    {
+                        continue;
+                    }
+
+                    let child = format!("{}/{}", path.trim_end_matches('/'), dirent.name());
+
+                    if Self::filter_mkdir(&dirent) && dirent.size() != 0 {
+                        let mut finding = Finding::new(
+                            &child,
+                            Problem::DirectorySize,
+                            vec![],
+                            format!("directory with size {}", dirent.size()),
+                        );
+
+                        if options.repair {
+                            entries[index].set_size(0);
+                            self.write_cluster_entries(cluster, &entries)?;
+                            finding.repair = Some(Repair::DirectorySize {
+                                path: child.clone(),
+                            });
+                        }
+
+                        report.findings.push(finding);
+                    }
+
+                    self.check_entry(&dirent, &child, tabs + 1, options, report)?;
+                }
+            }
+
+            cluster = self.next_cluster(cluster)?;
+
+            if cluster == Self::mark_bad_cluster() {
+                let bad = *chain.last().unwrap();
+                let mut finding = Finding::new(
+                    path,
+                    Problem::BadCluster,
+                    vec![bad],
+                    format!("chain runs into bad cluster {bad}"),
+                );
+
+                // the last cluster is the bad one, so the chain ends at the one before it
+                let before_bad = chain.len().checked_sub(2).map(|index| chain[index]);
+                if let (true, Some(last)) = (options.repair, before_bad) {
+                    self.set_cluster_value(last, Self::mark_read_done())?;
+                    finding.repair = Some(Repair::TruncatedChain {
+                        path: path.to_string(),
+                        cluster: last,
+                    });
+                }
+
+                report.findings.push(finding);
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn is_data_cluster(&self, cluster: u32) -> bool {
+        let header = self.header.as_ref().expect("Image is not formatted!");
+        (1..=header.data_cluster_count()).contains(&cluster)
+    }
+
+    /// Collects every cluster reachable from the root directory.
+    pub(super) fn reachable_clusters(&mut self) -> Result<HashSet<u32>, FATError> {
+        let mut reachable = HashSet::new();
+        let mut pending = vec![(1, true)];
+
+        while let Some((start, is_dir)) = pending.pop() {
+            let mut cluster = start;
+
+            while self.is_data_cluster(cluster) && reachable.insert(cluster) {
+                if is_dir {
+                    for entry in self.read_cluster_entries(cluster)? {
+                        // the `.` entry of a large directory points at its index
+                        if entry.name() == "." && entry.xattrs() != 0 {
+                            pending.push((entry.xattrs(), false));
+                        }
+                        if Self::filter_find(&entry) && entry.name() != "." && entry.name() != ".."
+                        {
+                            pending.push((entry.cluster(), Self::filter_mkdir(&entry)));
+                            if entry.xattrs() != 0 {
+                                pending.push((entry.xattrs(), false));
+                            }
+                        }
+                    }
+                }
+
+                cluster = self.next_cluster(cluster)?;
+            }
+        }
+
+        Ok(reachable)
+    }
+
+    /// Clusters of the chain starting at `start`, stopping at the end of the
+    /// chain, a bad cluster, or the first cluster seen twice.
+    pub(super) fn chain_of(&mut self, start: u32) -> Result<Vec<u32>, FATError> {
+        if let Some(volume) = &self.foreign {
+            return Ok(volume.chain(start));
+        }
+        let mut chain = vec![];
+        let mut seen = HashSet::new();
+        let mut cluster = start;
+
+        while self.is_data_cluster(cluster) && seen.insert(cluster) {
+            chain.push(cluster);
+            cluster = self.next_cluster(cluster)?;
+        }
+
+        Ok(chain)
+    }
+
+    /// Every entry reachable from the root directory, without following loops.
+    pub(super) fn located_entries(&mut self) -> Result<Vec<Located>, FATError> {
+        let mut located = vec![];
+        let mut visited = HashSet::new();
+        let mut pending = vec![(1, String::new())];
+
+        while let Some((start, prefix)) = pending.pop() {
+            for dir_cluster in self.chain_of(start)? {
+                if !visited.insert(dir_cluster) {
+                    continue;
+                }
+
+                for (index, entry) in self
+                    .read_cluster_entries(dir_cluster)?
+                    .into_iter()
+                    .enumerate()
+                {
+                    if !Self::filter_find(&entry) || entry.name() == "." || entry.name() == ".." {
+                        continue;
+                    }
+
+                    let path = format!("{prefix}/{}", entry.name());
+                    if Self::filter_mkdir(&entry) {
+                        pending.push((entry.cluster(), path.clone()));
+                    }
+
+                    let chain = self.chain_of(entry.cluster())?;
+                    located.push(Located {
+                        path,
+                        entry,
+                        dir_cluster,
+                        index,
+                        chain,
+                    });
+                }
+            }
+        }
+
+        Ok(located)
+    }
+
+    /// Gives the file at `located` a private copy of its chain from position
+    /// `from` on.
+    fn unshare_tail(&mut self, located: &Located, from: usize) -> Result<Vec<u32>, FATError> {
+        let tail = &located.chain[from..];
+        let head = self.allocate_clusters(tail.len() as u32)?;
+
+        let mut copy = vec![];
+        let mut target = head;
+        for cluster in tail {
+            let data = self.read_cluster(*cluster)?;
+            self.write_cluster(target, &data)?;
+            copy.push(target);
+            target = self.next_cluster(target)?;
+        }
+
+        if from == 0 {
+            let mut entries = self.read_cluster_entries(located.dir_cluster)?;
+            entries[located.index].set_cluster(head);
+            self.write_cluster_entries(located.dir_cluster, &entries)?;
+        } else {
+            self.set_cluster_value(located.chain[from - 1], head)?;
+        }
+
+        Ok(copy)
+    }
+
+    /// Reports chains that share clusters with an earlier entry. When
+    /// repairing, files get their own copy of the shared tail.
+    fn check_cross_links(
+        &mut self,
+        options: CheckOptions,
+        report: &mut CheckReport,
+    ) -> Result<(), FATError> {
+        let mut owners: HashMap<u32, String> = HashMap::new();
+
+        for located in self.located_entries()? {
+            let shared = located
+                .chain
+                .iter()
+                .position(|cluster| owners.contains_key(cluster));
+
+            let mut chain = located.chain.clone();
+
+            // a reflinked copy shares the whole chain on purpose
+            let reflinked = shared == Some(0)
+                && !Self::filter_mkdir(&located.entry)
+                && self.refcount(located.chain[0])? > 0;
+
+            if let Some(from) = shared.filter(|_| !reflinked) {
+                let cluster = located.chain[from];
+                let mut finding = Finding::new(
+                    &located.path,
+                    Problem::CrossLink,
+                    vec![cluster],
+                    format!("shares cluster {cluster} with {}", owners[&cluster]),
+                );
+
+                if options.repair && !Self::filter_mkdir(&located.entry) {
+                    chain.truncate(from);
+                    chain.extend(self.unshare_tail(&located, from)?);
+                    finding.repair = Some(Repair::CrossLink {
+                        path: located.path.clone(),
+                        cluster,
+                    });
+                }
+
+                report.findings.push(finding);
+            }
+
+            for cluster in chain {
+                owners
+                    .entry(cluster)
+                    .or_insert_with(|| located.path.clone());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reports files whose size needs a different number of clusters than
+    /// their chain has. When repairing, the size is set to the chain length.
+    fn check_sizes(
+        &mut self,
+        options: CheckOptions,
+        report: &mut CheckReport,
+    ) -> Result<(), FATError> {
+        let cluster_size = self.cluster_size() as u64;
+
+        for located in self.located_entries()? {
+            if Self::filter_mkdir(&located.entry) {
+                continue;
+            }
+
+            let size = located.entry.size();
+            let expected = self.stored_clusters(&located.entry)? as usize;
+            if expected == located.chain.len() {
+                continue;
+            }
+
+            let mut finding = Finding::new(
+                &located.path,
+                Problem::FileSize,
+                located.chain.clone(),
+                format!(
+                    "size {size} needs {expected} cluster(s), chain has {}",
+                    located.chain.len()
+                ),
+            );
+
+            // the size of a compressed or sparse file cannot be derived from its chain
+            if options.repair
+                && !Self::is_compressed(&located.entry)
+                && !Self::is_sparse(&located.entry)
+            {
+                let new = located.chain.len() as u64 * cluster_size;
+                let mut entries = self.read_cluster_entries(located.dir_cluster)?;
+                // every file owns at least one cluster, so one without a
+                // chain gets an empty one
+                if located.chain.is_empty() {
+                    let cluster = self.allocate_clusters(1)?;
+                    self.write_cluster(cluster, &self.empty_cluster())?;
+                    entries[located.index].set_cluster(cluster);
+                }
+                entries[located.index].set_size(new);
+                self.write_cluster_entries(located.dir_cluster, &entries)?;
+                finding.repair = Some(Repair::FileSize {
+                    path: located.path,
+                    old: size,
+                    new,
+                });
+            }
+
+            report.findings.push(finding);
+        }
+
+        Ok(())
+    }
+
+    /// Allocated clusters that no directory entry leads to, grouped into chains.
+    fn find_orphans(&mut self) -> Result<Vec<Vec<u32>>, FATError> {
+        let reachable = self.reachable_clusters()?;
+        let fat = self.read_whole_fat()?;
+
+        let orphans = (1..fat.len() as u32)
+            .filter(|cluster| self.is_data_cluster(*cluster))
+            .filter(|cluster| Self::is_used(fat[*cluster as usize]) && !reachable.contains(cluster))
+            .collect::<HashSet<_>>();
+        let targets = orphans
+            .iter()
+            .map(|cluster| fat[*cluster as usize])
+            .collect::<HashSet<_>>();
+
+        let mut heads = orphans
+            .iter()
+            .filter(|cluster| !targets.contains(cluster))
+            .copied()
+            .collect::<Vec<_>>();
+        heads.sort();
+        // chains that loop back on themselves have no head, start them anywhere
+        let mut rest = orphans.iter().copied().collect::<Vec<_>>();
+        rest.sort();
+        heads.extend(rest);
+
+        let mut taken = HashSet::new();
+        let mut chains = vec![];
+
+        for head in heads {
+            let mut chain = vec![];
+            let mut cluster = head;
+
+            while orphans.contains(&cluster) && taken.insert(cluster) {
+                chain.push(cluster);
+                cluster = fat[cluster as usize];
+            }
+
+            if !chain.is_empty() {
+                chains.push(chain);
+            }
+        }
+
+        Ok(chains)
+    }
+
+    /// Terminates an orphaned chain and attaches it under `/lost+found`.
+    ///
+    /// Returns the path of the recovered file.
+    fn recover_chain(&mut self, chain: &[u32]) -> Result<String, FATError> {
+        let last = *chain.last().unwrap();
+        if self.next_cluster(last)? != Self::mark_read_done() {
+            self.set_cluster_value(last, Self::mark_read_done())?;
+        }
+
+        if self.find_file(LOST_FOUND, Self::filter_mkdir).is_err() {
+            self.mkdir(LOST_FOUND)?;
+        }
+        let dir = self.find_file(LOST_FOUND, Self::filter_mkdir)?;
+
+        let name = (0..1000)
+            .map(|index| format!("FOUND.{index:03}"))
+            .find(|name| {
+                self.find_file(&format!("{LOST_FOUND}/{name}"), Self::filter_find)
+                    .is_err()
+            })
+            .ok_or(FATError::NotEnoughSpace)?;
+
+        let size = chain.len() as u64 * self.cluster_size() as u64;
+        let entry = Entry::new(&name, size, chain[0], Flags::Occupied as u32).unwrap();
+        self.insert_entry(dir.cluster(), entry)?;
+
+        Ok(format!("/{LOST_FOUND}/{name}"))
+    }
+
+    fn check_orphans(
+        &mut self,
+        options: CheckOptions,
+        report: &mut CheckReport,
+    ) -> Result<(), FATError> {
+        for chain in self.find_orphans()? {
+            let mut finding = Finding::new(
+                "",
+                Problem::Orphan,
+                chain.clone(),
+                format!("{} orphan cluster(s) starting at {}", chain.len(), chain[0]),
+            );
+
+            if options.repair {
+                let path = self.recover_chain(&chain)?;
+                finding.path = path.clone();
+                finding.repair = Some(Repair::Orphan {
+                    path,
+                    clusters: chain.len(),
+                });
+            }
+
+            report.findings.push(finding);
+        }
+
+        Ok(())
+    }
+
+    fn check_mirror(
+        &mut self,
+        options: CheckOptions,
+        report: &mut CheckReport,
+    ) -> Result<(), FATError> {
+        for sector in self.check_fat_mirror(options.use_mirror)? {
+            let mut finding = Finding::new(
+                "",
+                Problem::FatMirror,
+                vec![],
+                format!("FAT sector {sector} differs from mirror"),
+            );
+
+            if options.use_mirror {
+                finding.repair = Some(Repair::FatSector { sector });
+            }
+
+            report.findings.push(finding);
+        }
+
+        Ok(())
+    }
+
+    /// Walks the whole tree and reports the problems found.
+    ///
+    /// When repairing, chains are cut short before bad clusters and cycles,
+    /// directory sizes are reset, files sharing clusters get their own copies,
+    /// file sizes are matched to their chains, and unreachable chains are
+    /// attached under `/lost+found`.
+    pub fn check(&mut self, options: CheckOptions) -> Result<CheckReport, FATError> {
+        let mut report = CheckReport::default();
+        self.check_mirror(options, &mut report)?;
+
+        let root = Entry::new("/", 0, 1, Flags::Occupied as u32 | Flags::Directory as u32).unwrap();
+        self.check_entry(&root, "/", 0, options, &mut report)?;
+        self.check_cross_links(options, &mut report)?;
+        self.check_sizes(options, &mut report)?;
+        self.check_orphans(options, &mut report)?;
+
+        self.header.as_mut().unwrap().record_check();
+        self.store_header()?;
+        Ok(report)
+    }
+}
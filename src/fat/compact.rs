@@ -0,0 +1,62 @@
+use super::{dirent::Entry, FATError, FAT};
+
+impl FAT {
+    /// Packs the entries of the directory at `path` into its first clusters
+    /// and frees the clusters left empty at the end of its chain, returning
+    /// how many were freed.
+    ///
+    /// Removed entries are dropped along the way, so they can no longer be
+    /// undeleted.
+    pub fn compact_dir(&mut self, path: &str) -> Result<usize, FATError> {
+        let dir = self.find_file(path, Self::filter_mkdir)?;
+        self.transaction(|fs| fs.compact_chain(dir.cluster()))
+    }
+
+    /// Compacts the directory `dir` once at least half of its clusters would
+    /// be freed, so that one emptied by many removals shrinks back.
+    pub(super) fn compact_if_sparse(&mut self, dir: &Entry) -> Result<(), FATError> {
+        let chain = self.chain_of(dir.cluster())?;
+        if chain.len() < 2 {
+            return Ok(());
+        }
+
+        let mut live = 0;
+        for &cluster in &chain {
+            live += self
+                .read_cluster_entries(cluster)?
+                .iter()
+                .filter(|entry| Self::filter_find(entry))
+                .count();
+        }
+        if live.div_ceil(self.cluster_size() / 32) * 2 <= chain.len() {
+            self.compact_chain(dir.cluster())?;
+        }
+        Ok(())
+    }
+
+    fn compact_chain(&mut self, first: u32) -> Result<usize, FATError> {
+        let chain = self.chain_of(first)?;
+        let mut live = vec![];
+        for &cluster in &chain {
+            live.extend(
+                self.read_cluster_entries(cluster)?
+                    .into_iter()
+                    .filter(Self::filter_find),
+            );
+        }
+
+        // `.` and `..` come first and stay first, as the order is kept
+        let slots = self.cluster_size() / 32;
+        let keep = live.len().div_ceil(slots).max(1);
+        for (cluster, entries) in chain.iter().zip(live.chunks(slots)) {
+            self.write_cluster_entries(*cluster, entries)?;
+        }
+
+        if keep < chain.len() {
+            self.link_chain(&chain[keep - 1..keep])?;
+            self.dealloc_clusters(chain[keep])?;
+        }
+        self.reindex_dir(first)?;
+        Ok(chain.len() - keep)
+    }
+}
@@ -0,0 +1,226 @@
+use std::{io::Cursor, mem::size_of};
+
+use super::{
+    dirent::{Entry, Flags},
+    perms::OWNERSHIP,
+    FATError, FAT,
+};
+
+const MIN_MATCH: usize = 4;
+const MAX_MATCH: usize = MIN_MATCH + u8::MAX as usize;
+const WINDOW: usize = u16::MAX as usize;
+const HASH_BITS: u32 = 14;
+
+/// Compresses `input` with a byte-oriented LZ77.
+///
+/// Every group of eight tokens is preceded by a control byte whose bits tell
+/// literals (0) from matches (1). A literal is one byte, a match a 16-bit
+/// back offset followed by the match length minus [`MIN_MATCH`].
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2);
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let mut i = 0;
+
+    while i < input.len() {
+        let control = out.len();
+        out.push(0);
+
+        for bit in 0..8 {
+            if i >= input.len() {
+                break;
+            }
+
+            let mut len = 0;
+            let mut offset = 0;
+            if i + MIN_MATCH <= input.len() {
+                let word = u32::from_le_bytes(input[i..i + MIN_MATCH].try_into().unwrap());
+                let slot = (word.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize;
+                let candidate = table[slot];
+                table[slot] = i;
+
+                if candidate != usize::MAX && i - candidate <= WINDOW {
+                    let limit = MAX_MATCH.min(input.len() - i);
+                    while len < limit && input[candidate + len] == input[i + len] {
+                        len += 1;
+                    }
+                    offset = i - candidate;
+                }
+            }
+
+            if len >= MIN_MATCH {
+                out[control] |= 1 << bit;
+                out.extend_from_slice(&(offset as u16).to_le_bytes());
+                out.push((len - MIN_MATCH) as u8);
+                i += len;
+            } else {
+                out.push(input[i]);
+                i += 1;
+            }
+        }
+    }
+
+    out
+}
+
+/// Reverses [`compress`], failing on malformed input or a length mismatch.
+pub fn decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut bytes = input.iter().copied();
+
+    while out.len() < len {
+        let control = bytes.next()?;
+
+        for bit in 0..8 {
+            if out.len() >= len {
+                break;
+            }
+
+            if control & 1 << bit == 0 {
+                out.push(bytes.next()?);
+                continue;
+            }
+
+            let offset = u16::from_le_bytes([bytes.next()?, bytes.next()?]) as usize;
+            let count = bytes.next()? as usize + MIN_MATCH;
+            if offset == 0 || offset > out.len() {
+                return None;
+            }
+
+            let start = out.len() - offset;
+            for k in 0..count {
+                out.push(out[start + k]);
+            }
+        }
+    }
+
+    (out.len() == len).then_some(out)
+}
+
+impl FAT {
+    pub fn is_compressed(entry: &Entry) -> bool {
+        entry.flags() & Flags::Compressed as u32 != 0
+    }
+
+    /// Whether files are compressed as they are created.
+    pub(super) fn compress_by_default(&self) -> bool {
+        self.header
+            .as_ref()
+            .is_some_and(|header| header.compresses_by_default())
+    }
+
+    /// Prefixes the compressed form of `data` with its length, or returns
+    /// `None` when compressing would not save anything.
+    fn pack(data: &[u8]) -> Option<Vec<u8>> {
+        let compressed = compress(data);
+        let len = u32::try_from(compressed.len()).ok()?;
+        if compressed.len() + size_of::<u32>() >= data.len() {
+            return None;
+        }
+
+        let mut packed = len.to_le_bytes().to_vec();
+        packed.extend_from_slice(&compressed);
+        Some(packed)
+    }
+
+    /// Number of bytes the file's chain actually holds.
+    pub(super) fn stored_size(&mut self, entry: &Entry) -> Result<u64, FATError> {
+        if Self::is_sparse(entry) {
+            return self.sparse_stored_size(entry);
+        }
+        if !Self::is_compressed(entry) {
+            return Ok(entry.size());
+        }
+
+        let first = self.read_cluster(entry.cluster())?;
+        let len = u32::from_le_bytes(first[..size_of::<u32>()].try_into().unwrap());
+        Ok(size_of::<u32>() as u64 + len as u64)
+    }
+
+    /// Number of clusters the file's chain should have.
+    pub(super) fn stored_clusters(&mut self, entry: &Entry) -> Result<u32, FATError> {
+        let size = self.stored_size(entry)?;
+        Ok(size.div_ceil(self.cluster_size() as u64).max(1) as u32)
+    }
+
+    /// Reads the whole chain of a compressed file and returns its contents.
+    pub(super) fn read_compressed(&mut self, entry: &Entry) -> Result<Vec<u8>, FATError> {
+        let mut stored = vec![];
+        for cluster in self.chain_of(entry.cluster())? {
+            stored.extend(self.read_cluster(cluster)?);
+        }
+
+        let len = stored
+            .get(..size_of::<u32>())
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+            .ok_or(FATError::CannotRead)?;
+        let body = stored
+            .get(size_of::<u32>()..size_of::<u32>() + len)
+            .ok_or(FATError::CannotRead)?;
+
+        decompress(body, entry.size() as usize).ok_or(FATError::CannotRead)
+    }
+
+    /// Creates `path` from `data`, compressed when `compress` is set and it helps.
+    pub(super) fn store_data(
+        &mut self,
+        path: &str,
+        data: Vec<u8>,
+        compress: bool,
+    ) -> Result<(), FATError> {
+        let size = data.len() as u64;
+        match Some(&data)
+            .filter(|_| compress)
+            .and_then(|data| Self::pack(data))
+        {
+            Some(packed) => {
+                self.store_file(path, size, Flags::Compressed as u32, Cursor::new(packed))
+            }
+            None => self.store_file(path, size, 0, Cursor::new(data)),
+        }
+    }
+
+    /// Rewrites the file at `path` compressed or uncompressed.
+    ///
+    /// Files that would not get any smaller stay uncompressed.
+    pub fn set_compressed(&mut self, path: &str, compressed: bool) -> Result<(), FATError> {
+        let entry = self.find_file(path, Self::filter_find_file)?;
+        if Self::is_compressed(&entry) == compressed {
+            return Ok(());
+        }
+
+        let mut data = vec![];
+        self.read_entry(&entry, &mut data)?;
+        let (stored, flags) = match Some(&data)
+            .filter(|_| compressed)
+            .and_then(|data| Self::pack(data))
+        {
+            Some(packed) => (packed, Flags::Compressed as u32),
+            None if compressed => return Ok(()),
+            None => (data, 0),
+        };
+
+        // write the new chain before letting go of the old one
+        let cluster_size = self.cluster_size();
+        let head = self.allocate_clusters(stored.len().div_ceil(cluster_size) as u32)?;
+        let mut cluster = head;
+        for chunk in stored.chunks(cluster_size) {
+            let mut buffer = self.empty_cluster();
+            buffer[..chunk.len()].clone_from_slice(chunk);
+            self.write_cluster(cluster, &buffer)?;
+            cluster = self.next_cluster(cluster)?;
+        }
+
+        let (dir, filename) = Self::split_path(path);
+        let dir = self.find_file(dir, Self::filter_mkdir)?;
+        self.update_file_in_dir(
+            &dir,
+            |other| other.name() == filename && Self::filter_find_file(other),
+            |other| {
+                other.set_cluster(head);
+                other.set_flags(Flags::Occupied as u32 | flags | other.flags() & OWNERSHIP);
+            },
+        )?;
+
+        self.dealloc_clusters(entry.cluster())
+    }
+}
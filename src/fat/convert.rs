@@ -0,0 +1,480 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Cursor, Seek, SeekFrom, Write},
+};
+
+use crate::{time, units::Unit};
+
+use super::{
+    archive::{Counted, Member},
+    header::{FormatOptions, CLUSTER_SIZE, LABEL_LENGTH},
+    FATError, FAT,
+};
+
+const SECTOR: u64 = 512;
+const RESERVED_SECTORS: u64 = 32;
+const FSINFO_SECTOR: usize = 1;
+const BACKUP_BOOT_SECTOR: usize = 6;
+/// Fewer clusters than this and drivers take the volume for FAT16.
+const MIN_FAT32_CLUSTERS: u64 = 65536;
+const MAX_FAT32_CLUSTERS: u64 = 0x0FFF_FFF5;
+const END_OF_CHAIN: u32 = 0x0FFF_FFFF;
+
+const ATTR_VOLUME: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0F;
+const DIR_ENTRY: usize = 32;
+/// UTF-16 units of a name one long name entry holds.
+const LONG_NAME_CHARS: usize = 13;
+
+/// Cluster size Windows picks for a FAT32 volume of `bytes`.
+fn fat32_cluster_size(bytes: u64) -> u64 {
+    match bytes >> 20 {
+        0..=64 => 512,
+        65..=128 => 1024,
+        129..=256 => 2048,
+        257..=8192 => 4096,
+        8193..=16384 => 8192,
+        16385..=32768 => 16384,
+        _ => 32768,
+    }
+}
+
+/// Checksum of a short name that ties its long name entries to it.
+fn short_checksum(short: &[u8; 11]) -> u8 {
+    short
+        .iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// `name` cut down to the characters a short name may hold, and whether
+/// anything was lost on the way.
+fn short_part(name: &str, len: usize) -> (String, bool) {
+    let mut part = String::new();
+    let mut lossy = false;
+    for c in name.chars() {
+        match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => part.push(c.to_ascii_uppercase()),
+            '!' | '#' | '$' | '%' | '&' | '\'' | '(' | ')' | '-' | '@' | '^' | '_' | '`' | '{'
+            | '}' | '~' => part.push(c),
+            ' ' | '.' => lossy = true,
+            _ => {
+                part.push('_');
+                lossy = true;
+            }
+        }
+    }
+    if part.len() > len {
+        part.truncate(len);
+        lossy = true;
+    }
+    (part, lossy)
+}
+
+/// An 8.3 name for `name` no other entry of the directory has yet, with a
+/// `~N` tail when the name had to be shortened.
+fn short_name(name: &str, taken: &mut HashSet<[u8; 11]>) -> [u8; 11] {
+    let trimmed = name.trim_start_matches(['.', ' ']);
+    let (base, ext) = match trimmed.rsplit_once('.') {
+        Some((base, ext)) if !base.is_empty() => (base, ext),
+        _ => (trimmed, ""),
+    };
+    let (mut base, base_lossy) = short_part(base, 8);
+    let (ext, ext_lossy) = short_part(ext, 3);
+    if base.is_empty() {
+        base.push('_');
+    }
+
+    let pack = |base: &str| {
+        let mut short = [b' '; 11];
+        short[..base.len()].copy_from_slice(base.as_bytes());
+        short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+        short
+    };
+
+    let short = pack(&base);
+    if !(base_lossy || ext_lossy || trimmed.len() != name.len()) && taken.insert(short) {
+        return short;
+    }
+    (1..)
+        .map(|n| {
+            let tail = format!("~{n}");
+            let keep = base.len().min(8 - tail.len());
+            pack(&format!("{}{tail}", &base[..keep]))
+        })
+        .find(|short| taken.insert(*short))
+        .expect("a directory cannot use up every tail")
+}
+
+/// What `short` reads as, so a name that matches it needs no long name.
+fn short_display(short: &[u8; 11]) -> String {
+    let base = String::from_utf8_lossy(&short[..8]).trim_end().to_string();
+    let ext = String::from_utf8_lossy(&short[8..]).trim_end().to_string();
+    if ext.is_empty() {
+        base
+    } else {
+        format!("{base}.{ext}")
+    }
+}
+
+/// The long name entries of `name`, in the order they go on disk.
+fn long_entries(name: &str, checksum: u8) -> Vec<[u8; DIR_ENTRY]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    let count = units.len().div_ceil(LONG_NAME_CHARS);
+    if units.len() < count * LONG_NAME_CHARS {
+        units.push(0);
+    }
+    units.resize(count * LONG_NAME_CHARS, 0xFFFF);
+
+    (0..count)
+        .rev()
+        .map(|index| {
+            let mut entry = [0; DIR_ENTRY];
+            entry[0] = (index + 1) as u8 | if index + 1 == count { 0x40 } else { 0 };
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = checksum;
+            let chars = &units[index * LONG_NAME_CHARS..][..LONG_NAME_CHARS];
+            let offsets = (1..11)
+                .step_by(2)
+                .chain((14..26).step_by(2))
+                .chain([28, 30]);
+            for (offset, unit) in offsets.zip(chars) {
+                entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            entry
+        })
+        .collect()
+}
+
+fn short_entry(short: &[u8; 11], attributes: u8, cluster: u32, size: u32) -> [u8; DIR_ENTRY] {
+    let (time, date) = time::dos(time::now());
+    let mut entry = [0; DIR_ENTRY];
+    entry[..11].copy_from_slice(short);
+    entry[11] = attributes;
+    for at in [14, 22] {
+        entry[at..at + 2].copy_from_slice(&time.to_le_bytes());
+    }
+    for at in [16, 18, 24] {
+        entry[at..at + 2].copy_from_slice(&date.to_le_bytes());
+    }
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+    entry
+}
+
+/// A directory of the converted tree: its member, or `None` for the root,
+/// and the members in it.
+struct Dir {
+    member: Option<usize>,
+    children: Vec<usize>,
+}
+
+/// Where a member of the tree ends up on the FAT32 volume.
+#[derive(Clone, Copy, Default)]
+struct Placement {
+    short: [u8; 11],
+    long: bool,
+    cluster: u32,
+    clusters: u64,
+}
+
+impl FAT {
+    /// Bytes the data region of the image can hold.
+    fn data_capacity(&self) -> u64 {
+        if let Some(volume) = &self.foreign {
+            return volume.cluster_count as u64 * volume.cluster_size() as u64;
+        }
+        self.header.as_ref().map_or(0, |header| {
+            header.data_cluster_count() as u64 * header.cluster_size() as u64
+        })
+    }
+
+    fn volume_label(&self) -> &str {
+        match (&self.foreign, &self.header) {
+            (Some(volume), _) => &volume.label,
+            (None, Some(header)) => header.label(),
+            _ => "",
+        }
+    }
+
+    /// Writes the whole tree into `out` as a FAT32 volume that real operating
+    /// systems mount, as large as this image or as the tree needs, and
+    /// returns its length. Free space is skipped over rather than written, so
+    /// a file grown to that length stays sparse.
+    ///
+    /// Files are stored as they read, one contiguous run of clusters each;
+    /// files of 4 GiB and more do not fit FAT32 and fail with
+    /// [`FATError::FileTooLarge`].
+    pub fn convert_to_fat32<W: Write + Seek>(&mut self, mut out: W) -> Result<u64, FATError> {
+        let members = self.archive_members(".")?;
+        if members.iter().any(|member| member.size > u32::MAX as u64) {
+            return Err(FATError::FileTooLarge);
+        }
+
+        let mut dirs = vec![Dir {
+            member: None,
+            children: vec![],
+        }];
+        let mut dir_of = HashMap::from([(String::new(), 0)]);
+        for (index, member) in members.iter().enumerate() {
+            let name = member.name.trim_end_matches('/');
+            let parent = name.rfind('/').map_or("", |slash| &name[..=slash]);
+            let parent = *dir_of.get(parent).ok_or(FATError::CannotRead)?;
+            dirs[parent].children.push(index);
+            if member.dir {
+                dir_of.insert(member.name.clone(), dirs.len());
+                dirs.push(Dir {
+                    member: Some(index),
+                    children: vec![],
+                });
+            }
+        }
+
+        let label = short_part(self.volume_label(), 11).0;
+        let needed = members
+            .iter()
+            .map(|member| member.size.max(1).div_ceil(SECTOR) + 1)
+            .sum::<u64>()
+            * SECTOR;
+        let cluster_size = fat32_cluster_size(self.data_capacity().max(needed));
+
+        let mut placements = vec![Placement::default(); members.len()];
+        let mut dir_clusters = vec![0; dirs.len()];
+        for (index, dir) in dirs.iter().enumerate() {
+            let mut taken = HashSet::new();
+            let mut entries = if dir.member.is_some() { 2 } else { 0 } + !label.is_empty() as u64;
+            for &child in &dir.children {
+                let name = Member::base_name(&members[child]);
+                let short = short_name(name, &mut taken);
+                let long = short_display(&short) != name;
+                placements[child].short = short;
+                placements[child].long = long;
+                entries += 1;
+                if long {
+                    entries += name.encode_utf16().count().div_ceil(LONG_NAME_CHARS) as u64;
+                }
+            }
+            dir_clusters[index] = (entries * DIR_ENTRY as u64).div_ceil(cluster_size).max(1);
+        }
+
+        // Everything is laid out one after another from cluster 2 on, the
+        // root first and then the members in archive order.
+        let root_clusters = dir_clusters[0];
+        let mut next = 2 + root_clusters;
+        for (index, member) in members.iter().enumerate() {
+            let clusters = if member.dir {
+                let dir = dir_of[&member.name];
+                dir_clusters[dir]
+            } else {
+                member.size.div_ceil(cluster_size)
+            };
+            if clusters > 0 {
+                placements[index].cluster = next as u32;
+                placements[index].clusters = clusters;
+                next += clusters;
+            }
+        }
+
+        let used = next - 2;
+        let clusters = (self.data_capacity() / cluster_size)
+            .max(used)
+            .max(MIN_FAT32_CLUSTERS);
+        if clusters > MAX_FAT32_CLUSTERS {
+            return Err(FATError::NotEnoughSpace);
+        }
+        let fat_sectors = ((clusters + 2) * 4).div_ceil(SECTOR);
+        let data_sector = RESERVED_SECTORS + 2 * fat_sectors;
+        let sectors = data_sector + clusters * (cluster_size / SECTOR);
+        if sectors > u32::MAX as u64 {
+            return Err(FATError::NotEnoughSpace);
+        }
+
+        let mut reserved = vec![0; (RESERVED_SECTORS * SECTOR) as usize];
+        let boot = &mut reserved[..SECTOR as usize];
+        boot[..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+        boot[3..11].copy_from_slice(b"ZOS_RS  ");
+        boot[11..13].copy_from_slice(&(SECTOR as u16).to_le_bytes());
+        boot[13] = (cluster_size / SECTOR) as u8;
+        boot[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+        boot[16] = 2;
+        boot[21] = 0xF8;
+        boot[24..26].copy_from_slice(&63u16.to_le_bytes());
+        boot[26..28].copy_from_slice(&255u16.to_le_bytes());
+        boot[32..36].copy_from_slice(&(sectors as u32).to_le_bytes());
+        boot[36..40].copy_from_slice(&(fat_sectors as u32).to_le_bytes());
+        boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+        boot[48..50].copy_from_slice(&(FSINFO_SECTOR as u16).to_le_bytes());
+        boot[50..52].copy_from_slice(&(BACKUP_BOOT_SECTOR as u16).to_le_bytes());
+        boot[64] = 0x80;
+        boot[66] = 0x29;
+        boot[67..71].copy_from_slice(&(time::now() as u32).to_le_bytes());
+        let mut volume_label = [b' '; 11];
+        if label.is_empty() {
+            volume_label.copy_from_slice(b"NO NAME    ");
+        } else {
+            volume_label[..label.len()].copy_from_slice(label.as_bytes());
+        }
+        boot[71..82].copy_from_slice(&volume_label);
+        boot[82..90].copy_from_slice(b"FAT32   ");
+        boot[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+        let info = &mut reserved[FSINFO_SECTOR * SECTOR as usize..][..SECTOR as usize];
+        info[..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+        info[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+        info[488..492].copy_from_slice(&((clusters - used) as u32).to_le_bytes());
+        info[492..496].copy_from_slice(&(next as u32).to_le_bytes());
+        info[508..512].copy_from_slice(&0xAA55_0000u32.to_le_bytes());
+        reserved.copy_within(..2 * SECTOR as usize, BACKUP_BOOT_SECTOR * SECTOR as usize);
+
+        let mut fat = vec![0x0FFF_FFF8, END_OF_CHAIN];
+        let runs =
+            std::iter::once(2..2 + root_clusters).chain(placements.iter().map(|placement| {
+                placement.cluster as u64..placement.cluster as u64 + placement.clusters
+            }));
+        for run in runs.filter(|run| !run.is_empty()) {
+            fat.extend((run.start + 1..run.end).map(|cluster| cluster as u32));
+            fat.push(END_OF_CHAIN);
+        }
+        let fat: Vec<u8> = fat.iter().flat_map(|entry| entry.to_le_bytes()).collect();
+
+        let write = |out: &mut W, at: u64, bytes: &[u8]| {
+            out.seek(SeekFrom::Start(at))
+                .and_then(|_| out.write_all(bytes))
+                .map_err(|_| FATError::CannotWrite)
+        };
+        write(&mut out, 0, &reserved)?;
+        for copy in 0..2 {
+            write(
+                &mut out,
+                (RESERVED_SECTORS + copy * fat_sectors) * SECTOR,
+                &fat,
+            )?;
+        }
+
+        let data = data_sector * SECTOR;
+        let offset_of = |cluster: u64| data + (cluster - 2) * cluster_size;
+        for (index, dir) in dirs.iter().enumerate() {
+            let (cluster, parent) = match dir.member {
+                Some(member) => {
+                    let name = members[member].name.trim_end_matches('/');
+                    let parent = name.rfind('/').map_or(0, |slash| dir_of[&name[..=slash]]);
+                    let parent = match dirs[parent].member {
+                        Some(parent) => placements[parent].cluster,
+                        None => 0,
+                    };
+                    (placements[member].cluster, Some(parent))
+                }
+                None => (2, None),
+            };
+
+            let mut bytes = Vec::with_capacity((dir_clusters[index] * cluster_size) as usize);
+            if let Some(parent) = parent {
+                bytes.extend(short_entry(b".          ", ATTR_DIRECTORY, cluster, 0));
+                bytes.extend(short_entry(b"..         ", ATTR_DIRECTORY, parent, 0));
+            } else if !label.is_empty() {
+                bytes.extend(short_entry(&volume_label, ATTR_VOLUME, 0, 0));
+            }
+            for &child in &dir.children {
+                let member = &members[child];
+                let placement = &placements[child];
+                if placement.long {
+                    let checksum = short_checksum(&placement.short);
+                    for entry in long_entries(Member::base_name(member), checksum) {
+                        bytes.extend(entry);
+                    }
+                }
+                let attributes = if member.dir {
+                    ATTR_DIRECTORY
+                } else {
+                    ATTR_ARCHIVE
+                };
+                let size = if member.dir { 0 } else { member.size as u32 };
+                bytes.extend(short_entry(
+                    &placement.short,
+                    attributes,
+                    placement.cluster,
+                    size,
+                ));
+            }
+            bytes.resize((dir_clusters[index] * cluster_size) as usize, 0);
+            write(&mut out, offset_of(cluster as u64), &bytes)?;
+        }
+
+        for (member, placement) in members.iter().zip(&placements) {
+            if member.dir || placement.clusters == 0 {
+                continue;
+            }
+            out.seek(SeekFrom::Start(offset_of(placement.cluster as u64)))
+                .map_err(|_| FATError::CannotWrite)?;
+            let mut file = Counted::new(&mut out);
+            self.write_member(member, &mut file)?;
+            let rest = placement.clusters * cluster_size - member.size;
+            file.write_all(&vec![0; rest as usize])
+                .map_err(|_| FATError::CannotWrite)?;
+        }
+
+        let len = sectors * SECTOR;
+        let end = out
+            .seek(SeekFrom::End(0))
+            .map_err(|_| FATError::CannotWrite)?;
+        if end < len {
+            write(&mut out, len - 1, &[0])?;
+        }
+        out.flush().map_err(|_| FATError::CannotWrite)?;
+        Ok(len)
+    }
+
+    /// Formats `target` as a native image big enough for the whole tree and
+    /// copies the tree into it, so a volume read here can be changed.
+    pub fn convert_to_native(&mut self, target: &mut FAT) -> Result<(), FATError> {
+        let members = self.archive_members(".")?;
+
+        let mut children: HashMap<&str, u64> = HashMap::new();
+        for member in &members {
+            let name = member.name.trim_end_matches('/');
+            let parent = name.rfind('/').map_or("", |slash| &name[..slash]);
+            *children.entry(parent).or_default() += 1;
+        }
+        let most = children.values().copied().max().unwrap_or(0) + 2;
+        let cluster_size = (most * DIR_ENTRY as u64)
+            .next_power_of_two()
+            .max(CLUSTER_SIZE as u64);
+
+        let clusters: u64 = members
+            .iter()
+            .map(|member| member.size.div_ceil(cluster_size).max(1) + 1)
+            .sum::<u64>()
+            + 1;
+        let capacity = (clusters * cluster_size * 5 / 4).div_ceil(1 << 20) + 1;
+        let label = self.volume_label();
+        let options = FormatOptions {
+            cluster_size: cluster_size as u32,
+            label: if label.len() <= LABEL_LENGTH {
+                label.to_string()
+            } else {
+                String::new()
+            },
+            ..FormatOptions::default()
+        };
+        target
+            .format(Unit::MB(capacity as usize), &options)
+            .map_err(|_| FATError::CannotWrite)?;
+
+        target.transaction(|target| {
+            for member in &members {
+                let path = member.name.trim_end_matches('/');
+                if member.dir {
+                    target.mkdir(path)?;
+                } else {
+                    let mut data = Counted::new(Vec::with_capacity(member.size as usize));
+                    self.write_member(member, &mut data)?;
+                    target.new_file(path, Cursor::new(data.into_inner()))?;
+                }
+            }
+            Ok(())
+        })?;
+        target.sync()
+    }
+}
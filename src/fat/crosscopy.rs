@@ -0,0 +1,92 @@
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+
+use super::{perms::Access, FATError, FAT};
+
+/// A plain file of one image read a cluster at a time, so that it can be
+/// stored in another image without being held whole.
+struct ChainReader<'a> {
+    fat: &'a mut FAT,
+    chain: Vec<u32>,
+    size: u64,
+    position: u64,
+    /// The cluster read last, as the target may ask for it in pieces.
+    cached: Option<(u32, Vec<u8>)>,
+}
+
+impl Read for ChainReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let cluster_size = self.fat.cluster_size() as u64;
+        let cluster = *self
+            .chain
+            .get((self.position / cluster_size) as usize)
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        if self
+            .cached
+            .as_ref()
+            .is_none_or(|(cached, _)| *cached != cluster)
+        {
+            let bytes = self
+                .fat
+                .read_cluster(cluster)
+                .map_err(|_| io::Error::other("cannot read the source cluster"))?;
+            self.cached = Some((cluster, bytes));
+        }
+        let bytes = &self.cached.as_ref().unwrap().1;
+
+        let offset = self.position % cluster_size;
+        let n = (cluster_size - offset)
+            .min(self.size - self.position)
+            .min(buf.len() as u64) as usize;
+        buf[..n].copy_from_slice(&bytes[offset as usize..offset as usize + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for ChainReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or(io::ErrorKind::InvalidInput)?;
+        Ok(self.position)
+    }
+}
+
+impl FAT {
+    /// Creates `dest` in `target`, another image, with the contents of the
+    /// file `source`, streaming it cluster by cluster. Compressed and sparse
+    /// files, and files of real FAT volumes, are decoded whole first.
+    pub fn copy_to(&mut self, source: &str, target: &mut FAT, dest: &str) -> Result<(), FATError> {
+        let entry = self.find_file(source, Self::filter_find_file)?;
+        self.check_access(source, Access::Read)?;
+
+        if self.foreign.is_some() || Self::is_compressed(&entry) || Self::is_sparse(&entry) {
+            let mut data = vec![];
+            self.read_entry(&entry, &mut data)?;
+            return target.new_file(dest, Cursor::new(data));
+        }
+
+        let chain = if entry.size() == 0 {
+            vec![]
+        } else {
+            self.chain_of(entry.cluster())?
+        };
+        target.new_file(
+            dest,
+            ChainReader {
+                fat: self,
+                chain,
+                size: entry.size(),
+                position: 0,
+                cached: None,
+            },
+        )
+    }
+}
@@ -0,0 +1,204 @@
+use chacha20poly1305::{
+    aead::{AeadInPlace, KeyInit},
+    Tag, XChaCha20Poly1305, XNonce,
+};
+use sha2::Sha256;
+
+use super::{
+    header::{Header, AUTHENTICATED_VERSION},
+    uuid::fill_random,
+    FATError, FAT,
+};
+
+pub const KEY_LENGTH: usize = 32;
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 24;
+const TAG_LENGTH: usize = 16;
+
+/// Salt, sealed data key and its tag, as stored in the header.
+pub const KEY_SLOT_LENGTH: usize = SALT_LENGTH + KEY_LENGTH + TAG_LENGTH;
+
+/// Nonce and tag of the last write of a cluster, as stored in the seal table.
+pub const SEAL_LENGTH: usize = NONCE_LENGTH + TAG_LENGTH;
+
+/// PBKDF2-HMAC-SHA256 rounds spent deriving the key that seals the data key.
+const ITERATIONS: u32 = 600_000;
+
+pub type Key = [u8; KEY_LENGTH];
+
+/// What [`open_cluster`] needs besides the key; all zeros for a cluster that
+/// was never written encrypted and still holds plain zeros.
+pub type Seal = [u8; SEAL_LENGTH];
+
+/// The key that seals the data key, derived from `passphrase` and `salt`.
+fn sealing_key(passphrase: &str, salt: &[u8]) -> XChaCha20Poly1305 {
+    let mut key = [0; KEY_LENGTH];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, ITERATIONS, &mut key);
+    XChaCha20Poly1305::new(&key.into())
+}
+
+/// Generates a fresh data key and seals it under `passphrase`.
+///
+/// Returns the key slot to store in the header along with the key itself, or
+/// [`FATError::NoRandomness`] when no key can be drawn.
+pub fn seal_key(passphrase: &str) -> Result<([u8; KEY_SLOT_LENGTH], Key), FATError> {
+    let mut key = [0; KEY_LENGTH];
+    fill_random(&mut key)?;
+    let mut salt = [0; SALT_LENGTH];
+    fill_random(&mut salt)?;
+
+    // the sealing key depends on the salt, so it seals this one key only and
+    // a fixed nonce is never reused under it
+    let mut sealed = key;
+    let tag = sealing_key(passphrase, &salt)
+        .encrypt_in_place_detached(&XNonce::default(), &[], &mut sealed)
+        .map_err(|_| FATError::CannotWrite)?;
+
+    let mut slot = [0; KEY_SLOT_LENGTH];
+    slot[..SALT_LENGTH].clone_from_slice(&salt);
+    slot[SALT_LENGTH..SALT_LENGTH + KEY_LENGTH].clone_from_slice(&sealed);
+    slot[SALT_LENGTH + KEY_LENGTH..].clone_from_slice(&tag);
+    Ok((slot, key))
+}
+
+/// Recovers the data key from `slot`, or `None` if `passphrase` is wrong.
+pub fn open_key(slot: &[u8; KEY_SLOT_LENGTH], passphrase: &str) -> Option<Key> {
+    let (salt, rest) = slot.split_at(SALT_LENGTH);
+    let (sealed, tag) = rest.split_at(KEY_LENGTH);
+
+    let mut key: Key = sealed.try_into().unwrap();
+    sealing_key(passphrase, salt)
+        .decrypt_in_place_detached(&XNonce::default(), &[], &mut key, Tag::from_slice(tag))
+        .ok()?;
+    Some(key)
+}
+
+/// Encrypts `data`, the new contents of `cluster`, under a fresh random
+/// nonce. The cluster number is authenticated with it, so the result only
+/// decrypts where it was written.
+pub fn seal_cluster(key: &Key, cluster: u32, data: &mut [u8]) -> Result<Seal, FATError> {
+    let mut nonce = XNonce::default();
+    fill_random(&mut nonce)?;
+    let tag = XChaCha20Poly1305::new(key.into())
+        .encrypt_in_place_detached(&nonce, &cluster.to_le_bytes(), data)
+        .map_err(|_| FATError::CannotWrite)?;
+
+    let mut seal = [0; SEAL_LENGTH];
+    seal[..NONCE_LENGTH].clone_from_slice(&nonce);
+    seal[NONCE_LENGTH..].clone_from_slice(&tag);
+    Ok(seal)
+}
+
+/// Decrypts `data` as [`seal_cluster`] left it in `cluster`, failing with
+/// [`FATError::ChecksumMismatch`] when it was changed since or belongs to
+/// another cluster.
+pub fn open_cluster(key: &Key, cluster: u32, seal: &Seal, data: &mut [u8]) -> Result<(), FATError> {
+    let (nonce, tag) = seal.split_at(NONCE_LENGTH);
+    XChaCha20Poly1305::new(key.into())
+        .decrypt_in_place_detached(
+            XNonce::from_slice(nonce),
+            &cluster.to_le_bytes(),
+            data,
+            Tag::from_slice(tag),
+        )
+        .map_err(|_| FATError::ChecksumMismatch)
+}
+
+impl FAT {
+    pub fn is_encrypted(&self) -> bool {
+        self.header
+            .as_ref()
+            .is_some_and(|header| header.is_encrypted())
+    }
+
+    /// Whether the image is encrypted and no passphrase has been given yet.
+    pub fn is_locked(&self) -> bool {
+        self.is_encrypted() && self.key.is_none()
+    }
+
+    /// Unseals the data key with `passphrase`.
+    ///
+    /// Images encrypted before version 6 used ChaCha20 without
+    /// authentication and fail with [`FATError::UnsupportedEncryption`].
+    pub fn unlock(&mut self, passphrase: &str) -> Result<(), FATError> {
+        let header = self.header.as_ref().expect("Image is not formatted!");
+        if header.version() < AUTHENTICATED_VERSION {
+            return Err(FATError::UnsupportedEncryption);
+        }
+        self.key = Some(header.open_key(passphrase).ok_or(FATError::BadPassphrase)?);
+        Ok(())
+    }
+
+    fn seal_offset(&self, cluster: u32) -> u64 {
+        let header = self.header.as_ref().expect("Image is not formatted!");
+        self.sector_to_byte(header.seal_sector()) + cluster as u64 * SEAL_LENGTH as u64
+    }
+
+    /// The seal of the last encrypted write of `cluster`.
+    pub(super) fn seal(&mut self, cluster: u32) -> Result<Seal, FATError> {
+        let mut seal = [0; SEAL_LENGTH];
+        self.disk
+            .read_at(self.seal_offset(cluster), &mut seal)
+            .map_err(|_| FATError::CannotRead)?;
+        Ok(seal)
+    }
+
+    pub(super) fn set_seal(&mut self, cluster: u32, seal: &Seal) -> Result<(), FATError> {
+        self.disk
+            .write_journaled(self.seal_offset(cluster), seal)
+            .map_err(|_| FATError::CannotWrite)
+    }
+
+    /// Decrypts `data`, the contents `cluster` was written with under `seal`.
+    pub(super) fn decrypt_cluster(
+        &self,
+        cluster: u32,
+        seal: &Seal,
+        data: &mut [u8],
+    ) -> Result<(), FATError> {
+        if !self.is_encrypted() || *seal == [0; SEAL_LENGTH] {
+            return Ok(());
+        }
+
+        let key = self.key.as_ref().ok_or(FATError::Locked)?;
+        open_cluster(key, cluster, seal, data)
+    }
+
+    /// Encrypts `data` for its next write to `cluster` and records its seal.
+    pub(super) fn encrypt_cluster(
+        &mut self,
+        cluster: u32,
+        data: &mut [u8],
+    ) -> Result<(), FATError> {
+        if !self.is_encrypted() {
+            return Ok(());
+        }
+
+        let key = self.key.ok_or(FATError::Locked)?;
+        let seal = seal_cluster(&key, cluster, data)?;
+        self.set_seal(cluster, &seal)
+    }
+
+    /// Reads the whole seal table as laid out under `header`.
+    pub(super) fn read_seals(&mut self, header: &Header) -> Result<Vec<u8>, FATError> {
+        let mut table = vec![0; header.seal_sectors() as usize * self.bytes_per_sector()];
+        self.disk
+            .read_at(self.sector_to_byte(header.seal_sector()), &mut table)
+            .map_err(|_| FATError::CannotRead)?;
+        Ok(table)
+    }
+
+    /// Stores a seal table read by [`FAT::read_seals`] under the current
+    /// geometry, padding or truncating it to fit.
+    pub(super) fn write_seals(&mut self, mut table: Vec<u8>) -> Result<(), FATError> {
+        let header = self.header.clone().expect("Image is not formatted!");
+        if !header.is_encrypted() {
+            return Ok(());
+        }
+
+        table.resize(header.seal_sectors() as usize * self.bytes_per_sector(), 0);
+        self.disk
+            .write_at(self.sector_to_byte(header.seal_sector()), &table)
+            .map_err(|_| FATError::CannotWrite)
+    }
+}
@@ -0,0 +1,112 @@
+use std::{collections::HashMap, fmt::Display};
+
+use super::{
+    diff::hash,
+    dirent::{Entry, Flags},
+    FATError, FAT,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct DedupReport {
+    /// Directory entries now sharing another file's chain.
+    pub files: usize,
+    pub clusters_reclaimed: usize,
+}
+
+impl Display for DedupReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} file(s) deduplicated, {} cluster(s) reclaimed",
+            self.files, self.clusters_reclaimed
+        )
+    }
+}
+
+/// A chain and every directory entry pointing at it.
+struct Chain {
+    entry: Entry,
+    clusters: Vec<u32>,
+    owners: Vec<(u32, usize)>,
+}
+
+impl FAT {
+    /// Makes files with identical contents share one chain under the
+    /// reference count scheme and frees the duplicate chains.
+    pub fn dedup(&mut self) -> Result<DedupReport, FATError> {
+        if !self.has_refcounts() {
+            return Err(FATError::CannotWrite);
+        }
+
+        let mut chains: HashMap<u32, Chain> = HashMap::new();
+        for located in self.located_entries()? {
+            if Self::filter_mkdir(&located.entry) {
+                continue;
+            }
+
+            chains
+                .entry(located.entry.cluster())
+                .or_insert_with(|| Chain {
+                    entry: located.entry.clone(),
+                    clusters: located.chain.clone(),
+                    owners: vec![],
+                })
+                .owners
+                .push((located.dir_cluster, located.index));
+        }
+
+        let mut starts: Vec<u32> = chains.keys().copied().collect();
+        starts.sort_unstable();
+
+        let mut kept: HashMap<(u64, u32, u64), Vec<u32>> = HashMap::new();
+        let mut report = DedupReport::default();
+
+        for start in starts {
+            let chain = &chains[&start];
+            let entry = chain.entry.clone();
+            let mut contents = vec![];
+            self.read_entry(&entry, &mut contents)?;
+
+            // entries sharing a chain must agree on how it is encoded
+            let encoding = entry.flags() & (Flags::Compressed as u32 | Flags::Sparse as u32);
+            let key = (entry.size(), encoding, hash(&contents));
+            let candidates = kept.entry(key).or_default();
+            let mut original = None;
+            for candidate in candidates.iter() {
+                let mut other = vec![];
+                self.read_entry(&chains[candidate].entry, &mut other)?;
+                if other == contents {
+                    original = Some(*candidate);
+                    break;
+                }
+            }
+
+            let Some(original) = original else {
+                candidates.push(start);
+                continue;
+            };
+
+            let chain = chains.remove(&start).unwrap();
+            for (dir_cluster, index) in &chain.owners {
+                let mut entries = self.read_cluster_entries(*dir_cluster)?;
+                entries[*index].set_cluster(original);
+                self.write_cluster_entries(*dir_cluster, &entries)?;
+            }
+
+            for cluster in chains[&original].clusters.clone() {
+                let count = self.refcount(cluster)?;
+                self.set_refcount(cluster, count + chain.owners.len() as u32)?;
+            }
+
+            for cluster in &chain.clusters {
+                self.set_refcount(*cluster, 0)?;
+            }
+            self.dealloc_clusters(start)?;
+
+            report.files += chain.owners.len();
+            report.clusters_reclaimed += chain.clusters.len();
+        }
+
+        Ok(report)
+    }
+}
@@ -0,0 +1,130 @@
+use std::{collections::HashSet, fmt::Display};
+
+use super::{frag::count_extents, FATError, FAT};
+
+#[derive(Debug, Clone, Default)]
+pub struct DefragReport {
+    pub files_moved: usize,
+    pub clusters_moved: usize,
+    /// Fragmented files for which no free run was long enough.
+    pub skipped: usize,
+}
+
+impl Display for DefragReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} file(s), {} cluster(s) moved, {} file(s) skipped",
+            self.files_moved, self.clusters_moved, self.skipped
+        )
+    }
+}
+
+/// First run of at least `len` free clusters in `fat`, ignoring cluster 0 and
+/// clusters `reserved` rejects.
+fn find_free_run<R: Fn(u32) -> bool>(
+    fat: &[u32],
+    reserved: R,
+    limit: u32,
+    len: usize,
+) -> Option<u32> {
+    let mut start = 1;
+    let mut run = 0;
+
+    for cluster in 1..=limit {
+        if fat[cluster as usize] != 0 || reserved(cluster) {
+            run = 0;
+            start = cluster + 1;
+            continue;
+        }
+
+        run += 1;
+        if run == len {
+            return Some(start);
+        }
+    }
+
+    None
+}
+
+impl FAT {
+    /// Moves every fragmented file into a single run of free clusters.
+    ///
+    /// Directories are left in place. With `dry_run` set nothing is written;
+    /// the report tells how much would have moved.
+    pub fn defrag(&mut self, dry_run: bool) -> Result<DefragReport, FATError> {
+        let limit = self
+            .header
+            .as_ref()
+            .expect("Image is not formatted!")
+            .data_cluster_count();
+        let mut fat = self.read_whole_fat()?;
+        let mut report = DefragReport::default();
+        // a rollback would need the old chains, so they are not reused within
+        // the same pass
+        let mut released = HashSet::new();
+
+        for located in self.located_entries()? {
+            if Self::filter_mkdir(&located.entry) || count_extents(&located.chain) <= 1 {
+                continue;
+            }
+
+            // moving a reflinked chain would leave its other owners pointing at freed clusters
+            if self.refcount(located.chain[0])? > 0 {
+                report.skipped += 1;
+                continue;
+            }
+
+            let len = located.chain.len();
+            let reserved = |cluster| {
+                self.held.contains(&cluster)
+                    || self.freed.contains(&cluster)
+                    || released.contains(&cluster)
+            };
+            let Some(start) = find_free_run(&fat, reserved, limit, len) else {
+                report.skipped += 1;
+                continue;
+            };
+
+            let before = fat.clone();
+            for (offset, _) in located.chain.iter().enumerate() {
+                let target = start + offset as u32;
+                fat[target as usize] = if offset + 1 == len {
+                    Self::mark_read_done()
+                } else {
+                    target + 1
+                };
+            }
+
+            if !dry_run {
+                for (offset, cluster) in located.chain.iter().enumerate() {
+                    let data = self.read_cluster(*cluster)?;
+                    self.write_cluster(start + offset as u32, &data)?;
+                }
+                // link the copy before the entry points at it, free the old chain after
+                self.write_whole_fat(&before, &fat)?;
+
+                let mut entries = self.read_cluster_entries(located.dir_cluster)?;
+                entries[located.index].set_cluster(start);
+                self.write_cluster_entries(located.dir_cluster, &entries)?;
+            }
+
+            let linked = fat.clone();
+            for cluster in located.chain.iter() {
+                fat[*cluster as usize] = 0;
+                released.insert(*cluster);
+            }
+            if !dry_run {
+                self.write_whole_fat(&linked, &fat)?;
+                for cluster in located.chain.iter() {
+                    self.discard_cluster(*cluster)?;
+                }
+            }
+
+            report.files_moved += 1;
+            report.clusters_moved += len;
+        }
+
+        Ok(report)
+    }
+}
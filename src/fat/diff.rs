@@ -0,0 +1,130 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use super::{dirent::Entry, FATError, FAT};
+
+/// A single path that differs between two images.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    Added(String),
+    Removed(String),
+    Changed(String),
+}
+
+impl Display for Difference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Added(path) => write!(f, "+ {path}"),
+            Self::Removed(path) => write!(f, "- {path}"),
+            Self::Changed(path) => write!(f, "~ {path}"),
+        }
+    }
+}
+
+/// What [`compare`] looks at: nothing for a directory, the size and content
+/// hash of a file.
+pub type Fingerprint = Option<(u64, u64)>;
+
+/// 64-bit FNV-1a, enough to tell file contents apart without extra dependencies.
+pub fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn fingerprint(fat: &mut FAT, entry: &Entry) -> Result<Option<u64>, FATError> {
+    if FAT::filter_mkdir(entry) {
+        return Ok(None);
+    }
+
+    let mut contents = vec![];
+    fat.read_entry(entry, &mut contents)?;
+    Ok(Some(hash(&contents)))
+}
+
+/// Compares the trees of `current` and `other`.
+///
+/// Paths only present in `other` are reported as added, paths only present in
+/// `current` as removed. Files are changed when their size or content hash
+/// differ, or when a file replaced a directory of the same name.
+pub fn diff(current: &mut FAT, other: &mut FAT) -> Result<Vec<Difference>, FATError> {
+    let old = current.walk()?.into_iter().collect::<BTreeMap<_, _>>();
+    let new = other.walk()?.into_iter().collect::<BTreeMap<_, _>>();
+
+    let mut differences = vec![];
+
+    for (path, entry) in old.iter() {
+        let Some(other_entry) = new.get(path) else {
+            differences.push(Difference::Removed(path.clone()));
+            continue;
+        };
+
+        if FAT::filter_mkdir(entry) != FAT::filter_mkdir(other_entry)
+            || entry.size() != other_entry.size()
+            || fingerprint(current, entry)? != fingerprint(other, other_entry)?
+        {
+            differences.push(Difference::Changed(path.clone()));
+        }
+    }
+
+    for path in new.keys() {
+        if !old.contains_key(path) {
+            differences.push(Difference::Added(path.clone()));
+        }
+    }
+
+    differences.sort_by(|a, b| path_of(a).cmp(path_of(b)));
+    Ok(differences)
+}
+
+/// Compares two trees given as the fingerprints of their paths, reporting
+/// them as [`diff`] does: paths only in `new` as added, only in `old` as
+/// removed and the rest as changed when their fingerprints differ.
+pub fn compare(
+    old: &BTreeMap<String, Fingerprint>,
+    new: &BTreeMap<String, Fingerprint>,
+) -> Vec<Difference> {
+    let mut differences: Vec<_> = old
+        .iter()
+        .filter_map(|(path, fingerprint)| match new.get(path) {
+            None => Some(Difference::Removed(path.clone())),
+            Some(other) if other != fingerprint => Some(Difference::Changed(path.clone())),
+            Some(_) => None,
+        })
+        .chain(
+            new.keys()
+                .filter(|path| !old.contains_key(*path))
+                .map(|path| Difference::Added(path.clone())),
+        )
+        .collect();
+
+    differences.sort_by(|a, b| path_of(a).cmp(path_of(b)));
+    differences
+}
+
+impl FAT {
+    /// The fingerprints of everything below the directory at `dir`, by path
+    /// relative to it, for [`compare`]. Hidden entries are left out.
+    pub fn fingerprints(&mut self, dir: &str) -> Result<BTreeMap<String, Fingerprint>, FATError> {
+        let mut members = vec![];
+        self.collect_members(dir, "", &mut members)?;
+
+        let mut tree = BTreeMap::new();
+        for member in members {
+            let fingerprint = if member.dir {
+                None
+            } else {
+                let mut contents = vec![];
+                self.cat(&member.path, &mut contents)?;
+                Some((contents.len() as u64, hash(&contents)))
+            };
+            tree.insert(member.name.trim_end_matches('/').to_string(), fingerprint);
+        }
+        Ok(tree)
+    }
+}
+
+fn path_of(difference: &Difference) -> &str {
+    match difference {
+        Difference::Added(path) | Difference::Removed(path) | Difference::Changed(path) => path,
+    }
+}
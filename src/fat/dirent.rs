@@ -1,26 +1,75 @@
 use std::mem::size_of;
 use std::str;
 
+use super::{
+    perms::{Permissions, OWNERSHIP},
+    FATError,
+};
+
+/// Longest name an entry holds, in bytes.
+pub const NAME_LENGTH: usize = 12;
+
+/// Device names DOS reserves, with or without an extension, which a volume
+/// converted to FAT32 could not use.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Checks that `name` can be given to a new entry: not empty, at most
+/// [`NAME_LENGTH`] bytes, free of `/` and control characters (NUL among
+/// them), not made of dots alone and not a reserved device name.
+pub fn check_name(name: &str) -> Result<(), FATError> {
+    if name.len() > NAME_LENGTH {
+        return Err(FATError::FilenameTooLong);
+    }
+
+    let stem = name.split('.').next().unwrap_or_default();
+    if name.chars().all(|c| c == '.')
+        || name.chars().any(|c| c == '/' || c.is_control())
+        || RESERVED_NAMES
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+    {
+        return Err(FATError::InvalidName);
+    }
+
+    Ok(())
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Flags {
     Occupied = 1 << 0,
     Directory = 1 << 1,
     System = 1 << 2,
+    /// Left out of directory listings.
+    Hidden = 1 << 3,
+    /// Data clusters hold the file compressed.
+    Compressed = 1 << 4,
+    /// The chain starts with a map of which clusters of the file hold data;
+    /// the others are holes.
+    Sparse = 1 << 5,
+    /// An owner and mode are kept in the upper bits, see [`Permissions`].
+    Owned = 1 << 6,
 }
 
 #[derive(Debug, Clone)]
 pub struct Entry {
     name: String,
-    size: u32,
+    /// Low half at bytes 12..16, high half at 28..32; images before version 3
+    /// keep the high half zero.
+    size: u64,
     cluster: u32,
     flags: u32,
+    /// Cluster holding the extended attributes, 0 when there are none.
+    xattrs: u32,
 }
 
 impl Entry {
-    pub fn new(name: &str, size: u32, cluster: u32, flags: u32) -> Option<Self> {
+    pub fn new(name: &str, size: u64, cluster: u32, flags: u32) -> Option<Self> {
         let len = name.len();
 
-        if len > 12 {
+        if len > NAME_LENGTH {
             return None;
         }
 
@@ -32,6 +81,7 @@ impl Entry {
             size,
             cluster,
             flags,
+            xattrs: 0,
         })
     }
 
@@ -47,7 +97,14 @@ impl Entry {
             )
             .ok()?
             .to_string(),
-            size: u32::from_le_bytes(bytes.get(12..12 + size_of::<u32>())?.try_into().ok()?),
+            size: u32::from_le_bytes(bytes.get(12..12 + size_of::<u32>())?.try_into().ok()?) as u64
+                | (u32::from_le_bytes(
+                    bytes
+                        .get(12 + 4 * size_of::<u32>()..12 + 5 * size_of::<u32>())?
+                        .try_into()
+                        .ok()?,
+                ) as u64)
+                    << 32,
             cluster: u32::from_le_bytes(
                 bytes
                     .get(12 + size_of::<u32>()..12 + 2 * size_of::<u32>())?
@@ -60,10 +117,16 @@ impl Entry {
                     .try_into()
                     .ok()?,
             ),
+            xattrs: u32::from_le_bytes(
+                bytes
+                    .get(12 + 3 * size_of::<u32>()..12 + 4 * size_of::<u32>())?
+                    .try_into()
+                    .ok()?,
+            ),
         })
     }
 
-    pub fn size(&self) -> u32 {
+    pub fn size(&self) -> u64 {
         self.size
     }
 
@@ -80,15 +143,18 @@ impl Entry {
         self.flags
     }
 
-    pub fn set_name(&mut self, name: &str) -> Option<()> {
-        let len = name.len();
-        if len > 12 {
-            return None;
-        }
+    pub fn xattrs(&self) -> u32 {
+        self.xattrs
+    }
 
+    pub fn set_name(&mut self, name: &str) -> Result<(), FATError> {
+        check_name(name)?;
         self.name = name.to_string();
+        Ok(())
+    }
 
-        Some(())
+    pub fn set_size(&mut self, size: u64) {
+        self.size = size;
     }
 
     pub fn set_cluster(&mut self, cluster: u32) {
@@ -99,17 +165,35 @@ impl Entry {
         self.flags = flags;
     }
 
+    pub fn permissions(&self) -> Option<Permissions> {
+        Permissions::from_flags(self.flags)
+    }
+
+    /// Gives the entry an owner and mode, or takes them away so that anyone
+    /// may use it.
+    pub fn set_permissions(&mut self, permissions: Option<Permissions>) {
+        self.flags = self.flags & !OWNERSHIP | permissions.map_or(0, Permissions::to_flags);
+    }
+
+    pub fn set_xattrs(&mut self, cluster: u32) {
+        self.xattrs = cluster;
+    }
+
     pub fn as_bytes(&self) -> [u8; 32] {
         let mut v = [0; 32];
 
         let name_len = self.name.len();
 
-        v[0..name_len].clone_from_slice(&self.name.as_bytes());
-        v[12..12 + size_of::<u32>()].clone_from_slice(&u32::to_le_bytes(self.size));
+        v[0..name_len].clone_from_slice(self.name.as_bytes());
+        v[12..12 + size_of::<u32>()].clone_from_slice(&u32::to_le_bytes(self.size as u32));
         v[12 + size_of::<u32>()..12 + 2 * size_of::<u32>()]
             .clone_from_slice(&u32::to_le_bytes(self.cluster));
         v[12 + 2 * size_of::<u32>()..12 + 3 * size_of::<u32>()]
             .clone_from_slice(&u32::to_le_bytes(self.flags));
+        v[12 + 3 * size_of::<u32>()..12 + 4 * size_of::<u32>()]
+            .clone_from_slice(&u32::to_le_bytes(self.xattrs));
+        v[12 + 4 * size_of::<u32>()..12 + 5 * size_of::<u32>()]
+            .clone_from_slice(&u32::to_le_bytes((self.size >> 32) as u32));
 
         v
     }
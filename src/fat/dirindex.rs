@@ -0,0 +1,344 @@
+use std::mem::size_of;
+
+use super::{diff::hash, fatmanager::FATManager, FATError, FAT};
+
+/// Directories whose entries spill past this many clusters get an index on
+/// images with the feature.
+pub const INDEX_THRESHOLD: u32 = 4;
+
+const MAGIC: u32 = u32::from_le_bytes(*b"ZDIX");
+
+/// Share of the slots that may be used before the table is rebuilt larger.
+const MAX_LOAD: (usize, usize) = (3, 4);
+
+const SLOT_SIZE: usize = 2 * size_of::<u32>();
+
+/// Hash table of the names in a large directory, telling which of its
+/// clusters may hold a name so that a lookup reads only those.
+///
+/// Its chain hangs off the `xattrs` field of the directory's `.` entry. The
+/// first slot holds a magic number and the count of used slots; the others
+/// pair the hash of a name with the position, plus one, of the directory
+/// cluster holding it, and are probed linearly. Names are only ever added,
+/// so a removed one costs a needless read until the table is rebuilt.
+struct Index {
+    slots: Vec<(u32, u32)>,
+    used: usize,
+}
+
+impl Index {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            slots: vec![(0, 0); capacity],
+            used: 0,
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut slots = (0..bytes.len() / SLOT_SIZE).map(|slot| slot_at(bytes, slot * SLOT_SIZE));
+        let (magic, used) = slots.next()?;
+        let slots: Vec<_> = slots.collect();
+        (magic == MAGIC && !slots.is_empty()).then_some(Self {
+            slots,
+            used: used as usize,
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        std::iter::once((MAGIC, self.used as u32))
+            .chain(self.slots.iter().copied())
+            .flat_map(|(hash, position)| {
+                hash.to_le_bytes().into_iter().chain(position.to_le_bytes())
+            })
+            .collect()
+    }
+
+    fn probe(&self, hash: u32) -> impl Iterator<Item = usize> + '_ {
+        let start = hash as usize % self.slots.len();
+        (0..self.slots.len())
+            .map(move |offset| (start + offset) % self.slots.len())
+            .take_while(|slot| self.slots[*slot].1 != 0)
+    }
+
+    /// Records that cluster `position` of the directory holds a name hashing
+    /// to `hash`. Returns the slot changed, or `Err` when the table is too
+    /// full to take it.
+    fn insert(&mut self, hash: u32, position: u32) -> Result<Option<usize>, ()> {
+        if self
+            .probe(hash)
+            .any(|slot| self.slots[slot] == (hash, position + 1))
+        {
+            return Ok(None);
+        }
+        if (self.used + 1) * MAX_LOAD.1 > self.slots.len() * MAX_LOAD.0 {
+            return Err(());
+        }
+
+        let start = hash as usize % self.slots.len();
+        let slot = (0..self.slots.len())
+            .map(|offset| (start + offset) % self.slots.len())
+            .find(|slot| self.slots[*slot].1 == 0)
+            .ok_or(())?;
+        self.slots[slot] = (hash, position + 1);
+        self.used += 1;
+        Ok(Some(slot))
+    }
+}
+
+fn slot_at(bytes: &[u8], offset: usize) -> (u32, u32) {
+    let slot = &bytes[offset..offset + SLOT_SIZE];
+    (
+        u32::from_le_bytes(slot[..size_of::<u32>()].try_into().unwrap()),
+        u32::from_le_bytes(slot[size_of::<u32>()..].try_into().unwrap()),
+    )
+}
+
+fn name_hash(name: &str) -> u32 {
+    hash(name.as_bytes()) as u32
+}
+
+impl FAT {
+    fn indexes_dirs(&self) -> bool {
+        self.header
+            .as_ref()
+            .is_some_and(|header| header.has_dir_index())
+    }
+
+    /// The first cluster of the index of the directory starting at `dir`,
+    /// with the index itself.
+    fn read_index(&mut self, dir: u32) -> Result<Option<(u32, Index)>, FATError> {
+        let Some(first) = self.index_of(dir)? else {
+            return Ok(None);
+        };
+
+        let mut bytes = vec![];
+        for cluster in self.chain_of(first)? {
+            bytes.extend(self.read_cluster(cluster)?);
+        }
+        Ok(Index::from_bytes(&bytes).map(|index| (first, index)))
+    }
+
+    fn index_of(&mut self, dir: u32) -> Result<Option<u32>, FATError> {
+        let dot = &self.read_cluster_entries(dir)?[0];
+        Ok((dot.name() == "." && dot.xattrs() != 0).then_some(dot.xattrs()))
+    }
+
+    /// Positions of the clusters of the directory starting at `dir` that may
+    /// hold `name`, in order, reading only the parts of the index probed.
+    fn indexed_positions(&mut self, dir: u32, name: &str) -> Result<Option<Vec<u32>>, FATError> {
+        let Some(first) = self.index_of(dir)? else {
+            return Ok(None);
+        };
+        let chain = self.chain_of(first)?;
+        let cluster_size = self.cluster_size();
+        let capacity = chain.len() * cluster_size / SLOT_SIZE - 1;
+
+        let mut read = (0, self.read_cluster(chain[0])?);
+        if capacity == 0 || slot_at(&read.1, 0).0 != MAGIC {
+            return Ok(None);
+        }
+
+        let hash = name_hash(name);
+        let mut positions = vec![];
+        for offset in 0..capacity {
+            let at = ((hash as usize + offset) % capacity + 1) * SLOT_SIZE;
+            if at / cluster_size != read.0 {
+                read = (
+                    at / cluster_size,
+                    self.read_cluster(chain[at / cluster_size])?,
+                );
+            }
+
+            match slot_at(&read.1, at % cluster_size) {
+                (_, 0) => break,
+                (slot_hash, position) if slot_hash == hash => positions.push(position - 1),
+                _ => {}
+            }
+        }
+        positions.sort_unstable();
+        positions.dedup();
+        Ok(Some(positions))
+    }
+
+    /// Points the `.` entry of the directory starting at `dir` at a new
+    /// index, freeing the old one.
+    fn set_index(&mut self, dir: u32, first: u32) -> Result<(), FATError> {
+        let mut entries = self.read_cluster_entries(dir)?;
+        let old = entries[0].xattrs();
+        entries[0].set_xattrs(first);
+        self.write_cluster_entries(dir, &entries)?;
+        if old != 0 {
+            self.dealloc_clusters(old)?;
+        }
+        Ok(())
+    }
+
+    /// Calls `visit` with the position and number of each cluster of the
+    /// chain from `first` until it returns false, reading every FAT sector
+    /// once.
+    pub(super) fn walk_chain<F: FnMut(u32, u32) -> bool>(
+        &mut self,
+        first: u32,
+        mut visit: F,
+    ) -> Result<(), FATError> {
+        let mut manager = FATManager::new(self.clusters_per_fat_sector());
+        let mut cluster = first;
+        let mut position = 0;
+
+        while self.is_data_cluster(cluster) && visit(position, cluster) {
+            self.cache_fat(&mut manager, cluster)?;
+            cluster = manager.get_cluster_value(cluster).unwrap_or_default();
+            position += 1;
+        }
+        Ok(())
+    }
+
+    /// The clusters of the directory starting at `dir` that may hold `name`,
+    /// or `None` when the directory has no index and has to be read whole.
+    pub(super) fn indexed_clusters(
+        &mut self,
+        dir: u32,
+        name: &str,
+    ) -> Result<Option<Vec<u32>>, FATError> {
+        if !self.indexes_dirs() {
+            return Ok(None);
+        }
+        let Some(positions) = self.indexed_positions(dir, name)? else {
+            return Ok(None);
+        };
+
+        let mut positions = positions.into_iter().peekable();
+        let mut clusters = vec![];
+        self.walk_chain(dir, |position, cluster| {
+            if positions.next_if_eq(&position).is_some() {
+                clusters.push(cluster);
+            }
+            positions.peek().is_some()
+        })?;
+        Ok(Some(clusters))
+    }
+
+    /// Adds `name`, just stored in `cluster` of the directory starting at
+    /// `dir`, to the directory's index, creating the index once the
+    /// directory has grown past [`INDEX_THRESHOLD`] clusters.
+    pub(super) fn index_entry(
+        &mut self,
+        dir: u32,
+        cluster: u32,
+        name: &str,
+    ) -> Result<(), FATError> {
+        if !self.indexes_dirs() {
+            return Ok(());
+        }
+
+        let mut position = None;
+        self.walk_chain(dir, |at, current| {
+            if current == cluster {
+                position = Some(at);
+            }
+            position.is_none()
+        })?;
+        let Some(position) = position else {
+            return Ok(());
+        };
+
+        match self.read_index(dir)? {
+            Some((first, mut index)) => match index.insert(name_hash(name), position) {
+                Ok(None) => Ok(()),
+                Ok(Some(slot)) => self.store_index_slot(first, &index, slot),
+                Err(()) => self.build_index(dir),
+            },
+            None if position >= INDEX_THRESHOLD => self.build_index(dir),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes back the clusters of the index holding its header and `slot`.
+    fn store_index_slot(&mut self, first: u32, index: &Index, slot: usize) -> Result<(), FATError> {
+        let cluster_size = self.cluster_size();
+        let bytes = index.to_bytes();
+        let touched = [0, (slot + 1) * SLOT_SIZE / cluster_size];
+
+        let chain = self.chain_of(first)?;
+        for (position, cluster) in chain.into_iter().enumerate() {
+            if touched.contains(&position) {
+                let data = &bytes[position * cluster_size..(position + 1) * cluster_size];
+                self.write_metadata_cluster(cluster, data.to_vec())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Indexes every name in the directory starting at `dir` into a new
+    /// table with room to spare, written to fresh clusters so that a crash
+    /// or a snapshot keeps the old one intact.
+    fn build_index(&mut self, dir: u32) -> Result<(), FATError> {
+        let mut names = vec![];
+        for (position, cluster) in self.chain_of(dir)?.into_iter().enumerate() {
+            for entry in self.read_cluster_entries(cluster)? {
+                if Self::filter_find(&entry) {
+                    names.push((name_hash(entry.name()), position as u32));
+                }
+            }
+        }
+
+        let cluster_size = self.cluster_size();
+        let clusters = ((names.len() * 2 + 1) * SLOT_SIZE).div_ceil(cluster_size);
+        let mut index = Index::with_capacity(clusters * cluster_size / SLOT_SIZE - 1);
+        for (hash, position) in names {
+            index
+                .insert(hash, position)
+                .map_err(|_| FATError::NotEnoughSpace)?;
+        }
+
+        let first = self.allocate_clusters(clusters as u32)?;
+        let bytes = index.to_bytes();
+        for (cluster, data) in self
+            .chain_of(first)?
+            .into_iter()
+            .zip(bytes.chunks(cluster_size))
+        {
+            let mut data = data.to_vec();
+            data.resize(cluster_size, 0);
+            self.write_metadata_cluster(cluster, data)?;
+        }
+        self.set_index(dir, first)
+    }
+
+    /// Builds the index of the directory starting at `dir` anew after its
+    /// entries moved, or drops it once the directory is small again.
+    pub(super) fn reindex_dir(&mut self, dir: u32) -> Result<(), FATError> {
+        if !self.indexes_dirs() {
+            return Ok(());
+        }
+
+        if self.chain_of(dir)?.len() as u32 > INDEX_THRESHOLD {
+            self.build_index(dir)
+        } else if self.read_index(dir)?.is_some() {
+            self.set_index(dir, 0)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Frees the index of the directory starting at `dir`, which is being
+    /// removed.
+    pub(super) fn free_index(&mut self, dir: u32) -> Result<(), FATError> {
+        let dot = &self.read_cluster_entries(dir)?[0];
+        if dot.name() == "." && dot.xattrs() != 0 {
+            self.dealloc_clusters(dot.xattrs())?;
+        }
+        Ok(())
+    }
+
+    /// Adds the directory index feature; directories get an index as they
+    /// grow large.
+    pub fn enable_dir_index(&mut self) -> Result<(), FATError> {
+        let header = self.header.as_mut().expect("Image is not formatted!");
+        if header.has_dir_index() {
+            return Ok(());
+        }
+        header.enable_dir_index();
+        self.store_header()
+    }
+}
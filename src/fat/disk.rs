@@ -0,0 +1,460 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    thread,
+    time::Duration,
+};
+
+use tracing::{debug, trace};
+
+use super::journal::{self, Region, BLOCK_SIZE};
+
+/// Storage the image lives on.
+pub trait Backend: Send {
+    fn len(&self) -> io::Result<u64>;
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()>;
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+    /// Makes every completed write durable.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl Backend for File {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.read_exact(buf)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.write_all(buf)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Write::flush(self)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+/// An image held in memory, for targets without a host filesystem.
+#[derive(Debug, Clone, Default)]
+pub struct Memory(Vec<u8>);
+
+impl Memory {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+
+    /// The bytes at `offset`, or an error when `len` of them are not there.
+    fn range(&self, offset: u64, len: usize) -> io::Result<std::ops::Range<usize>> {
+        let start = usize::try_from(offset).map_err(|_| io::ErrorKind::UnexpectedEof)?;
+        let end = start.checked_add(len).ok_or(io::ErrorKind::UnexpectedEof)?;
+        if end > self.0.len() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(start..end)
+    }
+}
+
+impl Backend for Memory {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.0.len() as u64)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let range = self.range(offset, buf.len())?;
+        buf.copy_from_slice(&self.0[range]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        // like a file, the image grows to take writes past its end
+        let end = offset + buf.len() as u64;
+        if end > self.0.len() as u64 {
+            self.set_len(end)?;
+        }
+        let range = self.range(offset, buf.len())?;
+        self.0[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let len = usize::try_from(len).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        self.0.resize(len, 0);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Delays injected by [`Latency`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LatencyConfig {
+    /// Paid by every request that does not continue where the previous one ended.
+    pub seek: Duration,
+    /// Paid by every request.
+    pub io: Duration,
+}
+
+/// Makes a fast backend behave like a slow one, so that caching and batching
+/// can be measured on hardware where they would otherwise make no difference.
+pub struct Latency<B: Backend> {
+    inner: B,
+    config: LatencyConfig,
+    position: Option<u64>,
+}
+
+impl<B: Backend> Latency<B> {
+    pub fn new(inner: B, config: LatencyConfig) -> Self {
+        Self {
+            inner,
+            config,
+            position: None,
+        }
+    }
+
+    fn delay(&mut self, offset: u64, len: usize) {
+        let mut delay = self.config.io;
+        if self.position != Some(offset) {
+            delay += self.config.seek;
+        }
+
+        self.position = Some(offset + len as u64);
+        thread::sleep(delay);
+    }
+}
+
+impl<B: Backend> Backend for Latency<B> {
+    fn len(&self) -> io::Result<u64> {
+        self.inner.len()
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.delay(offset, buf.len());
+        self.inner.read_at(offset, buf)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.delay(offset, buf.len());
+        self.inner.write_at(offset, buf)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.inner.set_len(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync()
+    }
+}
+
+/// Byte-addressed access to the backing image.
+///
+/// Every request either transfers the whole buffer or fails, so a short
+/// read or write can never be mistaken for success.
+///
+/// While a transaction is running, journaled writes are held back in memory
+/// and reads see them; they reach the image together when it ends.
+pub struct Disk {
+    backend: Box<dyn Backend>,
+    /// Blocks written during the running transaction, by block number.
+    staged: Option<BTreeMap<u64, Vec<u8>>>,
+    /// Staged blocks as they were when each open savepoint was taken.
+    savepoints: Vec<BTreeMap<u64, Vec<u8>>>,
+    journal: Option<Region>,
+    /// Blocks read and written since the counts were last reset.
+    blocks_read: u64,
+    blocks_written: u64,
+}
+
+impl Disk {
+    pub fn new(file: File) -> Self {
+        Self::with_backend(Box::new(file))
+    }
+
+    pub fn with_backend(backend: Box<dyn Backend>) -> Self {
+        Self {
+            backend,
+            staged: None,
+            savepoints: vec![],
+            journal: None,
+            blocks_read: 0,
+            blocks_written: 0,
+        }
+    }
+
+    pub fn len(&self) -> io::Result<u64> {
+        self.backend.len()
+    }
+
+    pub fn is_empty(&self) -> io::Result<bool> {
+        self.backend.is_empty()
+    }
+
+    /// Blocks read and written since the image was opened or the counts were
+    /// reset, including those served from or held back in a transaction.
+    pub fn block_counts(&self) -> (u64, u64) {
+        (self.blocks_read, self.blocks_written)
+    }
+
+    pub fn reset_block_counts(&mut self) {
+        self.blocks_read = 0;
+        self.blocks_written = 0;
+    }
+
+    /// Numbers of the blocks overlapping `len` bytes at `offset`.
+    fn blocks(offset: u64, len: usize) -> std::ops::RangeInclusive<u64> {
+        offset / BLOCK_SIZE..=(offset + len.max(1) as u64 - 1) / BLOCK_SIZE
+    }
+
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        trace!(offset, len = buf.len(), "read");
+        self.blocks_read += Self::blocks(offset, buf.len()).count() as u64;
+        self.backend.read_at(offset, buf)?;
+
+        if let Some(staged) = &self.staged {
+            for (block, data) in staged.range(Self::blocks(offset, buf.len())) {
+                trace!(block, "read served from a held back block");
+                copy_overlap(block * BLOCK_SIZE, data, offset, buf);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        trace!(offset, len = buf.len(), "write");
+        self.blocks_written += Self::blocks(offset, buf.len()).count() as u64;
+        self.backend.write_at(offset, buf)?;
+
+        // keep held back blocks up to date, so ending or rolling back the
+        // transaction does not undo this write
+        for staged in self.staged.iter_mut().chain(&mut self.savepoints) {
+            for (block, data) in staged.range_mut(Self::blocks(offset, buf.len())) {
+                copy_overlap(offset, buf, block * BLOCK_SIZE, data);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes metadata, holding it back until the running transaction ends.
+    pub fn write_journaled(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        trace!(
+            offset,
+            len = buf.len(),
+            held_back = self.staged.is_some(),
+            "journaled write"
+        );
+        if self.staged.is_none() {
+            return self.backend.write_at(offset, buf);
+        }
+
+        for block in Self::blocks(offset, buf.len()) {
+            let capacity = self.journal.map_or(usize::MAX, |region| region.capacity());
+            let staged = self.staged.as_ref().unwrap();
+            if !staged.contains_key(&block)
+                && staged.len() >= capacity
+                && self.savepoints.is_empty()
+            {
+                // too big for one transaction, so it is committed in parts
+                self.checkpoint()?;
+            }
+
+            let data = match self.staged.as_mut().unwrap().entry(block) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let mut data = vec![0; BLOCK_SIZE as usize];
+                    self.backend.read_at(block * BLOCK_SIZE, &mut data)?;
+                    entry.insert(data)
+                }
+            };
+
+            copy_overlap(offset, buf, block * BLOCK_SIZE, data);
+        }
+
+        Ok(())
+    }
+
+    /// Starts holding journaled writes back, committing them through `journal`
+    /// when there is one.
+    pub fn begin(&mut self, journal: Option<Region>) {
+        self.staged.get_or_insert_with(BTreeMap::new);
+        self.journal = journal;
+    }
+
+    /// Remembers what is held back now, so that [`Disk::rollback`] can return
+    /// to it.
+    pub fn savepoint(&mut self) {
+        let staged = self.staged.get_or_insert_with(BTreeMap::new).clone();
+        self.savepoints.push(staged);
+    }
+
+    /// Forgets the most recent savepoint, keeping the writes made since.
+    pub fn release(&mut self) {
+        self.savepoints.pop();
+    }
+
+    /// Drops the writes held back since the most recent savepoint.
+    pub fn rollback(&mut self) {
+        if let Some(staged) = self.savepoints.pop() {
+            self.staged = Some(staged);
+        }
+    }
+
+    /// Commits the writes held back so far without ending the transaction.
+    ///
+    /// Whatever is committed stays, even if an open savepoint is rolled back
+    /// later.
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        let Some(staged) = self.staged.as_mut().map(std::mem::take) else {
+            return Ok(());
+        };
+        for savepoint in &mut self.savepoints {
+            savepoint.clear();
+        }
+        debug!(
+            blocks = staged.len(),
+            journaled = self.journal.is_some(),
+            "checkpoint"
+        );
+
+        let Some(region) = self.journal else {
+            return journal::apply(&mut *self.backend, &staged);
+        };
+
+        // savepoints keep everything in memory, so what is held back may not
+        // fit the journal in one piece
+        let mut blocks = staged.into_iter().peekable();
+        while blocks.peek().is_some() {
+            let part = blocks.by_ref().take(region.capacity()).collect();
+            journal::commit(&mut *self.backend, region, &part)?;
+        }
+
+        Ok(())
+    }
+
+    /// Commits the writes held back and ends the transaction.
+    pub fn end(&mut self) -> io::Result<()> {
+        self.checkpoint()?;
+        self.staged = None;
+        self.savepoints.clear();
+        Ok(())
+    }
+
+    /// Moves the journal of a running transaction, committing what is held
+    /// back in the old one first.
+    pub fn set_journal(&mut self, journal: Option<Region>) -> io::Result<()> {
+        self.checkpoint()?;
+        self.journal = journal;
+        Ok(())
+    }
+
+    /// Writes back a transaction the journal at `region` still holds.
+    pub fn replay(&mut self, region: Region) -> io::Result<usize> {
+        journal::replay(&mut *self.backend, region)
+    }
+
+    /// Shows a transaction the journal at `region` still holds to reads,
+    /// without writing anything.
+    pub fn load_journal(&mut self, region: Region) -> io::Result<()> {
+        self.staged = Some(journal::read(&mut *self.backend, region)?);
+        Ok(())
+    }
+
+    pub fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.backend.set_len(len)?;
+
+        for staged in self.staged.iter_mut().chain(&mut self.savepoints) {
+            staged.retain(|block, _| (block + 1) * BLOCK_SIZE <= len);
+        }
+
+        Ok(())
+    }
+
+    /// Copies `len` bytes from `from` to `to`; the ranges may overlap.
+    pub fn copy_within(&mut self, from: u64, to: u64, len: u64) -> io::Result<()> {
+        const CHUNK: u64 = 1 << 20;
+
+        let mut buf = vec![0; CHUNK.min(len) as usize];
+        let mut done = 0;
+
+        while done < len {
+            let n = CHUNK.min(len - done);
+            // copy back to front when moving forward so nothing is overwritten before it is read
+            let offset = if to > from { len - done - n } else { done };
+
+            self.read_at(from + offset, &mut buf[..n as usize])?;
+            self.write_at(to + offset, &buf[..n as usize])?;
+            done += n;
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.backend.flush()
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.backend.sync()
+    }
+}
+
+/// Copies the part of `src`, stored at `src_offset`, that overlaps `dst`,
+/// stored at `dst_offset`.
+pub(super) fn copy_overlap(src_offset: u64, src: &[u8], dst_offset: u64, dst: &mut [u8]) {
+    let start = src_offset.max(dst_offset);
+    let end = (src_offset + src.len() as u64).min(dst_offset + dst.len() as u64);
+    if start >= end {
+        return;
+    }
+
+    dst[(start - dst_offset) as usize..(end - dst_offset) as usize]
+        .clone_from_slice(&src[(start - src_offset) as usize..(end - src_offset) as usize]);
+}
+
+/// Fills `buf` from `reader`, stopping early only at end of input.
+///
+/// Returns the number of bytes read, which is less than `buf.len()` only for
+/// the final chunk of the stream.
+pub fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut done = 0;
+
+    while done < buf.len() {
+        match reader.read(&mut buf[done..]) {
+            Ok(0) => break,
+            Ok(n) => done += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(done)
+}
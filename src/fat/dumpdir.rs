@@ -0,0 +1,123 @@
+use std::fmt::Display;
+
+use super::{
+    dirent::{Entry, Flags},
+    FATError, FAT,
+};
+
+/// Names of the entry flags, by bit.
+const FLAG_NAMES: [(u32, &str); 7] = [
+    (Flags::Occupied as u32, "occupied"),
+    (Flags::Directory as u32, "directory"),
+    (Flags::System as u32, "system"),
+    (Flags::Hidden as u32, "hidden"),
+    (Flags::Compressed as u32, "compressed"),
+    (Flags::Sparse as u32, "sparse"),
+    (Flags::Owned as u32, "owned"),
+];
+
+/// What a directory slot holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotState {
+    /// Never used, all zeros.
+    Free,
+    /// Left behind by a removal, see `undelete`.
+    Deleted,
+    Live,
+    /// The name is not UTF-8.
+    Garbled,
+}
+
+/// One 32-byte slot of a directory's clusters, as `dumpdir` shows it.
+#[derive(Debug, Clone)]
+pub struct Slot {
+    pub cluster: u32,
+    /// Position of the slot within its cluster.
+    pub index: usize,
+    pub bytes: Vec<u8>,
+}
+
+impl Slot {
+    pub fn entry(&self) -> Option<Entry> {
+        Entry::from_bytes(&self.bytes)
+    }
+
+    pub fn state(&self) -> SlotState {
+        match self.entry() {
+            _ if self.bytes.iter().all(|byte| *byte == 0) => SlotState::Free,
+            None => SlotState::Garbled,
+            Some(entry) if entry.flags() == 0 => SlotState::Deleted,
+            Some(_) => SlotState::Live,
+        }
+    }
+}
+
+impl Display for Slot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = match self.state() {
+            SlotState::Free => "free",
+            SlotState::Deleted => "deleted",
+            SlotState::Live => "live",
+            SlotState::Garbled => "garbled",
+        };
+        write!(f, "{}:{:<3} ", self.cluster, self.index)?;
+        if self.state() == SlotState::Free {
+            return write!(f, "{state}");
+        }
+        write!(f, "{state:<8}")?;
+
+        match self.entry() {
+            None => {
+                for byte in &self.bytes {
+                    write!(f, " {byte:02x}")?;
+                }
+                Ok(())
+            }
+            Some(entry) => {
+                let flags: Vec<_> = FLAG_NAMES
+                    .iter()
+                    .filter(|(flag, _)| entry.flags() & flag != 0)
+                    .map(|(_, name)| *name)
+                    .collect();
+                write!(
+                    f,
+                    " name={:?} size={} cluster={} flags={:#010x} ({}) xattrs={}",
+                    entry.name(),
+                    entry.size(),
+                    entry.cluster(),
+                    entry.flags(),
+                    flags.join(", "),
+                    entry.xattrs()
+                )?;
+                if let Some(permissions) = entry.permissions() {
+                    write!(f, " owner={permissions}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl FAT {
+    /// Every slot of the clusters of the directory at `path`, used or not.
+    pub fn dump_dir(&mut self, path: &str) -> Result<Vec<Slot>, FATError> {
+        let dir = self.find_file(path, Self::filter_mkdir)?;
+        let mut slots = vec![];
+
+        for cluster in self.chain_of(dir.cluster())? {
+            let bytes = self.read_cluster(cluster)?;
+            slots.extend(
+                bytes
+                    .chunks_exact(32)
+                    .enumerate()
+                    .map(|(index, bytes)| Slot {
+                        cluster,
+                        index,
+                        bytes: bytes.to_vec(),
+                    }),
+            );
+        }
+
+        Ok(slots)
+    }
+}
@@ -0,0 +1,65 @@
+use std::fmt::Display;
+
+use super::{fatmanager::FATManager, FATError, FAT};
+
+/// What the FAT entry of a cluster says, as `dumpfat` shows it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Link {
+    Free,
+    /// The chain goes on at this cluster.
+    Next(u32),
+    /// The cluster ends its chain.
+    End,
+    Bad,
+    /// The entry points outside the data area.
+    Invalid,
+}
+
+impl Display for Link {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Link::Free => write!(f, "FREE"),
+            Link::Next(cluster) => write!(f, "chain→{cluster}"),
+            Link::End => write!(f, "EOF"),
+            Link::Bad => write!(f, "BAD"),
+            Link::Invalid => write!(f, "INVALID"),
+        }
+    }
+}
+
+impl FAT {
+    /// The raw FAT entries of up to `count` clusters from `start` on, stopping
+    /// at the last data cluster; none when `start` is not a data cluster.
+    pub fn fat_entries(&mut self, start: u32, count: u32) -> Result<Vec<(u32, u32)>, FATError> {
+        if !self.is_data_cluster(start) {
+            return Ok(vec![]);
+        }
+        let last = self
+            .header
+            .as_ref()
+            .expect("Image is not formatted!")
+            .data_cluster_count();
+
+        let mut manager = FATManager::new(self.clusters_per_fat_sector());
+        let mut entries = vec![];
+        for cluster in (start..=last).take(count as usize) {
+            self.cache_fat(&mut manager, cluster)?;
+            entries.push((
+                cluster,
+                manager.get_cluster_value(cluster).unwrap_or_default(),
+            ));
+        }
+        Ok(entries)
+    }
+
+    /// How the FAT entry `value` reads on this image.
+    pub fn link(&self, value: u32) -> Link {
+        match value {
+            0 => Link::Free,
+            end if end == Self::mark_read_done() => Link::End,
+            bad if bad == Self::mark_bad_cluster() => Link::Bad,
+            next if self.is_data_cluster(next) => Link::Next(next),
+            _ => Link::Invalid,
+        }
+    }
+}
@@ -1,18 +1,16 @@
-use std::{
-    collections::{hash_map::IntoIter, HashMap},
-    mem::size_of,
-};
+use std::collections::HashMap;
 
 pub struct FATManager {
-    fat_sectors: HashMap<u32, [u32; 128]>,
+    /// Each cached FAT sector as it was read and as it is now.
+    fat_sectors: HashMap<u32, (Vec<u32>, Vec<u32>)>,
     clusters_per_fat_sector: u32,
 }
 
 impl FATManager {
-    pub fn new() -> Self {
+    pub fn new(clusters_per_fat_sector: u32) -> Self {
         Self {
             fat_sectors: HashMap::new(),
-            clusters_per_fat_sector: 512 / size_of::<u32>() as u32,
+            clusters_per_fat_sector,
         }
     }
 
@@ -21,25 +19,28 @@ impl FATManager {
         self.fat_sectors.contains_key(&map_index)
     }
 
-    pub fn add_cluster(&mut self, cluster: u32, sector: [u32; 128]) {
+    pub fn add_cluster(&mut self, cluster: u32, sector: Vec<u32>) {
         let map_index = cluster / self.clusters_per_fat_sector;
-        self.fat_sectors.insert(map_index, sector).map(|_| ());
+        self.fat_sectors.insert(map_index, (sector.clone(), sector));
     }
 
     pub fn get_cluster_value(&self, cluster: u32) -> Option<u32> {
         let map_index = cluster / self.clusters_per_fat_sector;
         let fat_index = (cluster % self.clusters_per_fat_sector) as usize;
-        self.fat_sectors.get(&map_index)?.get(fat_index).cloned()
+        self.fat_sectors.get(&map_index)?.1.get(fat_index).cloned()
     }
 
     pub fn set_cluster_value(&mut self, cluster: u32, value: u32) -> Option<()> {
         let map_index = cluster / self.clusters_per_fat_sector;
         let fat_index = (cluster % self.clusters_per_fat_sector) as usize;
-        *self.fat_sectors.get_mut(&map_index)?.get_mut(fat_index)? = value;
+        *self.fat_sectors.get_mut(&map_index)?.1.get_mut(fat_index)? = value;
         Some(())
     }
 
-    pub fn flush(self) -> IntoIter<u32, [u32; 128]> {
-        self.fat_sectors.into_iter()
+    /// The cached sectors by index, each as read and as changed.
+    pub fn flush(self) -> impl Iterator<Item = (u32, Vec<u32>, Vec<u32>)> {
+        self.fat_sectors
+            .into_iter()
+            .map(|(index, (old, new))| (index, old, new))
     }
 }
@@ -0,0 +1,115 @@
+use std::fmt::Display;
+
+use super::{FATError, FAT};
+
+/// How a single file is laid out on disk.
+#[derive(Debug, Clone)]
+pub struct FileExtents {
+    pub path: String,
+    pub clusters: usize,
+    /// Runs of consecutive clusters in the chain.
+    pub extents: usize,
+}
+
+impl Display for FileExtents {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} extent(s), {} cluster(s)",
+            self.path, self.extents, self.clusters
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FragReport {
+    pub files: Vec<FileExtents>,
+    pub free_clusters: u32,
+    pub largest_free_run: u32,
+}
+
+impl FragReport {
+    pub fn extents(&self) -> usize {
+        self.files.iter().map(|file| file.extents).sum()
+    }
+
+    /// Share of files split into more than one extent, in percent.
+    pub fn fragmentation(&self) -> f64 {
+        if self.files.is_empty() {
+            return 0.0;
+        }
+
+        let fragmented = self.files.iter().filter(|file| file.extents > 1).count();
+        fragmented as f64 * 100.0 / self.files.len() as f64
+    }
+}
+
+impl Display for FragReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} file(s), {} extent(s), {:.1}% fragmented\nfree: {} cluster(s), largest free run: {} cluster(s)",
+            self.files.len(),
+            self.extents(),
+            self.fragmentation(),
+            self.free_clusters,
+            self.largest_free_run
+        )
+    }
+}
+
+/// Number of runs of consecutive clusters in `chain`.
+pub fn count_extents(chain: &[u32]) -> usize {
+    if chain.is_empty() {
+        return 0;
+    }
+
+    1 + chain
+        .windows(2)
+        .filter(|pair| pair[1] != pair[0] + 1)
+        .count()
+}
+
+impl FAT {
+    /// Reports the extents of every file at or below `path`, along with the
+    /// free space layout of the whole image.
+    pub fn fragmentation(&mut self, path: &str) -> Result<FragReport, FATError> {
+        let path = path.trim_end_matches('.').trim_end_matches('/');
+        let prefix = format!("/{path}");
+        if !path.is_empty() {
+            self.find_file(path, Self::filter_find)?;
+        }
+
+        let mut report = FragReport::default();
+
+        for (file_path, entry) in self.walk()? {
+            let selected = path.is_empty()
+                || file_path == prefix
+                || file_path.starts_with(&format!("{prefix}/"));
+            if !selected || Self::filter_mkdir(&entry) {
+                continue;
+            }
+
+            let chain = self.chain_of(entry.cluster())?;
+            report.files.push(FileExtents {
+                path: file_path,
+                clusters: chain.len(),
+                extents: count_extents(&chain),
+            });
+        }
+
+        let fat = self.read_whole_fat()?;
+        let mut run = 0;
+        for cluster in 1..fat.len() as u32 {
+            if self.is_data_cluster(cluster) && fat[cluster as usize] == 0 {
+                run += 1;
+                report.free_clusters += 1;
+                report.largest_free_run = report.largest_free_run.max(run);
+            } else {
+                run = 0;
+            }
+        }
+
+        Ok(report)
+    }
+}
@@ -1,13 +1,31 @@
-use crate::units::Unit;
-use std::{cmp::Ordering, fmt::Display};
+use crate::{time, units::Unit};
+use std::{cmp::Ordering, fmt::Display, mem::size_of, str};
+
+use super::{
+    crypto::{self, Key, KEY_SLOT_LENGTH, SEAL_LENGTH},
+    journal::JOURNAL_SIZE,
+    uuid::{Uuid, UUID_LENGTH},
+    FATError,
+};
 
 #[derive(Debug, Clone)]
 pub struct Header {
     bytes_per_sector: u32,
     sectors_per_cluster: u32,
-    sector_count: u32,
+    sector_count: u64,
     fat_count: u32,
     checksum: u32,
+    label: String,
+    uuid: Uuid,
+    version: u32,
+    created: u64,
+    state: u32,
+    mount_count: u32,
+    last_check: u64,
+    features: u32,
+    key_slot: [u8; KEY_SLOT_LENGTH],
+    /// Free data clusters as of the last clean `sync`, or [`FREE_UNKNOWN`].
+    free_clusters: u32,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -15,54 +33,275 @@ pub enum HeaderError {
     BadCapacity,
     BadChecksum,
     BadBytes,
+    BadSectorSize,
+    BadClusterSize,
+    BadFatCount,
+    BadLabel,
+    UnsupportedVersion,
     CannotFormat,
+    CannotWrite,
 }
 
-const BYTES_PER_SECTOR: u32 = 512;
-const SECTORS_PER_CLUSTER: u32 = 8;
+pub const BYTES_PER_SECTOR: u32 = 512;
+pub const CLUSTER_SIZE: u32 = 4096;
+pub const FAT_COUNT: u32 = 2;
+const MAX_FAT_COUNT: u32 = 4;
+/// Largest cluster size accepted, so that a single cluster always fits in
+/// memory.
+const MAX_CLUSTER_SIZE: u32 = 64 << 20;
+pub const LABEL_LENGTH: usize = 16;
+
+const CHECKSUM_OFFSET: usize = 4 * size_of::<u32>();
+const LABEL_OFFSET: usize = 5 * size_of::<u32>();
+const UUID_OFFSET: usize = LABEL_OFFSET + LABEL_LENGTH;
+const VERSION_OFFSET: usize = UUID_OFFSET + UUID_LENGTH;
+const CREATED_OFFSET: usize = VERSION_OFFSET + size_of::<u32>();
+const STATE_OFFSET: usize = CREATED_OFFSET + size_of::<u64>();
+const MOUNT_COUNT_OFFSET: usize = STATE_OFFSET + size_of::<u32>();
+const LAST_CHECK_OFFSET: usize = MOUNT_COUNT_OFFSET + size_of::<u32>();
+const FEATURES_OFFSET: usize = LAST_CHECK_OFFSET + size_of::<u64>();
+const KEY_SLOT_OFFSET: usize = FEATURES_OFFSET + size_of::<u32>();
+/// High half of the sector count; its low half sits among the leading words.
+const SECTOR_COUNT_HIGH_OFFSET: usize = KEY_SLOT_OFFSET + KEY_SLOT_LENGTH;
+const FREE_CLUSTERS_OFFSET: usize = SECTOR_COUNT_HIGH_OFFSET + size_of::<u32>();
+pub const HEADER_SIZE: usize = FREE_CLUSTERS_OFFSET + size_of::<u32>();
+
+/// Free cluster count of an image that has not been counted since it was
+/// formatted, resized or upgraded.
+const FREE_UNKNOWN: u32 = u32::MAX;
+
+/// Most entries a FAT can have: the two highest values mark the end of a
+/// chain and a bad cluster, so no cluster can be numbered with them.
+const MAX_CLUSTER_COUNT: u64 = 0xFFFF_FFFE;
+
+/// Number of mounts after which a `check` is suggested.
+pub const MAX_MOUNT_COUNT: u32 = 20;
+
+/// Set while the image is mounted and has been written to; cleared again by a
+/// clean `sync`/`exit`.
+const STATE_DIRTY: u32 = 1 << 0;
+
+/// A CRC32 table for the primary FAT and every data cluster sits between the
+/// FAT copies and the data region.
+const FEATURE_CHECKSUMS: u32 = 1 << 0;
+
+/// A table counting the extra directory entries that share each cluster sits
+/// between the checksum table and the data region.
+const FEATURE_REFCOUNTS: u32 = 1 << 1;
+
+/// Files are compressed as they are created.
+const FEATURE_COMPRESSION: u32 = 1 << 2;
+
+/// The data region is encrypted with a key sealed in the header, and a table
+/// of the nonce and tag of each cluster sits between the reference count
+/// table and the data region.
+const FEATURE_ENCRYPTION: u32 = 1 << 3;
+
+/// Metadata updates go through a write-ahead journal between the write count
+/// table and the data region.
+const FEATURE_JOURNAL: u32 = 1 << 4;
+
+/// Large directories keep a hash index of their names, see
+/// [`crate::fat::dirindex`]. It takes no region of its own.
+const FEATURE_DIR_INDEX: u32 = 1 << 5;
+
+/// On-disk format version written by `format`.
+///
+/// Version 0 images predate the field: they have no label, UUID, or creation
+/// time, but are otherwise readable and can be brought up to date by `migrate`.
+/// Version 1 images lack the feature word and thus the checksum table.
+/// Version 2 images keep file sizes in 32 bits, limiting files to 4 GiB.
+/// Version 3 images keep the sector count in 32 bits.
+/// Version 4 images keep no count of free clusters.
+/// Version 5 images encrypt without authentication, see
+/// [`AUTHENTICATED_VERSION`].
+pub const VERSION: u32 = 6;
+
+/// First version whose encrypted images are authenticated. Older ones kept a
+/// write count per cluster where the seal table is and cannot be unlocked.
+pub const AUTHENTICATED_VERSION: u32 = 6;
+
+/// Geometry requested by `format`.
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    pub bytes_per_sector: u32,
+    pub cluster_size: u32,
+    pub fat_count: u32,
+    pub label: String,
+    /// Zero-fill the data region instead of leaving it sparse.
+    pub preallocate: bool,
+    /// Compress new files by default.
+    pub compress: bool,
+    /// Encrypt the data region with a key sealed under this passphrase.
+    pub passphrase: Option<String>,
+    /// Index the names of large directories.
+    pub dir_index: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            bytes_per_sector: BYTES_PER_SECTOR,
+            cluster_size: CLUSTER_SIZE,
+            fat_count: FAT_COUNT,
+            label: String::new(),
+            preallocate: false,
+            compress: false,
+            passphrase: None,
+            dir_index: false,
+        }
+    }
+}
+
+/// The `N` bytes at `offset`.
+fn array_at<const N: usize>(bytes: &[u8], offset: usize) -> Result<[u8; N], HeaderError> {
+    bytes
+        .get(offset..offset + N)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(HeaderError::BadBytes)
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32, HeaderError> {
+    array_at(bytes, offset).map(u32::from_le_bytes)
+}
 
 impl Header {
-    fn capacity_to_sector_count(capacity: usize) -> u32 {
-        capacity as u32 / BYTES_PER_SECTOR
+    fn capacity_to_sector_count(capacity: usize, bytes_per_sector: u32) -> u64 {
+        (capacity / bytes_per_sector as usize) as u64
+    }
+
+    /// Rejects geometries with no data clusters or more clusters than the FAT
+    /// can number.
+    fn check_capacity(&self) -> Result<(), HeaderError> {
+        if self.sector_count / self.sectors_per_cluster as u64 > MAX_CLUSTER_COUNT
+            || self.data_cluster_count() == 0
+        {
+            return Err(HeaderError::BadCapacity);
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the geometry read from an image is one [`Header::new`]
+    /// could have produced, so that nothing derived from it overflows or
+    /// divides by zero.
+    pub fn check_geometry(&self) -> Result<(), HeaderError> {
+        if !self.bytes_per_sector.is_power_of_two() || self.bytes_per_sector < BYTES_PER_SECTOR {
+            return Err(HeaderError::BadSectorSize);
+        }
+
+        let cluster_size = self.bytes_per_sector.checked_mul(self.sectors_per_cluster);
+        if !self.sectors_per_cluster.is_power_of_two()
+            || cluster_size.is_none_or(|size| size > MAX_CLUSTER_SIZE)
+        {
+            return Err(HeaderError::BadClusterSize);
+        }
+
+        if self.fat_count == 0 || self.fat_count > MAX_FAT_COUNT {
+            return Err(HeaderError::BadFatCount);
+        }
+
+        if self
+            .sector_count
+            .checked_mul(self.bytes_per_sector as u64)
+            .is_none()
+        {
+            return Err(HeaderError::BadCapacity);
+        }
+
+        self.check_capacity()
+    }
+
+    /// Sum of every header word except the checksum itself.
+    fn sum(bytes: &[u8]) -> u32 {
+        bytes
+            .chunks_exact(size_of::<u32>())
+            .enumerate()
+            .filter(|(i, _)| i * size_of::<u32>() != CHECKSUM_OFFSET)
+            .map(|(_, word)| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .fold(0, u32::wrapping_add)
     }
 
     fn update_checksum(&mut self) {
-        self.checksum = u32::MAX
-            - (self.bytes_per_sector
-                + self.sectors_per_cluster
-                + self.sector_count
-                + self.fat_count)
-            + 1;
+        self.checksum = 0u32.wrapping_sub(Self::sum(&self.as_bytes()));
     }
 
-    pub fn new(capacity: Unit) -> Result<Self, HeaderError> {
+    fn check_label(label: &str) -> Result<(), HeaderError> {
+        if label.len() > LABEL_LENGTH || label.chars().any(|c| c.is_control()) {
+            return Err(HeaderError::BadLabel);
+        }
+
+        Ok(())
+    }
+
+    pub fn new(capacity: Unit, options: &FormatOptions) -> Result<Self, HeaderError> {
+        let bytes_per_sector = options.bytes_per_sector;
+        if !bytes_per_sector.is_power_of_two() || bytes_per_sector < BYTES_PER_SECTOR {
+            return Err(HeaderError::BadSectorSize);
+        }
+
+        if !options.cluster_size.is_power_of_two()
+            || options.cluster_size < bytes_per_sector
+            || options.cluster_size > MAX_CLUSTER_SIZE
+        {
+            return Err(HeaderError::BadClusterSize);
+        }
+
+        if options.fat_count == 0 || options.fat_count > MAX_FAT_COUNT {
+            return Err(HeaderError::BadFatCount);
+        }
+
+        Self::check_label(&options.label)?;
+
         let capacity = capacity.to_bytes();
-        if capacity % 512 != 0 {
+        if !capacity.is_multiple_of(bytes_per_sector as usize) {
             return Err(HeaderError::BadCapacity);
         }
 
-        let sector_count = Self::capacity_to_sector_count(capacity);
+        let sector_count = Self::capacity_to_sector_count(capacity, bytes_per_sector);
 
         let mut fat = Self {
-            bytes_per_sector: BYTES_PER_SECTOR,
-            sectors_per_cluster: SECTORS_PER_CLUSTER,
+            bytes_per_sector,
+            sectors_per_cluster: options.cluster_size / bytes_per_sector,
             sector_count,
-            fat_count: 2,
+            fat_count: options.fat_count,
             checksum: 0,
+            label: options.label.clone(),
+            uuid: Uuid::new_v4().map_err(|_| HeaderError::CannotFormat)?,
+            version: VERSION,
+            created: time::now(),
+            state: 0,
+            mount_count: 0,
+            last_check: 0,
+            features: FEATURE_CHECKSUMS
+                | FEATURE_REFCOUNTS
+                | FEATURE_JOURNAL
+                | if options.compress {
+                    FEATURE_COMPRESSION
+                } else {
+                    0
+                }
+                | if options.passphrase.is_some() {
+                    FEATURE_ENCRYPTION
+                } else {
+                    0
+                }
+                | if options.dir_index {
+                    FEATURE_DIR_INDEX
+                } else {
+                    0
+                },
+            key_slot: [0; KEY_SLOT_LENGTH],
+            free_clusters: FREE_UNKNOWN,
         };
 
+        fat.check_capacity()?;
         fat.update_checksum();
         Ok(fat)
     }
 
-    fn check_checksum(&self) -> Result<(), HeaderError> {
-        let sum = self
-            .checksum
-            .wrapping_add(self.bytes_per_sector)
-            .wrapping_add(self.sectors_per_cluster)
-            .wrapping_add(self.sector_count)
-            .wrapping_add(self.fat_count);
-        if sum == 0 {
+    fn check_checksum(bytes: &[u8], checksum: u32) -> Result<(), HeaderError> {
+        if checksum.wrapping_add(Self::sum(bytes)) == 0 {
             Ok(())
         } else {
             Err(HeaderError::BadChecksum)
@@ -70,31 +309,72 @@ impl Header {
     }
 
     pub fn from_raw_bytes(bytes: &[u8]) -> Result<Self, HeaderError> {
-        use std::mem::size_of;
-
         let u32_size = size_of::<u32>();
 
-        if bytes.len().cmp(&(5 * u32_size)) != Ordering::Equal {
+        if bytes.len().cmp(&HEADER_SIZE) != Ordering::Equal {
             return Err(HeaderError::BadBytes);
         }
-        let bytes_per_sector = u32::from_le_bytes(bytes[0..u32_size].try_into().unwrap());
-        let sectors_per_cluster =
-            u32::from_le_bytes(bytes[u32_size..2 * u32_size].try_into().unwrap());
-        let sector_count =
-            u32::from_le_bytes(bytes[2 * u32_size..3 * u32_size].try_into().unwrap());
-        let fat_count = u32::from_le_bytes(bytes[3 * u32_size..4 * u32_size].try_into().unwrap());
-        let checksum = u32::from_le_bytes(bytes[4 * u32_size..5 * u32_size].try_into().unwrap());
+        let bytes_per_sector = u32_at(bytes, 0)?;
+        let sectors_per_cluster = u32_at(bytes, u32_size)?;
+        let sector_count_low = u32_at(bytes, 2 * u32_size)?;
+        let fat_count = u32_at(bytes, 3 * u32_size)?;
+        let checksum = u32_at(bytes, 4 * u32_size)?;
+
+        Self::check_checksum(bytes, checksum)?;
+
+        let label = str::from_utf8(
+            &bytes[LABEL_OFFSET..LABEL_OFFSET + LABEL_LENGTH]
+                .iter()
+                .filter(|c| **c != 0)
+                .cloned()
+                .collect::<Vec<u8>>(),
+        )
+        .map_err(|_| HeaderError::BadLabel)?
+        .to_string();
+
+        let uuid = Uuid::from_bytes(array_at(bytes, UUID_OFFSET)?);
+
+        let version = u32_at(bytes, VERSION_OFFSET)?;
+        if version > VERSION {
+            return Err(HeaderError::UnsupportedVersion);
+        }
+
+        let sector_count_high = if version >= 4 {
+            u32_at(bytes, SECTOR_COUNT_HIGH_OFFSET)?
+        } else {
+            0
+        };
+        let sector_count = (sector_count_high as u64) << 32 | sector_count_low as u64;
+        let free_clusters = if version >= 5 {
+            u32_at(bytes, FREE_CLUSTERS_OFFSET)?
+        } else {
+            FREE_UNKNOWN
+        };
 
-        let fat = Self {
+        let created = u64::from_le_bytes(array_at(bytes, CREATED_OFFSET)?);
+        let state = u32_at(bytes, STATE_OFFSET)?;
+        let mount_count = u32_at(bytes, MOUNT_COUNT_OFFSET)?;
+        let last_check = u64::from_le_bytes(array_at(bytes, LAST_CHECK_OFFSET)?);
+        let features = u32_at(bytes, FEATURES_OFFSET)?;
+        let key_slot = array_at(bytes, KEY_SLOT_OFFSET)?;
+
+        Ok(Self {
             bytes_per_sector,
             sectors_per_cluster,
             sector_count,
             fat_count,
             checksum,
-        };
-
-        fat.check_checksum()?;
-        Ok(fat)
+            label,
+            uuid,
+            version,
+            created,
+            state,
+            mount_count,
+            last_check,
+            features,
+            key_slot,
+            free_clusters,
+        })
     }
 
     pub fn bytes_per_sector(&self) -> u32 {
@@ -105,21 +385,452 @@ impl Header {
         self.sectors_per_cluster
     }
 
-    pub fn sector_count(&self) -> u32 {
+    pub fn sector_count(&self) -> u64 {
         self.sector_count
     }
 
+    /// Number of FAT copies kept up to date; images before version 1 only
+    /// ever kept the first, whatever their header says.
     pub fn fat_count(&self) -> u32 {
-        self.fat_count
+        if self.has_legacy_layout() {
+            1
+        } else {
+            self.fat_count
+        }
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn uuid(&self) -> Uuid {
+        self.uuid
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.state & STATE_DIRTY == STATE_DIRTY
+    }
+
+    pub fn set_dirty(&mut self, dirty: bool) {
+        if dirty {
+            self.state |= STATE_DIRTY;
+        } else {
+            self.state &= !STATE_DIRTY;
+        }
+        self.update_checksum();
+    }
+
+    /// Free data clusters recorded by the last clean `sync`; unknown while the
+    /// image is dirty, as the count may not have been written since.
+    pub fn free_clusters(&self) -> Option<u32> {
+        Some(self.free_clusters)
+            .filter(|free| *free <= self.data_cluster_count() && !self.is_dirty())
+    }
+
+    pub fn set_free_clusters(&mut self, free: Option<u32>) {
+        self.free_clusters = free.unwrap_or(FREE_UNKNOWN);
+        self.update_checksum();
+    }
+
+    /// Same filesystem with a different total capacity.
+    pub fn resized(&self, capacity: Unit) -> Result<Self, HeaderError> {
+        let capacity = capacity.to_bytes();
+        if !capacity.is_multiple_of(self.bytes_per_sector as usize) {
+            return Err(HeaderError::BadCapacity);
+        }
+
+        let mut header = self.clone();
+        header.sector_count = Self::capacity_to_sector_count(capacity, self.bytes_per_sector);
+        header.free_clusters = FREE_UNKNOWN;
+        header.check_capacity()?;
+        header.update_checksum();
+        Ok(header)
+    }
+
+    /// Number of mounts since the last `check`.
+    pub fn mount_count(&self) -> u32 {
+        self.mount_count
+    }
+
+    pub fn record_mount(&mut self) {
+        self.mount_count = self.mount_count.saturating_add(1);
+        self.update_checksum();
+    }
+
+    pub fn record_check(&mut self) {
+        self.mount_count = 0;
+        self.last_check = time::now();
+        self.update_checksum();
+    }
+
+    /// Zeroes the UUID and the timestamps, which would otherwise differ
+    /// between two images built alike.
+    pub fn make_reproducible(&mut self) {
+        self.uuid = Uuid::from_bytes([0; UUID_LENGTH]);
+        self.created = 0;
+        self.last_check = 0;
+        self.update_checksum();
+    }
+
+    /// Upgrades the header to the current format version, filling in the
+    /// fields older versions lack. Returns the version it was upgraded from.
+    ///
+    /// Only the header changes; [`crate::fat::FAT::migrate`] moves the FAT
+    /// and the data region to where the new version expects them.
+    pub(super) fn migrate(&mut self) -> Result<u32, HeaderError> {
+        let old_version = self.version;
+
+        if old_version < 1 {
+            self.uuid = Uuid::new_v4().map_err(|_| HeaderError::CannotWrite)?;
+            self.created = time::now();
+        }
+
+        if old_version < 2 {
+            self.features |= FEATURE_CHECKSUMS;
+        }
+
+        // older entries leave the high half of the size zero, so they read
+        // back unchanged, and the high half of the sector count is written
+        // out with the header
+
+        self.version = VERSION;
+        self.update_checksum();
+        Ok(old_version)
+    }
+
+    pub fn set_label(&mut self, label: &str) -> Result<(), HeaderError> {
+        Self::check_label(label)?;
+        self.label = label.to_string();
+        self.update_checksum();
+        Ok(())
+    }
+
+    pub fn cluster_size(&self) -> u32 {
+        self.bytes_per_sector * self.sectors_per_cluster
+    }
+
+    /// Number of entries in one copy of the FAT.
+    pub fn cluster_count(&self) -> u32 {
+        (self.sector_count / self.sectors_per_cluster as u64) as u32
+    }
+
+    /// Number of sectors occupied by one copy of the FAT.
+    pub fn fat_sectors(&self) -> u32 {
+        (self.cluster_count() as u64 * size_of::<u32>() as u64)
+            .div_ceil(self.bytes_per_sector as u64) as u32
+    }
+
+    /// Whether the image is laid out as before version 1: a single FAT
+    /// followed by the data region, which starts where the room for every FAT
+    /// copy ends, rounded down to a whole sector.
+    pub fn has_legacy_layout(&self) -> bool {
+        self.version == 0
+    }
+
+    /// Whether directory entries keep the high half of the file size.
+    pub fn has_large_files(&self) -> bool {
+        self.version >= 3
+    }
+
+    pub fn has_checksums(&self) -> bool {
+        self.features & FEATURE_CHECKSUMS == FEATURE_CHECKSUMS
+    }
+
+    /// First sector of the checksum table, right after the last FAT copy.
+    pub fn checksum_sector(&self) -> u64 {
+        1 + self.fat_count as u64 * self.fat_sectors() as u64
+    }
+
+    /// Number of sectors occupied by the checksum table: one CRC32 per FAT
+    /// sector followed by one per cluster.
+    pub fn checksum_sectors(&self) -> u64 {
+        if !self.has_checksums() {
+            return 0;
+        }
+
+        ((self.fat_sectors() as u64 + self.cluster_count() as u64) * size_of::<u32>() as u64)
+            .div_ceil(self.bytes_per_sector as u64)
+    }
+
+    pub fn has_refcounts(&self) -> bool {
+        self.features & FEATURE_REFCOUNTS == FEATURE_REFCOUNTS
+    }
+
+    pub fn compresses_by_default(&self) -> bool {
+        self.features & FEATURE_COMPRESSION == FEATURE_COMPRESSION
+    }
+
+    pub fn enable_refcounts(&mut self) {
+        self.features |= FEATURE_REFCOUNTS;
+    }
+
+    /// First sector of the reference count table, right after the checksums.
+    pub fn refcount_sector(&self) -> u64 {
+        self.checksum_sector() + self.checksum_sectors()
+    }
+
+    /// Number of sectors occupied by the reference count table: one u32 per cluster.
+    pub fn refcount_sectors(&self) -> u64 {
+        if !self.has_refcounts() {
+            return 0;
+        }
+
+        (self.cluster_count() as u64 * size_of::<u32>() as u64)
+            .div_ceil(self.bytes_per_sector as u64)
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.features & FEATURE_ENCRYPTION == FEATURE_ENCRYPTION
+    }
+
+    /// Seals a freshly generated data key under `passphrase` and returns it.
+    pub fn seal_key(&mut self, passphrase: &str) -> Result<Key, FATError> {
+        let (slot, key) = crypto::seal_key(passphrase)?;
+        self.key_slot = slot;
+        self.features |= FEATURE_ENCRYPTION;
+        self.update_checksum();
+        Ok(key)
+    }
+
+    /// The data key, if `passphrase` is the one it was sealed under.
+    pub fn open_key(&self, passphrase: &str) -> Option<Key> {
+        crypto::open_key(&self.key_slot, passphrase)
+    }
+
+    /// First sector of the seal table, right after the reference counts.
+    pub fn seal_sector(&self) -> u64 {
+        self.refcount_sector() + self.refcount_sectors()
+    }
+
+    /// Number of sectors occupied by the seal table: a nonce and tag per
+    /// cluster, or a u32 write count on images before
+    /// [`AUTHENTICATED_VERSION`].
+    pub fn seal_sectors(&self) -> u64 {
+        if !self.is_encrypted() {
+            return 0;
+        }
+
+        let record = if self.version >= AUTHENTICATED_VERSION {
+            SEAL_LENGTH
+        } else {
+            size_of::<u32>()
+        };
+        (self.cluster_count() as u64 * record as u64).div_ceil(self.bytes_per_sector as u64)
     }
 
-    pub fn checksum(&self) -> u32 {
-        self.checksum
+    pub fn has_journal(&self) -> bool {
+        self.features & FEATURE_JOURNAL == FEATURE_JOURNAL
+    }
+
+    pub fn enable_journal(&mut self) {
+        self.features |= FEATURE_JOURNAL;
+    }
+
+    pub fn has_dir_index(&self) -> bool {
+        self.features & FEATURE_DIR_INDEX == FEATURE_DIR_INDEX
+    }
+
+    pub fn enable_dir_index(&mut self) {
+        self.features |= FEATURE_DIR_INDEX;
+        self.update_checksum();
+    }
+
+    /// First sector of the journal, right after the seal table.
+    pub fn journal_sector(&self) -> u64 {
+        self.seal_sector() + self.seal_sectors()
+    }
+
+    /// Number of sectors occupied by the journal; small images give it at
+    /// most a 64th of their space.
+    pub fn journal_sectors(&self) -> u64 {
+        if !self.has_journal() {
+            return 0;
+        }
+
+        (JOURNAL_SIZE / self.bytes_per_sector as u64).min(self.sector_count / 64)
+    }
+
+    pub fn first_data_sector(&self) -> u64 {
+        if self.has_legacy_layout() {
+            return 1 + self.fat_count as u64
+                * self.cluster_count() as u64
+                * size_of::<u32>() as u64
+                / self.bytes_per_sector as u64;
+        }
+
+        self.journal_sector() + self.journal_sectors()
+    }
+
+    /// Number of clusters that fit in the data region; they are numbered from 1.
+    pub fn data_cluster_count(&self) -> u32 {
+        (self.sector_count.saturating_sub(self.first_data_sector())
+            / self.sectors_per_cluster as u64) as u32
+    }
+
+    pub fn as_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut v = [0; HEADER_SIZE];
+
+        for (field, bytes) in std::iter::zip(
+            [
+                self.bytes_per_sector,
+                self.sectors_per_cluster,
+                self.sector_count as u32,
+                self.fat_count,
+                self.checksum,
+            ],
+            v.chunks_mut(size_of::<u32>()),
+        ) {
+            bytes.clone_from_slice(&field.to_le_bytes());
+        }
+
+        v[LABEL_OFFSET..LABEL_OFFSET + self.label.len()].clone_from_slice(self.label.as_bytes());
+        v[UUID_OFFSET..UUID_OFFSET + UUID_LENGTH].clone_from_slice(self.uuid.as_bytes());
+        v[VERSION_OFFSET..VERSION_OFFSET + size_of::<u32>()]
+            .clone_from_slice(&self.version.to_le_bytes());
+        v[CREATED_OFFSET..CREATED_OFFSET + size_of::<u64>()]
+            .clone_from_slice(&self.created.to_le_bytes());
+        v[STATE_OFFSET..STATE_OFFSET + size_of::<u32>()]
+            .clone_from_slice(&self.state.to_le_bytes());
+        v[MOUNT_COUNT_OFFSET..MOUNT_COUNT_OFFSET + size_of::<u32>()]
+            .clone_from_slice(&self.mount_count.to_le_bytes());
+        v[LAST_CHECK_OFFSET..LAST_CHECK_OFFSET + size_of::<u64>()]
+            .clone_from_slice(&self.last_check.to_le_bytes());
+        v[FEATURES_OFFSET..FEATURES_OFFSET + size_of::<u32>()]
+            .clone_from_slice(&self.features.to_le_bytes());
+        v[KEY_SLOT_OFFSET..KEY_SLOT_OFFSET + KEY_SLOT_LENGTH].clone_from_slice(&self.key_slot);
+        v[SECTOR_COUNT_HIGH_OFFSET..SECTOR_COUNT_HIGH_OFFSET + size_of::<u32>()]
+            .clone_from_slice(&((self.sector_count >> 32) as u32).to_le_bytes());
+        v[FREE_CLUSTERS_OFFSET..FREE_CLUSTERS_OFFSET + size_of::<u32>()]
+            .clone_from_slice(&self.free_clusters.to_le_bytes());
+
+        v
     }
 }
 
 impl Display for Header {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "FAT Info:\nBytes per sector: {}\nSectors per cluster: {}\nSector count: {}\nNumber of FATs: {}\n", self.bytes_per_sector, self.sectors_per_cluster, self.sector_count, self.fat_count)
+        let format = |timestamp| {
+            if timestamp == 0 {
+                "never".to_string()
+            } else {
+                time::format_timestamp(timestamp)
+            }
+        };
+
+        write!(f, "FAT Info:\nVersion: {}\nLabel: {}\nUUID: {}\nCreated: {}\nMount count: {}\nLast checked: {}\nBytes per sector: {}\nSectors per cluster: {}\nSector count: {}\nNumber of FATs: {}\nChecksums: {}\nReflinks: {}\nCompression: {}\nEncryption: {}\nJournal: {}\nDirectory index: {}\n", self.version, self.label, self.uuid, format(self.created), self.mount_count, format(self.last_check), self.bytes_per_sector, self.sectors_per_cluster, self.sector_count, self.fat_count, if self.has_checksums() { "yes" } else { "no" }, if self.has_refcounts() { "yes" } else { "no" }, if self.compresses_by_default() { "yes" } else { "no" }, if self.is_encrypted() { "yes" } else { "no" }, if self.has_journal() { "yes" } else { "no" }, if self.has_dir_index() { "yes" } else { "no" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sector_count_past_32_bits_round_trips() {
+        let header = Header::new(Unit::GB(3 * 1024), &FormatOptions::default()).unwrap();
+        assert!(header.sector_count() > u32::MAX as u64);
+
+        let read = Header::from_raw_bytes(&header.as_bytes()).unwrap();
+        assert_eq!(read.sector_count(), header.sector_count());
+        assert_eq!(read.data_cluster_count(), header.data_cluster_count());
+        assert_eq!(read.first_data_sector(), header.first_data_sector());
+    }
+
+    #[test]
+    fn data_clusters_fill_a_large_image() {
+        let header = Header::new(Unit::GB(3 * 1024), &FormatOptions::default()).unwrap();
+        let data_sectors = header.sector_count() - header.first_data_sector();
+        assert_eq!(
+            header.data_cluster_count() as u64,
+            data_sectors / header.sectors_per_cluster() as u64
+        );
+    }
+
+    #[test]
+    fn older_versions_ignore_the_high_sector_count() {
+        let header = Header::new(Unit::GB(8), &FormatOptions::default()).unwrap();
+        let mut bytes = header.as_bytes();
+        bytes[VERSION_OFFSET..VERSION_OFFSET + size_of::<u32>()]
+            .clone_from_slice(&3u32.to_le_bytes());
+        bytes[SECTOR_COUNT_HIGH_OFFSET..SECTOR_COUNT_HIGH_OFFSET + size_of::<u32>()]
+            .clone_from_slice(&1u32.to_le_bytes());
+        let checksum = 0u32.wrapping_sub(Header::sum(&bytes));
+        bytes[CHECKSUM_OFFSET..CHECKSUM_OFFSET + size_of::<u32>()]
+            .clone_from_slice(&checksum.to_le_bytes());
+
+        let read = Header::from_raw_bytes(&bytes).unwrap();
+        assert_eq!(read.sector_count(), header.sector_count());
+    }
+
+    #[test]
+    fn free_cluster_count_is_trusted_only_when_clean() {
+        let mut header = Header::new(Unit::MB(10), &FormatOptions::default()).unwrap();
+        assert_eq!(header.free_clusters(), None);
+
+        header.set_free_clusters(Some(42));
+        let read = Header::from_raw_bytes(&header.as_bytes()).unwrap();
+        assert_eq!(read.free_clusters(), Some(42));
+
+        header.set_dirty(true);
+        let read = Header::from_raw_bytes(&header.as_bytes()).unwrap();
+        assert_eq!(read.free_clusters(), None);
+    }
+
+    #[test]
+    fn rejects_more_clusters_than_the_fat_can_number() {
+        let options = FormatOptions {
+            cluster_size: BYTES_PER_SECTOR,
+            ..FormatOptions::default()
+        };
+        assert!(matches!(
+            Header::new(Unit::GB(3 * 1024), &options),
+            Err(HeaderError::BadCapacity)
+        ));
+    }
+
+    #[test]
+    fn rejects_geometry_that_could_not_have_been_formatted() {
+        let header = Header::new(Unit::MB(10), &FormatOptions::default()).unwrap();
+        assert!(header.check_geometry().is_ok());
+
+        for (offset, value) in [
+            (0, 0),
+            (0, 1000),
+            (size_of::<u32>(), 0),
+            (3 * size_of::<u32>(), 9),
+        ] {
+            let mut bytes = header.as_bytes();
+            bytes[offset..offset + size_of::<u32>()]
+                .clone_from_slice(&(value as u32).to_le_bytes());
+            let checksum = 0u32.wrapping_sub(Header::sum(&bytes));
+            bytes[CHECKSUM_OFFSET..CHECKSUM_OFFSET + size_of::<u32>()]
+                .clone_from_slice(&checksum.to_le_bytes());
+
+            let read = Header::from_raw_bytes(&bytes).unwrap();
+            assert!(read.check_geometry().is_err());
+        }
+    }
+
+    #[test]
+    fn reads_the_layout_of_images_before_version_1() {
+        // 1000 KiB as the first release formatted it: five words, no version
+        let mut bytes = [0; HEADER_SIZE];
+        for (index, word) in [512u32, 8, 2000, 2].into_iter().enumerate() {
+            bytes[index * size_of::<u32>()..(index + 1) * size_of::<u32>()]
+                .clone_from_slice(&word.to_le_bytes());
+        }
+        let checksum = 0u32.wrapping_sub(Header::sum(&bytes));
+        bytes[CHECKSUM_OFFSET..CHECKSUM_OFFSET + size_of::<u32>()]
+            .clone_from_slice(&checksum.to_le_bytes());
+
+        let header = Header::from_raw_bytes(&bytes).unwrap();
+        assert!(header.has_legacy_layout());
+        assert_eq!(header.fat_count(), 1);
+        assert_eq!(header.first_data_sector(), 1 + 2 * 250 / 128);
+        assert_eq!(header.data_cluster_count(), (2000 - 4) / 8);
     }
 }
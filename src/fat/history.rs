@@ -0,0 +1,120 @@
+use std::fmt::Display;
+
+use crate::time;
+
+use super::{batch::FsOp, dirent::Flags, perms::OWNERSHIP, uuid::Uuid, FATError, FAT};
+
+/// Hidden file every mutating command is recorded in.
+pub const HISTORY: &str = ".history";
+/// Records kept; older ones are dropped as new ones arrive.
+const MAX_RECORDS: usize = 1000;
+
+/// One command that changed, or tried to change, the image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// UUID of the image the command ran on, unknown for records kept before
+    /// it was.
+    pub uuid: Option<Uuid>,
+    /// Working directory relative paths in `command` start from.
+    pub directory: String,
+    pub command: String,
+    /// What the command printed when it finished, e.g. `OK`.
+    pub result: String,
+}
+
+impl Record {
+    /// Stored one per line, with tabs between the fields.
+    fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            self.timestamp,
+            self.uuid.map(|uuid| uuid.to_string()).unwrap_or_default(),
+            self.directory,
+            self.command,
+            self.result
+        )
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let (timestamp, rest) = line.split_once('\t')?;
+        // older records go on with the directory, which starts with `/`
+        let (uuid, rest) = match rest.split_once('\t')? {
+            (directory, _) if directory.starts_with('/') => (None, rest),
+            ("", rest) => (None, rest),
+            (uuid, rest) => (Some(uuid.parse().ok()?), rest),
+        };
+
+        let mut fields = rest.splitn(3, '\t');
+        Some(Self {
+            timestamp: timestamp.parse().ok()?,
+            uuid,
+            directory: fields.next()?.to_string(),
+            command: fields.next()?.to_string(),
+            result: fields.next()?.to_string(),
+        })
+    }
+}
+
+impl Display for Record {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {}> {}: {}",
+            time::format_timestamp(self.timestamp),
+            self.directory,
+            self.command,
+            self.result
+        )
+    }
+}
+
+impl FAT {
+    /// Recorded commands, oldest first.
+    pub fn history(&mut self) -> Result<Vec<Record>, FATError> {
+        if self.find_file(HISTORY, Self::filter_find_file).is_err() {
+            return Ok(vec![]);
+        }
+
+        let mut bytes = vec![];
+        self.cat(HISTORY, &mut bytes)?;
+
+        Ok(String::from_utf8_lossy(&bytes)
+            .lines()
+            .filter_map(Record::from_line)
+            .collect())
+    }
+
+    /// Appends `record` to the history, dropping the oldest records over the
+    /// limit. Its timestamp is zeroed on a deterministic image.
+    pub fn record(&mut self, mut record: Record) -> Result<(), FATError> {
+        if self.header.is_none() {
+            return Ok(());
+        }
+        if self.deterministic {
+            record.timestamp = 0;
+        }
+
+        let mut records = self.history()?;
+        records.push(record);
+        let excess = records.len().saturating_sub(MAX_RECORDS);
+        let text: String = records[excess..].iter().map(Record::to_line).collect();
+
+        self.transaction(|fs| {
+            fs.apply(&[FsOp::Write {
+                path: HISTORY.to_string(),
+                data: text.into_bytes(),
+            }])?;
+
+            let root = fs.find_file(".", Self::filter_mkdir)?;
+            fs.update_file_in_dir(
+                &root,
+                |entry| entry.name() == HISTORY && Self::filter_find_file(entry),
+                |entry| entry.set_flags(entry.flags() & !OWNERSHIP | Flags::Hidden as u32),
+            )?;
+
+            Ok(())
+        })
+    }
+}
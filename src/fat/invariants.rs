@@ -0,0 +1,187 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+};
+
+use super::{FATError, FAT};
+
+/// A structural rule of the image that does not hold.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// `cluster` belongs to the chains of two entries that are not reflinked
+    /// copies of each other.
+    SharedCluster {
+        cluster: u32,
+        first: String,
+        second: String,
+    },
+    /// The chain of `path` is not as long as its size needs.
+    ChainLength {
+        path: String,
+        expected: u32,
+        actual: u32,
+    },
+    /// The chain of `path` does not end with the end-of-chain mark after
+    /// `cluster`, but with a free or bad cluster or a loop.
+    UnterminatedChain { path: String, cluster: u32 },
+    /// `cluster` is marked used, but nothing leads to it.
+    LeakedCluster { cluster: u32 },
+    /// The `.` or `..` entry of the directory at `path` is missing or points
+    /// somewhere other than `expected`.
+    DotEntry {
+        path: String,
+        name: &'static str,
+        found: Option<u32>,
+        expected: u32,
+    },
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SharedCluster {
+                cluster,
+                first,
+                second,
+            } => write!(f, "cluster {cluster} is shared by {first} and {second}"),
+            Self::ChainLength {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{path}: chain has {actual} cluster(s), size needs {expected}"
+            ),
+            Self::UnterminatedChain { path, cluster } => {
+                write!(f, "{path}: chain is not terminated after cluster {cluster}")
+            }
+            Self::LeakedCluster { cluster } => {
+                write!(f, "cluster {cluster} is used but unreachable")
+            }
+            Self::DotEntry {
+                path,
+                name,
+                found: Some(found),
+                expected,
+            } => write!(
+                f,
+                "{path}: `{name}` points to {found} instead of {expected}"
+            ),
+            Self::DotEntry { path, name, .. } => write!(f, "{path}: `{name}` is missing"),
+        }
+    }
+}
+
+impl FAT {
+    /// Checks the rules every operation must leave the image in, without
+    /// changing anything: no two entries share clusters unless reflinked,
+    /// chains are as long as sizes need and properly terminated, clusters
+    /// nothing leads to are free, and `.` and `..` point where they should.
+    ///
+    /// Cheaper to act on than [`FAT::check`], e.g. after every step of a
+    /// property test.
+    pub fn verify_invariants(&mut self) -> Result<Vec<Violation>, FATError> {
+        let mut violations = self.verify_dot_entries()?;
+        let mut owners: HashMap<u32, String> = HashMap::new();
+
+        for located in self.located_entries()? {
+            let entry = &located.entry;
+            let dir = Self::filter_mkdir(entry);
+            let chain = &located.chain;
+
+            if let Some(&last) = chain.last() {
+                if self.next_cluster(last)? != Self::mark_read_done() {
+                    violations.push(Violation::UnterminatedChain {
+                        path: located.path.clone(),
+                        cluster: last,
+                    });
+                }
+            }
+
+            if !dir {
+                let expected = self.stored_clusters(entry)?;
+                if chain.len() != expected as usize {
+                    violations.push(Violation::ChainLength {
+                        path: located.path.clone(),
+                        expected,
+                        actual: chain.len() as u32,
+                    });
+                }
+            }
+
+            // a reflinked copy shares the whole chain on purpose
+            let reflinked = !dir
+                && chain
+                    .first()
+                    .is_some_and(|first| owners.contains_key(first))
+                && self.refcount(chain[0])? > 0;
+            for cluster in chain {
+                match owners.get(cluster) {
+                    Some(owner) if !reflinked => violations.push(Violation::SharedCluster {
+                        cluster: *cluster,
+                        first: owner.clone(),
+                        second: located.path.clone(),
+                    }),
+                    Some(_) => {}
+                    None => {
+                        owners.insert(*cluster, located.path.clone());
+                    }
+                }
+            }
+        }
+
+        let reachable = self.reachable_clusters()?;
+        let fat = self.read_whole_fat()?;
+        for cluster in 1..fat.len() as u32 {
+            if self.is_data_cluster(cluster)
+                && Self::is_used(fat[cluster as usize])
+                && !reachable.contains(&cluster)
+            {
+                violations.push(Violation::LeakedCluster { cluster });
+            }
+        }
+
+        Ok(violations)
+    }
+
+    /// Checks `.` and `..` of every directory reachable from the root, whose
+    /// own `..` points back to itself.
+    fn verify_dot_entries(&mut self) -> Result<Vec<Violation>, FATError> {
+        let mut violations = vec![];
+        let mut visited = HashSet::new();
+        let mut pending = vec![(1, 1, String::from("/"))];
+
+        while let Some((start, parent, path)) = pending.pop() {
+            if !self.is_data_cluster(start) || !visited.insert(start) {
+                continue;
+            }
+
+            let entries = self.read_cluster_entries(start)?;
+            for (name, expected) in [(".", start), ("..", parent)] {
+                let found = entries
+                    .iter()
+                    .find(|entry| Self::filter_find(entry) && entry.name() == name)
+                    .map(|entry| entry.cluster());
+                if found != Some(expected) {
+                    violations.push(Violation::DotEntry {
+                        path: path.clone(),
+                        name,
+                        found,
+                        expected,
+                    });
+                }
+            }
+
+            for cluster in self.chain_of(start)? {
+                for entry in self.read_cluster_entries(cluster)? {
+                    if Self::filter_mkdir(&entry) && entry.name() != "." && entry.name() != ".." {
+                        let child = format!("{}/{}", path.trim_end_matches('/'), entry.name());
+                        pending.push((entry.cluster(), start, child));
+                    }
+                }
+            }
+        }
+
+        Ok(violations)
+    }
+}
@@ -0,0 +1,186 @@
+use std::{collections::BTreeMap, io, mem::size_of};
+
+use super::{
+    disk::Backend,
+    header::{Header, HeaderError},
+    integrity::crc32,
+    FATError, FAT,
+};
+
+/// Unit metadata writes are staged and journaled in.
+pub const BLOCK_SIZE: u64 = 512;
+
+const MAGIC: &[u8; 4] = b"ZJNL";
+
+/// Bytes reserved for the journal on images that have one.
+pub const JOURNAL_SIZE: u64 = 1 << 20;
+
+/// Where the journal lives in the image.
+///
+/// The first block holds the commit record: the magic, the number of blocks
+/// in the transaction and a CRC32 of everything that follows. Next come the
+/// home block numbers, one u64 each, and then the blocks themselves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl Region {
+    /// Most blocks a single transaction can hold.
+    pub fn capacity(&self) -> usize {
+        let blocks = (self.len / BLOCK_SIZE).saturating_sub(1);
+        let per_block = BLOCK_SIZE / size_of::<u64>() as u64;
+        (blocks * per_block / (per_block + 1)) as usize
+    }
+
+    fn descriptor_len(count: usize) -> u64 {
+        (count as u64 * size_of::<u64>() as u64).div_ceil(BLOCK_SIZE) * BLOCK_SIZE
+    }
+}
+
+/// Writes `blocks` to the journal and then to their home locations.
+///
+/// Once the commit record is written the transaction survives a crash: it is
+/// replayed by [`replay`] on the next mount. Each step is synced before the
+/// next one starts, so the record never reaches the disk ahead of the body,
+/// nor is it cleared before the blocks are home.
+pub fn commit(
+    backend: &mut dyn Backend,
+    region: Region,
+    blocks: &BTreeMap<u64, Vec<u8>>,
+) -> io::Result<()> {
+    let mut body = vec![0; Region::descriptor_len(blocks.len()) as usize];
+    for (slot, block) in body.chunks_mut(size_of::<u64>()).zip(blocks.keys()) {
+        slot.clone_from_slice(&block.to_le_bytes());
+    }
+    for data in blocks.values() {
+        body.extend_from_slice(data);
+    }
+
+    let mut record = vec![0; BLOCK_SIZE as usize];
+    record[..4].clone_from_slice(MAGIC);
+    record[4..8].clone_from_slice(&(blocks.len() as u32).to_le_bytes());
+    record[8..12].clone_from_slice(&crc32(&body).to_le_bytes());
+
+    backend.write_at(region.offset + BLOCK_SIZE, &body)?;
+    backend.sync()?;
+    backend.write_at(region.offset, &record)?;
+    backend.sync()?;
+
+    apply(backend, blocks)?;
+    checkpoint(backend, region)
+}
+
+/// Clears the commit record once the blocks it covers are safely home.
+fn checkpoint(backend: &mut dyn Backend, region: Region) -> io::Result<()> {
+    backend.sync()?;
+    backend.write_at(region.offset, &[0; BLOCK_SIZE as usize])?;
+    backend.sync()
+}
+
+/// Writes `blocks` straight to their home locations.
+pub fn apply(backend: &mut dyn Backend, blocks: &BTreeMap<u64, Vec<u8>>) -> io::Result<()> {
+    for (block, data) in blocks {
+        backend.write_at(block * BLOCK_SIZE, data)?;
+    }
+    backend.flush()
+}
+
+/// Reads the committed transaction left in the journal, if there is one.
+///
+/// A torn commit record or body is treated as no transaction at all.
+pub fn read(backend: &mut dyn Backend, region: Region) -> io::Result<BTreeMap<u64, Vec<u8>>> {
+    let mut blocks = BTreeMap::new();
+
+    let mut record = vec![0; BLOCK_SIZE as usize];
+    backend.read_at(region.offset, &mut record)?;
+    if &record[..4] != MAGIC {
+        return Ok(blocks);
+    }
+
+    let count = u32::from_le_bytes(record[4..8].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(record[8..12].try_into().unwrap());
+    if count > region.capacity() {
+        return Ok(blocks);
+    }
+
+    let descriptor_len = Region::descriptor_len(count);
+    let mut body = vec![0; (descriptor_len + count as u64 * BLOCK_SIZE) as usize];
+    backend.read_at(region.offset + BLOCK_SIZE, &mut body)?;
+    if crc32(&body) != checksum {
+        return Ok(blocks);
+    }
+
+    let (descriptor, data) = body.split_at(descriptor_len as usize);
+    for (slot, data) in descriptor
+        .chunks(size_of::<u64>())
+        .zip(data.chunks(BLOCK_SIZE as usize))
+    {
+        blocks.insert(u64::from_le_bytes(slot.try_into().unwrap()), data.to_vec());
+    }
+
+    Ok(blocks)
+}
+
+/// Finishes a transaction interrupted after its commit record was written.
+///
+/// Returns the number of blocks written back.
+pub fn replay(backend: &mut dyn Backend, region: Region) -> io::Result<usize> {
+    let blocks = read(backend, region)?;
+    if blocks.is_empty() {
+        return Ok(0);
+    }
+
+    apply(backend, &blocks)?;
+    checkpoint(backend, region)?;
+    Ok(blocks.len())
+}
+
+impl FAT {
+    /// The journal region of the current geometry, if the image has one big
+    /// enough to hold a block.
+    pub(super) fn journal_region(&self) -> Option<Region> {
+        let header = self.header.as_ref()?;
+        if !header.has_journal() {
+            return None;
+        }
+
+        Some(Region {
+            offset: header.journal_sector() * header.bytes_per_sector() as u64,
+            len: header.journal_sectors() * header.bytes_per_sector() as u64,
+        })
+        .filter(|region| region.capacity() > 0)
+    }
+
+    /// Completes a transaction left in the journal by a crash.
+    ///
+    /// Returns the number of blocks written back.
+    pub fn replay_journal(&mut self) -> Result<usize, FATError> {
+        self.free_clusters = None;
+        match self.journal_region() {
+            Some(region) => self.disk.replay(region).map_err(|_| FATError::CannotWrite),
+            None => Ok(0),
+        }
+    }
+
+    /// Adds the journal to an image formatted without one.
+    pub fn enable_journal(&mut self) -> Result<(), HeaderError> {
+        let old: Header = self.header.clone().expect("Image is not formatted!");
+        if old.has_journal() {
+            return Ok(());
+        }
+
+        let mut new = old.clone();
+        new.enable_journal();
+
+        match self.evacuate(new.data_cluster_count()) {
+            Ok(()) => {}
+            Err(FATError::NotEnoughSpace) => return Err(HeaderError::BadCapacity),
+            Err(_) => return Err(HeaderError::CannotWrite),
+        }
+
+        self.relayout(&old, &new)
+            .map_err(|_| HeaderError::CannotWrite)
+    }
+}
@@ -0,0 +1,169 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use crate::json::{self, Value};
+
+use super::{dirent::Flags, FATError, FAT};
+
+/// What `meta dump` records of an entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Meta {
+    pub path: String,
+    pub size: u64,
+    pub flags: u32,
+    /// The clusters of its chain, in order.
+    pub chain: Vec<u32>,
+}
+
+impl Meta {
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"path\":{},\"size\":{},\"flags\":{},\"chain\":[{}]}}",
+            json::string(&self.path),
+            self.size,
+            self.flags,
+            self.chain
+                .iter()
+                .map(|cluster| cluster.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        )
+    }
+
+    pub fn from_json(value: &Value) -> Option<Self> {
+        let Some(Value::Array(chain)) = value.get("chain") else {
+            return None;
+        };
+        Some(Self {
+            path: value.get("path")?.as_str()?.to_string(),
+            size: value.get("size")?.as_u64()?,
+            flags: value.get("flags")?.as_u64()?.try_into().ok()?,
+            chain: chain
+                .iter()
+                .map(|cluster| cluster.as_u64()?.try_into().ok())
+                .collect::<Option<_>>()?,
+        })
+    }
+}
+
+/// Writes `entries` as a JSON array with one entry per line, so that dumps
+/// of the same tree are the same text and compare well with line tools.
+pub fn to_json(entries: &[Meta]) -> String {
+    let lines: Vec<_> = entries.iter().map(Meta::to_json).collect();
+    format!("[\n{}\n]\n", lines.join(",\n"))
+}
+
+/// Reads back a dump [`to_json`] wrote, `None` when the text is not one.
+pub fn from_json(text: &str) -> Option<Vec<Meta>> {
+    match json::parse(text)? {
+        Value::Array(entries) => entries.iter().map(Meta::from_json).collect(),
+        _ => None,
+    }
+}
+
+/// A path whose metadata differs between two dumps.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Added(String),
+    Removed(String),
+    /// What differs, e.g. `size 5 → 9`.
+    Changed {
+        path: String,
+        fields: Vec<String>,
+    },
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Added(path) => write!(f, "+ {path}"),
+            Self::Removed(path) => write!(f, "- {path}"),
+            Self::Changed { path, fields } => write!(f, "~ {path}: {}", fields.join(", ")),
+        }
+    }
+}
+
+fn chain_text(chain: &[u32]) -> String {
+    if chain.is_empty() {
+        return "none".to_string();
+    }
+    chain
+        .iter()
+        .map(|cluster| cluster.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Compares two dumps: paths only in `new` are added, paths only in `old`
+/// removed, and the rest changed when their size, flags or chain differ.
+pub fn compare_meta(old: &[Meta], new: &[Meta]) -> Vec<Change> {
+    let old: BTreeMap<_, _> = old.iter().map(|meta| (&meta.path, meta)).collect();
+    let new: BTreeMap<_, _> = new.iter().map(|meta| (&meta.path, meta)).collect();
+
+    let mut changes = vec![];
+    for (path, before) in old.iter() {
+        let Some(after) = new.get(path) else {
+            changes.push(Change::Removed(path.to_string()));
+            continue;
+        };
+
+        let mut fields = vec![];
+        if before.size != after.size {
+            fields.push(format!("size {} → {}", before.size, after.size));
+        }
+        if before.flags != after.flags {
+            fields.push(format!("flags {:#x} → {:#x}", before.flags, after.flags));
+        }
+        if before.chain != after.chain {
+            fields.push(format!(
+                "chain {} → {}",
+                chain_text(&before.chain),
+                chain_text(&after.chain)
+            ));
+        }
+        if !fields.is_empty() {
+            changes.push(Change::Changed {
+                path: path.to_string(),
+                fields,
+            });
+        }
+    }
+    changes.extend(
+        new.keys()
+            .filter(|path| !old.contains_key(*path))
+            .map(|path| Change::Added(path.to_string())),
+    );
+
+    changes.sort_by(|a, b| path_of(a).cmp(path_of(b)));
+    changes
+}
+
+fn path_of(change: &Change) -> &str {
+    match change {
+        Change::Added(path) | Change::Removed(path) | Change::Changed { path, .. } => path,
+    }
+}
+
+impl FAT {
+    /// The metadata of the root directory and of every entry below it,
+    /// hidden ones included, sorted by path.
+    pub fn meta(&mut self) -> Result<Vec<Meta>, FATError> {
+        let mut entries = vec![Meta {
+            path: "/".to_string(),
+            size: 0,
+            flags: Flags::Occupied as u32 | Flags::Directory as u32,
+            chain: self.chain_of(1)?,
+        }];
+
+        for (path, entry) in self.walk()? {
+            entries.push(Meta {
+                path,
+                size: entry.size(),
+                flags: entry.flags(),
+                chain: self.chain_of(entry.cluster())?,
+            });
+        }
+
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
+    }
+}
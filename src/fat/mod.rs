@@ -1,31 +1,142 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
+    fmt::Display,
     fs::File,
     io::{self, Read, Seek, SeekFrom, Write},
     mem::size_of,
+    path::PathBuf,
 };
 
 use crate::{fat::dirent::Flags, units::Unit};
 
 use self::{
+    alloc::AllocStrategy,
+    crypto::Key,
     dirent::Entry,
+    disk::{Backend, Disk, Latency, LatencyConfig},
     fatmanager::FATManager,
-    header::{Header, HeaderError},
+    header::{FormatOptions, Header, HeaderError, HEADER_SIZE, MAX_MOUNT_COUNT},
+    integrity::{crc32, Mismatch},
+    perms::{Access, OWNERSHIP},
+    stat::IoStats,
+    transaction::Savepoint,
 };
 
+pub mod alloc;
+pub mod archive;
+pub mod badblocks;
+pub mod batch;
+pub mod bench;
+pub mod check;
+pub mod compact;
+pub mod compress;
+pub mod convert;
+pub mod crosscopy;
+pub mod crypto;
+pub mod dedup;
+pub mod defrag;
+pub mod diff;
 pub mod dirent;
+pub mod dirindex;
+pub mod disk;
+pub mod dumpdir;
+pub mod dumpfat;
 mod fatmanager;
+pub mod frag;
 pub mod header;
-
+pub mod history;
+pub mod inflate;
+pub mod integrity;
+pub mod invariants;
+pub mod journal;
+pub mod meta;
+pub mod msdos;
+pub mod perms;
+pub mod random;
+pub mod raw;
+pub mod reflink;
+pub mod script;
+pub mod scrub;
+pub mod snapshot;
+pub mod sparse;
+pub mod stat;
+pub mod tar;
+mod transaction;
+pub mod trash;
+pub mod undelete;
+pub mod uuid;
+pub mod versions;
+pub mod xattr;
+pub mod zip;
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct FAT {
     header: Option<Header>,
-    file: File,
+    disk: Disk,
+    strategy: AllocStrategy,
+    /// Where the next-fit allocator resumes its search.
+    next_fit: u32,
+    /// Zero clusters as they are freed.
+    discard: bool,
+    /// Leave out what would differ between two builds of the same tree, see
+    /// [`FAT::set_deterministic`].
+    deterministic: bool,
+    /// Old versions kept per file when it is overwritten or removed.
+    max_versions: u32,
+    /// Directory snapshots of this image are stored in.
+    snapshots: Option<PathBuf>,
+    /// Clusters still used by a snapshot, which must not be reused.
+    held: HashSet<u32>,
+    /// Who entries are checked for, see [`FAT::set_user`].
+    user: Option<u16>,
+    /// Data key of an encrypted image, once unlocked.
+    key: Option<Key>,
+    /// Check reads against the checksum table.
+    verify: bool,
+    /// One per open transaction, innermost last.
+    savepoints: Vec<Savepoint>,
+    /// Clusters freed by the open transactions, which must not be reused
+    /// before they commit.
+    freed: HashSet<u32>,
+    /// Data clusters whose FAT entry is zero, while known; kept up to date
+    /// as FAT sectors are written and recorded in the header by `sync`.
+    free_clusters: Option<u32>,
+    /// What `stats` reports, apart from the block counts kept by `disk`.
+    io: IoStats,
+    /// A real FAT12/16/32 volume found where no native header is, served
+    /// read-only.
+    foreign: Option<msdos::Volume>,
+}
+
+/// Path the image's own operations know the root directory by; other paths
+/// have no leading slash.
+pub const ROOT: &str = ".";
+
+/// The path of `name` in the directory `dir`.
+pub fn join(dir: &str, name: &str) -> String {
+    if dir == ROOT {
+        name.to_string()
+    } else {
+        format!("{dir}/{name}")
+    }
 }
 
-static EMPTY_CLUSTER: [u8; 8192] = [0; 8192];
 static FAT_READ_DONE: u32 = 0xFFFFFFFF;
 static FAT_BAD_CLUSTER: u32 = 0xFFFFFFFE;
 
+/// Damage `bug` can inflict on a file, for exercising `check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corruption {
+    /// Link the last cluster back to the first.
+    Cycle,
+    /// Mark the middle cluster of the chain bad.
+    Bad,
+    /// Point the entry at an empty chain, leaving its clusters allocated.
+    Orphan,
+    /// Overwrite the size field.
+    Size(u64),
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FATError {
     FilenameTooLong,
@@ -35,111 +146,304 @@ pub enum FATError {
     NotEnoughSpace,
     FileExists,
     DirNotEmpty,
+    /// The snapshot belongs to another image or to a different layout.
+    SnapshotMismatch,
+    /// The image is encrypted and has not been unlocked.
+    Locked,
+    BadPassphrase,
+    /// A cluster no longer matches its checksum.
+    ChecksumMismatch,
+    /// The file is over 4 GiB and the image predates 64-bit file sizes.
+    FileTooLarge,
+    /// The name is empty, only dots, a reserved device name or holds `/` or
+    /// a control character.
+    InvalidName,
+    /// The entry is `.`, `..` or flagged System, so it may not be removed,
+    /// renamed or overwritten.
+    Protected,
+    /// The entry's owner and mode do not let the current user do this.
+    PermissionDenied,
+    /// A file was found where a directory is needed.
+    NotADirectory,
+    /// A directory was found where a file is needed.
+    IsADirectory,
+    /// The operating system has no random source to draw keys from.
+    NoRandomness,
+    /// The image was encrypted by an older version, without authentication.
+    UnsupportedEncryption,
 }
 
+impl Display for FATError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::FilenameTooLong => "file name too long",
+            Self::FileNotFound => "file not found",
+            Self::CannotRead => "cannot read the image",
+            Self::CannotWrite => "cannot write the image",
+            Self::NotEnoughSpace => "no space left on the image",
+            Self::FileExists => "file exists",
+            Self::DirNotEmpty => "directory not empty",
+            Self::SnapshotMismatch => "snapshot does not match the image",
+            Self::Locked => "image is locked",
+            Self::BadPassphrase => "wrong passphrase",
+            Self::ChecksumMismatch => "checksum mismatch",
+            Self::FileTooLarge => "file too large for this image version",
+            Self::InvalidName => "invalid file name",
+            Self::Protected => "entry is protected",
+            Self::PermissionDenied => "permission denied",
+            Self::NotADirectory => "not a directory",
+            Self::IsADirectory => "is a directory",
+            Self::NoRandomness => "no random source to draw keys from",
+            Self::UnsupportedEncryption => {
+                "image is encrypted without authentication, recreate it with this version"
+            }
+        })
+    }
+}
+
+impl std::error::Error for FATError {}
+
 impl FAT {
-    pub fn new(filename: String) -> io::Result<Self> {
-        let mut file = File::options()
+    /// Opens (or creates) the image, optionally behind a simulated-latency backend.
+    pub fn new(filename: String, latency: Option<LatencyConfig>) -> io::Result<Self> {
+        let file = File::options()
             .read(true)
             .write(true)
             .create(true)
-            .open(filename)?;
-        let filesize = file.metadata().unwrap().len() as usize;
+            .truncate(false)
+            .open(&filename)?;
+        let disk = match latency {
+            Some(config) => Disk::with_backend(Box::new(Latency::new(file, config))),
+            None => Disk::new(file),
+        };
+
+        Self::open(disk, Some(PathBuf::from(format!("{filename}.snapshots"))))
+    }
 
-        let header = if filesize < 5 * size_of::<u32>() {
+    /// Opens (or creates) an image kept by `backend`, such as
+    /// [`disk::Memory`]. Snapshots are not available, as they are kept next
+    /// to an image file.
+    pub fn with_backend(backend: Box<dyn Backend>) -> io::Result<Self> {
+        Self::open(Disk::with_backend(backend), None)
+    }
+
+    fn open(mut disk: Disk, snapshots: Option<PathBuf>) -> io::Result<Self> {
+        let filesize = disk.len()? as usize;
+
+        let header = if filesize < HEADER_SIZE {
             None
         } else {
-            let mut buffer = [0; 5 * size_of::<u32>()];
-            file.read_exact(&mut buffer)?;
+            let mut buffer = [0; HEADER_SIZE];
+            disk.read_at(0, &mut buffer)?;
             Header::from_raw_bytes(&buffer).ok()
         };
 
-        Ok(Self { header, file })
+        // a header no format could have written is not one of ours
+        let foreign = match &header {
+            Some(header) if header.check_geometry().is_ok() => None,
+            _ if filesize >= 512 => msdos::Volume::detect(&mut disk),
+            _ => None,
+        };
+        let header = header.filter(|_| foreign.is_none());
+
+        let mut fat = Self {
+            header,
+            disk,
+            strategy: AllocStrategy::default(),
+            next_fit: 1,
+            discard: false,
+            deterministic: false,
+            max_versions: 0,
+            snapshots,
+            held: HashSet::new(),
+            user: None,
+            key: None,
+            verify: false,
+            savepoints: vec![],
+            freed: HashSet::new(),
+            free_clusters: None,
+            io: IoStats::default(),
+            foreign,
+        };
+
+        if let Some(header) = fat.header.as_mut() {
+            fat.free_clusters = header.free_clusters();
+            header.record_mount();
+            fat.store_header().map_err(|_| io::ErrorKind::Other)?;
+        }
+        fat.reload_held();
+
+        Ok(fat)
+    }
+
+    /// Opens an existing image without modifying it, not even its mount count.
+    pub fn open_read_only(filename: &str) -> io::Result<Self> {
+        let mut disk = Disk::new(File::open(filename)?);
+
+        let mut buffer = [0; HEADER_SIZE];
+        disk.read_at(0, &mut buffer)?;
+        let header = Header::from_raw_bytes(&buffer)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "image is not formatted"))?;
+
+        Self::without_mount(header, disk)
+    }
+
+    /// Opens image bytes that may be anything, e.g. fuzzer input, without
+    /// modifying them.
+    ///
+    /// The header must be intact and describe a geometry that fits the image;
+    /// anything else fails here rather than panicking in a later operation.
+    pub fn open_untrusted(backend: Box<dyn Backend>) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "image header is not valid");
+        let mut disk = Disk::with_backend(backend);
+
+        let mut buffer = [0; HEADER_SIZE];
+        disk.read_at(0, &mut buffer)?;
+        let header = Header::from_raw_bytes(&buffer).map_err(|_| invalid())?;
+        header.check_geometry().map_err(|_| invalid())?;
+        if disk.len()? < header.sector_count() * header.bytes_per_sector() as u64 {
+            return Err(invalid());
+        }
+
+        Self::without_mount(header, disk)
+    }
+
+    fn without_mount(header: Header, disk: Disk) -> io::Result<Self> {
+        let free_clusters = header.free_clusters();
+        let mut fat = Self {
+            header: Some(header),
+            disk,
+            strategy: AllocStrategy::default(),
+            next_fit: 1,
+            discard: false,
+            deterministic: false,
+            max_versions: 0,
+            snapshots: None,
+            held: HashSet::new(),
+            user: None,
+            key: None,
+            verify: false,
+            savepoints: vec![],
+            freed: HashSet::new(),
+            free_clusters,
+            io: IoStats::default(),
+            foreign: None,
+        };
+
+        // a transaction left in the journal is shown without writing it back
+        if let Some(region) = fat.journal_region() {
+            fat.disk.load_journal(region)?;
+        }
+
+        Ok(fat)
     }
 
-    fn dealloc_clusters(&mut self, mut cluster: u32) -> Option<()> {
-        let mut manager = FATManager::new();
+    fn dealloc_clusters(&mut self, mut cluster: u32) -> Result<(), FATError> {
+        if self.is_data_cluster(cluster) && self.release_shared(cluster)? {
+            return Ok(());
+        }
+
+        tracing::debug!(first = cluster, "freeing chain");
+        let mut manager = FATManager::new(self.clusters_per_fat_sector());
 
         while cluster != Self::mark_read_done() {
-            if !manager.contains_cluster(cluster) {
-                manager.add_cluster(cluster, self.read_fat(cluster)?);
-            }
+            self.cache_fat(&mut manager, cluster)?;
 
             manager.set_cluster_value(cluster, 0);
+            self.discard_cluster(cluster)?;
+            self.io.clusters_freed += 1;
 
             cluster = self.next_cluster(cluster)?;
             if cluster == Self::mark_bad_cluster() {
-                return None;
+                return Err(FATError::CannotRead);
             }
         }
 
-        for (cluster, value) in manager.flush() {
-            self.write_fat(cluster * (512 / size_of::<u32>() as u32), value)?;
+        for (cluster, old, value) in manager.flush() {
+            self.write_fat(cluster * self.clusters_per_fat_sector(), &old, value)?;
         }
 
-        Some(())
+        Ok(())
+    }
+
+    pub fn discard(&self) -> bool {
+        self.discard
     }
 
-    fn allocate_clusters(&mut self, mut count: u32) -> Result<u32, FATError> {
-        let mut begin_cluster = 0;
-        let header = self.header.as_ref().expect("Filesystem is not formatted!");
+    /// Zero clusters as they are freed from now on.
+    pub fn set_discard(&mut self, discard: bool) {
+        self.discard = discard;
+    }
 
-        let cluster_count = header.sector_count() / header.sectors_per_cluster();
+    pub fn deterministic(&self) -> bool {
+        self.deterministic
+    }
 
-        let mut manager = FATManager::new();
+    /// Makes the same commands on the same inputs yield byte-identical
+    /// images: the UUID and timestamps of the header and of the history are
+    /// zeroed, clusters are allocated first-fit and zeroed as they are freed.
+    ///
+    /// Encrypted images still differ, as their key and salt are random.
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        self.deterministic = deterministic;
+        if deterministic {
+            self.strategy = AllocStrategy::First;
+            self.discard = true;
+        }
+    }
 
-        let mut prev_cluster = 0;
-        let mut current_cluster = 0;
+    /// Zeroes every free cluster that still holds data.
+    ///
+    /// Returns the number of clusters zeroed.
+    pub fn trim(&mut self) -> Result<u32, FATError> {
+        let fat = self.read_whole_fat()?;
+        let empty = self.empty_cluster();
+        let mut trimmed = 0;
+
+        for cluster in 1..fat.len() as u32 {
+            if !self.is_allocatable(cluster, fat[cluster as usize])
+                || !self.is_data_cluster(cluster)
+            {
+                continue;
+            }
 
-        loop {
-            if !manager.contains_cluster(current_cluster) {
-                manager.add_cluster(
-                    current_cluster,
-                    self.read_fat(current_cluster).ok_or(FATError::CannotRead)?,
-                );
+            if self.read_raw_cluster(cluster)? != empty {
+                self.zero_cluster(cluster)?;
+                trimmed += 1;
             }
+        }
 
-            let current_cluster_value = manager.get_cluster_value(current_cluster).unwrap();
+        Ok(trimmed)
+    }
 
-            if current_cluster_value == 0 {
-                if begin_cluster == 0 {
-                    begin_cluster = current_cluster;
-                }
-                if prev_cluster == 0 {
-                    prev_cluster = current_cluster;
-                } else {
-                    if !manager.contains_cluster(prev_cluster) {
-                        manager.add_cluster(
-                            prev_cluster,
-                            self.read_fat(prev_cluster).ok_or(FATError::CannotRead)?,
-                        );
-                    }
+    /// Allocates a chain of `count` clusters, at least one, and returns its
+    /// first cluster.
+    fn allocate_clusters(&mut self, count: u32) -> Result<u32, FATError> {
+        let clusters = self.find_free_clusters(count.max(1))?;
+        self.link_chain(&clusters)?;
 
-                    manager.set_cluster_value(prev_cluster, current_cluster);
-                    prev_cluster = current_cluster;
-                    count -= 1;
-                }
+        Ok(clusters[0])
+    }
 
-                if count == 1 {
-                    manager.set_cluster_value(current_cluster, Self::mark_read_done());
-                    for (cluster, value) in manager.flush() {
-                        self.write_fat(cluster * (512 / size_of::<u32>() as u32), value)
-                            .ok_or(FATError::CannotWrite)?;
-                    }
+    /// Links `clusters` into a single chain, in order.
+    fn link_chain(&mut self, clusters: &[u32]) -> Result<(), FATError> {
+        let mut manager = FATManager::new(self.clusters_per_fat_sector());
 
-                    return Ok(begin_cluster);
-                }
-            }
+        for (index, cluster) in clusters.iter().enumerate() {
+            self.cache_fat(&mut manager, *cluster)?;
 
-            current_cluster += 1;
-            if current_cluster == cluster_count {
-                return Err(FATError::NotEnoughSpace);
-            }
+            let next = clusters
+                .get(index + 1)
+                .copied()
+                .unwrap_or(Self::mark_read_done());
+            manager.set_cluster_value(*cluster, next);
         }
-    }
 
-    fn empty_cluster() -> &'static [u8; 8192] {
-        &EMPTY_CLUSTER
+        for (sector, old, value) in manager.flush() {
+            self.write_fat(sector * self.clusters_per_fat_sector(), &old, value)?;
+        }
+
+        Ok(())
     }
 
     fn mark_read_done() -> u32 {
@@ -160,103 +464,371 @@ impl FAT {
     }
 
     fn first_data_sector(&self) -> u64 {
-        let header = self.header.as_ref().expect("Image is not formatted!");
-        1 + (header.fat_count() * (header.sector_count() / header.sectors_per_cluster())
-            / (header.bytes_per_sector() / size_of::<u32>() as u32)) as u64
+        self.header
+            .as_ref()
+            .expect("Image is not formatted!")
+            .first_data_sector()
     }
 
     fn cluster_to_sector(&self, cluster: u32) -> u64 {
         let header = self.header.as_ref().expect("Image is not formatted!");
-        self.first_data_sector() + ((cluster - 1) * header.sectors_per_cluster()) as u64
+        self.first_data_sector() + (cluster - 1) as u64 * header.sectors_per_cluster() as u64
     }
 
-    fn read_sector(&mut self, sector: u64) -> Option<[u8; 512]> {
-        let mut buf = [0; 512];
-        self.file
-            .seek(SeekFrom::Start(self.sector_to_byte(sector)))
-            .ok()?;
-        self.file.read(&mut buf).ok()?;
-        Some(buf)
+    fn bytes_per_sector(&self) -> usize {
+        self.header
+            .as_ref()
+            .expect("Image is not formatted!")
+            .bytes_per_sector() as usize
     }
 
-    fn write_sector(&mut self, sector: u64, bytes: [u8; 512]) -> Option<()> {
-        self.file
-            .seek(SeekFrom::Start(self.sector_to_byte(sector)))
-            .ok()?;
-        self.file.write(&bytes).ok()?;
-        Some(())
+    fn clusters_per_fat_sector(&self) -> u32 {
+        (self.bytes_per_sector() / size_of::<u32>()) as u32
     }
 
-    fn read_cluster(&mut self, cluster: u32) -> Option<[u8; 4096]> {
-        let mut buf = [0; 4096];
-        self.file
-            .seek(SeekFrom::Start(
-                self.sector_to_byte(self.cluster_to_sector(cluster)),
-            ))
-            .ok()?;
-        self.file.read(&mut buf).ok()?;
-        Some(buf)
+    fn read_sector(&mut self, sector: u64) -> Result<Vec<u8>, FATError> {
+        let mut buf = vec![0; self.bytes_per_sector()];
+        self.disk
+            .read_at(self.sector_to_byte(sector), &mut buf)
+            .map_err(|_| FATError::CannotRead)?;
+        Ok(buf)
+    }
+
+    fn write_sector(&mut self, sector: u64, bytes: &[u8]) -> Result<(), FATError> {
+        self.mark_dirty()?;
+        self.disk
+            .write_journaled(self.sector_to_byte(sector), bytes)
+            .map_err(|_| FATError::CannotWrite)
+    }
+
+    fn cluster_size(&self) -> usize {
+        if let Some(volume) = &self.foreign {
+            return volume.cluster_size();
+        }
+        self.header
+            .as_ref()
+            .expect("Image is not formatted!")
+            .cluster_size() as usize
     }
 
-    fn write_cluster(&mut self, cluster: u32, bytes: [u8; 4096]) -> Option<()> {
-        self.file
-            .seek(SeekFrom::Start(
+    fn empty_cluster(&self) -> Vec<u8> {
+        vec![0; self.cluster_size()]
+    }
+
+    /// Reads a cluster as it is stored on disk, still encrypted on
+    /// encrypted images.
+    fn read_raw_cluster(&mut self, cluster: u32) -> Result<Vec<u8>, FATError> {
+        // a damaged entry or FAT can point anywhere
+        if !self.is_data_cluster(cluster) {
+            return Err(FATError::CannotRead);
+        }
+
+        let mut buf = self.empty_cluster();
+        self.disk
+            .read_at(
                 self.sector_to_byte(self.cluster_to_sector(cluster)),
-            ))
-            .ok()?;
-        self.file.write(&bytes).ok()?;
-        Some(())
+                &mut buf,
+            )
+            .map_err(|_| FATError::CannotRead)?;
+        Ok(buf)
+    }
+
+    fn write_raw_cluster(&mut self, cluster: u32, bytes: &[u8]) -> Result<(), FATError> {
+        self.mark_dirty()?;
+        self.disk
+            .write_at(self.sector_to_byte(self.cluster_to_sector(cluster)), bytes)
+            .map_err(|_| FATError::CannotWrite)?;
+        self.store_checksum(self.cluster_slot(cluster), bytes)
+    }
+
+    fn read_cluster(&mut self, cluster: u32) -> Result<Vec<u8>, FATError> {
+        let mut buf = self.read_raw_cluster(cluster)?;
+        self.verify_cluster(cluster, &buf)?;
+        if self.is_encrypted() {
+            let seal = self.seal(cluster)?;
+            self.decrypt_cluster(cluster, &seal, &mut buf)?;
+        }
+        Ok(buf)
+    }
+
+    fn write_cluster(&mut self, cluster: u32, bytes: &[u8]) -> Result<(), FATError> {
+        let mut buf = bytes.to_vec();
+        self.encrypt_cluster(cluster, &mut buf)?;
+        self.write_raw_cluster(cluster, &buf)
+    }
+
+    /// Overwrites a cluster with plain zeros, which read back as zeros even on
+    /// encrypted images.
+    fn zero_cluster(&mut self, cluster: u32) -> Result<(), FATError> {
+        if self.is_encrypted() {
+            self.set_seal(cluster, &[0; crypto::SEAL_LENGTH])?;
+        }
+        self.write_raw_cluster(cluster, &self.empty_cluster())
     }
 
-    fn read_cluster_entries(&mut self, cluster: u32) -> Option<Vec<Entry>> {
+    fn read_cluster_entries(&mut self, cluster: u32) -> Result<Vec<Entry>, FATError> {
         let bytes = self.read_cluster(cluster)?;
-        let mut v = vec![];
+        bytes
+            .chunks_exact(32)
+            .map(|bytes| Entry::from_bytes(bytes).ok_or(FATError::CannotRead))
+            .collect()
+    }
+
+    fn fat_count(&self) -> u32 {
+        self.header
+            .as_ref()
+            .expect("Image is not formatted!")
+            .fat_count()
+    }
+
+    /// Sector holding the FAT entry of `cluster` in the given copy of the FAT.
+    fn fat_sector(&self, copy: u32, cluster: u32) -> u64 {
+        let header = self.header.as_ref().expect("Image is not formatted!");
+        1 + copy as u64 * header.fat_sectors() as u64
+            + (cluster / self.clusters_per_fat_sector()) as u64
+    }
+
+    /// Reads the FAT sector containing `cluster` from the first copy that can
+    /// be read and, when verifying, matches its checksum.
+    fn read_fat(&mut self, cluster: u32) -> Result<Vec<u32>, FATError> {
+        let mut result = None;
+
+        for copy in 0..self.fat_count() {
+            self.io.fat_reads += 1;
+            let Ok(bytes) = self.read_sector(self.fat_sector(copy, cluster)) else {
+                continue;
+            };
+
+            if self.fat_sector_intact(cluster, &bytes)? {
+                result = Some(bytes);
+                break;
+            }
+            result.get_or_insert(bytes);
+        }
+
+        Ok(result
+            .ok_or(FATError::CannotRead)?
+            .chunks(size_of::<u32>())
+            .map(|data| u32::from_le_bytes(data.try_into().unwrap()))
+            .collect())
+    }
+
+    /// Makes sure `manager` holds the FAT sector containing `cluster`, reading
+    /// it only when it does not.
+    fn cache_fat(&mut self, manager: &mut FATManager, cluster: u32) -> Result<(), FATError> {
+        if manager.contains_cluster(cluster) {
+            self.io.cache_hits += 1;
+        } else {
+            self.io.cache_misses += 1;
+            manager.add_cluster(cluster, self.read_fat(cluster)?);
+        }
+        Ok(())
+    }
+
+    /// Writes the FAT sector containing `cluster` to every copy of the FAT,
+    /// replacing `old`, what the sector held before.
+    fn write_fat(&mut self, cluster: u32, old: &[u32], fat: Vec<u32>) -> Result<(), FATError> {
+        if let Some(free) = self.free_clusters {
+            // a count that no longer adds up is taken afresh
+            self.free_clusters = free
+                .checked_add(self.free_in_fat_sector(cluster, &fat))
+                .and_then(|free| free.checked_sub(self.free_in_fat_sector(cluster, old)));
+        }
+
+        let bytes = fat
+            .iter()
+            .flat_map(|data| data.to_le_bytes())
+            .collect::<Vec<_>>();
+
+        for copy in 0..self.fat_count() {
+            self.io.fat_writes += 1;
+            self.write_sector(self.fat_sector(copy, cluster), &bytes)?;
+        }
+
+        self.store_checksum(self.fat_slot(cluster), &bytes)
+    }
+
+    /// Checksum table slot of the FAT sector containing `cluster`.
+    fn fat_slot(&self, cluster: u32) -> u64 {
+        (cluster / self.clusters_per_fat_sector()) as u64
+    }
+
+    /// Checksum table slot of a data cluster.
+    fn cluster_slot(&self, cluster: u32) -> u64 {
+        let header = self.header.as_ref().expect("Image is not formatted!");
+        header.fat_sectors() as u64 + cluster as u64
+    }
+
+    /// Records the CRC32 of `data` in the checksum table, if the image has one.
+    fn store_checksum(&mut self, slot: u64, data: &[u8]) -> Result<(), FATError> {
+        let header = self.header.as_ref().expect("Image is not formatted!");
+        if !header.has_checksums() {
+            return Ok(());
+        }
+
+        let offset = self.sector_to_byte(header.checksum_sector()) + slot * size_of::<u32>() as u64;
+        self.disk
+            .write_journaled(offset, &crc32(data).to_le_bytes())
+            .map_err(|_| FATError::CannotWrite)
+    }
 
-        for i in (0..4096).step_by(32) {
-            v.push(Entry::from_bytes(&bytes[i..i + 32]).unwrap());
+    fn read_checksums(&mut self) -> Result<Vec<u32>, FATError> {
+        let header = self.header.as_ref().expect("Image is not formatted!");
+        let mut table = vec![0; self.sector_to_byte(header.checksum_sectors()) as usize];
+        self.disk
+            .read_at(self.sector_to_byte(header.checksum_sector()), &mut table)
+            .map_err(|_| FATError::CannotRead)?;
+
+        Ok(table
+            .chunks(size_of::<u32>())
+            .map(|data| u32::from_le_bytes(data.try_into().unwrap()))
+            .collect())
+    }
+
+    /// Whether `cluster` holds data covered by the checksum table.
+    fn is_used(value: u32) -> bool {
+        value != 0 && value != Self::mark_bad_cluster()
+    }
+
+    /// Recomputes the whole checksum table from the primary FAT and the used
+    /// clusters.
+    pub fn rebuild_checksums(&mut self) -> Result<(), FATError> {
+        let header = self.header.clone().expect("Image is not formatted!");
+        if !header.has_checksums() {
+            return Ok(());
+        }
+
+        let mut table = self.read_checksums()?;
+        let fat = self.read_whole_fat()?;
+        let per_sector = self.clusters_per_fat_sector();
+
+        for sector in 0..header.fat_sectors() {
+            let bytes = self.read_sector(self.fat_sector(0, sector * per_sector))?;
+            table[sector as usize] = crc32(&bytes);
+        }
+
+        for cluster in 1..=header.data_cluster_count() {
+            if Self::is_used(fat[cluster as usize]) {
+                let bytes = self.read_raw_cluster(cluster)?;
+                table[self.cluster_slot(cluster) as usize] = crc32(&bytes);
+            }
         }
 
-        Some(v)
+        let bytes = table
+            .iter()
+            .flat_map(|crc| crc.to_le_bytes())
+            .collect::<Vec<_>>();
+        self.disk
+            .write_at(self.sector_to_byte(header.checksum_sector()), &bytes)
+            .map_err(|_| FATError::CannotWrite)
     }
 
-    fn read_fat(&mut self, cluster: u32) -> Option<[u32; 512 / size_of::<u32>()]> {
-        let sector = 1 + cluster / (512 / size_of::<u32>() as u32);
-        let sector = self.read_sector(sector as u64)?;
+    /// Compares the primary FAT and every used cluster against the checksum
+    /// table, returning the regions that no longer match.
+    pub fn verify_image(&mut self) -> Result<Vec<Mismatch>, FATError> {
+        let header = self.header.clone().expect("Image is not formatted!");
+        if !header.has_checksums() {
+            return Ok(vec![]);
+        }
+
+        let table = self.read_checksums()?;
+        let per_sector = self.clusters_per_fat_sector();
+        let mut mismatches = vec![];
+
+        for sector in 0..header.fat_sectors() {
+            let bytes = self.read_sector(self.fat_sector(0, sector * per_sector))?;
+            if crc32(&bytes) != table[sector as usize] {
+                mismatches.push(Mismatch::FatSector(sector));
+            }
+        }
 
-        let mut fat: [u32; 512 / size_of::<u32>()] = [0; 512 / size_of::<u32>()];
+        let fat = self.read_whole_fat()?;
+        for cluster in 1..=header.data_cluster_count() {
+            if !Self::is_used(fat[cluster as usize]) {
+                continue;
+            }
 
-        for (data, res) in std::iter::zip(sector.chunks(4), fat.iter_mut()) {
-            *res = u32::from_le_bytes(data.try_into().unwrap());
+            let bytes = self.read_raw_cluster(cluster)?;
+            if crc32(&bytes) != table[self.cluster_slot(cluster) as usize] {
+                mismatches.push(Mismatch::Cluster(cluster));
+            }
         }
 
-        Some(fat)
+        Ok(mismatches)
     }
 
-    fn write_fat(&mut self, cluster: u32, fat: [u32; 512 / size_of::<u32>()]) -> Option<()> {
-        let sector = 1 + cluster / (512 / size_of::<u32>() as u32);
+    /// Reads the whole primary FAT, one entry per cluster.
+    fn read_whole_fat(&mut self) -> Result<Vec<u32>, FATError> {
+        let header = self.header.as_ref().expect("Image is not formatted!");
+        let cluster_count = header.cluster_count();
+        let fat_sectors = header.fat_sectors();
+
+        let mut fat = Vec::with_capacity(cluster_count as usize);
+        for sector in 0..fat_sectors {
+            fat.extend(self.read_fat(sector * self.clusters_per_fat_sector())?);
+        }
 
-        let mut bytes: [u8; 512] = [0; 512];
+        fat.truncate(cluster_count as usize);
+        Ok(fat)
+    }
 
-        for (data, res) in std::iter::zip(fat.iter(), bytes.chunks_mut(4)) {
-            res.clone_from_slice(&u32::to_le_bytes(*data));
+    /// Writes every sector of `fat` that differs from `old` to all FAT copies.
+    fn write_whole_fat(&mut self, old: &[u32], fat: &[u32]) -> Result<(), FATError> {
+        let per_sector = self.clusters_per_fat_sector() as usize;
+
+        for (index, (old, new)) in
+            std::iter::zip(old.chunks(per_sector), fat.chunks(per_sector)).enumerate()
+        {
+            if old != new {
+                let mut sector = new.to_vec();
+                sector.resize(per_sector, 0);
+                self.write_fat((index * per_sector) as u32, old, sector)?;
+            }
         }
 
-        self.write_sector(sector as u64, bytes)
+        Ok(())
     }
 
-    fn next_cluster(&mut self, cluster: u32) -> Option<u32> {
+    fn next_cluster(&mut self, cluster: u32) -> Result<u32, FATError> {
         let fat = self.read_fat(cluster)?;
-        Some(fat[(cluster as usize % (512 / size_of::<u32>()))])
+        Ok(fat[(cluster % self.clusters_per_fat_sector()) as usize])
+    }
+
+    /// The cluster after `cluster` in a directory's chain; past its last one
+    /// a new, empty cluster is linked in, so a full directory grows instead
+    /// of running out of slots.
+    fn next_dir_cluster(&mut self, cluster: u32) -> Result<u32, FATError> {
+        let next = self.next_cluster(cluster)?;
+        if next != Self::mark_read_done() {
+            return Ok(next);
+        }
+
+        let grown = self.allocate_clusters(1)?;
+        self.write_cluster_entries(grown, &[])?;
+        self.link_chain(&[cluster, grown])?;
+        Ok(grown)
     }
 
-    fn write_cluster_entries(&mut self, cluster: u32, entries: &Vec<Entry>) -> Option<()> {
-        let mut bytes = [0; 4096];
+    fn write_cluster_entries(&mut self, cluster: u32, entries: &[Entry]) -> Result<(), FATError> {
+        let mut bytes = self.empty_cluster();
 
-        for i in (0..4096).step_by(32) {
-            bytes[i..i + 32].clone_from_slice(&entries[i / 32].as_bytes());
+        for (entry, slot) in std::iter::zip(entries, bytes.chunks_mut(32)) {
+            slot.clone_from_slice(&entry.as_bytes());
         }
 
-        self.write_cluster(cluster, bytes)
+        self.write_metadata_cluster(cluster, bytes)
+    }
+
+    /// Writes a cluster of directory entries or of another structure kept
+    /// with them.
+    fn write_metadata_cluster(&mut self, cluster: u32, mut bytes: Vec<u8>) -> Result<(), FATError> {
+        // directories are metadata, so unlike file data they go through the journal
+        self.encrypt_cluster(cluster, &mut bytes)?;
+        self.mark_dirty()?;
+        self.disk
+            .write_journaled(self.sector_to_byte(self.cluster_to_sector(cluster)), &bytes)
+            .map_err(|_| FATError::CannotWrite)?;
+        self.store_checksum(self.cluster_slot(cluster), &bytes)
     }
 
     pub fn update_file_in_dir<F: Fn(&Entry) -> bool, U: Fn(&mut Entry)>(
@@ -268,20 +840,18 @@ impl FAT {
         let mut cluster = dir.cluster();
 
         loop {
-            let mut entries = self
-                .read_cluster_entries(cluster)
-                .ok_or(FATError::CannotRead)?;
+            let mut entries = self.read_cluster_entries(cluster)?;
 
             for entry in entries.iter_mut() {
                 if filter(entry) {
                     let cloned = entry.clone();
                     update(entry);
-                    self.write_cluster_entries(cluster, &entries);
+                    self.write_cluster_entries(cluster, &entries)?;
                     return Ok(cloned);
                 }
             }
 
-            cluster = self.next_cluster(cluster).ok_or(FATError::CannotRead)?;
+            cluster = self.next_cluster(cluster)?;
             if cluster == Self::mark_bad_cluster() {
                 return Err(FATError::CannotRead);
             }
@@ -289,49 +859,83 @@ impl FAT {
     }
 
     pub fn find_file(&mut self, path: &str, filter: fn(&Entry) -> bool) -> Result<Entry, FATError> {
-        let mut it = path.split('/').peekable();
+        self.resolve(path, filter).map_err(|(_, e)| e)
+    }
+
+    /// Like `find_file`, but a failure also says which `/`-separated
+    /// component of `path`, counted from 0, could not be resolved: one that
+    /// does not exist fails with [`FATError::FileNotFound`], a file where a
+    /// directory is needed with [`FATError::NotADirectory`], and a last
+    /// component `filter` rejects for being a directory with
+    /// [`FATError::IsADirectory`].
+    pub fn resolve(
+        &mut self,
+        path: &str,
+        filter: fn(&Entry) -> bool,
+    ) -> Result<Entry, (usize, FATError)> {
+        if self.foreign.is_some() {
+            let last = path.split('/').count() - 1;
+            return self.foreign_find(path, filter).map_err(|e| (last, e));
+        }
+        let mut components = path.split('/').enumerate().peekable();
         let mut current_cluster = 1;
 
-        'outer: while let Some(item) = it.next() {
-            let len = item.len();
-
-            if len > 12 {
-                return Err(FATError::FilenameTooLong);
-            }
-
-            loop {
-                let mut entries = self
-                    .read_cluster_entries(current_cluster)
-                    .ok_or(FATError::CannotRead)?;
-                for entry in entries.iter_mut() {
-                    if entry.name() == item {
-                        if it.peek().is_none() {
-                            if filter(&entry) {
-                                return Ok(entry.clone());
-                            }
-                        } else if entry.flags() & (Flags::Occupied as u32 | Flags::Directory as u32)
-                            == Flags::Occupied as u32 | Flags::Directory as u32
-                        {
-                            current_cluster = entry.cluster();
-                            continue 'outer;
-                        }
+        while let Some((index, item)) = components.next() {
+            if item.len() > dirent::NAME_LENGTH {
+                return Err((index, FATError::FilenameTooLong));
+            }
+            let last = components.peek().is_none();
+
+            // an indexed directory is read only where the name may be
+            let mut candidates = self
+                .indexed_clusters(current_cluster, item)
+                .map_err(|e| (index, e))?
+                .map(Vec::into_iter);
+
+            // an entry of the right name but the wrong kind is reported only
+            // if no entry of the right kind follows it
+            let mut mismatch = None;
+            let mut next = Some(current_cluster);
+            let found = 'search: loop {
+                let cluster = match &mut candidates {
+                    Some(candidates) => candidates.next(),
+                    None => next,
+                };
+                let Some(cluster) = cluster else {
+                    return Err((index, mismatch.unwrap_or(FATError::FileNotFound)));
+                };
+
+                let entries = self.read_cluster_entries(cluster).map_err(|e| (index, e))?;
+                for entry in entries {
+                    if entry.name() != item || !Self::filter_find(&entry) {
+                        continue;
+                    }
+                    if (last && filter(&entry)) || (!last && Self::filter_mkdir(&entry)) {
+                        break 'search entry;
                     }
+                    mismatch = Some(if Self::filter_mkdir(&entry) {
+                        FATError::IsADirectory
+                    } else {
+                        FATError::NotADirectory
+                    });
                 }
 
-                current_cluster = self
-                    .next_cluster(current_cluster)
-                    .ok_or(FATError::CannotRead)?;
-                if current_cluster == Self::mark_read_done() {
-                    return Err(FATError::FileNotFound);
+                if candidates.is_none() {
+                    let cluster = self.next_cluster(cluster).map_err(|e| (index, e))?;
+                    if cluster == Self::mark_bad_cluster() {
+                        return Err((index, FATError::CannotRead));
+                    }
+                    next = (cluster != Self::mark_read_done()).then_some(cluster);
                 }
+            };
 
-                if current_cluster == Self::mark_bad_cluster() {
-                    return Err(FATError::CannotRead);
-                }
+            if last {
+                return Ok(found);
             }
+            current_cluster = found.cluster();
         }
 
-        Err(FATError::FileNotFound)
+        Err((0, FATError::FileNotFound))
     }
 
     pub fn filter_ls(entry: &Entry) -> bool {
@@ -339,37 +943,32 @@ impl FAT {
             == Flags::Occupied as u32 | Flags::Directory as u32
     }
 
-    pub fn listings(&mut self, path: &str) -> Result<(), FATError> {
-        let dir = self.find_file(&path, FAT::filter_ls)?;
+    /// Entries of the directory at `path` that listings show, `.` and `..`
+    /// included.
+    pub fn read_dir(&mut self, path: &str) -> Result<Vec<Entry>, FATError> {
+        if self.foreign.is_some() {
+            return self.foreign_read_dir(path);
+        }
+        let dir = self.find_file(path, FAT::filter_ls)?;
+        let mut visible = vec![];
 
         let mut current_cluster = dir.cluster();
 
         while current_cluster != Self::mark_read_done() {
-            let entries = self
-                .read_cluster_entries(current_cluster)
-                .ok_or(FATError::CannotRead)?;
-            for entry in entries {
-                if entry.flags() & Flags::Occupied as u32 == Flags::Occupied as u32 {
-                    let spec = if entry.flags() & Flags::Directory as u32 == Flags::Directory as u32
-                    {
-                        "DIR"
-                    } else {
-                        "FILE"
-                    };
-                    println!("{spec}: {}", entry.name());
-                }
-            }
+            let entries = self.read_cluster_entries(current_cluster)?;
+            visible.extend(entries.into_iter().filter(|entry| {
+                entry.flags() & (Flags::Occupied as u32 | Flags::Hidden as u32)
+                    == Flags::Occupied as u32
+            }));
 
-            current_cluster = self
-                .next_cluster(current_cluster)
-                .ok_or(FATError::CannotRead)?;
+            current_cluster = self.next_cluster(current_cluster)?;
 
             if current_cluster == Self::mark_bad_cluster() {
                 return Err(FATError::CannotRead);
             }
         }
 
-        Ok(())
+        Ok(visible)
     }
 
     pub fn filter_mkdir(entry: &Entry) -> bool {
@@ -386,42 +985,53 @@ impl FAT {
     }
 
     fn split_path(path: &str) -> (&str, &str) {
-        path.rsplit_once('/').unwrap_or((".", path))
+        path.rsplit_once('/').unwrap_or((ROOT, path))
+    }
+
+    /// Fails with [`FATError::Protected`] when `path` names a dot entry or
+    /// an entry flagged System.
+    fn check_protected(&mut self, path: &str) -> Result<(), FATError> {
+        let (_, filename) = Self::split_path(path);
+        if filename == "." || filename == ".." {
+            return Err(FATError::Protected);
+        }
+
+        match self.find_file(path, Self::filter_find) {
+            Ok(entry) if entry.flags() & Flags::System as u32 != 0 => Err(FATError::Protected),
+            _ => Ok(()),
+        }
     }
 
     pub fn mkdir(&mut self, path: &str) -> Result<(), FATError> {
         let (dir, filename) = Self::split_path(path);
+        dirent::check_name(filename)?;
 
         if self.find_file(path, Self::filter_find).is_ok() {
             return Err(FATError::FileExists);
         }
 
         let entry = self.find_file(dir, Self::filter_mkdir)?;
+        self.check_access(dir, Access::Write)?;
 
         let mut new_entry = Entry::new(
             filename,
             0,
             0,
-            Flags::Occupied as u32 | Flags::Directory as u32,
+            Flags::Occupied as u32 | Flags::Directory as u32 | self.new_ownership(true),
         )
         .ok_or(FATError::FilenameTooLong)?;
 
         let mut current_cluster = entry.cluster();
 
         while current_cluster != Self::mark_read_done() {
-            let mut dirents = self
-                .read_cluster_entries(current_cluster)
-                .ok_or(FATError::CannotRead)?;
+            let mut dirents = self.read_cluster_entries(current_cluster)?;
             for dirent in dirents.iter_mut() {
                 if dirent.flags() & Flags::Occupied as u32 == 0 {
                     let cluster = self.allocate_clusters(1)?;
                     new_entry.set_cluster(cluster);
 
-                    self.write_cluster(cluster, FAT::empty_cluster()[0..4096].try_into().unwrap())
-                        .ok_or(FATError::CannotWrite)?;
-                    let mut entries = self
-                        .read_cluster_entries(cluster)
-                        .ok_or(FATError::CannotRead)?;
+                    self.write_cluster(cluster, &self.empty_cluster())?;
+                    let mut entries = self.read_cluster_entries(cluster)?;
 
                     entries[0] = Entry::new(
                         ".",
@@ -438,19 +1048,15 @@ impl FAT {
                     )
                     .unwrap();
 
-                    self.write_cluster_entries(cluster, &entries)
-                        .ok_or(FATError::CannotWrite)?;
+                    self.write_cluster_entries(cluster, &entries)?;
 
                     *dirent = new_entry;
-                    self.write_cluster_entries(current_cluster, &dirents)
-                        .ok_or(FATError::CannotWrite)?;
-                    return Ok(());
+                    self.write_cluster_entries(current_cluster, &dirents)?;
+                    return self.index_entry(entry.cluster(), current_cluster, filename);
                 }
             }
 
-            current_cluster = self
-                .next_cluster(current_cluster)
-                .ok_or(FATError::CannotRead)?;
+            current_cluster = self.next_dir_cluster(current_cluster)?;
 
             if current_cluster == Self::mark_bad_cluster() {
                 return Err(FATError::CannotRead);
@@ -460,33 +1066,60 @@ impl FAT {
         Err(FATError::NotEnoughSpace)
     }
 
+    /// Creates `path` with the contents of `infile`; a failure part way
+    /// leaves nothing allocated.
     pub fn new_file<T: Read + Seek>(&mut self, path: &str, mut infile: T) -> Result<(), FATError> {
+        self.transaction(|fs| {
+            if fs.compress_by_default() {
+                let mut data = vec![];
+                infile
+                    .read_to_end(&mut data)
+                    .map_err(|_| FATError::CannotRead)?;
+                return fs.store_data(path, data, true);
+            }
+
+            let size = infile
+                .seek(SeekFrom::End(0))
+                .map_err(|_| FATError::CannotRead)?;
+            fs.store_file(path, size, 0, infile)
+        })
+    }
+
+    /// Creates `path` with the contents of `infile`, which hold `size` bytes
+    /// of file data once decoded according to `flags`.
+    fn store_file<T: Read + Seek>(
+        &mut self,
+        path: &str,
+        size: u64,
+        flags: u32,
+        mut infile: T,
+    ) -> Result<(), FATError> {
+        self.check_file_size(size)?;
         let file_size = infile
             .seek(SeekFrom::End(0))
             .map_err(|_| FATError::CannotRead)?;
         infile.rewind().map_err(|_| FATError::CannotRead)?;
 
         let (dir, filename) = Self::split_path(path);
+        dirent::check_name(filename)?;
 
         if self.find_file(path, Self::filter_find).is_ok() {
             return Err(FATError::FileExists);
         }
 
+        self.check_access(dir, Access::Write)?;
         let dir = self.find_file(dir, Self::filter_mkdir)?;
-        let mut new_entry = Entry::new(filename, file_size as u32, 0, Flags::Occupied as u32)
-            .ok_or(FATError::FilenameTooLong)?;
+        let flags = Flags::Occupied as u32 | flags | self.new_ownership(false);
+        let mut new_entry =
+            Entry::new(filename, size, 0, flags).ok_or(FATError::FilenameTooLong)?;
 
         let mut current_cluster = dir.cluster();
 
         while current_cluster != Self::mark_read_done() {
-            let mut dirents = self
-                .read_cluster_entries(current_cluster)
-                .ok_or(FATError::CannotRead)?;
+            let mut dirents = self.read_cluster_entries(current_cluster)?;
             for dirent in dirents.iter_mut() {
                 if dirent.flags() & Flags::Occupied as u32 == 0 {
-                    let cluster_size = (self.header.as_ref().unwrap().sectors_per_cluster()
-                        * self.header.as_ref().unwrap().bytes_per_sector())
-                        as u64;
+                    let cluster_size = self.cluster_size() as u64;
                     let rem = file_size % cluster_size;
                     let cluster_count = file_size / cluster_size + if rem == 0 { 0 } else { 1 };
                     let mut cluster = self.allocate_clusters(cluster_count as u32)?;
@@ -494,25 +1127,22 @@ impl FAT {
 
                     loop {
                         let mut buffer = vec![0; cluster_size as usize];
-                        let n = infile.read(&mut buffer).map_err(|_| FATError::CannotRead)?;
+                        let n = disk::read_full(&mut infile, &mut buffer)
+                            .map_err(|_| FATError::CannotRead)?;
 
                         if n == 0 {
                             *dirent = new_entry;
-                            self.write_cluster_entries(current_cluster, &dirents)
-                                .ok_or(FATError::CannotWrite)?;
-                            return Ok(());
+                            self.write_cluster_entries(current_cluster, &dirents)?;
+                            return self.index_entry(dir.cluster(), current_cluster, filename);
                         }
 
-                        self.write_cluster(cluster, buffer[..].try_into().unwrap())
-                            .ok_or(FATError::CannotWrite)?;
-                        cluster = self.next_cluster(cluster).ok_or(FATError::CannotRead)?;
+                        self.write_cluster(cluster, &buffer)?;
+                        cluster = self.next_cluster(cluster)?;
                     }
                 }
             }
 
-            current_cluster = self
-                .next_cluster(current_cluster)
-                .ok_or(FATError::CannotRead)?;
+            current_cluster = self.next_dir_cluster(current_cluster)?;
 
             if current_cluster == Self::mark_bad_cluster() {
                 return Err(FATError::CannotRead);
@@ -522,62 +1152,62 @@ impl FAT {
         Err(FATError::NotEnoughSpace)
     }
 
-    pub fn cat<T: Write>(&mut self, path: &str, mut outfile: T) -> Result<(), FATError> {
+    /// Fails for sizes the image's directory entries cannot hold.
+    pub(super) fn check_file_size(&self, size: u64) -> Result<(), FATError> {
+        let large_files = self
+            .header
+            .as_ref()
+            .is_some_and(|header| header.has_large_files());
+        if size > u32::MAX as u64 && !large_files {
+            return Err(FATError::FileTooLarge);
+        }
+        Ok(())
+    }
+
+    pub fn cat<T: Write>(&mut self, path: &str, outfile: T) -> Result<(), FATError> {
         let entry = self.find_file(path, Self::filter_find_file)?;
+        self.check_access(path, Access::Read)?;
+        self.read_entry(&entry, outfile)
+    }
+
+    /// Writes the contents of the file described by `entry` to `outfile`.
+    fn read_entry<T: Write>(&mut self, entry: &Entry, mut outfile: T) -> Result<(), FATError> {
+        if self.foreign.is_some() {
+            return self.foreign_read(entry, outfile);
+        }
+        if Self::is_compressed(entry) {
+            let data = self.read_compressed(entry)?;
+            return outfile.write_all(&data).map_err(|_| FATError::CannotWrite);
+        }
+        if Self::is_sparse(entry) {
+            return self.read_sparse(entry, outfile);
+        }
 
         let mut size = entry.size();
         let mut cluster = entry.cluster();
 
         while cluster != Self::mark_read_done() {
-            let limit = size.min(4096);
-            let bytes = self.read_cluster(cluster).ok_or(FATError::CannotRead)?;
+            let limit = size.min(self.cluster_size() as u64);
+            let bytes = self.read_cluster(cluster)?;
             outfile
-                .write(&bytes[0..limit as usize])
+                .write_all(&bytes[0..limit as usize])
                 .map_err(|_| FATError::CannotWrite)?;
 
             size -= limit;
 
-            cluster = self.next_cluster(cluster).ok_or(FATError::CannotRead)?;
-            if cluster == Self::mark_bad_cluster() {
-                return Err(FATError::CannotRead);
-            }
-        }
-
-        Ok(())
-    }
-
-    pub fn info(&mut self, path: &str) -> Result<(), FATError> {
-        let entry = self.find_file(path, Self::filter_find)?;
-
-        let mut cluster = entry.cluster();
-        let mut clusters = vec![];
-
-        while cluster != Self::mark_read_done() {
-            clusters.push(cluster);
-            cluster = self.next_cluster(cluster).ok_or(FATError::CannotRead)?;
+            cluster = self.next_cluster(cluster)?;
             if cluster == Self::mark_bad_cluster() {
                 return Err(FATError::CannotRead);
             }
         }
 
-        println!(
-            "{} {}",
-            entry.name(),
-            clusters
-                .iter()
-                .map(|n| n.to_string())
-                .collect::<Vec<_>>()
-                .join(", ")
-        );
         Ok(())
     }
 
     fn is_empty(&mut self, entry: &Entry) -> Result<bool, FATError> {
         let mut cluster = entry.cluster();
         while cluster != Self::mark_read_done() {
-            let mut entries = self
-                .read_cluster_entries(cluster)
-                .ok_or(FATError::CannotRead)?;
+            let mut entries = self.read_cluster_entries(cluster)?;
 
             for entry in entries.iter_mut() {
                 if entry.name() == "." || entry.name() == ".." {
@@ -589,7 +1219,7 @@ impl FAT {
                 }
             }
 
-            cluster = self.next_cluster(cluster).ok_or(FATError::CannotRead)?;
+            cluster = self.next_cluster(cluster)?;
             if cluster == Self::mark_bad_cluster() {
                 return Err(FATError::CannotRead);
             }
@@ -599,34 +1229,43 @@ impl FAT {
     }
 
     fn remove(&mut self, path: &str, flags: u32) -> Result<(), FATError> {
+        self.check_protected(path)?;
+        self.check_parent_access(path, Access::Write)?;
         let (dir, filename) = Self::split_path(path);
         let dir = self.find_file(dir, Self::filter_mkdir)?;
 
         let mut current_cluster = dir.cluster();
 
         while current_cluster != Self::mark_read_done() {
-            let mut entries = self
-                .read_cluster_entries(current_cluster)
-                .ok_or(FATError::CannotRead)?;
+            let mut entries = self.read_cluster_entries(current_cluster)?;
 
             for entry in entries.iter_mut() {
-                if entry.name() == filename && entry.flags() == flags {
+                let attributes = Flags::Compressed as u32
+                    | Flags::Sparse as u32
+                    | Flags::Hidden as u32
+                    | OWNERSHIP;
+                if entry.name() == filename && entry.flags() & !attributes == flags {
                     if flags & Flags::Directory as u32 == Flags::Directory as u32
                         && !self.is_empty(entry)?
                     {
                         return Err(FATError::DirNotEmpty);
                     }
 
+                    if flags & Flags::Directory as u32 != 0 {
+                        self.free_index(entry.cluster())?;
+                    }
                     entry.set_flags(0);
-                    self.dealloc_clusters(entry.cluster());
-                    self.write_cluster_entries(current_cluster, &entries);
-                    return Ok(());
+                    self.dealloc_clusters(entry.cluster())?;
+                    if entry.xattrs() != 0 {
+                        self.dealloc_clusters(entry.xattrs())?;
+                        entry.set_xattrs(0);
+                    }
+                    self.write_cluster_entries(current_cluster, &entries)?;
+                    return self.compact_if_sparse(&dir);
                 }
             }
 
-            current_cluster = self
-                .next_cluster(current_cluster)
-                .ok_or(FATError::CannotRead)?;
+            current_cluster = self.next_cluster(current_cluster)?;
             if current_cluster == Self::mark_bad_cluster() {
                 return Err(FATError::CannotRead);
             }
@@ -639,11 +1278,66 @@ impl FAT {
         self.remove(path, Flags::Occupied as u32)
     }
 
+    /// Overwrites the file's clusters with random data, then removes it.
+    ///
+    /// Clusters still shared with a reflinked copy are left intact.
+    pub fn shred(&mut self, path: &str) -> Result<(), FATError> {
+        let entry = self.find_file(path, Self::filter_find_file)?;
+        if self.refcount(entry.cluster())? > 0 {
+            return self.remove_file(path);
+        }
+
+        let mut data = self.empty_cluster();
+
+        for cluster in self.chain_of(entry.cluster())? {
+            uuid::fill_random(&mut data)?;
+            self.write_cluster(cluster, &data)?;
+        }
+
+        self.remove_file(path)
+    }
+
     pub fn remove_dir(&mut self, path: &str) -> Result<(), FATError> {
         self.remove(path, Flags::Occupied as u32 | Flags::Directory as u32)
     }
 
+    /// Removes the directory `path` with everything under it, hidden entries
+    /// included, as one transaction.
+    pub fn remove_tree(&mut self, path: &str) -> Result<(), FATError> {
+        self.transaction(|fs| {
+            fs.check_protected(path)?;
+            let dir = fs.find_file(path, Self::filter_mkdir)?;
+            let mut entries = vec![];
+            let mut cluster = dir.cluster();
+            while cluster != Self::mark_read_done() {
+                entries.extend(
+                    fs.read_cluster_entries(cluster)?
+                        .into_iter()
+                        .filter(Self::filter_find)
+                        .filter(|entry| entry.name() != "." && entry.name() != ".."),
+                );
+                cluster = fs.next_cluster(cluster)?;
+                if cluster == Self::mark_bad_cluster() {
+                    return Err(FATError::CannotRead);
+                }
+            }
+
+            for entry in entries {
+                let child = format!("{path}/{}", entry.name());
+                if Self::filter_mkdir(&entry) {
+                    fs.remove_tree(&child)?;
+                } else {
+                    fs.remove_file(&child)?;
+                }
+            }
+            fs.remove_dir(path)
+        })
+    }
+
     pub fn move_file(&mut self, source: &str, dest: &str) -> Result<(), FATError> {
+        self.check_protected(source)?;
+        self.check_protected(dest)?;
+        dirent::check_name(Self::split_path(dest).1)?;
         if self.find_file(dest, Self::filter_find).is_ok() {
             return Err(FATError::FileExists);
         }
@@ -651,6 +1345,8 @@ impl FAT {
         if self.find_file(source, Self::filter_find_file).is_err() {
             return Err(FATError::FileNotFound);
         }
+        self.check_parent_access(source, Access::Write)?;
+        self.check_parent_access(dest, Access::Write)?;
 
         let (dir1, file1) = Self::split_path(source);
         let (dir2, file2) = Self::split_path(dest);
@@ -660,44 +1356,45 @@ impl FAT {
 
         let mut entry = self.update_file_in_dir(
             &dir_src,
-            |entry| entry.name() == file1 && entry.flags() == Flags::Occupied as u32,
+            |entry| entry.name() == file1 && Self::filter_find_file(entry),
             |entry| entry.set_flags(0),
         )?;
-        entry.set_name(file2).ok_or(FATError::FilenameTooLong)?;
-        self.update_file_in_dir(
-            &dir_dest,
-            |entry| entry.flags() & Flags::Occupied as u32 == 0,
-            |update| *update = entry.clone(),
-        )?;
-
-        Ok(())
+        entry.set_name(file2)?;
+        self.insert_entry(dir_dest.cluster(), entry)
     }
 
+    /// Copies `source` to `dest`; a failure part way leaves nothing allocated.
     pub fn copy(&mut self, source: &str, dest: &str) -> Result<(), FATError> {
+        self.transaction(|fs| fs.copy_file(source, dest))
+    }
+
+    fn copy_file(&mut self, source: &str, dest: &str) -> Result<(), FATError> {
+        self.check_protected(dest)?;
+        dirent::check_name(Self::split_path(dest).1)?;
         if self.find_file(dest, Self::filter_find).is_ok() {
             return Err(FATError::FileExists);
         }
 
         let entry = self.find_file(source, Self::filter_find_file)?;
-
-        let cluster_size = self.header.as_ref().unwrap().sectors_per_cluster()
-            * self.header.as_ref().unwrap().bytes_per_sector();
-        let rem = entry.size() % cluster_size;
-
-        let cluster_count = entry.size() / cluster_size + if rem == 0 { 0 } else { 1 };
+        self.check_access(source, Access::Read)?;
+        let cluster_count = self.stored_clusters(&entry)?;
 
         let (dir, filename) = Self::split_path(dest);
 
         let new_file_dir_entry = self.find_file(dir, Self::filter_mkdir)?;
+        self.check_access(dir, Access::Write)?;
 
-        let mut new_entry = Entry::new(filename, entry.size(), 0, Flags::Occupied as u32)
-            .ok_or(FATError::FilenameTooLong)?;
+        // the copy belongs to whoever made it
+        let mut flags = entry.flags();
+        if self.user.is_some() {
+            flags = flags & !OWNERSHIP | self.new_ownership(false);
+        }
+        let mut new_entry =
+            Entry::new(filename, entry.size(), 0, flags).ok_or(FATError::FilenameTooLong)?;
         let mut cluster = new_file_dir_entry.cluster();
 
         while cluster != Self::mark_read_done() {
-            let mut entries = self
-                .read_cluster_entries(cluster)
-                .ok_or(FATError::CannotRead)?;
+            let mut entries = self.read_cluster_entries(cluster)?;
             for dirent in entries.iter_mut() {
                 if dirent.flags() & Flags::Occupied as u32 == 0 {
                     let alloc = self
@@ -711,22 +1408,20 @@ impl FAT {
 
                     while cluster_a != Self::mark_read_done() || cluster_b != Self::mark_read_done()
                     {
-                        let cluster = self.read_cluster(cluster_b).ok_or(FATError::CannotRead)?;
-                        self.write_cluster(cluster_a, cluster)
-                            .ok_or(FATError::CannotWrite)?;
+                        let cluster = self.read_cluster(cluster_b)?;
+                        self.write_cluster(cluster_a, &cluster)?;
 
-                        cluster_a = self.next_cluster(cluster_a).ok_or(FATError::CannotRead)?;
-                        cluster_b = self.next_cluster(cluster_b).ok_or(FATError::CannotRead)?;
+                        cluster_a = self.next_cluster(cluster_a)?;
+                        cluster_b = self.next_cluster(cluster_b)?;
                     }
 
-                    self.write_cluster_entries(cluster, &entries)
-                        .ok_or(FATError::CannotRead)?;
+                    self.write_cluster_entries(cluster, &entries)?;
 
-                    return Ok(());
+                    return self.index_entry(new_file_dir_entry.cluster(), cluster, filename);
                 }
             }
 
-            cluster = self.next_cluster(cluster).ok_or(FATError::CannotRead)?;
+            cluster = self.next_dir_cluster(cluster)?;
             if cluster == Self::mark_bad_cluster() {
                 return Err(FATError::CannotRead);
             }
@@ -735,23 +1430,25 @@ impl FAT {
         Err(FATError::FileNotFound)
     }
 
-    pub fn set_cluster_value(&mut self, cluster: u32, value: u32) -> Option<()> {
-        let mut fat = self.read_fat(cluster)?;
-        let index = cluster as usize % (512 / size_of::<u32>());
+    pub fn set_cluster_value(&mut self, cluster: u32, value: u32) -> Result<(), FATError> {
+        let old = self.read_fat(cluster)?;
+        let mut fat = old.clone();
+        let index = (cluster % self.clusters_per_fat_sector()) as usize;
         fat[index] = value;
-        self.write_fat(cluster, fat)
+        self.write_fat(cluster, &old, fat)
     }
 
-    pub fn bug(&mut self, path: &str) -> Result<(), FATError> {
+    pub fn bug(&mut self, path: &str, corruption: Corruption) -> Result<(), FATError> {
+        self.unshare(path)?;
         let file = self.find_file(path, Self::filter_find_file)?;
 
         let mut cluster = file.cluster();
-        let last_cluster;
+        let mut chain = vec![];
 
         loop {
-            let next_cluster = self.next_cluster(cluster).ok_or(FATError::CannotRead)?;
+            chain.push(cluster);
+            let next_cluster = self.next_cluster(cluster)?;
             if next_cluster == Self::mark_read_done() {
-                last_cluster = cluster;
                 break;
             }
 
@@ -761,103 +1458,567 @@ impl FAT {
             cluster = next_cluster;
         }
 
-        self.set_cluster_value(last_cluster, file.cluster());
-        Ok(())
+        match corruption {
+            Corruption::Cycle => self.set_cluster_value(*chain.last().unwrap(), file.cluster()),
+            Corruption::Bad => {
+                self.set_cluster_value(chain[chain.len() / 2], Self::mark_bad_cluster())
+            }
+            Corruption::Orphan | Corruption::Size(_) => {
+                let (dir, filename) = Self::split_path(path);
+                let dir = self.find_file(dir, Self::filter_mkdir)?;
+                self.update_file_in_dir(
+                    &dir,
+                    |entry| entry.name() == filename && Self::filter_find_file(entry),
+                    |entry| match corruption {
+                        Corruption::Size(size) => entry.set_size(size),
+                        _ => entry.set_cluster(Self::mark_read_done()),
+                    },
+                )?;
+                Ok(())
+            }
+        }
     }
 
-    fn check_entry(&mut self, entry: &Entry, tabs: usize) -> Result<(), FATError> {
-        let mut cluster = entry.cluster();
-        let tabs_str = (0..tabs).map(|_| "\t").collect::<Vec<_>>().join("");
-        println!("{tabs_str}{}", entry.name());
-        if entry.flags() & Flags::Directory as u32 == Flags::Directory as u32 && entry.size() != 0 {
-            println!("{tabs_str} is a directory with size != 0");
-        }
+    /// Lists every entry below the root directory together with its absolute path.
+    pub fn walk(&mut self) -> Result<Vec<(String, Entry)>, FATError> {
+        let mut entries = vec![];
+        self.walk_dir(1, "", &mut entries, &mut HashSet::new())?;
+        Ok(entries)
+    }
 
-        let mut visited = HashSet::new();
+    fn walk_dir(
+        &mut self,
+        dir_cluster: u32,
+        prefix: &str,
+        out: &mut Vec<(String, Entry)>,
+        visited: &mut HashSet<u32>,
+    ) -> Result<(), FATError> {
+        let mut cluster = dir_cluster;
 
         while cluster != Self::mark_read_done() {
-            if visited.contains(&cluster) {
-                println!("{tabs_str} FAT contains a cycle! Cannot continue.");
+            if !visited.insert(cluster) {
                 return Ok(());
             }
 
-            visited.insert(cluster);
+            for entry in self.read_cluster_entries(cluster)? {
+                if !Self::filter_find(&entry) || entry.name() == "." || entry.name() == ".." {
+                    continue;
+                }
 
-            if entry.flags() & Flags::Directory as u32 == Flags::Directory as u32 {
-                let entries = self
-                    .read_cluster_entries(cluster)
-                    .ok_or(FATError::CannotRead)?;
-                for dirent in entries {
-                    if dirent.flags() & Flags::Occupied as u32 == Flags::Occupied as u32
-                        && dirent.name() != "."
-                        && dirent.name() != ".."
-                    {
-                        self.check_entry(&dirent, tabs + 1)?;
-                    }
+                let path = format!("{prefix}/{}", entry.name());
+                if Self::filter_mkdir(&entry) {
+                    self.walk_dir(entry.cluster(), &path, out, visited)?;
                 }
+                out.push((path, entry));
             }
 
-            cluster = self.next_cluster(cluster).ok_or(FATError::CannotRead)?;
+            cluster = self.next_cluster(cluster)?;
+            if cluster == Self::mark_bad_cluster() {
+                return Err(FATError::CannotRead);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stores `entry` in the first free slot of the directory at `dir_cluster`.
+    fn insert_entry(&mut self, dir_cluster: u32, entry: Entry) -> Result<(), FATError> {
+        let mut cluster = dir_cluster;
+
+        while cluster != Self::mark_read_done() {
+            let mut entries = self.read_cluster_entries(cluster)?;
+
+            if let Some(free) = entries
+                .iter_mut()
+                .find(|dirent| dirent.flags() & Flags::Occupied as u32 == 0)
+            {
+                let name = entry.name().to_string();
+                *free = entry;
+                self.write_cluster_entries(cluster, &entries)?;
+                return self.index_entry(dir_cluster, cluster, &name);
+            }
 
+            cluster = self.next_dir_cluster(cluster)?;
             if cluster == Self::mark_bad_cluster() {
-                println!("{tabs_str}  FAT contains bad sector(s)! Cannot continue.");
+                return Err(FATError::CannotRead);
+            }
+        }
+
+        Err(FATError::NotEnoughSpace)
+    }
+
+    /// Compares the primary FAT with its first mirror sector by sector.
+    ///
+    /// Returns the FAT sectors whose primary copy is unreadable or differs from
+    /// the mirror. With `repair` set, those sectors are overwritten with the
+    /// mirror contents.
+    pub fn check_fat_mirror(&mut self, repair: bool) -> Result<Vec<u32>, FATError> {
+        let header = self.header.as_ref().expect("Image is not formatted!");
+        let fat_sectors = header.fat_sectors();
+
+        if header.fat_count() < 2 {
+            return Ok(vec![]);
+        }
+
+        let mut mismatched = vec![];
+
+        for index in 0..fat_sectors {
+            let cluster = index * self.clusters_per_fat_sector();
+            let mirror = self.read_sector(self.fat_sector(1, cluster))?;
+            let primary = self.read_sector(self.fat_sector(0, cluster));
+
+            if primary.as_ref().ok() == Some(&mirror) {
+                continue;
+            }
+
+            if repair {
+                self.write_sector(self.fat_sector(0, cluster), &mirror)?;
+                self.store_checksum(self.fat_slot(cluster), &mirror)?;
+                self.free_clusters = None;
+            }
+
+            mismatched.push(index);
+        }
+
+        Ok(mismatched)
+    }
+
+    pub fn header(&self) -> Option<&Header> {
+        self.header.as_ref()
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        Some(self.header.as_ref()?.label())
+    }
+
+    pub fn set_label(&mut self, label: &str) -> Result<(), HeaderError> {
+        let header = self.header.as_mut().expect("Image is not formatted!");
+        header.set_label(label)?;
+        self.store_header().map_err(|_| HeaderError::CannotWrite)
+    }
+
+    /// Grows the image to `capacity`.
+    ///
+    /// The FAT grows with the image, so the data region is shifted towards the
+    /// end of the file to make room for it. Clusters are numbered relative to
+    /// the start of the data region, so chains and directory entries stay valid.
+    pub fn resize(&mut self, capacity: Unit) -> Result<(), HeaderError> {
+        let old = self.header.clone().expect("Image is not formatted!");
+        let new = old.resized(capacity)?;
+
+        if new.sector_count() < old.sector_count() {
+            return Err(HeaderError::BadCapacity);
+        }
+
+        self.relayout(&old, &new)
+            .map_err(|_| HeaderError::CannotWrite)
+    }
+
+    /// Shrinks the image to `capacity`.
+    ///
+    /// Clusters that would fall outside the smaller data region are first moved
+    /// into free clusters below the new limit, then the image is truncated.
+    pub fn shrink(&mut self, capacity: Unit) -> Result<(), HeaderError> {
+        let old = self.header.clone().expect("Image is not formatted!");
+        let new = old.resized(capacity)?;
+
+        if new.sector_count() > old.sector_count() {
+            return Err(HeaderError::BadCapacity);
+        }
+
+        match self.evacuate(new.data_cluster_count()) {
+            Ok(()) => {}
+            Err(FATError::NotEnoughSpace) => return Err(HeaderError::BadCapacity),
+            Err(_) => return Err(HeaderError::CannotWrite),
+        }
+
+        self.relayout(&old, &new)
+            .map_err(|_| HeaderError::CannotWrite)
+    }
+
+    /// Moves every used cluster numbered above `limit` into a free cluster at
+    /// or below it, fixing up FAT chains and directory entries.
+    fn evacuate(&mut self, limit: u32) -> Result<(), FATError> {
+        let old_fat = self.read_whole_fat()?;
+        let mut fat = old_fat.clone();
+
+        let is_link = |value: u32| value != 0 && value < fat.len() as u32;
+        let mut previous = HashMap::new();
+        for (cluster, value) in fat.iter().enumerate() {
+            if is_link(*value) {
+                previous.insert(*value, cluster as u32);
+            }
+        }
+
+        let mut free = (1..=limit).filter(|cluster| fat[*cluster as usize] == 0);
+        let mut moved = HashMap::new();
+
+        for cluster in limit + 1..fat.len() as u32 {
+            let value = fat[cluster as usize];
+            if value == 0 || value == Self::mark_bad_cluster() {
+                continue;
+            }
+
+            let target = free.next().ok_or(FATError::NotEnoughSpace)?;
+            moved.insert(cluster, target);
+        }
+        drop(free);
+
+        for (&cluster, &target) in &moved {
+            let data = self.read_cluster(cluster)?;
+            self.write_cluster(target, &data)?;
+        }
+
+        for (&cluster, &target) in &moved {
+            let value = old_fat[cluster as usize];
+            fat[target as usize] = *moved.get(&value).unwrap_or(&value);
+            fat[cluster as usize] = 0;
+
+            if let Some(previous) = previous.get(&cluster) {
+                if !moved.contains_key(previous) {
+                    fat[*previous as usize] = target;
+                }
+            }
+        }
+
+        for cluster in limit + 1..fat.len() as u32 {
+            fat[cluster as usize] = 0;
+        }
+
+        self.write_whole_fat(&old_fat, &fat)?;
+
+        if !moved.is_empty() {
+            let mut visited = HashSet::new();
+            self.remap_entries(1, &moved, &mut visited)?;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites directory entries below `dir_cluster` whose start cluster was moved.
+    fn remap_entries(
+        &mut self,
+        dir_cluster: u32,
+        moved: &HashMap<u32, u32>,
+        visited: &mut HashSet<u32>,
+    ) -> Result<(), FATError> {
+        let mut cluster = dir_cluster;
+
+        while cluster != Self::mark_read_done() {
+            if !visited.insert(cluster) {
                 return Ok(());
             }
+
+            let mut entries = self.read_cluster_entries(cluster)?;
+            let mut changed = false;
+
+            for entry in entries.iter_mut() {
+                if entry.flags() & Flags::Occupied as u32 == 0 {
+                    continue;
+                }
+
+                if let Some(target) = moved.get(&entry.cluster()) {
+                    entry.set_cluster(*target);
+                    changed = true;
+                }
+                if let Some(target) = moved.get(&entry.xattrs()) {
+                    entry.set_xattrs(*target);
+                    changed = true;
+                }
+            }
+
+            if changed {
+                self.write_cluster_entries(cluster, &entries)?;
+            }
+
+            for entry in entries {
+                if Self::filter_mkdir(&entry) && entry.name() != "." && entry.name() != ".." {
+                    self.remap_entries(entry.cluster(), moved, visited)?;
+                }
+            }
+
+            cluster = self.next_cluster(cluster)?;
+            if cluster == Self::mark_bad_cluster() {
+                return Err(FATError::CannotRead);
+            }
         }
+
         Ok(())
     }
 
-    pub fn check(&mut self) -> Result<(), FATError> {
-        let entry = Entry::new("/", 0, 1, Flags::Directory as u32).unwrap();
-        self.check_entry(&entry, 0)
+    /// Moves the data region and rewrites every FAT copy from the `old`
+    /// geometry to the `new` one, then stores the new header.
+    fn relayout(&mut self, old: &Header, new: &Header) -> Result<(), FATError> {
+        self.mark_dirty()?;
+
+        // the journal moves with the rest of the metadata
+        self.disk
+            .set_journal(None)
+            .map_err(|_| FATError::CannotWrite)?;
+
+        let bytes_per_sector = old.bytes_per_sector() as u64;
+
+        let mut fat = vec![0; old.fat_sectors() as usize * old.bytes_per_sector() as usize];
+        self.disk
+            .read_at(bytes_per_sector, &mut fat)
+            .map_err(|_| FATError::CannotRead)?;
+        fat.resize(
+            new.fat_sectors() as usize * new.bytes_per_sector() as usize,
+            0,
+        );
+
+        let used_clusters = old.data_cluster_count().min(new.data_cluster_count()) as u64;
+        let seals = self.read_seals(old)?;
+
+        if new.sector_count() > old.sector_count() {
+            self.disk
+                .set_len(new.sector_count() * bytes_per_sector)
+                .map_err(|_| FATError::CannotWrite)?;
+        }
+
+        self.disk
+            .copy_within(
+                old.first_data_sector() * bytes_per_sector,
+                new.first_data_sector() * bytes_per_sector,
+                used_clusters * old.cluster_size() as u64,
+            )
+            .map_err(|_| FATError::CannotWrite)?;
+
+        for copy in 0..new.fat_count() as u64 {
+            self.disk
+                .write_at(
+                    (1 + copy * new.fat_sectors() as u64) * bytes_per_sector,
+                    &fat,
+                )
+                .map_err(|_| FATError::CannotWrite)?;
+        }
+
+        if new.sector_count() < old.sector_count() {
+            self.disk
+                .set_len(new.sector_count() * bytes_per_sector)
+                .map_err(|_| FATError::CannotWrite)?;
+        }
+
+        let mut new = new.clone();
+        new.set_dirty(true);
+        self.header = Some(new);
+        self.free_clusters = None;
+        self.store_header()?;
+        self.write_seals(seals)?;
+        self.rebuild_checksums()?;
+        self.rebuild_refcounts()?;
+
+        let journal = self.journal_region();
+        self.disk
+            .set_journal(journal)
+            .map_err(|_| FATError::CannotWrite)
+    }
+
+    /// The whole image as it would be written to a file, including whatever
+    /// is still held back.
+    pub fn image_bytes(&mut self) -> Result<Vec<u8>, FATError> {
+        self.disk.flush().map_err(|_| FATError::CannotWrite)?;
+
+        let len = self.disk.len().map_err(|_| FATError::CannotRead)?;
+        let mut bytes = vec![0; usize::try_from(len).map_err(|_| FATError::CannotRead)?];
+        self.disk
+            .read_at(0, &mut bytes)
+            .map_err(|_| FATError::CannotRead)?;
+        Ok(bytes)
+    }
+
+    /// Copies the image to a new host file.
+    ///
+    /// With `compact` set only the metadata and used clusters are copied; free
+    /// clusters are left as holes, so the clone only occupies the space its
+    /// data needs.
+    pub fn clone_image(&mut self, path: &str, compact: bool) -> Result<(), FATError> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(|_| FATError::CannotWrite)?;
+        let mut target = Disk::new(file);
+
+        self.disk.flush().map_err(|_| FATError::CannotWrite)?;
+
+        let mut header = self.header.clone().expect("Image is not formatted!");
+        let bytes_per_sector = header.bytes_per_sector() as u64;
+        let image_size = header.sector_count() * bytes_per_sector;
+        let data_start = header.first_data_sector() * bytes_per_sector;
+
+        target
+            .set_len(image_size)
+            .map_err(|_| FATError::CannotWrite)?;
+
+        let mut metadata = vec![0; data_start as usize];
+        self.disk
+            .read_at(0, &mut metadata)
+            .map_err(|_| FATError::CannotRead)?;
+
+        header.set_dirty(false);
+        metadata[..HEADER_SIZE].clone_from_slice(&header.as_bytes());
+        target
+            .write_at(0, &metadata)
+            .map_err(|_| FATError::CannotWrite)?;
+
+        let fat = self.read_whole_fat()?;
+        for cluster in 1..=header.data_cluster_count() {
+            let value = fat[cluster as usize];
+            if compact && (value == 0 || value == Self::mark_bad_cluster()) {
+                continue;
+            }
+
+            let data = self.read_raw_cluster(cluster)?;
+            target
+                .write_at(self.sector_to_byte(self.cluster_to_sector(cluster)), &data)
+                .map_err(|_| FATError::CannotWrite)?;
+        }
+
+        target.flush().map_err(|_| FATError::CannotWrite)
+    }
+
+    /// Upgrades an image created by an older version of the tool in place.
+    ///
+    /// Returns the version the image had before the upgrade.
+    pub fn migrate(&mut self) -> Result<u32, HeaderError> {
+        let old = self.header.clone().expect("Image is not formatted!");
+        // their data would have to be encrypted again, which needs the old
+        // unauthenticated cipher
+        if old.is_encrypted() && old.version() < header::AUTHENTICATED_VERSION {
+            return Err(HeaderError::UnsupportedVersion);
+        }
+        let mut new = old.clone();
+        let old_version = new.migrate()?;
+
+        if new.first_data_sector() == old.first_data_sector() {
+            self.header = Some(new);
+            self.free_clusters = None;
+            self.store_header().map_err(|_| HeaderError::CannotWrite)?;
+            return Ok(old_version);
+        }
+
+        // the checksum table is inserted before the data region, which pushes
+        // the last clusters past the end of the image unless they are moved
+        match self.evacuate(new.data_cluster_count()) {
+            Ok(()) => {}
+            Err(FATError::NotEnoughSpace) => return Err(HeaderError::BadCapacity),
+            Err(_) => return Err(HeaderError::CannotWrite),
+        }
+
+        self.relayout(&old, &new)
+            .map_err(|_| HeaderError::CannotWrite)?;
+        Ok(old_version)
+    }
+
+    /// Whether the image was left dirty by a session that did not exit cleanly.
+    pub fn is_dirty(&self) -> bool {
+        self.header.as_ref().is_some_and(|header| header.is_dirty())
+    }
+
+    /// Whether the image has been mounted often enough since the last `check`
+    /// that another one is advisable.
+    pub fn check_due(&self) -> bool {
+        self.header
+            .as_ref()
+            .is_some_and(|header| header.mount_count() > MAX_MOUNT_COUNT)
     }
 
-    fn write_header(&mut self) -> Option<()> {
-        self.file.rewind().ok()?;
+    /// Persists the dirty flag before the first write of a session.
+    fn mark_dirty(&mut self) -> Result<(), FATError> {
+        match self.header.as_mut() {
+            Some(header) if !header.is_dirty() => {
+                header.set_dirty(true);
+                self.store_header()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Flushes all pending writes and marks the image clean.
+    pub fn sync(&mut self) -> Result<(), FATError> {
+        self.disk.checkpoint().map_err(|_| FATError::CannotWrite)?;
+        self.disk.flush().map_err(|_| FATError::CannotWrite)?;
+
+        match self.header.as_mut() {
+            Some(header) if header.is_dirty() || header.free_clusters() != self.free_clusters => {
+                header.set_dirty(false);
+                header.set_free_clusters(self.free_clusters);
+                self.store_header()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Rewrites the header in place without touching the rest of the image.
+    fn store_header(&mut self) -> Result<(), FATError> {
+        let header = self.header.as_mut().unwrap();
+        if self.deterministic {
+            header.make_reproducible();
+        }
+        let header_bytes = header.as_bytes();
+        self.disk
+            .write_at(0, &header_bytes)
+            .map_err(|_| FATError::CannotWrite)?;
+        self.disk.flush().map_err(|_| FATError::CannotWrite)
+    }
+
+    /// Lays out a fresh filesystem on the image.
+    ///
+    /// Only the FAT region is written explicitly; the data region is left as a
+    /// hole which sparse-capable hosts do not allocate until it is written.
+    /// With `preallocate` set the data region is zero-filled as well.
+    fn write_header(&mut self, preallocate: bool) -> Result<(), FATError> {
+        self.disk
+            .set_journal(None)
+            .map_err(|_| FATError::CannotWrite)?;
 
         let header = self.header.as_ref().unwrap();
+        let bytes_per_sector = header.bytes_per_sector();
+        let sector_count = header.sector_count();
+        let header_bytes = header.as_bytes();
 
-        self.file
-            .write(&header.bytes_per_sector().to_le_bytes())
-            .ok()?;
-        self.file
-            .write(&header.sectors_per_cluster().to_le_bytes())
-            .ok()?;
-        self.file.write(&header.sector_count().to_le_bytes()).ok()?;
-        self.file.write(&header.fat_count().to_le_bytes()).ok()?;
-        self.file.write(&header.checksum().to_le_bytes()).ok()?;
-
-        let cluster_count = header.sector_count() / header.sectors_per_cluster();
-
-        let fat_sectors = 1 + size_of::<u32>() as u32 * cluster_count / header.bytes_per_sector();
-
-        self.file
-            .seek(SeekFrom::Start(header.bytes_per_sector() as u64))
-            .ok()?;
-        for _ in 0..header.sector_count() - 1 {
-            self.file
-                .write(&FAT::empty_cluster()[0..header.bytes_per_sector() as usize])
-                .ok()?;
-        }
-
-        self.file
-            .seek(SeekFrom::Start(header.bytes_per_sector() as u64))
-            .ok()?;
-        self.file
-            .write(&FAT::mark_bad_cluster().to_le_bytes())
-            .ok()?;
-        self.file.write(&FAT::mark_read_done().to_le_bytes()).ok()?;
-
-        self.file
-            .seek(SeekFrom::Start(
-                ((1 + fat_sectors) * header.bytes_per_sector()) as u64,
-            ))
-            .ok()?;
-        self.file
-            .write(&FAT::mark_bad_cluster().to_le_bytes())
-            .ok()?;
-        self.file.write(&FAT::mark_read_done().to_le_bytes()).ok()?;
+        let fat_sectors = header.fat_sectors() as u64;
+        let fat_count = header.fat_count() as u64;
 
+        let zero_end = if preallocate {
+            sector_count
+        } else {
+            header.first_data_sector()
+        } * bytes_per_sector as u64;
+
+        self.disk.set_len(0).map_err(|_| FATError::CannotWrite)?;
+        self.disk
+            .set_len(sector_count * bytes_per_sector as u64)
+            .map_err(|_| FATError::CannotWrite)?;
+
+        let zeros = vec![0; 1 << 20];
+        let mut offset = bytes_per_sector as u64;
+        while offset < zero_end {
+            let n = (zero_end - offset).min(zeros.len() as u64);
+            self.disk
+                .write_at(offset, &zeros[..n as usize])
+                .map_err(|_| FATError::CannotWrite)?;
+            offset += n;
+        }
+
+        self.disk
+            .write_at(0, &header_bytes)
+            .map_err(|_| FATError::CannotWrite)?;
+
+        let mut marks = [0; 2 * size_of::<u32>()];
+        marks[0..size_of::<u32>()].clone_from_slice(&FAT::mark_bad_cluster().to_le_bytes());
+        marks[size_of::<u32>()..].clone_from_slice(&FAT::mark_read_done().to_le_bytes());
+
+        for copy in 0..fat_count {
+            self.disk
+                .write_at((1 + copy * fat_sectors) * bytes_per_sector as u64, &marks)
+                .map_err(|_| FATError::CannotWrite)?;
+        }
+
+        self.write_cluster(1, &self.empty_cluster())?;
         let mut entries = self.read_cluster_entries(1)?;
         entries[0] = Entry::new(
             ".",
@@ -874,14 +2035,1582 @@ impl FAT {
         )
         .unwrap();
         self.write_cluster_entries(1, &entries)?;
+        self.rebuild_checksums()?;
 
-        self.file.flush().ok()
+        let journal = self.journal_region();
+        self.disk
+            .set_journal(journal)
+            .map_err(|_| FATError::CannotWrite)?;
+        self.disk.flush().map_err(|_| FATError::CannotWrite)
     }
 
-    pub fn format(&mut self, capacity: Unit) -> Result<(), HeaderError> {
-        let header = Header::new(capacity)?;
+    pub fn format(&mut self, capacity: Unit, options: &FormatOptions) -> Result<(), HeaderError> {
+        let mut header = Header::new(capacity, options)?;
+        if self.deterministic {
+            header.make_reproducible();
+        }
+        self.key = options
+            .passphrase
+            .as_deref()
+            .map(|passphrase| header.seal_key(passphrase))
+            .transpose()
+            .map_err(|_| HeaderError::CannotFormat)?;
         self.header = Some(header);
-        self.write_header().ok_or(HeaderError::CannotFormat)?;
-        Ok(())
+        self.foreign = None;
+        self.free_clusters = None;
+        self.write_header(options.preallocate)
+            .map_err(|_| HeaderError::CannotFormat)?;
+        self.reload_held();
+        self.sync().map_err(|_| HeaderError::CannotFormat)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        ops::Range,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+
+    /// Image file in the temporary directory, removed when dropped.
+    struct TempImage(PathBuf);
+
+    impl TempImage {
+        fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!("zos_rs_{}_{name}.img", std::process::id())))
+        }
+
+        fn open(&self) -> FAT {
+            FAT::new(self.0.to_string_lossy().into_owned(), None).unwrap()
+        }
+
+        /// The image, freshly formatted to 10 MB with `options`.
+        fn formatted(&self, options: &FormatOptions) -> FAT {
+            let mut fat = self.open();
+            fat.format(Unit::MB(10), options).unwrap();
+            fat
+        }
+    }
+
+    /// An image on `backend`, freshly formatted to 10 MB with `options`.
+    fn formatted_on(backend: Box<dyn Backend>, options: &FormatOptions) -> FAT {
+        let mut fat = FAT::with_backend(backend).unwrap();
+        fat.format(Unit::MB(10), options).unwrap();
+        fat
+    }
+
+    /// An image kept in memory, freshly formatted to 10 MB.
+    fn formatted() -> FAT {
+        formatted_on(Box::new(disk::Memory::default()), &FormatOptions::default())
+    }
+
+    impl Drop for TempImage {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+            let _ = std::fs::remove_dir_all(self.0.with_extension("img.snapshots"));
+        }
+    }
+
+    #[test]
+    fn addresses_clusters_past_4gb() {
+        let image = TempImage::new("past_4gb");
+        let mut fat = image.open();
+        fat.format(Unit::GB(5), &FormatOptions::default()).unwrap();
+
+        let last = fat.header.as_ref().unwrap().data_cluster_count();
+        assert!(fat.sector_to_byte(fat.cluster_to_sector(last)) > u32::MAX as u64);
+
+        let data = vec![0xA5; fat.cluster_size()];
+        fat.write_cluster(last, &data).unwrap();
+        fat.sync().unwrap();
+        drop(fat);
+
+        let mut fat = image.open();
+        assert_eq!(fat.read_cluster(last).unwrap(), data);
+    }
+
+    #[test]
+    fn keeps_files_larger_than_4gb() {
+        let image = TempImage::new("large_file");
+        let mut fat = image.open();
+        fat.format(Unit::GB(5), &FormatOptions::default()).unwrap();
+
+        let size = 9 << 29;
+        fat.new_file("big", Cursor::new(b"head")).unwrap();
+        fat.write_at("big", size - 4, b"tail").unwrap();
+        fat.sync().unwrap();
+        drop(fat);
+
+        let mut fat = image.open();
+        let entry = fat.find_file("big", FAT::filter_find_file).unwrap();
+        assert_eq!(entry.size(), size);
+        assert_eq!(fat.chain_of(entry.cluster()).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn maps_clusters_of_a_multi_terabyte_image() {
+        let image = TempImage::new("multi_tb");
+        let mut fat = image.open();
+        let header = Header::new(Unit::GB(3 * 1024), &FormatOptions::default()).unwrap();
+        let last = header.data_cluster_count();
+        let expected = (header.first_data_sector()
+            + (last as u64 - 1) * header.sectors_per_cluster() as u64)
+            * header.bytes_per_sector() as u64;
+        fat.header = Some(header);
+
+        assert_eq!(fat.sector_to_byte(fat.cluster_to_sector(last)), expected);
+        assert!(expected > 2 << 40);
+    }
+
+    #[test]
+    fn reports_the_extents_of_a_fragmented_file() {
+        let mut fat = formatted();
+        let cluster = fat.cluster_size();
+
+        fat.new_file("a", Cursor::new(vec![1; cluster])).unwrap();
+        fat.new_file("b", Cursor::new(vec![2; cluster])).unwrap();
+        fat.remove_file("a").unwrap();
+        fat.new_file("c", Cursor::new(vec![3; 3 * cluster]))
+            .unwrap();
+
+        let stat = fat.stat("c").unwrap();
+        assert_eq!(stat.start, stat.clusters[0]);
+        let extents = stat.extents();
+        assert!(extents.len() > 1);
+        assert_eq!(extents.iter().map(|(_, count)| count).sum::<u32>(), 3);
+        assert!(stat.flags().is_empty());
+        assert_eq!(stat.letters(), "-----");
+    }
+
+    #[test]
+    fn reports_entries_and_usage_as_data() {
+        let mut fat = formatted();
+        let empty = fat.usage().unwrap();
+
+        let data = vec![7; fat.cluster_size() + 1];
+        fat.new_file("f", Cursor::new(&data)).unwrap();
+
+        let stat = fat.stat("f").unwrap();
+        assert!(!stat.dir);
+        assert_eq!(stat.size, data.len() as u64);
+        assert_eq!(stat.clusters.len(), 2);
+        assert_eq!(stat.allocated, 2 * fat.cluster_size() as u64);
+
+        let usage = fat.usage().unwrap();
+        assert_eq!(usage.total, empty.total);
+        let used = format!(
+            "Used clusters: {}\n",
+            usage.used() / fat.cluster_size() as u64
+        );
+        assert!(fat.header_info().unwrap().contains(&used));
+        assert_eq!(empty.free - usage.free, stat.allocated);
+
+        let names = fat
+            .read_dir(".")
+            .unwrap()
+            .iter()
+            .map(|entry| entry.name().to_string())
+            .collect::<Vec<_>>();
+        assert!(names.contains(&"f".to_string()));
+    }
+
+    #[test]
+    fn keeps_the_free_cluster_count_across_sessions() {
+        let image = TempImage::new("free_count");
+        let mut fat = image.formatted(&FormatOptions::default());
+        let empty = fat.usage().unwrap().free;
+
+        let data = vec![7; 3 * fat.cluster_size()];
+        fat.new_file("a", Cursor::new(&data)).unwrap();
+        fat.new_file("b", Cursor::new(&data)).unwrap();
+        fat.remove_file("a").unwrap();
+        fat.begin();
+        fat.new_file("c", Cursor::new(&data)).unwrap();
+        fat.rollback();
+        let free = fat.usage().unwrap().free;
+        assert_eq!(empty - free, 3 * fat.cluster_size() as u64);
+        fat.sync().unwrap();
+        drop(fat);
+
+        let mut fat = image.open();
+        assert!(fat.free_clusters.is_some());
+        assert_eq!(fat.usage().unwrap().free, free);
+        fat.free_clusters = None;
+        assert_eq!(fat.usage().unwrap().free, free);
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        let mut fat = formatted();
+        fat.new_file("f", Cursor::new(b"data")).unwrap();
+
+        for name in ["", "...", "con", "NUL.txt", "a\tb", "a\0"] {
+            assert_eq!(fat.mkdir(name), Err(FATError::InvalidName), "{name:?}");
+            assert_eq!(
+                fat.new_file(name, Cursor::new(b"x")),
+                Err(FATError::InvalidName)
+            );
+            assert_eq!(fat.move_file("f", name), Err(FATError::InvalidName));
+        }
+        assert_eq!(fat.mkdir("thirteen_char"), Err(FATError::FilenameTooLong));
+
+        fat.move_file("f", "console.txt").unwrap();
+        fat.mkdir(".hidden").unwrap();
+    }
+
+    #[test]
+    fn protects_dot_entries() {
+        let mut fat = formatted();
+        fat.mkdir("a").unwrap();
+        fat.new_file("f", Cursor::new(b"data")).unwrap();
+
+        for path in [".", "..", "a/.", "a/.."] {
+            assert_eq!(fat.remove_dir(path), Err(FATError::Protected), "{path}");
+            assert_eq!(fat.remove_tree(path), Err(FATError::Protected));
+            assert_eq!(fat.move_file(path, "b"), Err(FATError::Protected));
+            assert_eq!(fat.move_file("f", path), Err(FATError::Protected));
+            assert_eq!(fat.copy("f", path), Err(FATError::Protected));
+        }
+        assert_eq!(fat.trash("a/.."), Err(FATError::Protected));
+        assert!(fat.find_file("a/..", FAT::filter_mkdir).is_ok());
+    }
+
+    #[test]
+    fn grows_full_directories() {
+        let mut fat = formatted();
+        let slots = fat.cluster_size() / 32;
+        fat.mkdir("d").unwrap();
+
+        for i in 0..slots * 2 {
+            fat.new_file(&format!("d/f{i}"), Cursor::new(b"x")).unwrap();
+        }
+        fat.mkdir("d/sub").unwrap();
+        fat.new_file("g", Cursor::new(b"y")).unwrap();
+        fat.move_file("g", "d/g").unwrap();
+
+        let dir = fat.find_file("d", FAT::filter_mkdir).unwrap();
+        assert_eq!(fat.chain_of(dir.cluster()).unwrap().len(), 3);
+        assert_eq!(fat.read_dir("d").unwrap().len(), slots * 2 + 4);
+        let mut data = vec![];
+        fat.cat(&format!("d/f{}", slots * 2 - 1), &mut data)
+            .unwrap();
+        fat.cat("d/g", &mut data).unwrap();
+        assert_eq!(data, b"xy");
+        assert!(fat
+            .check(check::CheckOptions::default())
+            .unwrap()
+            .findings
+            .is_empty());
+    }
+
+    #[test]
+    fn compacts_directories() {
+        let mut fat = formatted();
+        let slots = fat.cluster_size() / 32;
+        fat.mkdir("d").unwrap();
+        for i in 0..slots * 2 {
+            fat.new_file(&format!("d/f{i}"), Cursor::new(b"x")).unwrap();
+        }
+        let chain = |fat: &mut FAT| {
+            let dir = fat.find_file("d", FAT::filter_mkdir).unwrap();
+            fat.chain_of(dir.cluster()).unwrap().len()
+        };
+        assert_eq!(chain(&mut fat), 3);
+
+        for i in (0..slots * 2).step_by(2) {
+            fat.remove_file(&format!("d/f{i}")).unwrap();
+        }
+        assert_eq!(chain(&mut fat), 3);
+        let free = fat.usage().unwrap().free;
+        assert_eq!(fat.compact_dir("d"), Ok(1));
+        assert_eq!(fat.usage().unwrap().free, free + fat.cluster_size() as u64);
+        assert_eq!(fat.read_dir("d").unwrap().len(), slots + 2);
+        let mut data = vec![];
+        fat.cat(&format!("d/f{}", slots * 2 - 1), &mut data)
+            .unwrap();
+        assert_eq!(data, b"x");
+
+        // emptied by removals, it shrinks on its own
+        for i in (1..slots * 2 - 2).step_by(2) {
+            fat.remove_file(&format!("d/f{i}")).unwrap();
+        }
+        assert_eq!(chain(&mut fat), 1);
+        assert_eq!(fat.read_dir("d").unwrap().len(), 3);
+        assert!(fat
+            .check(check::CheckOptions::default())
+            .unwrap()
+            .findings
+            .is_empty());
+    }
+
+    #[test]
+    fn indexes_large_directories() {
+        let mut reads = vec![];
+        for dir_index in [false, true] {
+            let options = FormatOptions {
+                dir_index,
+                ..FormatOptions::default()
+            };
+            let mut fat = formatted_on(Box::new(disk::Memory::default()), &options);
+            let empty = fat.usage().unwrap().free;
+            let count = fat.cluster_size() / 32 * 5;
+            fat.mkdir("d").unwrap();
+            for i in 0..count {
+                fat.new_file(&format!("d/f{i}"), Cursor::new(b"x")).unwrap();
+            }
+            let dir = fat.find_file("d", FAT::filter_mkdir).unwrap();
+            let dot = &fat.read_cluster_entries(dir.cluster()).unwrap()[0];
+            assert_eq!(dot.xattrs() != 0, dir_index);
+
+            let before = fat.stats().sector_reads;
+            let last = format!("d/f{}", count - 1);
+            assert!(fat.find_file(&last, FAT::filter_find_file).is_ok());
+            assert_eq!(
+                fat.find_file("d/none", FAT::filter_find).unwrap_err(),
+                FATError::FileNotFound
+            );
+            reads.push(fat.stats().sector_reads - before);
+
+            fat.new_file("g", Cursor::new(b"y")).unwrap();
+            fat.move_file("g", "d/g").unwrap();
+            for i in 0..count - 10 {
+                fat.remove_file(&format!("d/f{i}")).unwrap();
+            }
+            assert_eq!(fat.compact_dir("d"), Ok(0));
+            let mut data = vec![];
+            fat.cat("d/g", &mut data).unwrap();
+            fat.cat(&last, &mut data).unwrap();
+            assert_eq!(data, b"yx");
+            assert!(fat
+                .check(check::CheckOptions::default())
+                .unwrap()
+                .findings
+                .is_empty());
+
+            fat.remove_tree("d").unwrap();
+            assert_eq!(fat.usage().unwrap().free, empty);
+        }
+        assert!(reads[1] < reads[0], "{reads:?}");
+    }
+
+    #[test]
+    fn says_which_component_failed_to_resolve() {
+        let mut fat = formatted();
+        fat.mkdir("a").unwrap();
+        fat.new_file("a/f", Cursor::new(b"data")).unwrap();
+
+        assert!(fat.resolve("a/f", FAT::filter_find_file).is_ok());
+        assert_eq!(
+            fat.resolve("a/g", FAT::filter_find_file).unwrap_err(),
+            (1, FATError::FileNotFound)
+        );
+        assert_eq!(
+            fat.resolve("b/f", FAT::filter_find_file).unwrap_err(),
+            (0, FATError::FileNotFound)
+        );
+        assert_eq!(
+            fat.resolve("a/f/g", FAT::filter_find).unwrap_err(),
+            (1, FATError::NotADirectory)
+        );
+        assert_eq!(
+            fat.resolve("a/f", FAT::filter_mkdir).unwrap_err(),
+            (1, FATError::NotADirectory)
+        );
+        assert_eq!(
+            fat.resolve("a", FAT::filter_find_file).unwrap_err(),
+            (0, FATError::IsADirectory)
+        );
+    }
+
+    #[test]
+    fn enforces_owner_and_mode() {
+        let mut fat = formatted();
+        fat.new_file("open", Cursor::new(b"anyone")).unwrap();
+        fat.set_user(Some(1000));
+        fat.mkdir("home").unwrap();
+        fat.new_file("home/f", Cursor::new(b"secret")).unwrap();
+        assert_eq!(
+            fat.permissions("home/f")
+                .unwrap()
+                .map(|p| (p.owner, p.mode)),
+            Some((1000, 0o644))
+        );
+        fat.chmod("home/f", 0o600).unwrap();
+        assert_eq!(fat.chown("home/f", 1001), Err(FATError::PermissionDenied));
+
+        fat.set_user(Some(1001));
+        let mut data = vec![];
+        assert_eq!(
+            fat.cat("home/f", &mut data),
+            Err(FATError::PermissionDenied)
+        );
+        assert_eq!(
+            fat.write_at("home/f", 0, b"x"),
+            Err(FATError::PermissionDenied)
+        );
+        assert_eq!(fat.remove_file("home/f"), Err(FATError::PermissionDenied));
+        assert_eq!(
+            fat.new_file("home/g", Cursor::new(b"")),
+            Err(FATError::PermissionDenied)
+        );
+        assert_eq!(fat.chmod("home/f", 0o666), Err(FATError::PermissionDenied));
+        fat.cat("open", &mut data).unwrap();
+
+        fat.set_user(Some(0));
+        fat.chown("home/f", 1001).unwrap();
+        fat.set_user(Some(1001));
+        fat.cat("home/f", &mut data).unwrap();
+        assert_eq!(data, b"anyonesecret");
+    }
+
+    #[test]
+    fn reads_byte_ranges() {
+        let mut fat = formatted();
+        let cluster_size = fat.cluster_size();
+
+        let contents: Vec<u8> = (0..cluster_size * 5).map(|i| (i % 251) as u8).collect();
+        fat.new_file("f", Cursor::new(&contents)).unwrap();
+        let mut whole = vec![];
+        fat.cat("f", &mut whole).unwrap();
+
+        let mut range = vec![];
+        fat.reset_stats();
+        fat.read_at("f", cluster_size as u64 * 3 + 10, 20, &mut range)
+            .unwrap();
+        assert_eq!(
+            range,
+            &contents[cluster_size * 3 + 10..cluster_size * 3 + 30]
+        );
+        assert!(fat.stats().sector_reads < whole.len() as u64 / 512);
+
+        let size = contents.len() as u64;
+        for (offset, length) in [(0, cluster_size as u64 + 1), (size - 1, 2), (10, u64::MAX)] {
+            let mut range = vec![];
+            fat.read_at("f", offset, length, &mut range).unwrap();
+            let end = offset.saturating_add(length).min(size);
+            assert_eq!(range, &contents[offset as usize..end as usize]);
+        }
+
+        let mut range = vec![];
+        fat.read_at("f", contents.len() as u64, 10, &mut range)
+            .unwrap();
+        assert!(range.is_empty());
+
+        fat.new_file("s", Cursor::new(b"head")).unwrap();
+        fat.write_at("s", cluster_size as u64 * 4 - 2, b"tail")
+            .unwrap();
+        let mut sparse = vec![];
+        fat.cat("s", &mut sparse).unwrap();
+        let mut range = vec![];
+        fat.read_at("s", 2, cluster_size as u64 * 4, &mut range)
+            .unwrap();
+        assert_eq!(range, &sparse[2..]);
+    }
+
+    #[test]
+    fn reads_and_writes_raw_blocks() {
+        let mut fat = formatted();
+        fat.new_file("f", Cursor::new(b"hello world")).unwrap();
+        let cluster = fat.find_file("f", FAT::filter_find_file).unwrap().cluster();
+
+        let bytes = fat.raw_read(raw::Block::Cluster(cluster)).unwrap();
+        assert_eq!(&bytes[..11], b"hello world");
+        fat.raw_write(raw::Block::Cluster(cluster), b"HELLO")
+            .unwrap();
+        let mut data = vec![];
+        fat.cat("f", &mut data).unwrap();
+        assert_eq!(data, b"HELLO world");
+
+        let sector = fat.cluster_to_sector(cluster);
+        assert_eq!(
+            &fat.raw_read(raw::Block::Sector(sector)).unwrap()[..5],
+            b"HELLO"
+        );
+        assert_eq!(
+            fat.raw_write(raw::Block::Sector(0), b"x"),
+            Err(FATError::Protected)
+        );
+        let past = fat.header.as_ref().unwrap().sector_count();
+        assert!(fat.raw_read(raw::Block::Sector(past)).is_err());
+        assert!(fat
+            .raw_write(
+                raw::Block::Cluster(cluster),
+                &vec![0; fat.cluster_size() + 1]
+            )
+            .is_err());
+
+        fat.set_user(Some(5));
+        assert_eq!(
+            fat.raw_read(raw::Block::Cluster(cluster)),
+            Err(FATError::PermissionDenied)
+        );
+    }
+
+    #[test]
+    fn dumps_the_fat() {
+        let mut fat = formatted();
+        let data = vec![1; 3 * fat.cluster_size()];
+        fat.new_file("f", Cursor::new(&data)).unwrap();
+        let first = fat.find_file("f", FAT::filter_find_file).unwrap().cluster();
+        fat.bug("f", Corruption::Bad).unwrap();
+
+        let links: Vec<_> = fat
+            .fat_entries(first, 4)
+            .unwrap()
+            .into_iter()
+            .map(|(_, value)| fat.link(value))
+            .collect();
+        assert_eq!(
+            links,
+            [
+                dumpfat::Link::Next(first + 1),
+                dumpfat::Link::Bad,
+                dumpfat::Link::End,
+                dumpfat::Link::Free
+            ]
+        );
+        assert_eq!(fat.link(u32::MAX - 5), dumpfat::Link::Invalid);
+        assert!(fat.fat_entries(0, 4).unwrap().is_empty());
+    }
+
+    #[test]
+    fn dumps_every_directory_slot() {
+        let mut fat = formatted();
+        fat.mkdir("d").unwrap();
+        fat.new_file("d/a", Cursor::new(b"a")).unwrap();
+        fat.new_file("d/b", Cursor::new(b"b")).unwrap();
+        fat.remove_file("d/a").unwrap();
+
+        let slots = fat.dump_dir("d").unwrap();
+        assert_eq!(slots.len(), fat.cluster_size() / 32);
+        let states: Vec<_> = slots.iter().take(5).map(|slot| slot.state()).collect();
+        use dumpdir::SlotState::*;
+        assert_eq!(states, [Live, Live, Deleted, Live, Free]);
+        assert_eq!(slots[2].entry().unwrap().name(), "a");
+        assert!(slots[3].to_string().contains("name=\"b\" size=1"));
+    }
+
+    #[test]
+    fn counts_io_until_reset() {
+        let mut fat = formatted();
+        fat.reset_stats();
+        assert_eq!(fat.stats(), IoStats::default());
+
+        let data = vec![7; 2 * fat.cluster_size()];
+        fat.new_file("f", Cursor::new(&data)).unwrap();
+        fat.remove_file("f").unwrap();
+
+        let stats = fat.stats();
+        assert_eq!(stats.clusters_allocated, 2);
+        assert_eq!(stats.clusters_freed, 2);
+        assert!(stats.sector_reads > 0 && stats.sector_writes > 0);
+        assert!(stats.fat_reads > 0 && stats.fat_writes > 0);
+        assert!(stats.cache_hits > 0 && stats.cache_misses > 0);
+
+        fat.reset_stats();
+        assert_eq!(fat.stats(), IoStats::default());
+    }
+
+    #[test]
+    fn bench_leaves_the_image_as_it_was() {
+        let mut fat = formatted();
+        let before = fat.usage().unwrap();
+
+        let phases = fat.bench(1 << 20, 300).unwrap();
+        let names = phases.iter().map(|phase| phase.name).collect::<Vec<_>>();
+        assert_eq!(names, ["create", "read", "overwrite", "delete"]);
+        assert!(phases.iter().all(|phase| phase.ops == 300));
+
+        assert_eq!(fat.usage().unwrap().free, before.free);
+        assert!(fat.find_file(bench::SCRATCH, FAT::filter_find).is_err());
+    }
+
+    #[test]
+    fn exports_the_tree_as_tar() {
+        let mut fat = formatted();
+        fat.mkdir("docs").unwrap();
+        fat.new_file("docs/a.txt", Cursor::new(b"hello tar".to_vec()))
+            .unwrap();
+        fat.new_file("b", Cursor::new(vec![7; 600])).unwrap();
+
+        let mut archive = vec![];
+        fat.export_tar(".", &mut archive).unwrap();
+        assert_eq!(archive.len() % 512, 0);
+
+        let mut members = vec![];
+        let mut offset = 0;
+        while archive[offset..offset + 512].iter().any(|&byte| byte != 0) {
+            let block = &archive[offset..offset + 512];
+            let name = String::from_utf8_lossy(&block[..100])
+                .trim_end_matches('\0')
+                .to_string();
+            let size =
+                u64::from_str_radix(std::str::from_utf8(&block[124..135]).unwrap(), 8).unwrap();
+            let sum: u32 = block[..148]
+                .iter()
+                .chain(&[b' '; 8])
+                .chain(&block[156..])
+                .map(|&byte| byte as u32)
+                .sum();
+            assert_eq!(
+                u32::from_str_radix(std::str::from_utf8(&block[148..154]).unwrap(), 8).unwrap(),
+                sum
+            );
+
+            offset += 512;
+            members.push((name, archive[offset..offset + size as usize].to_vec()));
+            offset += (size as usize).div_ceil(512) * 512;
+        }
+        members.sort();
+        assert_eq!(
+            members,
+            [
+                (String::from("b"), vec![7; 600]),
+                (String::from("docs/"), vec![]),
+                (String::from("docs/a.txt"), b"hello tar".to_vec()),
+            ]
+        );
+
+        let mut subtree = vec![];
+        fat.export_tar("docs/a.txt", &mut subtree).unwrap();
+        assert_eq!(&subtree[..6], b"a.txt\0");
+        assert!(fat.export_tar("missing", io::sink()).is_err());
+    }
+
+    #[test]
+    fn imports_an_exported_tree_or_nothing() {
+        let mut fat = formatted();
+        fat.mkdir("docs").unwrap();
+        fat.new_file("docs/a.txt", Cursor::new(b"hello tar".to_vec()))
+            .unwrap();
+        fat.mkrandom("docs/big", 3 * fat.cluster_size() as u64 + 1, 1)
+            .unwrap();
+        let mut archive = vec![];
+        fat.export_tar("docs", &mut archive).unwrap();
+
+        fat.mkdir("copy").unwrap();
+        let mut imported = vec![];
+        fat.import_tar("copy", Cursor::new(&archive), |path, _| {
+            imported.push(path.to_string())
+        })
+        .unwrap();
+        assert_eq!(imported.len(), 3);
+        for name in ["a.txt", "big"] {
+            let (mut original, mut copy) = (vec![], vec![]);
+            fat.cat(&format!("docs/{name}"), &mut original).unwrap();
+            fat.cat(&format!("copy/docs/{name}"), &mut copy).unwrap();
+            assert_eq!(original, copy);
+        }
+
+        // does not fit: nothing may be left behind
+        fat.mkdir("full").unwrap();
+        let free = fat.usage().unwrap().free;
+        let mut huge = vec![];
+        fat.mkrandom("huge", free / 3 * 2, 2).unwrap();
+        fat.export_tar("huge", &mut huge).unwrap();
+        let before = fat.usage().unwrap().free;
+        assert_eq!(
+            fat.import_tar("full", Cursor::new(&huge), |_, _| {}),
+            Err(FATError::NotEnoughSpace)
+        );
+        assert_eq!(fat.usage().unwrap().free, before);
+        assert_eq!(fat.read_dir("full").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn exports_a_recreation_script() {
+        let mut fat = formatted();
+        fat.mkdir("docs").unwrap();
+        fat.new_file("docs/a.txt", Cursor::new(b"alpha")).unwrap();
+        fat.chmod("docs/a.txt", 0o600).unwrap();
+        fat.set_xattr("docs", "mime", "text/plain").unwrap();
+
+        let mut script = vec![];
+        let mut files = vec![];
+        fat.export_script(&mut script, "data", |name| {
+            files.push(name.to_string());
+            Ok(vec![])
+        })
+        .unwrap();
+
+        assert_eq!(files, ["docs/a.txt"]);
+        assert_eq!(
+            String::from_utf8(script).unwrap(),
+            "mkdir /docs\nxattr set /docs mime text/plain\nincp data/docs/a.txt /docs/a.txt\nchmod 600 /docs/a.txt\nchown 0 /docs/a.txt\n"
+        );
+
+        let mut contents = vec![];
+        let mut file = Some(&mut contents);
+        fat.export_script(io::sink(), "data", |_| Ok(file.take().unwrap()))
+            .unwrap();
+        assert_eq!(contents, b"alpha");
+
+        fat.new_file("two words", Cursor::new(b"")).unwrap();
+        let mut created = 0;
+        assert_eq!(
+            fat.export_script(io::sink(), "data", |_| {
+                created += 1;
+                Ok(io::sink())
+            }),
+            Err(FATError::InvalidName)
+        );
+        assert_eq!(created, 0);
+    }
+
+    #[test]
+    fn builds_identical_images_when_deterministic() {
+        let build = |image: &TempImage| {
+            let mut fat = image.open();
+            fat.set_deterministic(true);
+            fat.format(Unit::MB(10), &FormatOptions::default()).unwrap();
+            fat.mkdir("docs").unwrap();
+            fat.new_file("docs/a.txt", Cursor::new(vec![7; 3 * fat.cluster_size()]))
+                .unwrap();
+            fat.new_file("b.txt", Cursor::new(b"beta")).unwrap();
+            fat.remove_file("docs/a.txt").unwrap();
+            fat.new_file("docs/c.txt", Cursor::new(b"gamma")).unwrap();
+            fat.check(check::CheckOptions::default()).unwrap();
+            fat.sync().unwrap();
+            drop(fat);
+            std::fs::read(&image.0).unwrap()
+        };
+
+        let first = TempImage::new("deterministic_first");
+        let second = TempImage::new("deterministic_second");
+        let bytes = build(&first);
+        assert!(bytes == build(&second));
+
+        let header = first.open().header.unwrap();
+        assert_eq!(header.uuid().as_bytes(), &[0; uuid::UUID_LENGTH]);
+    }
+
+    #[test]
+    fn compares_trees_by_fingerprint() {
+        let mut fat = formatted();
+        fat.mkdir("docs").unwrap();
+        fat.mkdir("docs/sub").unwrap();
+        fat.new_file("docs/a.txt", Cursor::new(b"alpha")).unwrap();
+        fat.new_file("docs/sub/b.txt", Cursor::new(b"beta"))
+            .unwrap();
+
+        let tree = fat.fingerprints("docs").unwrap();
+        assert_eq!(
+            tree.keys().collect::<Vec<_>>(),
+            ["a.txt", "sub", "sub/b.txt"]
+        );
+        assert_eq!(tree["sub"], None);
+        assert_eq!(tree["a.txt"], Some((5, diff::hash(b"alpha"))));
+
+        let mut host = tree.clone();
+        host.remove("sub/b.txt");
+        host.insert("a.txt".to_string(), Some((5, diff::hash(b"alphA"))));
+        host.insert("c.txt".to_string(), Some((0, diff::hash(b""))));
+        assert_eq!(
+            diff::compare(&tree, &host),
+            [
+                diff::Difference::Changed("a.txt".to_string()),
+                diff::Difference::Added("c.txt".to_string()),
+                diff::Difference::Removed("sub/b.txt".to_string()),
+            ]
+        );
+        assert_eq!(fat.fingerprints("docs/a.txt"), Err(FATError::NotADirectory));
+    }
+
+    #[test]
+    fn dumps_and_compares_metadata() {
+        let mut fat = formatted();
+        fat.mkdir("docs").unwrap();
+        fat.new_file("docs/a.txt", Cursor::new(b"alpha")).unwrap();
+        fat.new_file("b.txt", Cursor::new(b"beta")).unwrap();
+
+        let before = fat.meta().unwrap();
+        assert_eq!(before[0].path, "/");
+        let text = meta::to_json(&before);
+        assert_eq!(text.lines().count(), before.len() + 2);
+        assert_eq!(meta::from_json(&text).unwrap(), before);
+        assert_eq!(meta::from_json("{}"), None);
+
+        fat.remove_file("b.txt").unwrap();
+        fat.write_at("docs/a.txt", 5, &vec![1; fat.cluster_size()])
+            .unwrap();
+        fat.mkdir("new").unwrap();
+        let changes = meta::compare_meta(&before, &fat.meta().unwrap());
+
+        assert_eq!(changes[0], meta::Change::Removed("/b.txt".to_string()));
+        let meta::Change::Changed { path, fields } = &changes[1] else {
+            panic!("{changes:?}");
+        };
+        assert_eq!(path, "/docs/a.txt");
+        assert!(fields[0].starts_with("size 5 → "));
+        assert!(fields.last().unwrap().starts_with("chain "));
+        assert_eq!(changes[2], meta::Change::Added("/new".to_string()));
+    }
+
+    /// Memory storage with byte ranges that fail reads, and ones that drop
+    /// what is written to them.
+    #[derive(Default)]
+    struct Media {
+        memory: disk::Memory,
+        unreadable: Vec<Range<u64>>,
+        forgetful: Vec<Range<u64>>,
+        /// Syncs left before the power is cut. While set, writes only reach
+        /// `memory` when synced, and the ones pending at the cut are lost.
+        syncs: Option<usize>,
+        pending: Vec<(u64, Vec<u8>)>,
+    }
+
+    /// [`Media`] that can still be changed while an image is on it.
+    #[derive(Clone, Default)]
+    struct Flaky(Arc<Mutex<Media>>);
+
+    impl disk::Backend for Flaky {
+        fn len(&self) -> io::Result<u64> {
+            disk::Backend::len(&self.0.lock().unwrap().memory)
+        }
+
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+            let media = &mut *self.0.lock().unwrap();
+            if media.unreadable.iter().any(|range| range.contains(&offset)) {
+                return Err(io::ErrorKind::Other.into());
+            }
+            disk::Backend::read_at(&mut media.memory, offset, buf)?;
+            for (at, data) in &media.pending {
+                disk::copy_overlap(*at, data, offset, buf);
+            }
+            Ok(())
+        }
+
+        fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+            let media = &mut *self.0.lock().unwrap();
+            if media.forgetful.iter().any(|range| range.contains(&offset)) {
+                return Ok(());
+            }
+            match media.syncs {
+                Some(0) => Err(io::ErrorKind::Other.into()),
+                Some(_) => {
+                    media.pending.push((offset, buf.to_vec()));
+                    Ok(())
+                }
+                None => disk::Backend::write_at(&mut media.memory, offset, buf),
+            }
+        }
+
+        fn set_len(&mut self, len: u64) -> io::Result<()> {
+            disk::Backend::set_len(&mut self.0.lock().unwrap().memory, len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            let media = &mut *self.0.lock().unwrap();
+            let Some(syncs) = media.syncs.as_mut() else {
+                return Ok(());
+            };
+            if *syncs == 0 {
+                media.pending.clear();
+                return Err(io::ErrorKind::Other.into());
+            }
+            *syncs -= 1;
+            for (offset, data) in std::mem::take(&mut media.pending) {
+                disk::Backend::write_at(&mut media.memory, offset, &data)?;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn marks_failing_clusters_bad() {
+        let flaky = Flaky::default();
+        let mut fat = formatted_on(Box::new(flaky.clone()), &FormatOptions::default());
+        fat.new_file("a.txt", Cursor::new(b"alpha")).unwrap();
+        let used = fat
+            .find_file("a.txt", FAT::filter_find_file)
+            .unwrap()
+            .cluster();
+
+        let offset = |fat: &FAT, cluster| fat.sector_to_byte(fat.cluster_to_sector(cluster));
+        let (free, forgetful) = (used + 10, used + 20);
+        {
+            let media = &mut *flaky.0.lock().unwrap();
+            for cluster in [used, free] {
+                media
+                    .unreadable
+                    .push(offset(&fat, cluster)..offset(&fat, cluster) + 1);
+            }
+            media
+                .forgetful
+                .push(offset(&fat, forgetful)..offset(&fat, forgetful) + 1);
+        }
+
+        let free_before = fat.usage().unwrap().free;
+        let report = fat.badblocks(false).unwrap();
+        assert_eq!(report.marked, [free]);
+        assert_eq!(report.in_use, [used]);
+        let (_, value) = fat.fat_entries(free, 1).unwrap()[0];
+        assert_eq!(fat.link(value), dumpfat::Link::Bad);
+
+        let report = fat.badblocks(true).unwrap();
+        assert_eq!(report.marked, [forgetful]);
+        assert_eq!(report.known, 1);
+        let lost = 2 * fat.cluster_size() as u64;
+        assert_eq!(fat.usage().unwrap().free, free_before - lost);
+    }
+
+    #[test]
+    fn round_trips_through_zip() {
+        let mut fat = formatted();
+        fat.mkdir("docs").unwrap();
+        fat.new_file("docs/a.txt", Cursor::new(b"hello zip".to_vec()))
+            .unwrap();
+        let mut archive = vec![];
+        fat.export_zip("docs", &mut archive).unwrap();
+
+        fat.mkdir("copy").unwrap();
+        fat.import_zip("copy", Cursor::new(&archive), |_, _| {})
+            .unwrap();
+        let mut data = vec![];
+        fat.cat("copy/docs/a.txt", &mut data).unwrap();
+        assert_eq!(data, b"hello zip");
+
+        // raw DEFLATE with fixed codes, as zip tools write small files
+        let deflated = [
+            75, 76, 74, 78, 132, 33, 133, 140, 212, 156, 156, 124, 8, 9, 0,
+        ];
+        assert_eq!(
+            inflate::inflate(&deflated, 100).unwrap(),
+            b"abcabcabcabc hello hello"
+        );
+        assert_eq!(inflate::inflate(&deflated, 10), None);
+    }
+
+    #[test]
+    fn mkrandom_is_deterministic_per_seed() {
+        let mut fat = formatted();
+        let size = 3 * fat.cluster_size() as u64 + 5;
+        fat.mkrandom("a", size, 7).unwrap();
+        fat.mkrandom("b", size, 7).unwrap();
+        fat.mkrandom("c", size, 8).unwrap();
+
+        let mut contents = ["a", "b", "c"].map(|name| {
+            let mut data = vec![];
+            fat.cat(name, &mut data).unwrap();
+            data
+        });
+        assert_eq!(contents[0].len() as u64, size);
+        assert_eq!(contents[0], contents[1]);
+        assert_ne!(contents[0], contents[2]);
+
+        let mut random = random::RandomData::new(7, size);
+        random.seek(SeekFrom::Start(size - 9)).unwrap();
+        let mut tail = vec![];
+        random.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, contents[0].split_off(size as usize - 9));
+    }
+
+    #[test]
+    fn reports_broken_invariants() {
+        use invariants::Violation;
+
+        let mut fat = formatted();
+        fat.mkdir("d").unwrap();
+        fat.mkdir("d/e").unwrap();
+        let data = vec![1; 3 * fat.cluster_size()];
+        for name in ["d/a", "d/e/b", "c"] {
+            fat.new_file(name, Cursor::new(&data)).unwrap();
+        }
+        fat.reflink("c", "d/r").unwrap();
+        assert_eq!(fat.verify_invariants().unwrap(), []);
+
+        fat.bug("d/a", Corruption::Size(1)).unwrap();
+        fat.bug("d/e/b", Corruption::Cycle).unwrap();
+        let violations = fat.verify_invariants().unwrap();
+        assert!(violations.contains(&Violation::ChainLength {
+            path: "/d/a".to_string(),
+            expected: 1,
+            actual: 3,
+        }));
+        assert!(violations
+            .iter()
+            .any(|violation| matches!(violation, Violation::UnterminatedChain { path, .. } if path == "/d/e/b")));
+
+        fat.bug("c", Corruption::Orphan).unwrap();
+        assert!(fat
+            .verify_invariants()
+            .unwrap()
+            .iter()
+            .any(|violation| matches!(violation, Violation::LeakedCluster { .. })));
+    }
+
+    #[test]
+    fn opens_untrusted_bytes_without_panicking() {
+        assert!(FAT::open_untrusted(Box::new(disk::Memory::new(vec![0xFF; 4096]))).is_err());
+
+        let mut fat = FAT::with_backend(Box::new(disk::Memory::default())).unwrap();
+        fat.format(Unit::MB(1), &FormatOptions::default()).unwrap();
+        fat.new_file("f", Cursor::new(b"data")).unwrap();
+        let mut bytes = fat.image_bytes().unwrap();
+        assert!(FAT::open_untrusted(Box::new(disk::Memory::new(bytes[..4096].to_vec()))).is_err());
+
+        // point `f` at cluster 0, which is not a data cluster
+        let root = fat.sector_to_byte(fat.cluster_to_sector(1)) as usize;
+        let slot = (0..bytes.len() / 32)
+            .map(|index| root + index * 32)
+            .find(|offset| bytes[*offset..].starts_with(b"f\0"))
+            .unwrap();
+        bytes[slot + 16..slot + 20].clone_from_slice(&0u32.to_le_bytes());
+
+        let mut fat = FAT::open_untrusted(Box::new(disk::Memory::new(bytes))).unwrap();
+        assert_eq!(fat.cat("f", io::sink()), Err(FATError::CannotRead));
+        assert!(!fat.verify_invariants().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reads_a_real_fat12_floppy() {
+        let mut bytes = vec![0; 720 * 512];
+        bytes[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+        bytes[11..13].copy_from_slice(&512u16.to_le_bytes());
+        bytes[13] = 1;
+        bytes[14..16].copy_from_slice(&1u16.to_le_bytes());
+        bytes[16] = 2;
+        bytes[17..19].copy_from_slice(&16u16.to_le_bytes());
+        bytes[19..21].copy_from_slice(&720u16.to_le_bytes());
+        bytes[22..24].copy_from_slice(&3u16.to_le_bytes());
+        bytes[43..54].copy_from_slice(b"FLOPPY     ");
+        bytes[510..512].copy_from_slice(&[0x55, 0xAA]);
+        // clusters 2 -> 3 -> end, packed 12 bits each
+        bytes[512..518].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0x03, 0xF0, 0xFF]);
+
+        let root = 7 * 512;
+        let mut long = [0xFF; 32];
+        long[0] = 0x41;
+        long[11] = 0x0F;
+        long[12] = 0;
+        long[26..28].fill(0);
+        for (at, unit) in [1, 3, 5, 7, 9, 14, 16, 18, 20, 22]
+            .into_iter()
+            .zip("Hello.txt\0".encode_utf16())
+        {
+            long[at..at + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        bytes[root..root + 32].copy_from_slice(&long);
+        let short = root + 32;
+        bytes[short..short + 11].copy_from_slice(b"HELLO   TXT");
+        bytes[short + 11] = 0x20;
+        bytes[short + 26..short + 28].copy_from_slice(&2u16.to_le_bytes());
+        bytes[short + 28..short + 32].copy_from_slice(&600u32.to_le_bytes());
+        let content: Vec<u8> = (0..600).map(|index| index as u8).collect();
+        bytes[8 * 512..8 * 512 + 600].copy_from_slice(&content);
+
+        let mut fat = FAT::with_backend(Box::new(disk::Memory::new(bytes))).unwrap();
+        let volume = fat.foreign().unwrap();
+        assert_eq!(volume.fat_type, msdos::FatType::Fat12);
+        assert_eq!(volume.label, "FLOPPY");
+
+        let names: Vec<_> = fat
+            .read_dir(".")
+            .unwrap()
+            .iter()
+            .map(|entry| entry.name().to_string())
+            .collect();
+        assert_eq!(names, [".", "..", "Hello.txt"]);
+
+        let mut data = vec![];
+        fat.cat("hello.TXT", &mut data).unwrap();
+        assert_eq!(data, content);
+        assert_eq!(fat.chain_of(2).unwrap(), [2, 3]);
+    }
+
+    #[test]
+    fn converts_to_fat32_and_back() {
+        let mut fat = formatted();
+        fat.mkdir("docs").unwrap();
+        let content: Vec<u8> = (0..5000).map(|index| (index * 7) as u8).collect();
+        fat.new_file("docs/notes.md", Cursor::new(&content))
+            .unwrap();
+        fat.new_file("README.TXT", Cursor::new(b"hi")).unwrap();
+
+        let mut image = Cursor::new(vec![]);
+        let len = fat.convert_to_fat32(&mut image).unwrap();
+        assert_eq!(image.get_ref().len() as u64, len);
+
+        let mut fat32 = FAT::with_backend(Box::new(disk::Memory::new(image.into_inner()))).unwrap();
+        assert_eq!(fat32.foreign().unwrap().fat_type, msdos::FatType::Fat32);
+        let mut data = vec![];
+        fat32.cat("docs/notes.md", &mut data).unwrap();
+        assert_eq!(data, content);
+
+        let mut native = FAT::with_backend(Box::new(disk::Memory::default())).unwrap();
+        fat32.convert_to_native(&mut native).unwrap();
+        let mut data = vec![];
+        native.cat("README.TXT", &mut data).unwrap();
+        assert_eq!(data, b"hi");
+        assert!(native
+            .new_file("docs/more", Cursor::new(b"writable"))
+            .is_ok());
+    }
+
+    #[test]
+    fn copies_a_file_into_another_image() {
+        let mut source = formatted();
+        let content: Vec<u8> = (0..20000).map(|index| (index % 251) as u8).collect();
+        source.new_file("data", Cursor::new(&content)).unwrap();
+
+        let options = FormatOptions {
+            cluster_size: 1024,
+            ..FormatOptions::default()
+        };
+        let mut target = formatted_on(Box::new(disk::Memory::default()), &options);
+        source.copy_to("data", &mut target, "copy").unwrap();
+        assert_eq!(
+            source.copy_to("data", &mut target, "copy"),
+            Err(FATError::FileExists)
+        );
+
+        let mut data = vec![];
+        target.cat("copy", &mut data).unwrap();
+        assert_eq!(data, content);
+    }
+
+    #[test]
+    fn removes_a_whole_tree() {
+        let mut fat = formatted();
+        let free = fat.usage().unwrap().free;
+        fat.mkdir("a").unwrap();
+        fat.mkdir("a/b").unwrap();
+        fat.new_file("a/b/f", Cursor::new(vec![1; 10000])).unwrap();
+        fat.new_file("a/g", Cursor::new(b"g")).unwrap();
+
+        assert_eq!(fat.remove_dir("a"), Err(FATError::DirNotEmpty));
+        fat.remove_tree("a").unwrap();
+        assert!(fat.find_file("a", FAT::filter_find).is_err());
+        assert_eq!(fat.usage().unwrap().free, free);
+    }
+
+    #[test]
+    fn reopens_an_image_kept_in_memory() {
+        let mut fat = formatted();
+        fat.mkdir("docs").unwrap();
+        fat.new_file("docs/a.txt", Cursor::new(b"in memory"))
+            .unwrap();
+        let bytes = fat.image_bytes().unwrap();
+
+        let mut fat = FAT::with_backend(Box::new(disk::Memory::new(bytes))).unwrap();
+        let mut data = vec![];
+        fat.cat("docs/a.txt", &mut data).unwrap();
+        assert_eq!(data, b"in memory");
+    }
+
+    fn unhex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&text[index..index + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn matches_xchacha20_poly1305_test_vector() {
+        use chacha20poly1305::{aead::AeadInPlace, KeyInit, XChaCha20Poly1305, XNonce};
+
+        // draft-irtf-cfrg-xchacha-03, A.3.1
+        let key: Vec<_> = (0x80..0xA0).collect();
+        let nonce: Vec<_> = (0x40..0x58).collect();
+        let aad = unhex("50515253c0c1c2c3c4c5c6c7");
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+
+        let mut data = plaintext.to_vec();
+        let tag = XChaCha20Poly1305::new(key.as_slice().into())
+            .encrypt_in_place_detached(XNonce::from_slice(&nonce), &aad, &mut data)
+            .unwrap();
+        assert_eq!(&data[..16], unhex("bd6d179d3e83d43b9576579493c0e939"));
+        assert_eq!(tag.as_slice(), unhex("c0875924c1c7987947deafd8780acf49"));
+    }
+
+    #[test]
+    fn matches_pbkdf2_sha256_test_vector() {
+        // RFC 7914, section 11
+        let mut key = [0; 64];
+        pbkdf2::pbkdf2_hmac::<sha2::Sha256>(b"passwd", b"salt", 1, &mut key);
+        assert_eq!(
+            key.to_vec(),
+            unhex(concat!(
+                "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc",
+                "49ca9cccf179b645991664b39d77ef317c71b845b1e30bd509112041d3a19783"
+            ))
+        );
+    }
+
+    #[test]
+    fn seals_clusters_to_their_place() {
+        let key = [7; crypto::KEY_LENGTH];
+        let plaintext = vec![0x5A; 512];
+
+        let mut data = plaintext.clone();
+        let seal = crypto::seal_cluster(&key, 3, &mut data).unwrap();
+        assert_ne!(data, plaintext);
+
+        let mut opened = data.clone();
+        crypto::open_cluster(&key, 3, &seal, &mut opened).unwrap();
+        assert_eq!(opened, plaintext);
+
+        let mut moved = data.clone();
+        assert!(matches!(
+            crypto::open_cluster(&key, 4, &seal, &mut moved),
+            Err(FATError::ChecksumMismatch)
+        ));
+
+        let mut tampered = data;
+        tampered[100] ^= 1;
+        assert!(matches!(
+            crypto::open_cluster(&key, 3, &seal, &mut tampered),
+            Err(FATError::ChecksumMismatch)
+        ));
+    }
+
+    #[test]
+    fn rejects_tampered_clusters_of_an_encrypted_image() {
+        let options = FormatOptions {
+            passphrase: Some("secret".to_string()),
+            ..FormatOptions::default()
+        };
+        let image = TempImage::new("encrypted");
+        let mut fat = image.formatted(&options);
+        fat.new_file("a", Cursor::new(b"sealed contents")).unwrap();
+        fat.sync().unwrap();
+        drop(fat);
+
+        let mut fat = image.open();
+        assert!(fat.is_locked());
+        assert!(matches!(fat.unlock("wrong"), Err(FATError::BadPassphrase)));
+        fat.unlock("secret").unwrap();
+        let mut data = vec![];
+        fat.cat("a", &mut data).unwrap();
+        assert_eq!(data, b"sealed contents");
+
+        let cluster = fat.find_file("a", FAT::filter_find_file).unwrap().cluster();
+        let mut raw = fat.read_raw_cluster(cluster).unwrap();
+        assert!(!raw.windows(6).any(|window| window == b"sealed"));
+        raw[0] ^= 1;
+        fat.write_raw_cluster(cluster, &raw).unwrap();
+        assert!(matches!(
+            fat.read_cluster(cluster),
+            Err(FATError::ChecksumMismatch)
+        ));
+    }
+
+    #[test]
+    fn repairs_an_orphaned_file_in_one_pass() {
+        let mut fat = formatted();
+        fat.new_file("x.bin", Cursor::new(vec![3; fat.cluster_size() * 2]))
+            .unwrap();
+        fat.bug("x.bin", Corruption::Orphan).unwrap();
+
+        let repair = check::CheckOptions {
+            repair: true,
+            ..check::CheckOptions::default()
+        };
+        assert!(!fat.check(repair).unwrap().findings.is_empty());
+        assert!(fat.check(repair).unwrap().findings.is_empty());
+        assert!(fat
+            .check(check::CheckOptions::default())
+            .unwrap()
+            .findings
+            .is_empty());
+        assert_eq!(fat.verify_invariants().unwrap(), []);
+        let mut data = vec![];
+        fat.cat("x.bin", &mut data).unwrap();
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn replays_a_commit_torn_by_a_crash() {
+        let flaky = Flaky::default();
+        let mut fat = formatted_on(Box::new(flaky.clone()), &FormatOptions::default());
+        fat.new_file("a.txt", Cursor::new(b"alpha")).unwrap();
+        let free = fat.usage().unwrap().free;
+
+        // the power goes once the commit record is synced, before any block
+        // of the transaction is home
+        flaky.0.lock().unwrap().syncs = Some(2);
+        assert!(fat.copy("a.txt", "b.txt").is_err());
+        drop(fat);
+
+        let memory = flaky.0.lock().unwrap().memory.clone();
+        let mut fat = FAT::with_backend(Box::new(memory)).unwrap();
+        assert!(fat.find_file("b.txt", FAT::filter_find_file).is_err());
+        assert!(fat.replay_journal().unwrap() > 0);
+
+        let mut data = vec![];
+        fat.cat("b.txt", &mut data).unwrap();
+        assert_eq!(data, b"alpha");
+        assert_eq!(fat.usage().unwrap().free, free - fat.cluster_size() as u64);
+        assert!(fat
+            .check(check::CheckOptions::default())
+            .unwrap()
+            .findings
+            .is_empty());
+    }
+
+    #[test]
+    fn recounts_free_clusters_that_no_longer_add_up() {
+        let mut fat = formatted();
+        let free = fat.usage().unwrap().free;
+
+        // a stale count, as a header from another tool might leave it
+        fat.free_clusters = Some(0);
+        let last = fat.header.as_ref().unwrap().data_cluster_count();
+        fat.set_cluster_value(last, FAT::mark_bad_cluster())
+            .unwrap();
+        assert_eq!(fat.free_clusters, None);
+        assert_eq!(fat.usage().unwrap().free, free - fat.cluster_size() as u64);
+    }
+
+    #[test]
+    fn records_the_volume_uuid_in_history() {
+        let mut fat = formatted();
+        // a record kept before the UUID was
+        fat.new_file(history::HISTORY, Cursor::new(b"7\t/docs\tmkdir a\tOK\n"))
+            .unwrap();
+
+        let uuid = fat.header().map(|header| header.uuid());
+        fat.record(history::Record {
+            timestamp: 9,
+            uuid,
+            directory: "/".to_string(),
+            command: "mkdir b".to_string(),
+            result: "OK".to_string(),
+        })
+        .unwrap();
+
+        let records = fat.history().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].uuid, None);
+        assert_eq!(records[0].directory, "/docs");
+        assert_eq!(records[1].uuid, uuid);
+        assert_eq!(records[1].command, "mkdir b");
+    }
+
+    #[test]
+    fn resizes_keeping_contents_and_free_space() {
+        let mut fat = formatted();
+        let data: Vec<u8> = (0..3 * fat.cluster_size()).map(|i| i as u8).collect();
+        fat.mkdir("docs").unwrap();
+        fat.new_file("docs/a.bin", Cursor::new(&data)).unwrap();
+        let clusters = fat.header.as_ref().unwrap().data_cluster_count();
+        let free = fat.usage().unwrap().free;
+
+        fat.resize(Unit::MB(20)).unwrap();
+        let grown = fat.header.as_ref().unwrap().data_cluster_count();
+        assert!(grown > clusters);
+        assert_eq!(
+            fat.usage().unwrap().free,
+            free + (grown - clusters) as u64 * fat.cluster_size() as u64
+        );
+        let mut read = vec![];
+        fat.cat("docs/a.bin", &mut read).unwrap();
+        assert_eq!(read, data);
+
+        fat.shrink(Unit::MB(10)).unwrap();
+        assert_eq!(fat.usage().unwrap().free, free);
+        let mut read = vec![];
+        fat.cat("docs/a.bin", &mut read).unwrap();
+        assert_eq!(read, data);
+        assert!(fat
+            .check(check::CheckOptions::default())
+            .unwrap()
+            .findings
+            .is_empty());
+    }
+
+    #[test]
+    fn restores_a_snapshot_byte_for_byte() {
+        let image = TempImage::new("snapshot");
+        let mut fat = image.formatted(&FormatOptions::default());
+        let data: Vec<u8> = (0..2 * fat.cluster_size()).map(|i| i as u8).collect();
+        fat.new_file("a.bin", Cursor::new(&data)).unwrap();
+        fat.create_snapshot("before").unwrap();
+
+        fat.write_at("a.bin", 10, b"changed").unwrap();
+        fat.new_file("b.txt", Cursor::new(b"new")).unwrap();
+        fat.restore_snapshot("before").unwrap();
+
+        let mut read = vec![];
+        fat.cat("a.bin", &mut read).unwrap();
+        assert_eq!(read, data);
+        assert!(fat.find_file("b.txt", FAT::filter_find_file).is_err());
+        fat.delete_snapshot("before").unwrap();
+        assert!(fat
+            .check(check::CheckOptions::default())
+            .unwrap()
+            .findings
+            .is_empty());
+    }
+
+    #[test]
+    fn writing_a_reflink_leaves_its_source_alone() {
+        let mut fat = formatted();
+        fat.enable_reflinks().unwrap();
+        let data: Vec<u8> = (0..2 * fat.cluster_size()).map(|i| i as u8).collect();
+        fat.new_file("a.bin", Cursor::new(&data)).unwrap();
+
+        fat.reflink("a.bin", "b.bin").unwrap();
+        fat.write_at("b.bin", 10, b"changed").unwrap();
+
+        let mut read = vec![];
+        fat.cat("a.bin", &mut read).unwrap();
+        assert_eq!(read, data);
+        let mut read = vec![];
+        fat.cat("b.bin", &mut read).unwrap();
+        assert_eq!(&read[10..17], b"changed");
+        assert_eq!(read[17..], data[17..]);
+        assert!(fat
+            .check(check::CheckOptions::default())
+            .unwrap()
+            .findings
+            .is_empty());
+    }
+
+    #[test]
+    fn round_trips_compressed_files() {
+        let options = FormatOptions {
+            compress: true,
+            ..FormatOptions::default()
+        };
+        let mut fat = formatted_on(Box::new(disk::Memory::default()), &options);
+
+        let text = b"the quick brown fox jumps over the lazy dog\n".repeat(500);
+        // xorshift, so the noise does not compress
+        let mut state = 0x9E37_79B9_u32;
+        let noise: Vec<u8> = (0..20_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        fat.new_file("text.txt", Cursor::new(&text)).unwrap();
+        fat.new_file("noise.bin", Cursor::new(&noise)).unwrap();
+
+        let entry = fat.find_file("text.txt", FAT::filter_find_file).unwrap();
+        assert!(FAT::is_compressed(&entry));
+        let entry = fat.find_file("noise.bin", FAT::filter_find_file).unwrap();
+        assert!(!FAT::is_compressed(&entry));
+        for (path, data) in [("text.txt", &text), ("noise.bin", &noise)] {
+            let mut read = vec![];
+            fat.cat(path, &mut read).unwrap();
+            assert_eq!(&read, data);
+        }
+
+        fat.set_compressed("text.txt", false).unwrap();
+        let mut read = vec![];
+        fat.cat("text.txt", &mut read).unwrap();
+        assert_eq!(read, text);
+    }
+
+    #[test]
+    fn rolls_back_a_failed_batch_without_leaks() {
+        let mut fat = formatted();
+        fat.new_file("a.txt", Cursor::new(b"alpha")).unwrap();
+        let free = fat.usage().unwrap().free;
+
+        let ops = [
+            batch::FsOp::Mkdir("docs".to_string()),
+            batch::FsOp::Write {
+                path: "docs/b.bin".to_string(),
+                data: vec![7; 3 * fat.cluster_size()],
+            },
+            batch::FsOp::Remove("a.txt".to_string()),
+            batch::FsOp::Copy {
+                source: "missing".to_string(),
+                dest: "c.txt".to_string(),
+            },
+        ];
+        assert!(fat.apply(&ops).is_err());
+
+        assert!(fat.find_file("docs", FAT::filter_mkdir).is_err());
+        let mut read = vec![];
+        fat.cat("a.txt", &mut read).unwrap();
+        assert_eq!(read, b"alpha");
+        assert_eq!(fat.usage().unwrap().free, free);
+        assert!(fat
+            .check(check::CheckOptions::default())
+            .unwrap()
+            .findings
+            .is_empty());
+    }
+
+    #[test]
+    fn removes_sparse_files_through_the_trash() {
+        let mut fat = formatted();
+        let free = fat.usage().unwrap().free;
+
+        let hole = 8 * fat.cluster_size() as u64;
+        for path in ["a.bin", "b.bin"] {
+            fat.new_file(path, Cursor::new(b"head")).unwrap();
+            fat.write_at(path, hole, b"tail").unwrap();
+            let entry = fat.find_file(path, FAT::filter_find_file).unwrap();
+            assert!(FAT::is_sparse(&entry));
+        }
+
+        fat.remove_file("a.bin").unwrap();
+        fat.trash("b.bin").unwrap();
+        assert_eq!(fat.empty_trash().unwrap(), 1);
+        assert!(fat.list_trash().unwrap().is_empty());
+        assert!(fat
+            .check(check::CheckOptions::default())
+            .unwrap()
+            .findings
+            .is_empty());
+        // the trash keeps its directory
+        let trash = fat.find_file(trash::TRASH, FAT::filter_mkdir).unwrap();
+        let directory = fat.chain_of(trash.cluster()).unwrap().len() as u64;
+        assert_eq!(
+            fat.usage().unwrap().free,
+            free - directory * fat.cluster_size() as u64
+        );
+    }
+
+    #[test]
+    fn migrates_an_image_of_the_first_release() {
+        // 1000 KiB as the first release formatted it: five words, no version
+        let mut bytes = [0; HEADER_SIZE];
+        let words = [512u32, 8, 2000, 2];
+        let checksum = 0u32.wrapping_sub(words.iter().sum());
+        for (index, word) in words.into_iter().chain([checksum]).enumerate() {
+            bytes[index * size_of::<u32>()..(index + 1) * size_of::<u32>()]
+                .clone_from_slice(&word.to_le_bytes());
+        }
+        let mut fat = FAT::with_backend(Box::new(disk::Memory::default())).unwrap();
+        fat.header = Some(Header::from_raw_bytes(&bytes).unwrap());
+        fat.write_header(false).unwrap();
+
+        let data: Vec<u8> = (0..3 * fat.cluster_size()).map(|i| i as u8).collect();
+        fat.mkdir("docs").unwrap();
+        fat.new_file("docs/a.bin", Cursor::new(&data)).unwrap();
+        fat.new_file("b.txt", Cursor::new(b"beta")).unwrap();
+        assert_eq!(fat.first_data_sector(), 4);
+
+        assert_eq!(fat.migrate().unwrap(), 0);
+        let bytes = fat.image_bytes().unwrap();
+        let mut fat = FAT::with_backend(Box::new(disk::Memory::new(bytes))).unwrap();
+        assert_eq!(fat.header().unwrap().version(), header::VERSION);
+        assert!(fat.first_data_sector() > 4);
+
+        let mut read = vec![];
+        fat.cat("docs/a.bin", &mut read).unwrap();
+        assert_eq!(read, data);
+        let mut read = vec![];
+        fat.cat("b.txt", &mut read).unwrap();
+        assert_eq!(read, b"beta");
+        assert!(fat
+            .check(check::CheckOptions::default())
+            .unwrap()
+            .findings
+            .is_empty());
     }
 }
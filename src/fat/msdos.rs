@@ -0,0 +1,441 @@
+use std::{fmt::Display, io::Write};
+
+use super::{
+    dirent::{Entry, Flags},
+    disk::Disk,
+    FATError, FAT,
+};
+
+const SECTOR: usize = 512;
+/// Partition types of an MBR that hold a FAT file system.
+const FAT_PARTITIONS: [u8; 6] = [0x01, 0x04, 0x06, 0x0B, 0x0C, 0x0E];
+
+const ATTR_HIDDEN: u8 = 0x02;
+const ATTR_VOLUME: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// A genuine FAT12/16/32 volume, as on floppies and SD cards, which this
+/// crate can read but not change.
+pub struct Volume {
+    pub fat_type: FatType,
+    /// Byte offset of the volume, past the MBR of a partitioned dump.
+    pub offset: u64,
+    pub bytes_per_sector: u32,
+    pub sectors_per_cluster: u32,
+    pub cluster_count: u32,
+    pub label: String,
+    /// Sector the fixed root directory of FAT12/16 starts at.
+    root_sector: u32,
+    root_entries: u32,
+    /// First cluster of the root directory of FAT32.
+    root_cluster: u32,
+    first_data_sector: u32,
+    /// The first FAT, read once when the volume is opened.
+    fat: Vec<u8>,
+}
+
+/// An entry of a real directory, with its long name when it has one.
+#[derive(Debug, Clone)]
+struct DirEntry {
+    short: String,
+    long: Option<String>,
+    attributes: u8,
+    cluster: u32,
+    size: u32,
+}
+
+impl DirEntry {
+    /// `.` or `..` of the root, which has no such entries of its own.
+    fn root(name: &str) -> Self {
+        Self {
+            short: name.to_string(),
+            long: None,
+            attributes: ATTR_DIRECTORY,
+            cluster: 0,
+            size: 0,
+        }
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.short.eq_ignore_ascii_case(name)
+            || self
+                .long
+                .as_ref()
+                .is_some_and(|long| long.to_lowercase() == name.to_lowercase())
+    }
+
+    /// The entry as listings show it: by its long name where that fits an
+    /// [`Entry`], by its short one otherwise.
+    fn to_entry(&self) -> Option<Entry> {
+        let mut flags = Flags::Occupied as u32;
+        if self.attributes & ATTR_DIRECTORY != 0 {
+            flags |= Flags::Directory as u32;
+        }
+        let name = match &self.long {
+            Some(long) if long.len() <= 12 => long,
+            _ => &self.short,
+        };
+        Entry::new(name, self.size as u64, self.cluster, flags)
+    }
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+/// `NAME.EXT` of an 8.3 entry, lowercased where Windows NT marks a part so.
+fn short_name(raw: &[u8]) -> String {
+    let part = |bytes: &[u8], lower: bool| {
+        let part: String = bytes.iter().map(|&byte| byte as char).collect();
+        let part = part.trim_end().to_string();
+        if lower {
+            part.to_lowercase()
+        } else {
+            part
+        }
+    };
+    let mut name = part(&raw[..8], raw[12] & 0x08 != 0);
+    if raw[0] == 0x05 {
+        name.replace_range(..1, "\u{E5}");
+    }
+    let ext = part(&raw[8..11], raw[12] & 0x10 != 0);
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(&ext);
+    }
+    name
+}
+
+impl Volume {
+    /// Looks for a FAT boot sector at the start of `disk`, or at the start of
+    /// the first FAT partition when the disk begins with an MBR.
+    pub fn detect(disk: &mut Disk) -> Option<Self> {
+        let mut sector = [0; SECTOR];
+        disk.read_at(0, &mut sector).ok()?;
+        if let Some(volume) = Self::from_boot_sector(disk, &sector, 0) {
+            return Some(volume);
+        }
+
+        if u16_at(&sector, 510) != 0xAA55 {
+            return None;
+        }
+        (0..4).find_map(|index| {
+            let partition = &sector[446 + 16 * index..462 + 16 * index];
+            if !FAT_PARTITIONS.contains(&partition[4]) {
+                return None;
+            }
+            let offset = u32_at(partition, 8) as u64 * SECTOR as u64;
+            let mut boot = [0; SECTOR];
+            disk.read_at(offset, &mut boot).ok()?;
+            Self::from_boot_sector(disk, &boot, offset)
+        })
+    }
+
+    /// Reads the BIOS parameter block of a boot sector, rejecting anything
+    /// a FAT driver would not mount.
+    fn from_boot_sector(disk: &mut Disk, boot: &[u8; SECTOR], offset: u64) -> Option<Self> {
+        if !matches!(boot[0], 0xEB | 0xE9) || u16_at(boot, 510) != 0xAA55 {
+            return None;
+        }
+
+        let bytes_per_sector = u16_at(boot, 11) as u32;
+        let sectors_per_cluster = boot[13] as u32;
+        let reserved = u16_at(boot, 14) as u32;
+        let fats = boot[16] as u32;
+        let root_entries = u16_at(boot, 17) as u32;
+        let total = match u16_at(boot, 19) {
+            0 => u32_at(boot, 32),
+            total => total as u32,
+        };
+        let sectors_per_fat = match u16_at(boot, 22) {
+            0 => u32_at(boot, 36),
+            sectors => sectors as u32,
+        };
+
+        if !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || fats == 0
+            || sectors_per_fat == 0
+        {
+            return None;
+        }
+
+        let root_sector = fats.checked_mul(sectors_per_fat)?.checked_add(reserved)?;
+        let root_sectors = (root_entries * 32).div_ceil(bytes_per_sector);
+        let first_data_sector = root_sector.checked_add(root_sectors)?;
+        let cluster_count = total.checked_sub(first_data_sector)? / sectors_per_cluster;
+        // the type follows from the cluster count alone
+        let fat_type = match cluster_count {
+            0..4085 => FatType::Fat12,
+            4085..65525 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        if (fat_type == FatType::Fat32) != (root_entries == 0) {
+            return None;
+        }
+
+        let label_at = if fat_type == FatType::Fat32 { 71 } else { 43 };
+        let label = String::from_utf8_lossy(&boot[label_at..label_at + 11])
+            .trim_end()
+            .to_string();
+
+        let fat_bytes = match fat_type {
+            FatType::Fat12 => ((cluster_count as usize + 2) * 3).div_ceil(2),
+            FatType::Fat16 => (cluster_count as usize + 2) * 2,
+            FatType::Fat32 => (cluster_count as usize + 2) * 4,
+        };
+        let fat_offset = offset + (reserved * bytes_per_sector) as u64;
+        if fat_bytes as u64 > sectors_per_fat as u64 * bytes_per_sector as u64
+            || fat_offset + fat_bytes as u64 > disk.len().ok()?
+        {
+            return None;
+        }
+        let mut fat = vec![0; fat_bytes];
+        disk.read_at(fat_offset, &mut fat).ok()?;
+
+        Some(Self {
+            fat_type,
+            offset,
+            bytes_per_sector,
+            sectors_per_cluster,
+            cluster_count,
+            label,
+            root_sector,
+            root_entries,
+            root_cluster: if fat_type == FatType::Fat32 {
+                u32_at(boot, 44)
+            } else {
+                0
+            },
+            first_data_sector,
+            fat,
+        })
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        (self.bytes_per_sector * self.sectors_per_cluster) as usize
+    }
+
+    fn is_data_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+
+    /// The cluster after `cluster`, or `None` at the end of the chain.
+    fn next(&self, cluster: u32) -> Option<u32> {
+        let index = cluster as usize;
+        let (next, end) = match self.fat_type {
+            FatType::Fat12 => {
+                let pair = u16_at(&self.fat, index + index / 2) as u32;
+                let next = if cluster & 1 == 1 {
+                    pair >> 4
+                } else {
+                    pair & 0xFFF
+                };
+                (next, 0xFF8)
+            }
+            FatType::Fat16 => (u16_at(&self.fat, index * 2) as u32, 0xFFF8),
+            FatType::Fat32 => (u32_at(&self.fat, index * 4) & 0x0FFF_FFFF, 0x0FFF_FFF8),
+        };
+        (next < end && self.is_data_cluster(next)).then_some(next)
+    }
+
+    /// Clusters of the chain starting at `start`, cut short where it loops.
+    pub fn chain(&self, start: u32) -> Vec<u32> {
+        let mut chain = vec![];
+        let mut cluster = Some(start).filter(|&cluster| self.is_data_cluster(cluster));
+        while let Some(current) = cluster {
+            if chain.len() > self.cluster_count as usize {
+                break;
+            }
+            chain.push(current);
+            cluster = self.next(current);
+        }
+        chain
+    }
+
+    fn sector_offset(&self, sector: u32) -> u64 {
+        self.offset + sector as u64 * self.bytes_per_sector as u64
+    }
+
+    fn read_cluster(&self, disk: &mut Disk, cluster: u32) -> Result<Vec<u8>, FATError> {
+        let sector = self.first_data_sector + (cluster - 2) * self.sectors_per_cluster;
+        let mut buffer = vec![0; self.cluster_size()];
+        disk.read_at(self.sector_offset(sector), &mut buffer)
+            .map_err(|_| FATError::CannotRead)?;
+        Ok(buffer)
+    }
+
+    /// Raw entries of the directory at `cluster`, where 0 stands for the root
+    /// as it does in `..` entries.
+    fn read_dir(&self, disk: &mut Disk, cluster: u32) -> Result<Vec<DirEntry>, FATError> {
+        let cluster = if cluster == 0 {
+            self.root_cluster
+        } else {
+            cluster
+        };
+        let bytes = if cluster == 0 {
+            let mut bytes = vec![0; self.root_entries as usize * 32];
+            disk.read_at(self.sector_offset(self.root_sector), &mut bytes)
+                .map_err(|_| FATError::CannotRead)?;
+            bytes
+        } else {
+            let mut bytes = vec![];
+            for cluster in self.chain(cluster) {
+                bytes.extend(self.read_cluster(disk, cluster)?);
+            }
+            bytes
+        };
+
+        let mut entries = vec![];
+        let mut long: Vec<(u8, Vec<u16>)> = vec![];
+        for raw in bytes.chunks_exact(32) {
+            match raw[0] {
+                0x00 => break,
+                0xE5 => {
+                    long.clear();
+                    continue;
+                }
+                _ => {}
+            }
+
+            if raw[11] & 0x3F == ATTR_LONG_NAME {
+                let units = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30]
+                    .iter()
+                    .map(|&at| u16_at(raw, at))
+                    .take_while(|&unit| unit != 0)
+                    .collect();
+                long.push((raw[0] & 0x1F, units));
+                continue;
+            }
+            if raw[11] & ATTR_VOLUME != 0 {
+                long.clear();
+                continue;
+            }
+
+            // the parts come last first
+            long.sort_by_key(|(order, _)| *order);
+            let units: Vec<u16> = long.drain(..).flat_map(|(_, units)| units).collect();
+            let cluster = if self.fat_type == FatType::Fat32 {
+                ((u16_at(raw, 20) as u32) << 16) | u16_at(raw, 26) as u32
+            } else {
+                u16_at(raw, 26) as u32
+            };
+            entries.push(DirEntry {
+                short: short_name(raw),
+                long: (!units.is_empty()).then(|| String::from_utf16_lossy(&units)),
+                attributes: raw[11],
+                // `..` of a child of the FAT32 root names the root by 0 too
+                cluster: if cluster == self.root_cluster {
+                    0
+                } else {
+                    cluster
+                },
+                size: u32_at(raw, 28),
+            });
+        }
+
+        if cluster == self.root_cluster {
+            entries.splice(0..0, [DirEntry::root("."), DirEntry::root("..")]);
+        }
+        Ok(entries)
+    }
+}
+
+impl Display for Volume {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.fat_type {
+            FatType::Fat12 => "FAT12",
+            FatType::Fat16 => "FAT16",
+            FatType::Fat32 => "FAT32",
+        };
+        writeln!(f, "{kind} volume (read-only)")?;
+        writeln!(f, "Label: {}", self.label)?;
+        writeln!(f, "Offset: {} B", self.offset)?;
+        writeln!(f, "Cluster size: {} B", self.cluster_size())?;
+        writeln!(f, "Clusters: {}", self.cluster_count)
+    }
+}
+
+impl FAT {
+    /// The real FAT volume this image holds instead of a native one.
+    pub fn foreign(&self) -> Option<&Volume> {
+        self.foreign.as_ref()
+    }
+
+    fn foreign_entries(&mut self, cluster: u32) -> Result<Vec<DirEntry>, FATError> {
+        let volume = self.foreign.as_ref().ok_or(FATError::CannotRead)?;
+        volume.read_dir(&mut self.disk, cluster)
+    }
+
+    pub(super) fn foreign_find(
+        &mut self,
+        path: &str,
+        filter: fn(&Entry) -> bool,
+    ) -> Result<Entry, FATError> {
+        let mut current = DirEntry::root(".");
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            if current.attributes & ATTR_DIRECTORY == 0 {
+                return Err(FATError::FileNotFound);
+            }
+            current = self
+                .foreign_entries(current.cluster)?
+                .into_iter()
+                .find(|entry| entry.matches(name))
+                .ok_or(FATError::FileNotFound)?;
+        }
+
+        current
+            .to_entry()
+            .filter(filter)
+            .ok_or(FATError::FileNotFound)
+    }
+
+    pub(super) fn foreign_read_dir(&mut self, path: &str) -> Result<Vec<Entry>, FATError> {
+        let dir = self.foreign_find(path, Self::filter_mkdir)?;
+        Ok(self
+            .foreign_entries(dir.cluster())?
+            .iter()
+            .filter(|entry| entry.attributes & ATTR_HIDDEN == 0)
+            .filter_map(DirEntry::to_entry)
+            .collect())
+    }
+
+    pub(super) fn foreign_read<T: Write>(
+        &mut self,
+        entry: &Entry,
+        mut outfile: T,
+    ) -> Result<(), FATError> {
+        let volume = self.foreign.as_ref().ok_or(FATError::CannotRead)?;
+
+        let mut left = entry.size();
+        for cluster in volume.chain(entry.cluster()) {
+            if left == 0 {
+                break;
+            }
+            let data = volume.read_cluster(&mut self.disk, cluster)?;
+            let n = left.min(data.len() as u64) as usize;
+            outfile
+                .write_all(&data[..n])
+                .map_err(|_| FATError::CannotWrite)?;
+            left -= n as u64;
+        }
+
+        if left != 0 {
+            return Err(FATError::CannotRead);
+        }
+        Ok(())
+    }
+}
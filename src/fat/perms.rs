@@ -0,0 +1,185 @@
+use std::fmt::Display;
+
+use super::{
+    dirent::{Entry, Flags},
+    FATError, FAT,
+};
+
+/// Mode given to files created while a user is set.
+pub const FILE_MODE: u16 = 0o644;
+/// Mode given to directories created while a user is set.
+pub const DIR_MODE: u16 = 0o755;
+
+/// Where the mode and the owner of an entry flagged [`Flags::Owned`] sit in
+/// its flags word.
+const MODE_SHIFT: u32 = 7;
+const OWNER_SHIFT: u32 = 16;
+/// Bits of the flags word ownership takes up.
+pub const OWNERSHIP: u32 = Flags::Owned as u32 | 0o777 << MODE_SHIFT | 0xFFFF << OWNER_SHIFT;
+
+/// Owner and rwx mode of an entry, as `chown` and `chmod` set them.
+///
+/// There are no groups: the owner bits apply to the owner and the bits for
+/// others to everyone else, the group bits are kept but never consulted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    pub owner: u16,
+    pub mode: u16,
+}
+
+/// What an operation does to an entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read = 0o4,
+    Write = 0o2,
+}
+
+impl Permissions {
+    pub fn from_flags(flags: u32) -> Option<Self> {
+        (flags & Flags::Owned as u32 != 0).then_some(Self {
+            owner: (flags >> OWNER_SHIFT) as u16,
+            mode: (flags >> MODE_SHIFT) as u16 & 0o777,
+        })
+    }
+
+    /// The ownership bits of a flags word.
+    pub fn to_flags(self) -> u32 {
+        Flags::Owned as u32
+            | ((self.mode & 0o777) as u32) << MODE_SHIFT
+            | (self.owner as u32) << OWNER_SHIFT
+    }
+
+    /// Whether `user` may `access` the entry.
+    pub fn allows(&self, user: u16, access: Access) -> bool {
+        let bits = if user == self.owner {
+            self.mode >> 6
+        } else {
+            self.mode
+        };
+        bits & access as u16 != 0
+    }
+}
+
+impl Display for Permissions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let letters: String = (0..9)
+            .map(|bit| {
+                if self.mode & (0o400 >> bit) == 0 {
+                    '-'
+                } else {
+                    ['r', 'w', 'x'][bit % 3]
+                }
+            })
+            .collect();
+        write!(f, "{} {letters}", self.owner)
+    }
+}
+
+impl FAT {
+    /// Who operations are checked for; `None` or 0 may do anything.
+    pub fn user(&self) -> Option<u16> {
+        self.user
+    }
+
+    pub fn set_user(&mut self, user: Option<u16>) {
+        self.user = user;
+    }
+
+    pub(super) fn is_root(&self) -> bool {
+        self.user.is_none_or(|user| user == 0)
+    }
+
+    /// Ownership flags for a new entry: the current user with the default
+    /// mode, none when no user is set.
+    pub(super) fn new_ownership(&self, dir: bool) -> u32 {
+        self.user.map_or(0, |owner| {
+            Permissions {
+                owner,
+                mode: if dir { DIR_MODE } else { FILE_MODE },
+            }
+            .to_flags()
+        })
+    }
+
+    /// Fails with [`FATError::PermissionDenied`] unless the user may `access`
+    /// the entry at `path`. Paths that do not resolve pass, for the operation
+    /// to report; entries without an owner and the root directory are open
+    /// to everyone.
+    pub(super) fn check_access(&mut self, path: &str, access: Access) -> Result<(), FATError> {
+        if self.is_root() || self.foreign.is_some() {
+            return Ok(());
+        }
+        let user = self.user.unwrap_or_default();
+
+        match self.find_file(path, Self::filter_find) {
+            Ok(entry) if entry.permissions().is_some_and(|p| !p.allows(user, access)) => {
+                Err(FATError::PermissionDenied)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Like `check_access` for the directory holding `path`.
+    pub(super) fn check_parent_access(
+        &mut self,
+        path: &str,
+        access: Access,
+    ) -> Result<(), FATError> {
+        let (dir, _) = Self::split_path(path);
+        self.check_access(dir, access)
+    }
+
+    pub fn permissions(&mut self, path: &str) -> Result<Option<Permissions>, FATError> {
+        Ok(self.find_file(path, Self::filter_find)?.permissions())
+    }
+
+    /// Gives the entry at `path` to `owner`, keeping its mode or giving it the
+    /// default one. Only root may.
+    pub fn chown(&mut self, path: &str, owner: u16) -> Result<(), FATError> {
+        if !self.is_root() {
+            return Err(FATError::PermissionDenied);
+        }
+        self.update_permissions(path, |entry| {
+            let mode = match entry.permissions() {
+                Some(permissions) => permissions.mode,
+                None if Self::filter_mkdir(entry) => DIR_MODE,
+                None => FILE_MODE,
+            };
+            Permissions { owner, mode }
+        })
+    }
+
+    /// Sets the mode of the entry at `path`; an entry without an owner
+    /// becomes the current user's. Only its owner and root may.
+    pub fn chmod(&mut self, path: &str, mode: u16) -> Result<(), FATError> {
+        let user = self.user.unwrap_or_default();
+        let root = self.is_root();
+        let current = self.permissions(path)?;
+        if !root && current.is_some_and(|permissions| permissions.owner != user) {
+            return Err(FATError::PermissionDenied);
+        }
+
+        self.update_permissions(path, |entry| Permissions {
+            owner: entry
+                .permissions()
+                .map_or(user, |permissions| permissions.owner),
+            mode: mode & 0o777,
+        })
+    }
+
+    fn update_permissions<F: Fn(&Entry) -> Permissions>(
+        &mut self,
+        path: &str,
+        permissions: F,
+    ) -> Result<(), FATError> {
+        self.check_protected(path)?;
+        let (dir, filename) = Self::split_path(path);
+        let dir = self.find_file(dir, Self::filter_mkdir)?;
+        self.update_file_in_dir(
+            &dir,
+            |entry| entry.name() == filename && entry.flags() & Flags::Occupied as u32 != 0,
+            |entry| entry.set_permissions(Some(permissions(entry))),
+        )?;
+        Ok(())
+    }
+}
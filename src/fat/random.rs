@@ -0,0 +1,71 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use super::{FATError, FAT};
+
+/// `len` bytes of pseudo-random data, the same for the same seed.
+///
+/// Every 8 bytes are derived from their position alone, so the data can be
+/// read from anywhere without generating what comes before it.
+pub struct RandomData {
+    seed: u64,
+    len: u64,
+    position: u64,
+}
+
+impl RandomData {
+    pub fn new(seed: u64, len: u64) -> Self {
+        Self {
+            seed,
+            len,
+            position: 0,
+        }
+    }
+
+    /// SplitMix64 of the `index`-th word.
+    fn word(&self, index: u64) -> u64 {
+        let mut z = self
+            .seed
+            .wrapping_add(index.wrapping_add(1).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+impl Read for RandomData {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.len.saturating_sub(self.position).min(buf.len() as u64) as usize;
+
+        let mut filled = 0;
+        while filled < n {
+            let word = self.word(self.position / 8).to_le_bytes();
+            let within = (self.position % 8) as usize;
+            let take = (8 - within).min(n - filled);
+            buf[filled..filled + take].clone_from_slice(&word[within..within + take]);
+            filled += take;
+            self.position += take as u64;
+        }
+
+        Ok(n)
+    }
+}
+
+impl Seek for RandomData {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or(io::ErrorKind::InvalidInput)?;
+        Ok(self.position)
+    }
+}
+
+impl FAT {
+    /// Creates `path` with `size` bytes of [`RandomData`] from `seed`, without
+    /// staging them anywhere first.
+    pub fn mkrandom(&mut self, path: &str, size: u64, seed: u64) -> Result<(), FATError> {
+        self.new_file(path, RandomData::new(seed, size))
+    }
+}
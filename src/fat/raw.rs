@@ -0,0 +1,74 @@
+use super::{disk::Disk, header::HEADER_SIZE, FATError, FAT};
+
+/// A block of the image addressed directly, for `raw`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Block {
+    Sector(u64),
+    Cluster(u32),
+}
+
+impl FAT {
+    /// The bytes of `block` as stored, still encrypted on encrypted images
+    /// and not checked against any checksum. Only root may.
+    pub fn raw_read(&mut self, block: Block) -> Result<Vec<u8>, FATError> {
+        if !self.is_root() {
+            return Err(FATError::PermissionDenied);
+        }
+        match block {
+            Block::Sector(sector) => {
+                self.check_raw_sector(sector)?;
+                self.read_sector(sector)
+            }
+            Block::Cluster(cluster) => self.read_raw_cluster(cluster),
+        }
+    }
+
+    /// Overwrites `block` from its start with `data`, keeping the bytes past
+    /// its end. Nothing is checked or kept consistent: only the checksum of a
+    /// data cluster is updated, so that it reads back. Only root may.
+    ///
+    /// The sectors of the header are refused, as the header held in memory
+    /// would be written back over them.
+    pub fn raw_write(&mut self, block: Block, data: &[u8]) -> Result<(), FATError> {
+        let mut bytes = self.raw_read(block)?;
+        if data.len() > bytes.len() {
+            return Err(FATError::FileTooLarge);
+        }
+        bytes[..data.len()].copy_from_slice(data);
+
+        match block {
+            Block::Sector(sector) => {
+                if self.sector_to_byte(sector) < HEADER_SIZE as u64 {
+                    return Err(FATError::Protected);
+                }
+                self.write_sector(sector, &bytes)?;
+                // the sector may be one of the FAT
+                self.free_clusters = None;
+                Ok(())
+            }
+            Block::Cluster(cluster) => self.write_raw_cluster(cluster, &bytes),
+        }
+    }
+
+    /// Byte access to the whole image, e.g. to export it as a block device.
+    ///
+    /// Everything held back is written out first. Whatever is written changes
+    /// the image under the filesystem, so it must not be used through `self`
+    /// afterwards.
+    pub fn export(&mut self) -> Result<&mut Disk, FATError> {
+        self.sync()?;
+        Ok(&mut self.disk)
+    }
+
+    fn check_raw_sector(&self, sector: u64) -> Result<(), FATError> {
+        let count = self
+            .header
+            .as_ref()
+            .expect("Image is not formatted!")
+            .sector_count();
+        if sector >= count {
+            return Err(FATError::CannotRead);
+        }
+        Ok(())
+    }
+}
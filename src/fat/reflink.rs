@@ -0,0 +1,162 @@
+use std::{collections::HashMap, mem::size_of};
+
+use super::{
+    header::{Header, HeaderError},
+    FATError, FAT,
+};
+
+impl FAT {
+    pub(super) fn has_refcounts(&self) -> bool {
+        self.header
+            .as_ref()
+            .is_some_and(|header| header.has_refcounts())
+    }
+
+    fn refcount_offset(&self, cluster: u32) -> u64 {
+        let header = self.header.as_ref().expect("Image is not formatted!");
+        self.sector_to_byte(header.refcount_sector()) + cluster as u64 * size_of::<u32>() as u64
+    }
+
+    /// Number of directory entries sharing `cluster` besides its first owner.
+    pub(super) fn refcount(&mut self, cluster: u32) -> Result<u32, FATError> {
+        if !self.has_refcounts() {
+            return Ok(0);
+        }
+
+        let mut bytes = [0; size_of::<u32>()];
+        self.disk
+            .read_at(self.refcount_offset(cluster), &mut bytes)
+            .map_err(|_| FATError::CannotRead)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub(super) fn set_refcount(&mut self, cluster: u32, value: u32) -> Result<(), FATError> {
+        self.disk
+            .write_journaled(self.refcount_offset(cluster), &value.to_le_bytes())
+            .map_err(|_| FATError::CannotWrite)
+    }
+
+    /// Creates `dest` as a new entry sharing the cluster chain of `source`.
+    pub fn reflink(&mut self, source: &str, dest: &str) -> Result<(), FATError> {
+        if !self.has_refcounts() {
+            return Err(FATError::CannotWrite);
+        }
+
+        self.check_protected(dest)?;
+        if self.find_file(dest, Self::filter_find).is_ok() {
+            return Err(FATError::FileExists);
+        }
+
+        let mut entry = self.find_file(source, Self::filter_find_file)?;
+        let (dir, filename) = Self::split_path(dest);
+        let dir = self.find_file(dir, Self::filter_mkdir)?;
+        entry.set_name(filename)?;
+
+        let chain = self.chain_of(entry.cluster())?;
+        self.insert_entry(dir.cluster(), entry)?;
+
+        for cluster in chain {
+            let count = self.refcount(cluster)?;
+            self.set_refcount(cluster, count + 1)?;
+        }
+
+        Ok(())
+    }
+
+    /// Drops one owner of the chain at `start` if it is shared.
+    ///
+    /// Returns false, touching nothing, when the chain has a single owner and
+    /// may be freed.
+    pub(super) fn release_shared(&mut self, start: u32) -> Result<bool, FATError> {
+        if self.refcount(start)? == 0 {
+            return Ok(false);
+        }
+
+        for cluster in self.chain_of(start)? {
+            let count = self.refcount(cluster)?;
+            self.set_refcount(cluster, count.saturating_sub(1))?;
+        }
+
+        Ok(true)
+    }
+
+    /// Gives the file at `path` a private copy of a shared chain, so it can be
+    /// written in place without the other owners seeing the change.
+    pub(super) fn unshare(&mut self, path: &str) -> Result<(), FATError> {
+        let entry = self.find_file(path, Self::filter_find_file)?;
+        if self.refcount(entry.cluster())? == 0 {
+            return Ok(());
+        }
+
+        let chain = self.chain_of(entry.cluster())?;
+        let head = self.allocate_clusters(chain.len() as u32)?;
+
+        let mut target = head;
+        for cluster in &chain {
+            let data = self.read_cluster(*cluster)?;
+            self.write_cluster(target, &data)?;
+            target = self.next_cluster(target)?;
+        }
+
+        let (dir, filename) = Self::split_path(path);
+        let dir = self.find_file(dir, Self::filter_mkdir)?;
+        self.update_file_in_dir(
+            &dir,
+            |other| other.name() == filename && Self::filter_find_file(other),
+            |other| other.set_cluster(head),
+        )?;
+
+        self.release_shared(entry.cluster())?;
+        Ok(())
+    }
+
+    /// Recounts the owners of every cluster from the directory tree.
+    pub(super) fn rebuild_refcounts(&mut self) -> Result<(), FATError> {
+        if !self.has_refcounts() {
+            return Ok(());
+        }
+
+        let mut owners: HashMap<u32, u32> = HashMap::new();
+        for located in self.located_entries()? {
+            for cluster in located.chain {
+                *owners.entry(cluster).or_default() += 1;
+            }
+        }
+
+        let header = self.header.as_ref().expect("Image is not formatted!");
+        let mut table = vec![0; header.refcount_sectors() as usize * self.bytes_per_sector()];
+        for (cluster, count) in owners {
+            let offset = cluster as usize * size_of::<u32>();
+            if let Some(slot) = table.get_mut(offset..offset + size_of::<u32>()) {
+                slot.clone_from_slice(&(count - 1).to_le_bytes());
+            }
+        }
+
+        let offset = self.refcount_offset(0);
+        self.disk
+            .write_at(offset, &table)
+            .map_err(|_| FATError::CannotWrite)
+    }
+
+    /// Adds the reference count table to an image formatted without one.
+    pub fn enable_reflinks(&mut self) -> Result<(), HeaderError> {
+        let old: Header = self.header.clone().expect("Image is not formatted!");
+        if old.has_refcounts() {
+            return Ok(());
+        }
+
+        let mut new = old.clone();
+        new.enable_refcounts();
+
+        // the table is inserted before the data region, which pushes the last
+        // clusters past the end of the image unless they are moved
+        match self.evacuate(new.data_cluster_count()) {
+            Ok(()) => {}
+            Err(FATError::NotEnoughSpace) => return Err(HeaderError::BadCapacity),
+            Err(_) => return Err(HeaderError::CannotWrite),
+        }
+
+        self.relayout(&old, &new)
+            .map_err(|_| HeaderError::CannotWrite)
+    }
+}
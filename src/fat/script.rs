@@ -0,0 +1,81 @@
+use std::io::{self, Write};
+
+use super::{FATError, FAT};
+
+/// A script line of `words`, which must each be a single word as `load`
+/// splits lines on whitespace; only the last may hold single spaces.
+fn line(words: &[&str]) -> Result<String, FATError> {
+    let (last, rest) = words.split_last().ok_or(FATError::InvalidName)?;
+    let broken = |word: &str| word.is_empty() || word.contains(char::is_whitespace);
+    if rest.iter().any(|word| broken(word))
+        || last.split_whitespace().collect::<Vec<_>>().join(" ") != *last
+    {
+        return Err(FATError::InvalidName);
+    }
+    Ok(words.join(" ") + "\n")
+}
+
+impl FAT {
+    /// Writes to `script` the commands that rebuild the tree in a freshly
+    /// formatted image when run with `load`: `mkdir` and `incp` for every
+    /// directory and file, then `attr`, `chmod`, `chown` and `xattr` for what
+    /// is set on them.
+    ///
+    /// The contents of each file go to what `create` opens for its path
+    /// relative to the root; the `incp` lines name it as that path under the
+    /// host directory `data`.
+    ///
+    /// Fails with [`FATError::InvalidName`] for a name or attribute a script
+    /// line cannot hold, e.g. one with a space, before `create` is called for
+    /// any file.
+    pub fn export_script<W: Write, D: Write, F: FnMut(&str) -> io::Result<D>>(
+        &mut self,
+        mut script: W,
+        data: &str,
+        mut create: F,
+    ) -> Result<(), FATError> {
+        // every line is checked before anything is written, so that a name
+        // the script cannot hold leaves no data files behind
+        let mut lines = vec![];
+        for member in self.archive_members(".")? {
+            let name = member.name.trim_end_matches('/').to_string();
+            let path = format!("/{name}");
+
+            let mut text = if member.dir {
+                line(&["mkdir", &path])?
+            } else {
+                line(&["incp", &format!("{data}/{name}"), &path])?
+            };
+
+            let stat = self.stat(&member.path)?;
+            if stat.compressed {
+                text += &line(&["attr", "+c", &path])?;
+            }
+            if let Some(permissions) = stat.permissions {
+                let mode = format!("{:o}", permissions.mode);
+                text += &line(&["chmod", &mode, &path])?;
+                let owner = permissions.owner.to_string();
+                text += &line(&["chown", &owner, &path])?;
+            }
+            for (key, value) in self.list_xattrs(&member.path)? {
+                text += &line(&["xattr", "set", &path, &key, &value])?;
+            }
+
+            let file = (!member.dir).then_some((member.path, name));
+            lines.push((file, text));
+        }
+
+        for (file, text) in lines {
+            if let Some((path, name)) = file {
+                let mut file = create(&name).map_err(|_| FATError::CannotWrite)?;
+                self.cat(&path, &mut file)?;
+                file.flush().map_err(|_| FATError::CannotWrite)?;
+            }
+            script
+                .write_all(text.as_bytes())
+                .map_err(|_| FATError::CannotWrite)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,216 @@
+use std::{collections::HashMap, fmt::Display, mem::size_of};
+
+use super::{
+    integrity::{crc32, Mismatch},
+    FATError, FAT,
+};
+
+/// Where an intact copy of a corrupted region was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    FatCopy(u32),
+    /// Another cluster recorded with the same checksum.
+    Cluster(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scrubbed {
+    pub mismatch: Mismatch,
+    /// Set when the region was repaired.
+    pub source: Option<Source>,
+}
+
+impl Display for Scrubbed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.source {
+            None => write!(f, "{}", self.mismatch),
+            Some(Source::FatCopy(copy)) => {
+                write!(f, "{}, repaired from FAT copy {copy}", self.mismatch)
+            }
+            Some(Source::Cluster(cluster)) => {
+                write!(f, "{}, repaired from cluster {cluster}", self.mismatch)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ScrubReport {
+    pub fat_sectors: u32,
+    pub clusters: u32,
+    pub found: Vec<Scrubbed>,
+}
+
+impl Display for ScrubReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let repaired = self
+            .found
+            .iter()
+            .filter(|scrubbed| scrubbed.source.is_some())
+            .count();
+        write!(
+            f,
+            "{} FAT sector(s), {} cluster(s) scrubbed: {} mismatch(es), {} repaired",
+            self.fat_sectors,
+            self.clusters,
+            self.found.len(),
+            repaired
+        )
+    }
+}
+
+impl FAT {
+    /// Whether every cluster and FAT sector read is checked against the
+    /// checksum table.
+    pub fn verify(&self) -> bool {
+        self.verify
+    }
+
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    fn stored_checksum(&mut self, slot: u64) -> Result<u32, FATError> {
+        let header = self.header.as_ref().expect("Image is not formatted!");
+        let offset = self.sector_to_byte(header.checksum_sector()) + slot * size_of::<u32>() as u64;
+
+        let mut bytes = [0; size_of::<u32>()];
+        self.disk
+            .read_at(offset, &mut bytes)
+            .map_err(|_| FATError::CannotRead)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    /// Whether `bytes`, read from the FAT sector containing `cluster`, match
+    /// the checksum table.
+    pub(super) fn fat_sector_intact(
+        &mut self,
+        cluster: u32,
+        bytes: &[u8],
+    ) -> Result<bool, FATError> {
+        if !self.verify
+            || !self
+                .header
+                .as_ref()
+                .is_some_and(|header| header.has_checksums())
+        {
+            return Ok(true);
+        }
+
+        Ok(crc32(bytes) == self.stored_checksum(self.fat_slot(cluster))?)
+    }
+
+    /// Fails a read of `cluster` whose stored `bytes` do not match the
+    /// checksum table.
+    ///
+    /// Free clusters are not covered by the table, so they always pass.
+    pub(super) fn verify_cluster(&mut self, cluster: u32, bytes: &[u8]) -> Result<(), FATError> {
+        if !self.verify
+            || !self
+                .header
+                .as_ref()
+                .is_some_and(|header| header.has_checksums())
+        {
+            return Ok(());
+        }
+
+        if crc32(bytes) == self.stored_checksum(self.cluster_slot(cluster))? {
+            return Ok(());
+        }
+
+        let per_sector = self.clusters_per_fat_sector();
+        let value = self.read_fat(cluster)?[(cluster % per_sector) as usize];
+        if Self::is_used(value) {
+            return Err(FATError::ChecksumMismatch);
+        }
+
+        Ok(())
+    }
+
+    /// Checks every FAT copy and every used cluster against the checksum
+    /// table.
+    ///
+    /// With `repair` set, corrupted FAT sectors are rewritten from a copy that
+    /// still matches, and corrupted clusters from another cluster recorded
+    /// with the same checksum.
+    pub fn scrub(&mut self, repair: bool) -> Result<ScrubReport, FATError> {
+        let header = self.header.clone().expect("Image is not formatted!");
+        let mut report = ScrubReport::default();
+        if !header.has_checksums() {
+            return Ok(report);
+        }
+
+        let table = self.read_checksums()?;
+        let per_sector = self.clusters_per_fat_sector();
+
+        for sector in 0..header.fat_sectors() {
+            let expected = table[sector as usize];
+            let mut copies = vec![];
+            for copy in 0..header.fat_count() {
+                copies.push(self.read_sector(self.fat_sector(copy, sector * per_sector))?);
+            }
+            report.fat_sectors += 1;
+
+            if copies.iter().all(|bytes| crc32(bytes) == expected) {
+                continue;
+            }
+
+            let intact = copies.iter().position(|bytes| crc32(bytes) == expected);
+            let source = match intact {
+                Some(intact) if repair => {
+                    for (copy, bytes) in copies.iter().enumerate() {
+                        if crc32(bytes) != expected {
+                            let sector = self.fat_sector(copy as u32, sector * per_sector);
+                            self.write_sector(sector, &copies[intact])?;
+                        }
+                    }
+                    Some(Source::FatCopy(intact as u32))
+                }
+                _ => None,
+            };
+
+            report.found.push(Scrubbed {
+                mismatch: Mismatch::FatSector(sector),
+                source,
+            });
+        }
+
+        let fat = self.read_whole_fat()?;
+        let mut intact = HashMap::new();
+        let mut corrupted = vec![];
+
+        for cluster in 1..=header.data_cluster_count() {
+            if !Self::is_used(fat[cluster as usize]) {
+                continue;
+            }
+            report.clusters += 1;
+
+            let expected = table[self.cluster_slot(cluster) as usize];
+            if crc32(&self.read_raw_cluster(cluster)?) == expected {
+                intact.entry(expected).or_insert(cluster);
+            } else {
+                corrupted.push(cluster);
+            }
+        }
+
+        for cluster in corrupted {
+            let expected = table[self.cluster_slot(cluster) as usize];
+            let source = match intact.get(&expected) {
+                Some(&duplicate) if repair => {
+                    // copied as stored, since that is what the checksum covers
+                    let bytes = self.read_raw_cluster(duplicate)?;
+                    self.write_raw_cluster(cluster, &bytes)?;
+                    Some(Source::Cluster(duplicate))
+                }
+                _ => None,
+            };
+
+            report.found.push(Scrubbed {
+                mismatch: Mismatch::Cluster(cluster),
+                source,
+            });
+        }
+
+        Ok(report)
+    }
+}
@@ -0,0 +1,250 @@
+use std::{collections::HashSet, fs, path::PathBuf};
+
+use super::{
+    crypto::{Seal, SEAL_LENGTH},
+    FATError, FAT,
+};
+
+const MAGIC: &[u8; 4] = b"ZSNP";
+
+/// Saved state of the whole filesystem: the FAT and every directory cluster.
+///
+/// File data is not copied. Instead, clusters a snapshot uses are held, so
+/// the allocator never hands them out again while the snapshot exists.
+/// Snapshots live next to the image, in `<image>.snapshots/`.
+struct Snapshot {
+    uuid: [u8; 16],
+    first_data_sector: u64,
+    data_clusters: u32,
+    fat: Vec<u32>,
+    directories: Vec<(u32, Vec<u8>)>,
+    /// On encrypted images directories are kept as stored on disk, along with
+    /// the seal they were encrypted under.
+    seals: Option<Vec<Seal>>,
+}
+
+impl Snapshot {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&self.uuid);
+        bytes.extend_from_slice(&self.first_data_sector.to_le_bytes());
+        bytes.extend_from_slice(&self.data_clusters.to_le_bytes());
+
+        bytes.extend_from_slice(&(self.fat.len() as u32).to_le_bytes());
+        for value in &self.fat {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+
+        bytes.extend_from_slice(&(self.directories.len() as u32).to_le_bytes());
+        for (cluster, data) in &self.directories {
+            bytes.extend_from_slice(&cluster.to_le_bytes());
+            bytes.extend_from_slice(data);
+        }
+
+        for seal in self.seals.iter().flatten() {
+            bytes.extend_from_slice(seal);
+        }
+
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8], cluster_size: usize) -> Option<Self> {
+        let mut rest = bytes.strip_prefix(MAGIC)?;
+        let mut take = |len: usize| {
+            let (head, tail) = rest.split_at_checked(len)?;
+            rest = tail;
+            Some(head)
+        };
+
+        let uuid = take(16)?.try_into().ok()?;
+        let first_data_sector = u64::from_le_bytes(take(8)?.try_into().ok()?);
+        let data_clusters = u32::from_le_bytes(take(4)?.try_into().ok()?);
+
+        let fat_len = u32::from_le_bytes(take(4)?.try_into().ok()?);
+        let fat = (0..fat_len)
+            .map(|_| Some(u32::from_le_bytes(take(4)?.try_into().ok()?)))
+            .collect::<Option<Vec<_>>>()?;
+
+        let directory_count = u32::from_le_bytes(take(4)?.try_into().ok()?);
+        let directories = (0..directory_count)
+            .map(|_| {
+                let cluster = u32::from_le_bytes(take(4)?.try_into().ok()?);
+                Some((cluster, take(cluster_size)?.to_vec()))
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        let seals = if rest.is_empty() {
+            None
+        } else {
+            let mut take = |len: usize| {
+                let (head, tail) = rest.split_at_checked(len)?;
+                rest = tail;
+                Some(head)
+            };
+            Some(
+                (0..directory_count)
+                    .map(|_| take(SEAL_LENGTH)?.try_into().ok())
+                    .collect::<Option<Vec<_>>>()?,
+            )
+        };
+
+        Some(Self {
+            uuid,
+            first_data_sector,
+            data_clusters,
+            fat,
+            directories,
+            seals,
+        })
+    }
+}
+
+impl FAT {
+    fn snapshot_path(&self, name: &str) -> Result<PathBuf, FATError> {
+        let dir = self.snapshots.as_ref().ok_or(FATError::CannotWrite)?;
+        Ok(dir.join(name))
+    }
+
+    /// Names of the snapshots taken of this image.
+    pub fn list_snapshots(&mut self) -> Result<Vec<String>, FATError> {
+        let mut names = vec![];
+        let Some(dir) = self.snapshots.clone() else {
+            return Ok(names);
+        };
+        let Ok(entries) = fs::read_dir(dir) else {
+            return Ok(names);
+        };
+
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if self.read_snapshot(&name).is_ok() {
+                names.push(name);
+            }
+        }
+
+        names.sort();
+        Ok(names)
+    }
+
+    fn read_snapshot(&mut self, name: &str) -> Result<Snapshot, FATError> {
+        let bytes = fs::read(self.snapshot_path(name)?).map_err(|_| FATError::FileNotFound)?;
+        let snapshot =
+            Snapshot::from_bytes(&bytes, self.cluster_size()).ok_or(FATError::CannotRead)?;
+
+        let header = self.header.as_ref().expect("Image is not formatted!");
+        if snapshot.uuid != *header.uuid().as_bytes() {
+            return Err(FATError::SnapshotMismatch);
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Recomputes which clusters the snapshots of this image still need.
+    pub(super) fn reload_held(&mut self) {
+        self.held.clear();
+        if self.header.is_none() {
+            return;
+        }
+
+        for name in self.list_snapshots().unwrap_or_default() {
+            if let Ok(snapshot) = self.read_snapshot(&name) {
+                for (cluster, value) in snapshot.fat.iter().enumerate().skip(1) {
+                    if Self::is_used(*value) {
+                        self.held.insert(cluster as u32);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether the allocator may hand out `cluster`, whose FAT entry is `value`.
+    pub(super) fn is_allocatable(&self, cluster: u32, value: u32) -> bool {
+        value == 0 && !self.held.contains(&cluster) && !self.freed.contains(&cluster)
+    }
+
+    pub fn create_snapshot(&mut self, name: &str) -> Result<(), FATError> {
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            return Err(FATError::FileNotFound);
+        }
+        let path = self.snapshot_path(name)?;
+        if path.exists() {
+            return Err(FATError::FileExists);
+        }
+
+        let mut clusters: HashSet<u32> = self.chain_of(1)?.into_iter().collect();
+        for (_, entry) in self.walk()? {
+            if Self::filter_mkdir(&entry) {
+                clusters.extend(self.chain_of(entry.cluster())?);
+            }
+        }
+
+        let mut clusters: Vec<u32> = clusters.into_iter().collect();
+        clusters.sort_unstable();
+        let mut directories = vec![];
+        let mut seals = vec![];
+        for cluster in clusters {
+            if self.is_encrypted() {
+                directories.push((cluster, self.read_raw_cluster(cluster)?));
+                seals.push(self.seal(cluster)?);
+            } else {
+                directories.push((cluster, self.read_cluster(cluster)?));
+            }
+        }
+
+        let header = self.header.as_ref().expect("Image is not formatted!");
+        let snapshot = Snapshot {
+            uuid: *header.uuid().as_bytes(),
+            first_data_sector: self.first_data_sector(),
+            data_clusters: header.data_cluster_count(),
+            fat: self.read_whole_fat()?,
+            directories,
+            seals: self.is_encrypted().then_some(seals),
+        };
+
+        fs::create_dir_all(path.parent().unwrap()).map_err(|_| FATError::CannotWrite)?;
+        fs::write(&path, snapshot.to_bytes()).map_err(|_| FATError::CannotWrite)?;
+
+        self.reload_held();
+        Ok(())
+    }
+
+    /// Rolls the filesystem back to the state recorded in snapshot `name`.
+    ///
+    /// The snapshot itself is kept, so it can be restored again later.
+    pub fn restore_snapshot(&mut self, name: &str) -> Result<(), FATError> {
+        let snapshot = self.read_snapshot(name)?;
+
+        let header = self.header.as_ref().expect("Image is not formatted!");
+        if snapshot.first_data_sector != self.first_data_sector()
+            || snapshot.data_clusters != header.data_cluster_count()
+        {
+            return Err(FATError::SnapshotMismatch);
+        }
+
+        let current = self.read_whole_fat()?;
+        if current.len() != snapshot.fat.len() {
+            return Err(FATError::SnapshotMismatch);
+        }
+
+        self.write_whole_fat(&current, &snapshot.fat)?;
+        for (index, (cluster, data)) in snapshot.directories.iter().enumerate() {
+            let mut data = data.clone();
+            if let Some(seals) = &snapshot.seals {
+                self.decrypt_cluster(*cluster, &seals[index], &mut data)?;
+            }
+            self.write_cluster(*cluster, &data)?;
+        }
+
+        self.reload_held();
+        self.rebuild_refcounts()
+    }
+
+    /// Drops snapshot `name`, releasing the clusters only it was holding.
+    pub fn delete_snapshot(&mut self, name: &str) -> Result<(), FATError> {
+        self.read_snapshot(name)?;
+        fs::remove_file(self.snapshot_path(name)?).map_err(|_| FATError::CannotWrite)?;
+
+        self.reload_held();
+        Ok(())
+    }
+}
@@ -0,0 +1,287 @@
+use std::{collections::BTreeMap, io::Write, mem::size_of};
+
+use super::{
+    dirent::{Entry, Flags},
+    perms::Access,
+    FATError, FAT,
+};
+
+/// Data of the clusters of a file that hold anything, by their index in the
+/// file; the clusters in between are holes.
+type Extents = BTreeMap<u32, Vec<u8>>;
+
+impl FAT {
+    pub fn is_sparse(entry: &Entry) -> bool {
+        entry.flags() & Flags::Sparse as u32 != 0
+    }
+
+    /// The map a sparse file's chain starts with: the number of clusters that
+    /// hold data, then the index in the file of each of them, in chain order.
+    ///
+    /// Returns the indices and the number of clusters the map takes up.
+    fn read_sparse_map(&mut self, chain: &[u32]) -> Result<(Vec<u32>, usize), FATError> {
+        let cluster_size = self.cluster_size();
+        let first = self.read_cluster(*chain.first().ok_or(FATError::CannotRead)?)?;
+        let count = u32::from_le_bytes(first[..size_of::<u32>()].try_into().unwrap()) as usize;
+        let map_clusters = ((count + 1) * size_of::<u32>()).div_ceil(cluster_size);
+
+        let mut map = first;
+        for cluster in chain.iter().take(map_clusters).skip(1) {
+            map.extend(self.read_cluster(*cluster)?);
+        }
+
+        let indices = map
+            .get(size_of::<u32>()..(count + 1) * size_of::<u32>())
+            .ok_or(FATError::CannotRead)?
+            .chunks(size_of::<u32>())
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        Ok((indices, map_clusters))
+    }
+
+    /// Number of bytes the chain of a sparse file holds.
+    pub(super) fn sparse_stored_size(&mut self, entry: &Entry) -> Result<u64, FATError> {
+        let (indices, map_clusters) = self.read_sparse_map(&[entry.cluster()])?;
+        Ok(((map_clusters + indices.len()) * self.cluster_size()) as u64)
+    }
+
+    /// Writes the contents of a sparse file to `outfile`, holes as zeros.
+    pub(super) fn read_sparse<T: Write>(
+        &mut self,
+        entry: &Entry,
+        mut outfile: T,
+    ) -> Result<(), FATError> {
+        let chain = self.chain_of(entry.cluster())?;
+        let (indices, map_clusters) = self.read_sparse_map(&chain)?;
+        let mut data = indices
+            .into_iter()
+            .zip(chain[map_clusters..].iter().copied());
+        let mut next = data.next();
+
+        let cluster_size = self.cluster_size() as u64;
+        let mut left = entry.size();
+        let mut index = 0;
+
+        while left > 0 {
+            let bytes = match next {
+                Some((at, cluster)) if at == index => {
+                    next = data.next();
+                    self.read_cluster(cluster)?
+                }
+                _ => self.empty_cluster(),
+            };
+
+            let limit = left.min(cluster_size);
+            outfile
+                .write_all(&bytes[..limit as usize])
+                .map_err(|_| FATError::CannotWrite)?;
+            left -= limit;
+            index += 1;
+        }
+
+        Ok(())
+    }
+
+    /// The clusters of the file that hold data.
+    fn read_extents(&mut self, entry: &Entry) -> Result<Extents, FATError> {
+        let mut extents = Extents::new();
+
+        if Self::is_sparse(entry) {
+            let chain = self.chain_of(entry.cluster())?;
+            let (indices, map_clusters) = self.read_sparse_map(&chain)?;
+            for (index, cluster) in indices.into_iter().zip(&chain[map_clusters..]) {
+                extents.insert(index, self.read_cluster(*cluster)?);
+            }
+        } else {
+            let mut data = vec![];
+            self.read_entry(entry, &mut data)?;
+            data.resize(data.len().next_multiple_of(self.cluster_size()), 0);
+            for (index, chunk) in data.chunks(self.cluster_size()).enumerate() {
+                extents.insert(index as u32, chunk.to_vec());
+            }
+        }
+
+        Ok(extents)
+    }
+
+    /// Replaces the contents of `path` with `size` bytes of which only
+    /// `extents` hold data; clusters of zeros become holes as well.
+    ///
+    /// A file without holes is stored the usual way. The new chain is written
+    /// before the old one is let go of.
+    fn store_extents(
+        &mut self,
+        path: &str,
+        size: u64,
+        mut extents: Extents,
+    ) -> Result<(), FATError> {
+        self.check_file_size(size)?;
+        let entry = self.find_file(path, Self::filter_find_file)?;
+        let cluster_size = self.cluster_size();
+        let count = size.div_ceil(cluster_size as u64) as u32;
+        extents.retain(|index, data| *index < count && data.iter().any(|byte| *byte != 0));
+
+        let mut stored = vec![];
+        let flags = if extents.len() == count as usize {
+            Flags::Occupied as u32
+        } else {
+            let mut map = (extents.len() as u32).to_le_bytes().to_vec();
+            for index in extents.keys() {
+                map.extend_from_slice(&index.to_le_bytes());
+            }
+            map.resize(map.len().next_multiple_of(cluster_size), 0);
+            stored.extend(map.chunks(cluster_size).map(|chunk| chunk.to_vec()));
+            Flags::Occupied as u32 | Flags::Sparse as u32
+        };
+        stored.extend(extents.into_values());
+
+        let head = self.allocate_clusters(stored.len() as u32)?;
+        let mut cluster = head;
+        for data in stored {
+            self.write_cluster(cluster, &data)?;
+            cluster = self.next_cluster(cluster)?;
+        }
+
+        let encoding = Flags::Occupied as u32 | Flags::Compressed as u32 | Flags::Sparse as u32;
+        let (dir, filename) = Self::split_path(path);
+        let dir = self.find_file(dir, Self::filter_mkdir)?;
+        self.update_file_in_dir(
+            &dir,
+            |other| other.name() == filename && Self::filter_find_file(other),
+            |other| {
+                other.set_cluster(head);
+                other.set_size(size);
+                other.set_flags(other.flags() & !encoding | flags);
+            },
+        )?;
+
+        self.dealloc_clusters(entry.cluster())
+    }
+
+    /// Sets the size of the file at `path`, cutting it short or extending it
+    /// with a hole that takes up no clusters.
+    pub fn truncate(&mut self, path: &str, size: u64) -> Result<(), FATError> {
+        self.transaction(|fs| {
+            let entry = fs.find_file(path, Self::filter_find_file)?;
+            fs.check_access(path, Access::Write)?;
+            let mut extents = fs.read_extents(&entry)?;
+
+            // the tail of a cluster cut in half must read back as zeros when
+            // the file grows again
+            let cluster_size = fs.cluster_size() as u64;
+            let partial = (size % cluster_size) as usize;
+            if let Some(data) = extents.get_mut(&((size / cluster_size) as u32)) {
+                data[partial..].fill(0);
+            }
+
+            fs.store_extents(path, size, extents)
+        })
+    }
+
+    /// Writes up to `length` bytes of the file at `path` from `offset` on to
+    /// `outfile`, reading only the clusters that hold them. Nothing is written
+    /// past the end of the file. Compressed files, and files of real FAT
+    /// volumes, are decoded whole first.
+    pub fn read_at<T: Write>(
+        &mut self,
+        path: &str,
+        offset: u64,
+        length: u64,
+        mut outfile: T,
+    ) -> Result<(), FATError> {
+        let entry = self.find_file(path, Self::filter_find_file)?;
+        self.check_access(path, Access::Read)?;
+
+        let end = offset.saturating_add(length).min(entry.size());
+        if offset >= end {
+            return Ok(());
+        }
+
+        if self.foreign.is_some() || Self::is_compressed(&entry) {
+            let mut data = vec![];
+            self.read_entry(&entry, &mut data)?;
+            return outfile
+                .write_all(&data[offset as usize..end as usize])
+                .map_err(|_| FATError::CannotWrite);
+        }
+
+        let cluster_size = self.cluster_size() as u64;
+        let (first, last) = (
+            (offset / cluster_size) as u32,
+            ((end - 1) / cluster_size) as u32,
+        );
+
+        // the cluster holding each index in the range, none for a hole
+        let clusters: Vec<Option<u32>> = if Self::is_sparse(&entry) {
+            let chain = self.chain_of(entry.cluster())?;
+            let (indices, map_clusters) = self.read_sparse_map(&chain)?;
+            let data: BTreeMap<_, _> = indices
+                .into_iter()
+                .zip(chain[map_clusters..].iter().copied())
+                .collect();
+            (first..=last)
+                .map(|index| data.get(&index).copied())
+                .collect()
+        } else {
+            let mut clusters = vec![];
+            self.walk_chain(entry.cluster(), |index, cluster| {
+                if index >= first {
+                    clusters.push(Some(cluster));
+                }
+                index < last
+            })?;
+            if clusters.len() != (last - first + 1) as usize {
+                return Err(FATError::CannotRead);
+            }
+            clusters
+        };
+
+        for (index, cluster) in (first..).zip(clusters) {
+            let bytes = match cluster {
+                Some(cluster) => self.read_cluster(cluster)?,
+                None => self.empty_cluster(),
+            };
+
+            let start = index as u64 * cluster_size;
+            let from = offset.max(start) - start;
+            let to = end.min(start + cluster_size) - start;
+            outfile
+                .write_all(&bytes[from as usize..to as usize])
+                .map_err(|_| FATError::CannotWrite)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes `data` into the file at `path` from `offset` on, growing it as
+    /// needed. A gap between the old end of the file and `offset` becomes a
+    /// hole.
+    pub fn write_at(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<(), FATError> {
+        let end = offset
+            .checked_add(data.len() as u64)
+            .ok_or(FATError::NotEnoughSpace)?;
+
+        self.transaction(|fs| {
+            let entry = fs.find_file(path, Self::filter_find_file)?;
+            fs.check_access(path, Access::Write)?;
+            let mut extents = fs.read_extents(&entry)?;
+            let cluster_size = fs.cluster_size();
+
+            let mut position = offset as usize;
+            let mut rest = data;
+            while !rest.is_empty() {
+                let within = position % cluster_size;
+                let n = rest.len().min(cluster_size - within);
+                let cluster = extents
+                    .entry((position / cluster_size) as u32)
+                    .or_insert_with(|| vec![0; cluster_size]);
+                cluster[within..within + n].clone_from_slice(&rest[..n]);
+
+                position += n;
+                rest = &rest[n..];
+            }
+
+            fs.store_extents(path, entry.size().max(end), extents)
+        })
+    }
+}
@@ -0,0 +1,288 @@
+use std::fmt::Display;
+
+use crate::json;
+
+use super::{
+    dirent::{Entry, Flags},
+    perms::Permissions,
+    FATError, FAT,
+};
+
+/// What `info`, `stat` and `ls -l` report about an entry.
+#[derive(Debug, Clone)]
+pub struct Stat {
+    pub name: String,
+    pub dir: bool,
+    pub size: u64,
+    /// First cluster of its chain, as the entry records it.
+    pub start: u32,
+    /// Clusters of its chain, in order.
+    pub clusters: Vec<u32>,
+    /// Bytes its chain takes up.
+    pub allocated: u64,
+    pub compressed: bool,
+    pub sparse: bool,
+    pub hidden: bool,
+    pub system: bool,
+    pub permissions: Option<Permissions>,
+}
+
+impl Stat {
+    /// Runs of consecutive clusters in the chain, as (first cluster, count).
+    pub fn extents(&self) -> Vec<(u32, u32)> {
+        let mut extents: Vec<(u32, u32)> = vec![];
+        for &cluster in &self.clusters {
+            match extents.last_mut() {
+                Some((first, count)) if *first + *count == cluster => *count += 1,
+                _ => extents.push((cluster, 1)),
+            }
+        }
+        extents
+    }
+
+    /// Names of the flags set on the entry.
+    pub fn flags(&self) -> Vec<&'static str> {
+        [
+            ("hidden", self.hidden),
+            ("system", self.system),
+            ("compressed", self.compressed),
+            ("sparse", self.sparse),
+        ]
+        .iter()
+        .filter(|(_, set)| *set)
+        .map(|(name, _)| *name)
+        .collect()
+    }
+
+    /// The flags as fixed letters, `-` where unset: d(irectory), h(idden),
+    /// s(ystem), c(ompressed), p for sparse.
+    pub fn letters(&self) -> String {
+        [
+            ('d', self.dir),
+            ('h', self.hidden),
+            ('s', self.system),
+            ('c', self.compressed),
+            ('p', self.sparse),
+        ]
+        .iter()
+        .map(|&(letter, set)| if set { letter } else { '-' })
+        .collect()
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"name\":{},\"dir\":{},\"size\":{},\"allocated\":{},\"start\":{},\"clusters\":[{}],\"extents\":[{}],\"compressed\":{},\"sparse\":{},\"hidden\":{},\"system\":{},\"owner\":{},\"mode\":{}}}",
+            json::string(&self.name),
+            self.dir,
+            self.size,
+            self.allocated,
+            self.start,
+            self.clusters
+                .iter()
+                .map(|cluster| cluster.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+            self.extents()
+                .iter()
+                .map(|(first, count)| format!("{{\"start\":{},\"count\":{}}}", first, count))
+                .collect::<Vec<_>>()
+                .join(","),
+            self.compressed,
+            self.sparse,
+            self.hidden,
+            self.system,
+            self.permissions
+                .map_or("null".to_string(), |permissions| permissions.owner.to_string()),
+            self.permissions.map_or("null".to_string(), |permissions| {
+                json::string(&format!("{:o}", permissions.mode))
+            })
+        )
+    }
+}
+
+/// How much of the data region is in use, as `df` reports it.
+#[derive(Debug, Clone, Copy)]
+pub struct Usage {
+    pub total: u64,
+    /// Bytes a new file could still be given.
+    pub free: u64,
+}
+
+impl Usage {
+    pub fn used(&self) -> u64 {
+        self.total - self.free
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"total\":{},\"used\":{},\"free\":{}}}",
+            self.total,
+            self.used(),
+            self.free
+        )
+    }
+}
+
+impl Display for Usage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "total: {} B, used: {} B, free: {} B",
+            self.total,
+            self.used(),
+            self.free
+        )
+    }
+}
+
+/// Disk and FAT traffic since the image was opened, as `stats` reports it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IoStats {
+    /// 512-byte sectors read from and written to the image.
+    pub sector_reads: u64,
+    pub sector_writes: u64,
+    /// FAT sectors read and written, counting every copy of the FAT.
+    pub fat_reads: u64,
+    pub fat_writes: u64,
+    /// FAT lookups answered by a FAT sector read earlier in the same
+    /// operation, and those that had to read one.
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub clusters_allocated: u64,
+    pub clusters_freed: u64,
+}
+
+impl IoStats {
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"sector_reads\":{},\"sector_writes\":{},\"fat_reads\":{},\"fat_writes\":{},\"cache_hits\":{},\"cache_misses\":{},\"clusters_allocated\":{},\"clusters_freed\":{}}}",
+            self.sector_reads,
+            self.sector_writes,
+            self.fat_reads,
+            self.fat_writes,
+            self.cache_hits,
+            self.cache_misses,
+            self.clusters_allocated,
+            self.clusters_freed
+        )
+    }
+}
+
+impl Display for IoStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "sector reads: {}", self.sector_reads)?;
+        writeln!(f, "sector writes: {}", self.sector_writes)?;
+        writeln!(f, "FAT reads: {}", self.fat_reads)?;
+        writeln!(f, "FAT writes: {}", self.fat_writes)?;
+        writeln!(f, "cache hits: {}", self.cache_hits)?;
+        writeln!(f, "cache misses: {}", self.cache_misses)?;
+        writeln!(f, "clusters allocated: {}", self.clusters_allocated)?;
+        write!(f, "clusters freed: {}", self.clusters_freed)
+    }
+}
+
+impl FAT {
+    pub fn stat(&mut self, path: &str) -> Result<Stat, FATError> {
+        let entry = self.find_file(path, Self::filter_find)?;
+        self.stat_entry(&entry)
+    }
+
+    /// Like `stat`, for an entry already read, e.g. by `read_dir`.
+    pub fn stat_entry(&mut self, entry: &Entry) -> Result<Stat, FATError> {
+        let clusters = self.chain_of(entry.cluster())?;
+
+        Ok(Stat {
+            name: entry.name().to_string(),
+            dir: Self::filter_mkdir(entry),
+            size: entry.size(),
+            start: entry.cluster(),
+            allocated: (clusters.len() * self.cluster_size()) as u64,
+            clusters,
+            compressed: Self::is_compressed(entry),
+            sparse: Self::is_sparse(entry),
+            hidden: entry.flags() & Flags::Hidden as u32 != 0,
+            system: entry.flags() & Flags::System as u32 != 0,
+            permissions: entry.permissions(),
+        })
+    }
+
+    /// Free and total space, answered from the free cluster count without
+    /// reading the FAT unless snapshots or an open transaction hold clusters
+    /// back.
+    pub fn usage(&mut self) -> Result<Usage, FATError> {
+        let limit = self
+            .header
+            .as_ref()
+            .expect("Image is not formatted!")
+            .data_cluster_count();
+
+        let free = match self.free_clusters {
+            Some(free) if self.held.is_empty() && self.freed.is_empty() => free as u64,
+            _ => {
+                let fat = self.read_whole_fat()?;
+                self.free_clusters = Some(
+                    (1..=limit)
+                        .filter(|cluster| fat[*cluster as usize] == 0)
+                        .count() as u32,
+                );
+                (1..=limit)
+                    .filter(|cluster| self.is_allocatable(*cluster, fat[*cluster as usize]))
+                    .count() as u64
+            }
+        };
+        let cluster_size = self.cluster_size() as u64;
+        Ok(Usage {
+            total: limit as u64 * cluster_size,
+            free: free * cluster_size,
+        })
+    }
+
+    /// The header as `fsinfo` prints it, followed by how many data clusters
+    /// are used and free.
+    pub fn header_info(&mut self) -> Result<String, FATError> {
+        let header = self
+            .header
+            .as_ref()
+            .ok_or(FATError::CannotRead)?
+            .to_string();
+        let usage = self.usage()?;
+        let cluster_size = self.cluster_size() as u64;
+
+        Ok(format!(
+            "{header}Cluster size: {cluster_size} B\nData clusters: {}\nUsed clusters: {}\nFree clusters: {}\n",
+            usage.total / cluster_size,
+            usage.used() / cluster_size,
+            usage.free / cluster_size
+        ))
+    }
+
+    /// Data clusters among those covered by the FAT sector containing
+    /// `cluster` whose entry in `values`, that sector, is zero.
+    pub(super) fn free_in_fat_sector(&self, cluster: u32, values: &[u32]) -> u32 {
+        let limit = self
+            .header
+            .as_ref()
+            .expect("Image is not formatted!")
+            .data_cluster_count();
+        let first = cluster - cluster % self.clusters_per_fat_sector();
+
+        (first..)
+            .zip(values)
+            .filter(|(cluster, value)| (1..=limit).contains(cluster) && **value == 0)
+            .count() as u32
+    }
+
+    pub fn stats(&self) -> IoStats {
+        let (sector_reads, sector_writes) = self.disk.block_counts();
+        IoStats {
+            sector_reads,
+            sector_writes,
+            ..self.io
+        }
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.disk.reset_block_counts();
+        self.io = IoStats::default();
+    }
+}
@@ -0,0 +1,237 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::time;
+
+use super::{
+    archive::{Counted, Section},
+    FATError, FAT,
+};
+
+const BLOCK: usize = 512;
+/// Largest size the 11 octal digits of a ustar header can hold.
+const MAX_USTAR_SIZE: u64 = 0o777_7777_7777;
+
+/// Writes `value` as zero-padded octal filling `field` but its last byte.
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{value:0width$o}", width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+}
+
+/// A ustar header block. `name` and `size` are truncated when they do not
+/// fit; a PAX header before it carries the real values.
+fn header(name: &str, size: u64, kind: u8, mtime: u64) -> [u8; BLOCK] {
+    let mut block = [0; BLOCK];
+    let name = &name.as_bytes()[..name.len().min(100)];
+    block[..name.len()].copy_from_slice(name);
+    octal(
+        &mut block[100..108],
+        if kind == b'5' { 0o755 } else { 0o644 },
+    );
+    octal(&mut block[108..116], 0);
+    octal(&mut block[116..124], 0);
+    octal(&mut block[124..136], size.min(MAX_USTAR_SIZE));
+    octal(&mut block[136..148], mtime);
+    block[148..156].fill(b' ');
+    block[156] = kind;
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+
+    let sum: u32 = block.iter().map(|&byte| byte as u32).sum();
+    octal(&mut block[148..155], sum as u64);
+    block[154] = 0;
+    block
+}
+
+/// Reads a numeric header field, in octal or, with the high bit of its first
+/// byte set, in base 256 as GNU tar writes large sizes.
+fn number(field: &[u8]) -> Option<u64> {
+    if field[0] & 0x80 != 0 {
+        return field[1..].iter().try_fold(0u64, |value, &byte| {
+            value.checked_mul(256)?.checked_add(byte as u64)
+        });
+    }
+    let digits = std::str::from_utf8(field).ok()?;
+    let digits = digits.trim_matches(|c| c == '\0' || c == ' ');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+fn text(field: &[u8]) -> String {
+    let end = field
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// A PAX record, whose length counts its own digits.
+fn record(key: &str, value: &str) -> String {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    format!("{len} {key}={value}\n")
+}
+
+/// Values of the `path` and `size` records of a PAX header.
+fn parse_records(data: &[u8]) -> Option<(Option<String>, Option<u64>)> {
+    let (mut path, mut size) = (None, None);
+    let mut rest = data;
+    while !rest.is_empty() {
+        let space = rest.iter().position(|&byte| byte == b' ')?;
+        let len: usize = std::str::from_utf8(&rest[..space]).ok()?.parse().ok()?;
+        let record = rest.get(space + 1..len)?.strip_suffix(b"\n")?;
+        let (key, value) = std::str::from_utf8(record).ok()?.split_once('=')?;
+        match key {
+            "path" => path = Some(value.to_string()),
+            "size" => size = Some(value.parse().ok()?),
+            _ => {}
+        }
+        rest = &rest[len..];
+    }
+    Some((path, size))
+}
+
+fn padded(len: u64) -> u64 {
+    len.div_ceil(BLOCK as u64) * BLOCK as u64
+}
+
+fn pad(out: &mut impl Write, len: u64) -> io::Result<()> {
+    let rest = (BLOCK - (len % BLOCK as u64) as usize) % BLOCK;
+    out.write_all(&[0; BLOCK][..rest])
+}
+
+/// Writes the headers of one member, with a PAX header first when its name
+/// or size does not fit ustar.
+fn header_of(out: &mut impl Write, name: &str, size: u64, kind: u8, mtime: u64) -> io::Result<()> {
+    let mut pax = String::new();
+    if name.len() > 100 {
+        pax += &record("path", name);
+    }
+    if size > MAX_USTAR_SIZE {
+        pax += &record("size", &size.to_string());
+    }
+    if !pax.is_empty() {
+        out.write_all(&header("PaxHeader", pax.len() as u64, b'x', mtime))?;
+        out.write_all(pax.as_bytes())?;
+        pad(out, pax.len() as u64)?;
+    }
+    out.write_all(&header(name, size, kind, mtime))
+}
+
+impl FAT {
+    /// Streams the directory or file at `path` into `out` as a tar archive,
+    /// named after its last component, or the whole tree when `path` is the
+    /// root. Compressed and sparse files are stored as they read.
+    pub fn export_tar<W: Write>(&mut self, path: &str, out: W) -> Result<(), FATError> {
+        let mut out = Counted::new(out);
+        let mtime = time::now();
+
+        for member in self.archive_members(path)? {
+            let kind = if member.dir { b'5' } else { b'0' };
+            header_of(&mut out, &member.name, member.size, kind, mtime)
+                .map_err(|_| FATError::CannotWrite)?;
+            if !member.dir {
+                self.write_member(&member, &mut out)?;
+                pad(&mut out, member.size).map_err(|_| FATError::CannotWrite)?;
+            }
+        }
+
+        out.write_all(&[0; 2 * BLOCK])
+            .and_then(|_| out.flush())
+            .map_err(|_| FATError::CannotWrite)
+    }
+
+    /// Creates the directories and files of the tar archive `archive` under
+    /// the directory `dest` in one pass, calling `progress` with each member
+    /// and how many bytes of the archive are done.
+    ///
+    /// Runs as one transaction, so an archive that does not fit, or that
+    /// names a file already there, leaves the image as it was. Links and
+    /// other special members are skipped.
+    pub fn import_tar<R: Read + Seek>(
+        &mut self,
+        dest: &str,
+        mut archive: R,
+        mut progress: impl FnMut(&str, u64),
+    ) -> Result<(), FATError> {
+        let dest = self.unpack_dest(dest)?;
+
+        self.transaction(|fs| {
+            let mut offset = 0;
+            let (mut long_path, mut long_size) = (None, None);
+
+            loop {
+                let mut block = [0; BLOCK];
+                archive
+                    .seek(SeekFrom::Start(offset))
+                    .and_then(|_| archive.read_exact(&mut block))
+                    .map_err(|_| FATError::CannotRead)?;
+                if block.iter().all(|&byte| byte == 0) {
+                    return Ok(());
+                }
+
+                let sum: u64 = block[..148]
+                    .iter()
+                    .chain(&[b' '; 8])
+                    .chain(&block[156..])
+                    .map(|&byte| byte as u64)
+                    .sum();
+                if number(&block[148..156]) != Some(sum) {
+                    return Err(FATError::CannotRead);
+                }
+
+                let size = match long_size.take() {
+                    Some(size) => size,
+                    None => number(&block[124..136]).ok_or(FATError::CannotRead)?,
+                };
+                let name = long_path.take().unwrap_or_else(|| {
+                    let prefix = text(&block[345..500]);
+                    let name = text(&block[..100]);
+                    if &block[257..262] == b"ustar" && !prefix.is_empty() {
+                        format!("{prefix}/{name}")
+                    } else {
+                        name
+                    }
+                });
+                let data = offset + BLOCK as u64;
+                offset = data + padded(size);
+
+                let kind = block[156];
+                if kind == b'x' || kind == b'L' {
+                    if size > 1 << 20 {
+                        return Err(FATError::CannotRead);
+                    }
+                    let mut content = vec![0; size as usize];
+                    archive
+                        .read_exact(&mut content)
+                        .map_err(|_| FATError::CannotRead)?;
+                    if kind == b'L' {
+                        long_path = Some(text(&content));
+                    } else {
+                        (long_path, long_size) =
+                            parse_records(&content).ok_or(FATError::CannotRead)?;
+                    }
+                    continue;
+                }
+
+                if !matches!(kind, 0 | b'0' | b'5') {
+                    continue;
+                }
+                let Some(path) = fs.unpack_path(dest, &name)? else {
+                    continue;
+                };
+
+                if kind == b'5' {
+                    fs.ensure_dir(&path)?;
+                } else {
+                    fs.new_file(&path, Section::new(&mut archive, data, size))?;
+                }
+                progress(&path, offset);
+            }
+        })
+    }
+}
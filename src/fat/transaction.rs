@@ -0,0 +1,110 @@
+use std::collections::HashSet;
+
+use tracing::debug;
+
+use super::{FATError, FAT};
+
+/// In-memory state to return to when a transaction is rolled back.
+pub(super) struct Savepoint {
+    next_fit: u32,
+    freed: HashSet<u32>,
+    free_clusters: Option<u32>,
+}
+
+impl FAT {
+    /// Starts a transaction: FAT and directory updates are held back until
+    /// [`FAT::commit`] and dropped by [`FAT::rollback`].
+    ///
+    /// Transactions nest; only the outermost commit reaches the image, through
+    /// the journal when there is one.
+    pub fn begin(&mut self) {
+        let region = self.journal_region();
+        self.disk.begin(region);
+        self.disk.savepoint();
+        self.savepoints.push(Savepoint {
+            next_fit: self.next_fit,
+            freed: self.freed.clone(),
+            free_clusters: self.free_clusters,
+        });
+        debug!(depth = self.savepoints.len(), "begin transaction");
+    }
+
+    /// Keeps the updates made since the matching [`FAT::begin`].
+    pub fn commit(&mut self) -> Result<(), FATError> {
+        self.savepoints.pop();
+        self.disk.release();
+        if !self.savepoints.is_empty() {
+            return Ok(());
+        }
+
+        debug!(freed = self.freed.len(), "commit transaction");
+        self.disk.end().map_err(|_| FATError::CannotWrite)?;
+
+        // only now that the transaction cannot be undone may its freed
+        // clusters be overwritten
+        for cluster in std::mem::take(&mut self.freed) {
+            self.discard_cluster(cluster)?;
+        }
+
+        Ok(())
+    }
+
+    /// Drops the updates made since the matching [`FAT::begin`].
+    ///
+    /// File data written in the meantime only ever went to clusters that were
+    /// free, so the image is left as it was. Operations that change the layout
+    /// commit as they go and are not undone.
+    pub fn rollback(&mut self) {
+        let Some(savepoint) = self.savepoints.pop() else {
+            return;
+        };
+
+        debug!(depth = self.savepoints.len() + 1, "roll back transaction");
+        self.disk.rollback();
+        self.next_fit = savepoint.next_fit;
+        self.freed = savepoint.freed;
+        self.free_clusters = savepoint.free_clusters;
+
+        if self.savepoints.is_empty() {
+            // nothing is held back, so ending cannot fail
+            let _ = self.disk.end();
+        }
+    }
+
+    /// Runs `f` as a transaction, committing when it succeeds and rolling back
+    /// when it fails.
+    pub fn transaction<T, F>(&mut self, f: F) -> Result<T, FATError>
+    where
+        F: FnOnce(&mut FAT) -> Result<T, FATError>,
+    {
+        self.begin();
+        match f(self) {
+            Ok(value) => {
+                self.commit()?;
+                Ok(value)
+            }
+            Err(err) => {
+                self.rollback();
+                Err(err)
+            }
+        }
+    }
+
+    /// Lets go of the data of a cluster that was just freed, zeroing it when
+    /// discard is on.
+    ///
+    /// Within a transaction the data may still be needed by a rollback, so the
+    /// cluster is neither reused nor zeroed until the transaction commits.
+    pub(super) fn discard_cluster(&mut self, cluster: u32) -> Result<(), FATError> {
+        if !self.savepoints.is_empty() {
+            self.freed.insert(cluster);
+            return Ok(());
+        }
+
+        if self.discard && !self.held.contains(&cluster) {
+            self.zero_cluster(cluster)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+use std::{fmt::Display, io::Cursor};
+
+use super::{batch::FsOp, dirent::Flags, perms::OWNERSHIP, FATError, FAT};
+
+/// Hidden directory removed files are moved to.
+pub const TRASH: &str = ".trash";
+/// Maps the numbered files in the trash back to where they came from.
+const INDEX: &str = ".trash/index";
+
+/// A removed file waiting in the trash.
+#[derive(Debug, Clone)]
+pub struct Trashed {
+    pub id: u32,
+    /// Absolute path the file was removed from.
+    pub path: String,
+}
+
+impl Display for Trashed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.id, self.path)
+    }
+}
+
+impl FAT {
+    fn trash_path(id: u32) -> String {
+        format!("{TRASH}/{id}")
+    }
+
+    fn read_trash_index(&mut self) -> Result<Vec<Trashed>, FATError> {
+        if self.find_file(INDEX, Self::filter_find_file).is_err() {
+            return Ok(vec![]);
+        }
+
+        let mut index = vec![];
+        self.cat(INDEX, &mut index)?;
+
+        Ok(String::from_utf8_lossy(&index)
+            .lines()
+            .filter_map(|line| {
+                let (id, path) = line.split_once(' ')?;
+                Some(Trashed {
+                    id: id.parse().ok()?,
+                    path: path.to_string(),
+                })
+            })
+            .collect())
+    }
+
+    fn write_trash_index(&mut self, index: &[Trashed]) -> Result<(), FATError> {
+        if self.find_file(INDEX, Self::filter_find_file).is_ok() {
+            self.remove_file(INDEX)?;
+        }
+
+        if index.is_empty() {
+            return Ok(());
+        }
+
+        self.new_file(INDEX, Cursor::new(Self::trash_index_bytes(index)))
+    }
+
+    fn trash_index_bytes(index: &[Trashed]) -> Vec<u8> {
+        let text: String = index.iter().map(|item| format!("{item}\n")).collect();
+        text.into_bytes()
+    }
+
+    /// Replaces the index, which must exist, with `index`.
+    fn trash_index_op(index: &[Trashed]) -> FsOp {
+        if index.is_empty() {
+            return FsOp::Remove(INDEX.to_string());
+        }
+
+        FsOp::Write {
+            path: INDEX.to_string(),
+            data: Self::trash_index_bytes(index),
+        }
+    }
+
+    /// Creates the hidden trash directory unless it already exists.
+    fn ensure_trash(&mut self) -> Result<(), FATError> {
+        if self.find_file(TRASH, Self::filter_mkdir).is_ok() {
+            return Ok(());
+        }
+
+        self.mkdir(TRASH)?;
+        let root = self.find_file(".", Self::filter_mkdir)?;
+        self.update_file_in_dir(
+            &root,
+            |entry| entry.name() == TRASH && entry.flags() & Flags::Occupied as u32 != 0,
+            // open to every user, whoever happened to create it
+            |entry| entry.set_flags(entry.flags() & !OWNERSHIP | Flags::Hidden as u32),
+        )?;
+
+        Ok(())
+    }
+
+    /// Moves the file into the trash, or removes it for good if it is already there.
+    pub fn trash(&mut self, path: &str) -> Result<(), FATError> {
+        self.check_protected(path)?;
+        if path.starts_with(&format!("{TRASH}/")) {
+            let mut index = self.read_trash_index()?;
+            self.remove_file(path)?;
+            index.retain(|item| Self::trash_path(item.id) != path);
+            return self.write_trash_index(&index);
+        }
+
+        self.find_file(path, Self::filter_find_file)?;
+        self.ensure_trash()?;
+
+        let mut index = self.read_trash_index()?;
+        let id = index.iter().map(|item| item.id).max().unwrap_or(0) + 1;
+
+        index.push(Trashed {
+            id,
+            path: format!("/{path}"),
+        });
+        self.apply(&[
+            FsOp::Rename {
+                source: path.to_string(),
+                dest: Self::trash_path(id),
+            },
+            Self::trash_index_op(&index),
+        ])
+    }
+
+    pub fn list_trash(&mut self) -> Result<Vec<Trashed>, FATError> {
+        self.read_trash_index()
+    }
+
+    /// Moves a file from the trash back to where it was removed from.
+    pub fn restore(&mut self, id: u32) -> Result<(), FATError> {
+        let mut index = self.read_trash_index()?;
+        let position = index
+            .iter()
+            .position(|item| item.id == id)
+            .ok_or(FATError::FileNotFound)?;
+
+        let original = index[position].path.trim_start_matches('/').to_string();
+        index.remove(position);
+        self.apply(&[
+            FsOp::Rename {
+                source: Self::trash_path(id),
+                dest: original,
+            },
+            Self::trash_index_op(&index),
+        ])
+    }
+
+    /// Removes everything in the trash for good, returning how many files were freed.
+    ///
+    /// Files that cannot be removed stay in the trash, and the first error is
+    /// returned once the index lists only them.
+    pub fn empty_trash(&mut self) -> Result<u32, FATError> {
+        let index = self.read_trash_index()?;
+        let mut removed = 0;
+        let mut kept = vec![];
+        let mut error = None;
+
+        for item in index {
+            let path = Self::trash_path(item.id);
+            match self.remove_file(&path) {
+                Ok(()) => removed += 1,
+                // gone already, so there is nothing left to free
+                Err(FATError::FileNotFound)
+                    if self.find_file(&path, Self::filter_find).is_err() => {}
+                Err(e) => {
+                    error.get_or_insert(e);
+                    kept.push(item);
+                }
+            }
+        }
+
+        self.write_trash_index(&kept)?;
+        match error {
+            Some(e) => Err(e),
+            None => Ok(removed),
+        }
+    }
+}
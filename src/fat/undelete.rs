@@ -0,0 +1,90 @@
+use std::fmt::Display;
+
+use super::{dirent::Flags, join, FATError, FAT};
+
+/// A removed directory entry whose data still looks intact.
+///
+/// Freeing a file clears its chain, so the data is assumed to be the
+/// contiguous run the allocator normally hands out.
+#[derive(Debug, Clone)]
+pub struct Deleted {
+    pub name: String,
+    pub size: u64,
+    pub clusters: Vec<u32>,
+}
+
+impl Display for Deleted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} byte(s), {} cluster(s)",
+            self.name,
+            self.size,
+            self.clusters.len()
+        )
+    }
+}
+
+impl FAT {
+    /// Lists removed files in `dir` whose clusters are all still free.
+    pub fn deleted_files(&mut self, dir: &str) -> Result<Vec<Deleted>, FATError> {
+        let dir = self.find_file(dir, Self::filter_mkdir)?;
+        let fat = self.read_whole_fat()?;
+        let cluster_size = self.cluster_size() as u64;
+        let mut found = vec![];
+
+        for cluster in self.chain_of(dir.cluster())? {
+            for entry in self.read_cluster_entries(cluster)? {
+                if entry.flags() != 0 || entry.name().is_empty() {
+                    continue;
+                }
+
+                let count = entry.size().div_ceil(cluster_size).max(1);
+                let clusters: Vec<u32> = (entry.cluster()..)
+                    .take(count as usize)
+                    .take_while(|&c| self.is_data_cluster(c) && fat[c as usize] == 0)
+                    .collect();
+
+                if clusters.len() == count as usize {
+                    found.push(Deleted {
+                        name: entry.name().to_string(),
+                        size: entry.size(),
+                        clusters,
+                    });
+                }
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Brings a removed file in `dir` back by relinking its clusters.
+    pub fn undelete(&mut self, dir: &str, name: &str) -> Result<(), FATError> {
+        let path = if dir.is_empty() {
+            name.to_string()
+        } else {
+            join(dir, name)
+        };
+
+        if self.find_file(&path, Self::filter_find).is_ok() {
+            return Err(FATError::FileExists);
+        }
+
+        let deleted = self
+            .deleted_files(dir)?
+            .into_iter()
+            .find(|deleted| deleted.name == name)
+            .ok_or(FATError::FileNotFound)?;
+
+        let dir = self.find_file(dir, Self::filter_mkdir)?;
+        let first = deleted.clusters[0];
+        self.update_file_in_dir(
+            &dir,
+            |entry| entry.flags() == 0 && entry.name() == name && entry.cluster() == first,
+            |entry| entry.set_flags(Flags::Occupied as u32),
+        )?;
+        self.reindex_dir(dir.cluster())?;
+
+        self.link_chain(&deleted.clusters)
+    }
+}
@@ -0,0 +1,69 @@
+use std::{fmt::Display, str::FromStr};
+
+use super::FATError;
+
+pub const UUID_LENGTH: usize = 16;
+
+/// Random (version 4) UUID identifying a formatted image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Uuid([u8; UUID_LENGTH]);
+
+impl Uuid {
+    pub fn new_v4() -> Result<Self, FATError> {
+        let mut bytes = [0; UUID_LENGTH];
+        fill_random(&mut bytes)?;
+
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+        Ok(Self(bytes))
+    }
+
+    pub fn from_bytes(bytes: [u8; UUID_LENGTH]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; UUID_LENGTH] {
+        &self.0
+    }
+}
+
+/// Fills `buf` from the operating system's random source.
+///
+/// Keys and salts are drawn from it, so there is no fallback when the source
+/// is unavailable, only [`FATError::NoRandomness`].
+pub fn fill_random(buf: &mut [u8]) -> Result<(), FATError> {
+    getrandom::getrandom(buf).map_err(|_| FATError::NoRandomness)
+}
+
+impl Display for Uuid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                write!(f, "-")?;
+            }
+            write!(f, "{byte:02x}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Uuid {
+    type Err = ();
+
+    /// Reads the hyphenated form [`Uuid`] is displayed in.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let hyphens: Vec<_> = text.match_indices('-').map(|(index, _)| index).collect();
+        if text.len() != 36 || hyphens != [8, 13, 18, 23] {
+            return Err(());
+        }
+
+        let digits = text.replace('-', "");
+        let mut bytes = [0; UUID_LENGTH];
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&digits[index * 2..index * 2 + 2], 16).map_err(|_| ())?;
+        }
+        Ok(Self(bytes))
+    }
+}
@@ -0,0 +1,121 @@
+use std::fmt::Display;
+
+use super::{FATError, FAT};
+
+/// Separates a file name from its version number, as in `name;2`.
+const SEPARATOR: char = ';';
+
+/// An older copy of a file kept next to it.
+#[derive(Debug, Clone)]
+pub struct Version {
+    pub number: u32,
+    pub size: u64,
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {} byte(s)", self.number, self.size)
+    }
+}
+
+impl FAT {
+    /// How many old versions are kept per file; 0 turns versioning off.
+    pub fn max_versions(&self) -> u32 {
+        self.max_versions
+    }
+
+    pub fn set_max_versions(&mut self, max_versions: u32) {
+        self.max_versions = max_versions;
+    }
+
+    fn version_path(path: &str, number: u32) -> String {
+        format!("{path}{SEPARATOR}{number}")
+    }
+
+    /// Lists the kept versions of `path`, oldest first.
+    pub fn versions(&mut self, path: &str) -> Result<Vec<Version>, FATError> {
+        let (dir, name) = Self::split_path(path);
+        let dir = self.find_file(dir, Self::filter_mkdir)?;
+        let prefix = format!("{name}{SEPARATOR}");
+        let mut versions = vec![];
+
+        for cluster in self.chain_of(dir.cluster())? {
+            for entry in self.read_cluster_entries(cluster)? {
+                if !Self::filter_find_file(&entry) {
+                    continue;
+                }
+
+                let number = entry
+                    .name()
+                    .strip_prefix(&prefix)
+                    .and_then(|number| number.parse().ok());
+                if let Some(number) = number {
+                    versions.push(Version {
+                        number,
+                        size: entry.size(),
+                    });
+                }
+            }
+        }
+
+        versions.sort_by_key(|version| version.number);
+        Ok(versions)
+    }
+
+    /// Renames `path` to its next version and drops the oldest versions over
+    /// the limit, never dropping `protect`.
+    ///
+    /// Returns false, leaving the file alone, when versioning is off or the
+    /// versioned name would not fit in a directory entry.
+    fn archive(&mut self, path: &str, protect: Option<u32>) -> Result<bool, FATError> {
+        if self.max_versions == 0 || self.find_file(path, Self::filter_find_file).is_err() {
+            return Ok(false);
+        }
+
+        let versions = self.versions(path)?;
+        let next = versions.last().map_or(1, |version| version.number + 1);
+        let target = Self::version_path(path, next);
+        if Self::split_path(&target).1.len() > 12 {
+            return Ok(false);
+        }
+
+        self.move_file(path, &target)?;
+
+        let mut kept: Vec<u32> = versions.iter().map(|version| version.number).collect();
+        kept.push(next);
+        let mut excess = kept.len().saturating_sub(self.max_versions as usize);
+
+        for number in kept {
+            if excess == 0 {
+                break;
+            }
+            if Some(number) != protect {
+                self.remove_file(&Self::version_path(path, number))?;
+                excess -= 1;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Keeps the current contents of `path` as a version before it is
+    /// overwritten or removed.
+    pub fn keep_version(&mut self, path: &str) -> Result<bool, FATError> {
+        self.archive(path, None)
+    }
+
+    /// Replaces `path` with its version `number`, keeping the replaced
+    /// contents as a new version.
+    pub fn revert(&mut self, path: &str, number: u32) -> Result<(), FATError> {
+        let version = Self::version_path(path, number);
+        self.find_file(&version, Self::filter_find_file)?;
+
+        if self.find_file(path, Self::filter_find_file).is_ok()
+            && !self.archive(path, Some(number))?
+        {
+            self.remove_file(path)?;
+        }
+
+        self.copy(&version, path)
+    }
+}
@@ -0,0 +1,142 @@
+use std::{collections::BTreeMap, mem::size_of};
+
+use super::{dirent::Entry, FATError, FAT};
+
+/// Longest key an attribute can have.
+pub const MAX_KEY_LEN: usize = u8::MAX as usize;
+
+/// Parses an attribute cluster: records of a key length (u8), a value length
+/// (u16), the key and the value, ended by a zero key length.
+fn parse(bytes: &[u8]) -> Option<BTreeMap<String, String>> {
+    let mut attributes = BTreeMap::new();
+    let mut rest = bytes;
+
+    loop {
+        let (&key_len, tail) = rest.split_first()?;
+        if key_len == 0 {
+            return Some(attributes);
+        }
+
+        let value_len = u16::from_le_bytes(tail.get(..size_of::<u16>())?.try_into().ok()?);
+        let tail = &tail[size_of::<u16>()..];
+        let key = tail.get(..key_len as usize)?;
+        let value = tail.get(key_len as usize..key_len as usize + value_len as usize)?;
+
+        attributes.insert(
+            String::from_utf8(key.to_vec()).ok()?,
+            String::from_utf8(value.to_vec()).ok()?,
+        );
+        rest = &tail[key_len as usize + value_len as usize..];
+    }
+}
+
+/// Lays `attributes` out as [`parse`] expects, or returns `None` when they do
+/// not fit in `len` bytes.
+fn serialize(attributes: &BTreeMap<String, String>, len: usize) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    for (key, value) in attributes {
+        bytes.push(key.len() as u8);
+        bytes.extend_from_slice(&(u16::try_from(value.len()).ok()?).to_le_bytes());
+        bytes.extend_from_slice(key.as_bytes());
+        bytes.extend_from_slice(value.as_bytes());
+    }
+
+    // room for the terminating zero
+    if bytes.len() >= len {
+        return None;
+    }
+    bytes.resize(len, 0);
+    Some(bytes)
+}
+
+impl FAT {
+    /// Directory cluster and slot of the entry at `path`, with the entry.
+    fn entry_slot(&mut self, path: &str) -> Result<(u32, usize, Entry), FATError> {
+        let (dir, name) = Self::split_path(path);
+        if name.is_empty() || name == "." || name == ".." {
+            return Err(FATError::FileNotFound);
+        }
+
+        let dir = self.find_file(dir, Self::filter_mkdir)?;
+        for cluster in self.chain_of(dir.cluster())? {
+            for (index, entry) in self.read_cluster_entries(cluster)?.into_iter().enumerate() {
+                if Self::filter_find(&entry) && entry.name() == name {
+                    return Ok((cluster, index, entry));
+                }
+            }
+        }
+
+        Err(FATError::FileNotFound)
+    }
+
+    fn read_xattrs(&mut self, entry: &Entry) -> Result<BTreeMap<String, String>, FATError> {
+        if entry.xattrs() == 0 {
+            return Ok(BTreeMap::new());
+        }
+
+        parse(&self.read_cluster(entry.xattrs())?).ok_or(FATError::CannotRead)
+    }
+
+    /// Replaces the attributes of the entry at `path`.
+    ///
+    /// They are written to a fresh cluster and the old one is freed, so a
+    /// crash leaves either the old or the new set.
+    fn write_xattrs(
+        &mut self,
+        path: &str,
+        attributes: &BTreeMap<String, String>,
+    ) -> Result<(), FATError> {
+        let (dir_cluster, index, entry) = self.entry_slot(path)?;
+
+        let mut cluster = 0;
+        if !attributes.is_empty() {
+            let bytes =
+                serialize(attributes, self.cluster_size()).ok_or(FATError::NotEnoughSpace)?;
+            cluster = self.allocate_clusters(1)?;
+            self.write_cluster(cluster, &bytes)?;
+        }
+
+        let mut entries = self.read_cluster_entries(dir_cluster)?;
+        entries[index].set_xattrs(cluster);
+        self.write_cluster_entries(dir_cluster, &entries)?;
+
+        if entry.xattrs() != 0 {
+            self.dealloc_clusters(entry.xattrs())?;
+        }
+
+        Ok(())
+    }
+
+    /// Extended attributes of the file or directory at `path`, by key.
+    pub fn list_xattrs(&mut self, path: &str) -> Result<BTreeMap<String, String>, FATError> {
+        let (_, _, entry) = self.entry_slot(path)?;
+        self.read_xattrs(&entry)
+    }
+
+    pub fn get_xattr(&mut self, path: &str, key: &str) -> Result<Option<String>, FATError> {
+        Ok(self.list_xattrs(path)?.remove(key))
+    }
+
+    /// Sets `key` to `value`, failing with [`FATError::NotEnoughSpace`] when
+    /// the attributes would outgrow a cluster.
+    pub fn set_xattr(&mut self, path: &str, key: &str, value: &str) -> Result<(), FATError> {
+        if key.is_empty() || key.len() > MAX_KEY_LEN {
+            return Err(FATError::FilenameTooLong);
+        }
+
+        let mut attributes = self.list_xattrs(path)?;
+        attributes.insert(key.to_string(), value.to_string());
+        self.transaction(|fs| fs.write_xattrs(path, &attributes))
+    }
+
+    /// Removes `key`, returning whether it was set.
+    pub fn remove_xattr(&mut self, path: &str, key: &str) -> Result<bool, FATError> {
+        let mut attributes = self.list_xattrs(path)?;
+        if attributes.remove(key).is_none() {
+            return Ok(false);
+        }
+
+        self.transaction(|fs| fs.write_xattrs(path, &attributes))?;
+        Ok(true)
+    }
+}
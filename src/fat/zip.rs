@@ -0,0 +1,388 @@
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+use crate::time;
+
+use super::{
+    archive::{Counted, Section},
+    inflate::inflate,
+    integrity::crc32_update,
+    FATError, FAT,
+};
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR: u32 = 0x0807_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const END: u32 = 0x0605_4b50;
+const ZIP64_END: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+const ZIP64_EXTRA: u16 = 0x0001;
+
+/// Sizes and CRC come after the data.
+const FLAG_DESCRIPTOR: u16 = 1 << 3;
+const FLAG_UTF8: u16 = 1 << 11;
+const FLAG_ENCRYPTED: u16 = 1;
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+/// Made on Unix, so the high half of the external attributes is a mode.
+const MADE_BY_UNIX: u16 = 3 << 8;
+
+/// A member as the central directory describes it.
+struct CentralEntry {
+    name: String,
+    flags: u16,
+    method: u16,
+    crc: u32,
+    compressed: u64,
+    size: u64,
+    offset: u64,
+    external: u32,
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+}
+
+/// CRC-32 of everything `reader` holds.
+fn crc_of(mut reader: impl Read) -> io::Result<u32> {
+    let mut crc = 0;
+    let mut buffer = vec![0; 64 << 10];
+    loop {
+        match reader.read(&mut buffer)? {
+            0 => return Ok(crc),
+            n => crc = crc32_update(crc, &buffer[..n]),
+        }
+    }
+}
+
+/// Passes what is written to `inner` on, keeping its CRC-32.
+struct Checksummed<'a, W> {
+    inner: &'a mut W,
+    crc: u32,
+}
+
+impl<W: Write> Write for Checksummed<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.crc = crc32_update(self.crc, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl FAT {
+    /// Streams the directory or file at `path` into `out` as a zip archive,
+    /// named as [`FAT::export_tar`] names them. Files are stored rather than
+    /// deflated, each followed by its CRC, so nothing has to be read twice;
+    /// zip64 records are added once sizes or offsets need them.
+    pub fn export_zip<W: Write>(&mut self, path: &str, out: W) -> Result<(), FATError> {
+        let mut out = Counted::new(out);
+        let (time, date) = time::dos(time::now());
+        let mut central = vec![];
+        let mut count = 0u64;
+
+        for member in self.archive_members(path)? {
+            let offset = out.written;
+            let zip64 = member.size >= u32::MAX as u64;
+            let flags = FLAG_UTF8 | if member.dir { 0 } else { FLAG_DESCRIPTOR };
+            let needed: u16 = if zip64 { 45 } else { 20 };
+
+            let mut local = vec![];
+            local.extend(LOCAL_HEADER.to_le_bytes());
+            local.extend(needed.to_le_bytes());
+            local.extend(flags.to_le_bytes());
+            local.extend(STORED.to_le_bytes());
+            local.extend(time.to_le_bytes());
+            local.extend(date.to_le_bytes());
+            // CRC and sizes follow in the data descriptor
+            local.extend(0u32.to_le_bytes());
+            let unknown = if zip64 { u32::MAX } else { 0 };
+            local.extend(unknown.to_le_bytes());
+            local.extend(unknown.to_le_bytes());
+            local.extend((member.name.len() as u16).to_le_bytes());
+            local.extend((if zip64 { 20u16 } else { 0 }).to_le_bytes());
+            local.extend(member.name.as_bytes());
+            if zip64 {
+                local.extend(ZIP64_EXTRA.to_le_bytes());
+                local.extend(16u16.to_le_bytes());
+                local.extend([0; 16]);
+            }
+            out.write_all(&local).map_err(|_| FATError::CannotWrite)?;
+
+            let mut crc = 0;
+            if !member.dir {
+                let mut checksummed = Checksummed {
+                    inner: &mut out,
+                    crc: 0,
+                };
+                let start = checksummed.inner.written;
+                self.cat(&member.path, &mut checksummed)?;
+                crc = checksummed.crc;
+                if out.written - start != member.size {
+                    return Err(FATError::CannotRead);
+                }
+
+                let mut descriptor = vec![];
+                descriptor.extend(DATA_DESCRIPTOR.to_le_bytes());
+                descriptor.extend(crc.to_le_bytes());
+                if zip64 {
+                    descriptor.extend(member.size.to_le_bytes());
+                    descriptor.extend(member.size.to_le_bytes());
+                } else {
+                    descriptor.extend((member.size as u32).to_le_bytes());
+                    descriptor.extend((member.size as u32).to_le_bytes());
+                }
+                out.write_all(&descriptor)
+                    .map_err(|_| FATError::CannotWrite)?;
+            }
+
+            let mut extra = vec![];
+            if zip64 {
+                extra.extend(member.size.to_le_bytes());
+                extra.extend(member.size.to_le_bytes());
+            }
+            if offset >= u32::MAX as u64 {
+                extra.extend(offset.to_le_bytes());
+            }
+            let zip64_central = !extra.is_empty();
+            let (mode, dos): (u32, u32) = if member.dir {
+                (0o040755, 0x10)
+            } else {
+                (0o100644, 0)
+            };
+
+            central.extend(CENTRAL_HEADER.to_le_bytes());
+            central.extend((MADE_BY_UNIX | if zip64_central { 45 } else { 20 }).to_le_bytes());
+            central.extend((if zip64_central { 45u16 } else { 20 }).to_le_bytes());
+            central.extend(flags.to_le_bytes());
+            central.extend(STORED.to_le_bytes());
+            central.extend(time.to_le_bytes());
+            central.extend(date.to_le_bytes());
+            central.extend(crc.to_le_bytes());
+            let size = if zip64 { u32::MAX } else { member.size as u32 };
+            central.extend(size.to_le_bytes());
+            central.extend(size.to_le_bytes());
+            central.extend((member.name.len() as u16).to_le_bytes());
+            let extra_len = if zip64_central { extra.len() + 4 } else { 0 };
+            central.extend((extra_len as u16).to_le_bytes());
+            // comment, starting disk and internal attributes
+            central.extend([0; 6]);
+            central.extend((mode << 16 | dos).to_le_bytes());
+            central.extend((offset.min(u32::MAX as u64) as u32).to_le_bytes());
+            central.extend(member.name.as_bytes());
+            if zip64_central {
+                central.extend(ZIP64_EXTRA.to_le_bytes());
+                central.extend((extra.len() as u16).to_le_bytes());
+                central.extend(extra);
+            }
+            count += 1;
+        }
+
+        let start = out.written;
+        out.write_all(&central).map_err(|_| FATError::CannotWrite)?;
+        let len = central.len() as u64;
+
+        let mut end = vec![];
+        if count >= u16::MAX as u64 || start >= u32::MAX as u64 || len >= u32::MAX as u64 {
+            let zip64_end = out.written;
+            end.extend(ZIP64_END.to_le_bytes());
+            end.extend(44u64.to_le_bytes());
+            end.extend((MADE_BY_UNIX | 45).to_le_bytes());
+            end.extend(45u16.to_le_bytes());
+            end.extend([0; 8]);
+            end.extend(count.to_le_bytes());
+            end.extend(count.to_le_bytes());
+            end.extend(len.to_le_bytes());
+            end.extend(start.to_le_bytes());
+
+            end.extend(ZIP64_LOCATOR.to_le_bytes());
+            end.extend(0u32.to_le_bytes());
+            end.extend(zip64_end.to_le_bytes());
+            end.extend(1u32.to_le_bytes());
+        }
+        end.extend(END.to_le_bytes());
+        end.extend([0; 4]);
+        let count = count.min(u16::MAX as u64) as u16;
+        end.extend(count.to_le_bytes());
+        end.extend(count.to_le_bytes());
+        end.extend((len.min(u32::MAX as u64) as u32).to_le_bytes());
+        end.extend((start.min(u32::MAX as u64) as u32).to_le_bytes());
+        end.extend(0u16.to_le_bytes());
+
+        out.write_all(&end)
+            .and_then(|_| out.flush())
+            .map_err(|_| FATError::CannotWrite)
+    }
+
+    /// Creates the directories and files of the zip archive `archive` under
+    /// the directory `dest`, calling `progress` with each member and how many
+    /// bytes of the archive are done.
+    ///
+    /// Stored members go straight from the archive into the image, deflated
+    /// ones through memory. Like [`FAT::import_tar`], either everything is
+    /// imported or nothing.
+    pub fn import_zip<R: Read + Seek>(
+        &mut self,
+        dest: &str,
+        mut archive: R,
+        mut progress: impl FnMut(&str, u64),
+    ) -> Result<(), FATError> {
+        let dest = self.unpack_dest(dest)?;
+        let entries = read_central_directory(&mut archive).ok_or(FATError::CannotRead)?;
+
+        self.transaction(|fs| {
+            for entry in entries {
+                if entry.flags & FLAG_ENCRYPTED != 0 {
+                    return Err(FATError::CannotRead);
+                }
+
+                let mut local = [0; 30];
+                archive
+                    .seek(SeekFrom::Start(entry.offset))
+                    .and_then(|_| archive.read_exact(&mut local))
+                    .map_err(|_| FATError::CannotRead)?;
+                if u32_at(&local, 0) != Some(LOCAL_HEADER) {
+                    return Err(FATError::CannotRead);
+                }
+                let data = entry.offset
+                    + 30
+                    + u16_at(&local, 26).unwrap_or_default() as u64
+                    + u16_at(&local, 28).unwrap_or_default() as u64;
+
+                let dir = entry.name.ends_with('/') || entry.external & 0x10 != 0;
+                let Some(path) = fs.unpack_path(dest, &entry.name)? else {
+                    continue;
+                };
+
+                if dir {
+                    fs.ensure_dir(&path)?;
+                } else {
+                    match entry.method {
+                        STORED if entry.compressed == entry.size => {
+                            let section = Section::new(&mut archive, data, entry.size);
+                            if crc_of(section).ok() != Some(entry.crc) {
+                                return Err(FATError::CannotRead);
+                            }
+                            fs.new_file(&path, Section::new(&mut archive, data, entry.size))?;
+                        }
+                        DEFLATED => {
+                            let mut compressed = vec![];
+                            Section::new(&mut archive, data, entry.compressed)
+                                .read_to_end(&mut compressed)
+                                .map_err(|_| FATError::CannotRead)?;
+                            let limit = usize::try_from(entry.size).unwrap_or(usize::MAX);
+                            let content = inflate(&compressed, limit)
+                                .filter(|content| {
+                                    content.len() as u64 == entry.size
+                                        && crc32_update(0, content) == entry.crc
+                                })
+                                .ok_or(FATError::CannotRead)?;
+                            fs.new_file(&path, Cursor::new(content))?;
+                        }
+                        _ => return Err(FATError::CannotRead),
+                    }
+                }
+                progress(&path, data + entry.compressed);
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Members listed by the central directory of `archive`, zip64 included.
+fn read_central_directory<R: Read + Seek>(archive: &mut R) -> Option<Vec<CentralEntry>> {
+    let len = archive.seek(SeekFrom::End(0)).ok()?;
+    // the end record is 22 bytes followed by a comment of up to 64 KiB
+    let tail_start = len.saturating_sub(22 + u16::MAX as u64);
+    let mut tail = vec![];
+    archive.seek(SeekFrom::Start(tail_start)).ok()?;
+    archive.read_to_end(&mut tail).ok()?;
+    let end = (0..tail.len().checked_sub(21)?)
+        .rev()
+        .find(|&at| u32_at(&tail, at) == Some(END))?;
+
+    let mut count = u16_at(&tail, end + 10)? as u64;
+    let mut size = u32_at(&tail, end + 12)? as u64;
+    let mut start = u32_at(&tail, end + 16)? as u64;
+
+    if end >= 20 && u32_at(&tail, end - 20) == Some(ZIP64_LOCATOR) {
+        let mut record = [0; 56];
+        archive
+            .seek(SeekFrom::Start(u64_at(&tail, end - 12)?))
+            .ok()?;
+        archive.read_exact(&mut record).ok()?;
+        if u32_at(&record, 0) != Some(ZIP64_END) {
+            return None;
+        }
+        count = u64_at(&record, 32)?;
+        size = u64_at(&record, 40)?;
+        start = u64_at(&record, 48)?;
+    }
+
+    if start.checked_add(size)? > len {
+        return None;
+    }
+    let mut directory = vec![];
+    Section::new(archive, start, size)
+        .read_to_end(&mut directory)
+        .ok()?;
+
+    let mut entries = vec![];
+    let mut at = 0;
+    for _ in 0..count {
+        if u32_at(&directory, at)? != CENTRAL_HEADER {
+            return None;
+        }
+        let name_len = u16_at(&directory, at + 28)? as usize;
+        let extra_len = u16_at(&directory, at + 30)? as usize;
+        let comment_len = u16_at(&directory, at + 32)? as usize;
+        let flags = u16_at(&directory, at + 8)?;
+
+        let name = directory.get(at + 46..at + 46 + name_len)?;
+        let name = String::from_utf8_lossy(name).replace('\\', "/");
+        let mut entry = CentralEntry {
+            name,
+            flags,
+            method: u16_at(&directory, at + 10)?,
+            crc: u32_at(&directory, at + 16)?,
+            compressed: u32_at(&directory, at + 20)? as u64,
+            size: u32_at(&directory, at + 24)? as u64,
+            offset: u32_at(&directory, at + 42)? as u64,
+            external: u32_at(&directory, at + 38)?,
+        };
+
+        // zip64 values replace, in order, the fields that are all ones
+        let extra = directory.get(at + 46 + name_len..at + 46 + name_len + extra_len)?;
+        let mut field = 0;
+        while field + 4 <= extra.len() {
+            let id = u16_at(extra, field)?;
+            let len = u16_at(extra, field + 2)? as usize;
+            if id == ZIP64_EXTRA {
+                let mut values = extra.get(field + 4..field + 4 + len)?.chunks_exact(8);
+                for value in [&mut entry.size, &mut entry.compressed, &mut entry.offset] {
+                    if *value == u32::MAX as u64 {
+                        *value = u64::from_le_bytes(values.next()?.try_into().ok()?);
+                    }
+                }
+            }
+            field += 4 + len;
+        }
+
+        entries.push(entry);
+        at += 46 + name_len + extra_len + comment_len;
+    }
+
+    Some(entries)
+}
@@ -0,0 +1,305 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+use tracing::warn;
+
+use crate::fat::{batch::FsOp, FAT, ROOT};
+
+/// Port FTP servers usually listen on.
+pub const FTP_PORT: u16 = 21;
+
+/// How long a client has to open a data connection, and how long a transfer
+/// may stall.
+const DATA_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Serves the FAT tree to one FTP client at a time. Any user name and
+/// password are accepted, so only bind it where every client may change the
+/// image; transfers are binary and passive only.
+pub struct Server<'a> {
+    fat: &'a mut FAT,
+    /// Working directory as the client sees it, e.g. `/` or `/CP`.
+    cwd: String,
+    passive: Option<TcpListener>,
+    rename_from: Option<String>,
+}
+
+impl<'a> Server<'a> {
+    pub fn new(fat: &'a mut FAT) -> Self {
+        Self {
+            fat,
+            cwd: "/".to_string(),
+            passive: None,
+            rename_from: None,
+        }
+    }
+
+    /// Accepts clients on `address` one after another.
+    pub fn listen(&mut self, address: SocketAddr) -> io::Result<()> {
+        let listener = TcpListener::bind(address)?;
+        for stream in listener.incoming() {
+            // a client that fails only ends its own session
+            if let Err(error) = stream.and_then(|stream| self.serve(stream)) {
+                warn!(%error, "FTP session failed");
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolves `path` against the working directory to a path in the image
+    /// and the absolute path the client knows it by.
+    fn resolve(&self, path: &str) -> (String, String) {
+        let mut parts: Vec<&str> = if path.starts_with('/') {
+            vec![]
+        } else {
+            self.cwd
+                .split('/')
+                .filter(|part| !part.is_empty())
+                .collect()
+        };
+
+        for part in path.split('/') {
+            match part {
+                "" | "." => {}
+                ".." => {
+                    parts.pop();
+                }
+                _ => parts.push(part),
+            }
+        }
+
+        let inner = if parts.is_empty() {
+            ROOT.to_string()
+        } else {
+            parts.join("/")
+        };
+        (inner, format!("/{}", parts.join("/")))
+    }
+
+    /// Waits up to [`DATA_TIMEOUT`] for the client to connect to the passive
+    /// port.
+    fn open_data(&mut self) -> Result<TcpStream, &'static str> {
+        let listener = self.passive.take().ok_or("Use PASV or EPSV first")?;
+        listener
+            .set_nonblocking(true)
+            .map_err(|_| "Cannot open data connection")?;
+
+        let deadline = Instant::now() + DATA_TIMEOUT;
+        let stream = loop {
+            match listener.accept() {
+                Ok((stream, _)) => break stream,
+                Err(error)
+                    if error.kind() == io::ErrorKind::WouldBlock && Instant::now() < deadline =>
+                {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                    return Err("Data connection timed out")
+                }
+                Err(_) => return Err("Cannot open data connection"),
+            }
+        };
+
+        stream
+            .set_nonblocking(false)
+            .and_then(|()| stream.set_read_timeout(Some(DATA_TIMEOUT)))
+            .and_then(|()| stream.set_write_timeout(Some(DATA_TIMEOUT)))
+            .map_err(|_| "Cannot open data connection")?;
+        Ok(stream)
+    }
+
+    /// Runs a session, then syncs the image even if the session failed.
+    fn serve(&mut self, stream: TcpStream) -> io::Result<()> {
+        let session = self.session(stream);
+        self.fat
+            .sync()
+            .map_err(|_| io::Error::from(io::ErrorKind::Other))?;
+        session
+    }
+
+    /// Answers commands until the client quits or hangs up.
+    fn session(&mut self, stream: TcpStream) -> io::Result<()> {
+        let mut control = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        self.cwd = "/".to_string();
+        self.passive = None;
+        self.rename_from = None;
+
+        writeln!(control, "220 zos_rs FTP server ready\r")?;
+
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                break;
+            }
+
+            let line = line.trim_end_matches(['\r', '\n']);
+            let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+            let command = command.to_ascii_uppercase();
+            if command == "QUIT" {
+                writeln!(control, "221 Bye\r")?;
+                break;
+            }
+
+            let reply = self.handle(&command, argument, &mut control)?;
+            writeln!(control, "{reply}\r")?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs one command and returns its final reply; preliminary replies of
+    /// transfers are written to `control` as they happen.
+    fn handle(
+        &mut self,
+        command: &str,
+        argument: &str,
+        control: &mut TcpStream,
+    ) -> io::Result<String> {
+        let (path, absolute) = self.resolve(argument);
+
+        Ok(match command {
+            "USER" => "331 Any password will do".to_string(),
+            "PASS" => "230 Logged in".to_string(),
+            "SYST" => "215 UNIX Type: L8".to_string(),
+            "FEAT" => "211-Features:\r\n EPSV\r\n PASV\r\n SIZE\r\n211 End".to_string(),
+            "NOOP" => "200 OK".to_string(),
+            "TYPE" | "MODE" | "STRU" => "200 OK".to_string(),
+            "PWD" | "XPWD" => format!("257 \"{}\"", self.cwd),
+            "CWD" | "XCWD" | "CDUP" => {
+                let (path, absolute) = if command == "CDUP" {
+                    self.resolve("..")
+                } else {
+                    (path, absolute)
+                };
+                match self.fat.find_file(&path, FAT::filter_mkdir) {
+                    Ok(_) => {
+                        self.cwd = absolute;
+                        "250 OK".to_string()
+                    }
+                    Err(error) => format!("550 {error}"),
+                }
+            }
+            "PASV" | "EPSV" => {
+                let IpAddr::V4(ip) = control.local_addr()?.ip() else {
+                    return Ok("522 Only IPv4 is supported".to_string());
+                };
+                let listener = TcpListener::bind((ip, 0))?;
+                let port = listener.local_addr()?.port();
+                self.passive = Some(listener);
+
+                if command == "EPSV" {
+                    format!("229 Entering Extended Passive Mode (|||{port}|)")
+                } else {
+                    let [a, b, c, d] = ip.octets();
+                    format!(
+                        "227 Entering Passive Mode ({a},{b},{c},{d},{},{})",
+                        port >> 8,
+                        port & 0xFF
+                    )
+                }
+            }
+            "LIST" | "NLST" => {
+                // options such as `-la` are not supported and list the working directory
+                let (path, _) = if argument.starts_with('-') {
+                    self.resolve("")
+                } else {
+                    (path, absolute)
+                };
+                let entries = match self.fat.read_dir(&path) {
+                    Ok(entries) => entries,
+                    Err(error) => return Ok(format!("550 {error}")),
+                };
+
+                let mut listing = String::new();
+                for entry in entries {
+                    if entry.name() == "." || entry.name() == ".." {
+                        continue;
+                    }
+                    if command == "NLST" {
+                        listing += &format!("{}\r\n", entry.name());
+                    } else if FAT::filter_mkdir(&entry) {
+                        listing +=
+                            &format!("drwxr-xr-x 1 zos zos 0 Jan  1  1970 {}\r\n", entry.name());
+                    } else {
+                        listing += &format!(
+                            "-rw-r--r-- 1 zos zos {} Jan  1  1970 {}\r\n",
+                            entry.size(),
+                            entry.name()
+                        );
+                    }
+                }
+
+                self.send(control, listing.as_bytes())?
+            }
+            "RETR" => {
+                let mut data = vec![];
+                match self.fat.cat(&path, &mut data) {
+                    Ok(()) => self.send(control, &data)?,
+                    Err(error) => format!("550 {error}"),
+                }
+            }
+            "STOR" => {
+                let mut stream = match self.open_data() {
+                    Ok(stream) => stream,
+                    Err(reason) => return Ok(format!("425 {reason}")),
+                };
+                writeln!(control, "150 Receiving data\r")?;
+                let mut data = vec![];
+                stream.read_to_end(&mut data)?;
+
+                match self.fat.apply(&[FsOp::Write { path, data }]) {
+                    Ok(()) => "226 Transfer complete".to_string(),
+                    Err(error) => format!("552 {error}"),
+                }
+            }
+            "SIZE" => match self.fat.find_file(&path, FAT::filter_find_file) {
+                Ok(entry) => format!("213 {}", entry.size()),
+                Err(error) => format!("550 {error}"),
+            },
+            "DELE" => match self.fat.transaction(|fs| fs.remove_file(&path)) {
+                Ok(()) => "250 Deleted".to_string(),
+                Err(error) => format!("550 {error}"),
+            },
+            "MKD" | "XMKD" => match self.fat.transaction(|fs| fs.mkdir(&path)) {
+                Ok(()) => format!("257 \"{absolute}\" created"),
+                Err(error) => format!("550 {error}"),
+            },
+            "RMD" | "XRMD" => match self.fat.transaction(|fs| fs.remove_dir(&path)) {
+                Ok(()) => "250 Removed".to_string(),
+                Err(error) => format!("550 {error}"),
+            },
+            "RNFR" => match self.fat.find_file(&path, FAT::filter_find_file) {
+                Ok(_) => {
+                    self.rename_from = Some(path);
+                    "350 Ready for RNTO".to_string()
+                }
+                Err(error) => format!("550 {error}"),
+            },
+            "RNTO" => {
+                let Some(source) = self.rename_from.take() else {
+                    return Ok("503 Use RNFR first".to_string());
+                };
+                match self.fat.transaction(|fs| fs.move_file(&source, &path)) {
+                    Ok(()) => "250 Renamed".to_string(),
+                    Err(error) => format!("553 {error}"),
+                }
+            }
+            _ => "502 Command not implemented".to_string(),
+        })
+    }
+
+    /// Sends `data` over the data connection and returns the final reply.
+    fn send(&mut self, control: &mut TcpStream, data: &[u8]) -> io::Result<String> {
+        let mut stream = match self.open_data() {
+            Ok(stream) => stream,
+            Err(reason) => return Ok(format!("425 {reason}")),
+        };
+        writeln!(control, "150 Sending data\r")?;
+        stream.write_all(data)?;
+        Ok("226 Transfer complete".to_string())
+    }
+}
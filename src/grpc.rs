@@ -0,0 +1,265 @@
+// handlers answer with `tonic::Status`, which is large by design
+#![allow(clippy::result_large_err)]
+
+use std::{
+    error::Error,
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use tonic::{Code, Request, Response, Status};
+
+use crate::fat::{batch::FsOp, check::CheckOptions, FATError, FAT, ROOT};
+
+use self::image_server::{Image, ImageServer};
+
+include!(concat!(env!("OUT_DIR"), "/zos.Image.rs"));
+
+/// Port the gRPC service listens on unless told otherwise.
+pub const GRPC_PORT: u16 = 50051;
+
+// Messages of proto/zos.proto; the tags must match the ones there.
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Empty {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PathRequest {
+    #[prost(string, tag = "1")]
+    pub path: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Entry {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(bool, tag = "2")]
+    pub dir: bool,
+    #[prost(uint64, tag = "3")]
+    pub size: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListDirReply {
+    #[prost(message, repeated, tag = "1")]
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FileData {
+    #[prost(string, tag = "1")]
+    pub path: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct MoveRequest {
+    #[prost(string, tag = "1")]
+    pub from: String,
+    #[prost(string, tag = "2")]
+    pub to: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CheckRequest {
+    #[prost(bool, tag = "1")]
+    pub repair: bool,
+    #[prost(bool, tag = "2")]
+    pub use_mirror: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Finding {
+    #[prost(string, tag = "1")]
+    pub path: String,
+    #[prost(string, tag = "2")]
+    pub kind: String,
+    #[prost(uint32, repeated, tag = "3")]
+    pub clusters: Vec<u32>,
+    #[prost(string, tag = "4")]
+    pub message: String,
+    #[prost(bool, tag = "5")]
+    pub repaired: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CheckReply {
+    #[prost(uint64, tag = "1")]
+    pub files: u64,
+    #[prost(uint64, tag = "2")]
+    pub directories: u64,
+    #[prost(uint64, tag = "3")]
+    pub errors: u64,
+    #[prost(uint64, tag = "4")]
+    pub warnings: u64,
+    #[prost(uint64, tag = "5")]
+    pub repairs: u64,
+    #[prost(int32, tag = "6")]
+    pub exit_code: i32,
+    #[prost(message, repeated, tag = "7")]
+    pub findings: Vec<Finding>,
+}
+
+fn status(error: FATError) -> Status {
+    let code = match error {
+        FATError::FileNotFound => Code::NotFound,
+        FATError::FileExists => Code::AlreadyExists,
+        FATError::DirNotEmpty | FATError::NotADirectory | FATError::IsADirectory => {
+            Code::FailedPrecondition
+        }
+        FATError::NotEnoughSpace => Code::ResourceExhausted,
+        FATError::FilenameTooLong | FATError::InvalidName | FATError::FileTooLarge => {
+            Code::InvalidArgument
+        }
+        FATError::Protected
+        | FATError::PermissionDenied
+        | FATError::Locked
+        | FATError::BadPassphrase => Code::PermissionDenied,
+        _ => Code::Internal,
+    };
+    Status::new(code, error.to_string())
+}
+
+/// `path` as the image's own operations know it.
+fn image_path(path: &str) -> String {
+    let parts = path
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect::<Vec<_>>();
+    if parts.is_empty() {
+        ROOT.to_string()
+    } else {
+        parts.join("/")
+    }
+}
+
+/// The `zos.Image` service; calls take turns on the image, and those that
+/// change it are synced before they return.
+struct Service {
+    fat: Arc<Mutex<FAT>>,
+}
+
+impl Service {
+    fn fat(&self) -> Result<MutexGuard<'_, FAT>, Status> {
+        self.fat
+            .lock()
+            .map_err(|_| Status::unavailable("image is unavailable"))
+    }
+
+    /// Runs `op` as one transaction and syncs the image after it.
+    fn change(
+        &self,
+        op: impl FnOnce(&mut FAT) -> Result<(), FATError>,
+    ) -> Result<Response<Empty>, Status> {
+        let mut fat = self.fat()?;
+        fat.transaction(op)
+            .and_then(|()| fat.sync())
+            .map_err(status)?;
+        Ok(Response::new(Empty {}))
+    }
+}
+
+#[tonic::async_trait]
+impl Image for Service {
+    async fn list_dir(
+        &self,
+        request: Request<PathRequest>,
+    ) -> Result<Response<ListDirReply>, Status> {
+        let path = image_path(&request.into_inner().path);
+        let entries = self.fat()?.read_dir(&path).map_err(status)?;
+
+        Ok(Response::new(ListDirReply {
+            entries: entries
+                .iter()
+                .filter(|entry| entry.name() != "." && entry.name() != "..")
+                .map(|entry| Entry {
+                    name: entry.name().to_string(),
+                    dir: FAT::filter_mkdir(entry),
+                    size: entry.size(),
+                })
+                .collect(),
+        }))
+    }
+
+    async fn read_file(&self, request: Request<PathRequest>) -> Result<Response<FileData>, Status> {
+        let path = request.into_inner().path;
+        let mut data = vec![];
+        self.fat()?
+            .cat(&image_path(&path), &mut data)
+            .map_err(status)?;
+        Ok(Response::new(FileData { path, data }))
+    }
+
+    async fn write_file(&self, request: Request<FileData>) -> Result<Response<Empty>, Status> {
+        let FileData { path, data } = request.into_inner();
+        let path = image_path(&path);
+        self.change(|fs| fs.apply(&[FsOp::Write { path, data }]))
+    }
+
+    async fn remove(&self, request: Request<PathRequest>) -> Result<Response<Empty>, Status> {
+        let path = image_path(&request.into_inner().path);
+        self.change(|fs| {
+            if FAT::filter_mkdir(&fs.find_file(&path, FAT::filter_find)?) {
+                fs.remove_dir(&path)
+            } else {
+                fs.remove_file(&path)
+            }
+        })
+    }
+
+    async fn mkdir(&self, request: Request<PathRequest>) -> Result<Response<Empty>, Status> {
+        let path = image_path(&request.into_inner().path);
+        self.change(|fs| fs.mkdir(&path))
+    }
+
+    async fn move_file(&self, request: Request<MoveRequest>) -> Result<Response<Empty>, Status> {
+        let MoveRequest { from, to } = request.into_inner();
+        self.change(|fs| fs.move_file(&image_path(&from), &image_path(&to)))
+    }
+
+    async fn check(&self, request: Request<CheckRequest>) -> Result<Response<CheckReply>, Status> {
+        let CheckRequest { repair, use_mirror } = request.into_inner();
+        let mut fat = self.fat()?;
+        let report = fat
+            .check(CheckOptions { repair, use_mirror })
+            .map_err(status)?;
+        if repair {
+            fat.sync().map_err(status)?;
+        }
+
+        Ok(Response::new(CheckReply {
+            files: report.files as u64,
+            directories: report.directories as u64,
+            errors: report.errors() as u64,
+            warnings: report.warnings() as u64,
+            repairs: report.repairs() as u64,
+            exit_code: report.exit_code(),
+            findings: report
+                .findings
+                .iter()
+                .map(|finding| Finding {
+                    path: finding.path.clone(),
+                    kind: finding.problem.as_str().to_string(),
+                    clusters: finding.clusters.clone(),
+                    message: finding.message.clone(),
+                    repaired: finding.repair.is_some(),
+                })
+                .collect(),
+        }))
+    }
+}
+
+/// Serves the `zos.Image` service on `address` until the process is stopped.
+pub fn serve(fat: FAT, address: SocketAddr) -> Result<(), Box<dyn Error>> {
+    let service = Service {
+        fat: Arc::new(Mutex::new(fat)),
+    };
+
+    tokio::runtime::Runtime::new()?.block_on(
+        tonic::transport::Server::builder()
+            .add_service(ImageServer::new(service))
+            .serve(address),
+    )?;
+    Ok(())
+}
@@ -0,0 +1,322 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Instant,
+};
+
+use crate::{
+    fat::{batch::FsOp, check::CheckOptions, FATError, FAT, ROOT},
+    json,
+    metrics::Metrics,
+};
+
+/// Port the JSON API listens on unless told otherwise.
+pub const HTTP_PORT: u16 = 8080;
+
+/// Largest request body accepted unless told otherwise.
+pub const MAX_BODY: u64 = 64 << 20;
+
+/// A finished response: status line, content type and body.
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: &'static str, body: String) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: body.into_bytes(),
+        }
+    }
+
+    fn ok() -> Self {
+        Self::json("200 OK", "{\"ok\":true}".to_string())
+    }
+
+    fn error(status: &'static str, message: &str) -> Self {
+        Self::json(status, format!("{{\"error\":{}}}", json::string(message)))
+    }
+
+    fn from_error(error: FATError) -> Self {
+        let status = match error {
+            FATError::FileNotFound | FATError::NotADirectory => "404 Not Found",
+            FATError::FileExists | FATError::DirNotEmpty | FATError::IsADirectory => "409 Conflict",
+            FATError::NotEnoughSpace => "507 Insufficient Storage",
+            FATError::FileTooLarge => "413 Payload Too Large",
+            FATError::FilenameTooLong | FATError::InvalidName => "400 Bad Request",
+            FATError::Protected
+            | FATError::PermissionDenied
+            | FATError::Locked
+            | FATError::BadPassphrase => "403 Forbidden",
+            _ => "500 Internal Server Error",
+        };
+        Self::error(status, &error.to_string())
+    }
+}
+
+/// Decodes `%XX` escapes and `+` of a query string value.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        out.push(byte);
+                        i += 2;
+                    }
+                    Err(_) => out.push(b'%'),
+                }
+            }
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// The `path` parameter of `query` as a path in the image.
+fn query_path(query: &str) -> String {
+    let value = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == "path")
+        .map(|(_, value)| percent_decode(value))
+        .unwrap_or_default();
+
+    let parts = value
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect::<Vec<_>>();
+    if parts.is_empty() {
+        ROOT.to_string()
+    } else {
+        parts.join("/")
+    }
+}
+
+/// Endpoints of the JSON API, whose latencies `/metrics` reports.
+const ENDPOINTS: [&str; 4] = ["/ls", "/file", "/mkdir", "/check"];
+
+/// Serves a JSON API over HTTP, one thread per connection, with every
+/// request holding the image for as long as it runs. `GET /metrics` reports
+/// the image's counters in the Prometheus text format.
+pub struct Server {
+    fat: Arc<Mutex<FAT>>,
+    metrics: Arc<Mutex<Metrics>>,
+    /// Requests with a longer body are refused with 413.
+    max_body: u64,
+}
+
+impl Server {
+    pub fn new(fat: FAT) -> Self {
+        Self {
+            fat: Arc::new(Mutex::new(fat)),
+            metrics: Arc::default(),
+            max_body: MAX_BODY,
+        }
+    }
+
+    pub fn set_max_body(&mut self, max_body: u64) {
+        self.max_body = max_body;
+    }
+
+    /// Accepts clients on `address` until the process is stopped.
+    pub fn listen(&self, address: SocketAddr) -> io::Result<()> {
+        let listener = TcpListener::bind(address)?;
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let fat = Arc::clone(&self.fat);
+            let metrics = Arc::clone(&self.metrics);
+            let max_body = self.max_body;
+            thread::spawn(move || {
+                // a client that goes away mid-request only loses its own answer
+                let _ = Self::serve(&fat, &metrics, max_body, stream);
+            });
+        }
+        Ok(())
+    }
+
+    /// Reads one request, answers it and closes the connection.
+    fn serve(
+        fat: &Mutex<FAT>,
+        metrics: &Mutex<Metrics>,
+        max_body: u64,
+        stream: TcpStream,
+    ) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let mut words = line.split_whitespace();
+        let (Some(method), Some(target)) = (words.next(), words.next()) else {
+            return Self::respond(
+                &mut writer,
+                Response::error("400 Bad Request", "Bad request"),
+            );
+        };
+        let (route, query) = target.split_once('?').unwrap_or((target, ""));
+
+        let mut length = 0;
+        let mut expect_continue = false;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    length = value.trim().parse().unwrap_or(0);
+                } else if name.trim().eq_ignore_ascii_case("expect")
+                    && value.trim().eq_ignore_ascii_case("100-continue")
+                {
+                    expect_continue = true;
+                }
+            }
+        }
+
+        // the body is never read, the connection is closed after the answer
+        if length > max_body {
+            return Self::respond(
+                &mut writer,
+                Response::error("413 Payload Too Large", "Request body too large"),
+            );
+        }
+        if expect_continue {
+            write!(writer, "HTTP/1.1 100 Continue\r\n\r\n")?;
+        }
+
+        let mut body = vec![];
+        reader.take(length).read_to_end(&mut body)?;
+
+        let (Ok(mut fat), Ok(mut metrics)) = (fat.lock(), metrics.lock()) else {
+            return Self::respond(
+                &mut writer,
+                Response::error("500 Internal Server Error", "Image is unavailable"),
+            );
+        };
+
+        let response = if (method, route) == ("GET", "/metrics") {
+            match metrics.render(&mut fat) {
+                Ok(text) => Response {
+                    status: "200 OK",
+                    content_type: "text/plain; version=0.0.4",
+                    body: text.into_bytes(),
+                },
+                Err(error) => Response::from_error(error),
+            }
+        } else {
+            let start = Instant::now();
+            let response = Self::handle(&mut fat, method, route, query, body);
+            if ENDPOINTS.contains(&route) {
+                metrics.record(&format!("{method} {route}"), start.elapsed());
+            }
+            response
+        };
+
+        // the image is not held while the client reads the answer
+        drop((fat, metrics));
+        Self::respond(&mut writer, response)
+    }
+
+    fn respond(stream: &mut TcpStream, response: Response) -> io::Result<()> {
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            response.status,
+            response.content_type,
+            response.body.len()
+        )?;
+        stream.write_all(&response.body)
+    }
+
+    fn handle(fat: &mut FAT, method: &str, route: &str, query: &str, body: Vec<u8>) -> Response {
+        let path = query_path(query);
+
+        let result = match (method, route) {
+            ("GET", "/ls") => fat.read_dir(&path).map(|entries| {
+                let entries = entries
+                    .iter()
+                    .filter(|entry| entry.name() != "." && entry.name() != "..")
+                    .map(|entry| {
+                        format!(
+                            "{{\"name\":{},\"dir\":{},\"size\":{}}}",
+                            json::string(entry.name()),
+                            FAT::filter_mkdir(entry),
+                            entry.size()
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                Response::json("200 OK", format!("[{}]", entries.join(",")))
+            }),
+            ("GET", "/file") => {
+                let mut data = vec![];
+                fat.cat(&path, &mut data).map(|()| Response {
+                    status: "200 OK",
+                    content_type: "application/octet-stream",
+                    body: data,
+                })
+            }
+            ("PUT", "/file") => fat
+                .apply(&[FsOp::Write { path, data: body }])
+                .and_then(|()| fat.sync())
+                .map(|()| Response::ok()),
+            ("DELETE", "/file") => fat
+                .transaction(|fs| {
+                    if FAT::filter_mkdir(&fs.find_file(&path, FAT::filter_find)?) {
+                        fs.remove_dir(&path)
+                    } else {
+                        fs.remove_file(&path)
+                    }
+                })
+                .and_then(|()| fat.sync())
+                .map(|()| Response::ok()),
+            ("POST", "/mkdir") => fat
+                .transaction(|fs| fs.mkdir(&path))
+                .and_then(|()| fat.sync())
+                .map(|()| Response::ok()),
+            ("GET", "/check") => fat
+                .check(CheckOptions {
+                    repair: false,
+                    use_mirror: false,
+                })
+                .map(|report| {
+                    let findings = report
+                        .findings
+                        .iter()
+                        .map(|finding| finding.to_json())
+                        .collect::<Vec<_>>();
+                    Response::json(
+                        "200 OK",
+                        format!(
+                            "{{\"files\":{},\"directories\":{},\"errors\":{},\"warnings\":{},\"findings\":[{}]}}",
+                            report.files,
+                            report.directories,
+                            report.errors(),
+                            report.warnings(),
+                            findings.join(",")
+                        ),
+                    )
+                }),
+            (_, "/ls" | "/file" | "/mkdir" | "/check" | "/metrics") => Ok(Response::error(
+                "405 Method Not Allowed",
+                "Method not allowed",
+            )),
+            _ => Ok(Response::error("404 Not Found", "No such endpoint")),
+        };
+
+        result.unwrap_or_else(Response::from_error)
+    }
+}
@@ -0,0 +1,8 @@
+//! The image format and its operations, without any of the frontends, so
+//! that they can be built for targets without a host filesystem such as
+//! `wasm32-unknown-unknown`.
+
+pub mod fat;
+pub mod json;
+pub mod time;
+pub mod units;
@@ -0,0 +1,71 @@
+use std::{
+    fs::File,
+    io::{self, Seek, Write},
+};
+
+use crate::fat::{uuid::Uuid, FAT};
+
+/// Keeps an image to one process at a time through `<image>.lock`.
+///
+/// The file is locked by the operating system, so a process that dies lets
+/// go of it, and it names the UUID of the image and the process holding it,
+/// so tools can tell images apart whatever their file names.
+pub struct Lock {
+    file: File,
+    uuid: Option<Uuid>,
+}
+
+impl Lock {
+    /// Takes the lock of the image in the host file `image`, failing with
+    /// [`io::ErrorKind::WouldBlock`] when another process holds it. Taken
+    /// before the image is opened, it names no UUID until [`Lock::refresh`].
+    pub fn acquire(image: &str) -> io::Result<Self> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(format!("{image}.lock"))?;
+        file.try_lock().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("{image} is in use by another process"),
+            )
+        })?;
+
+        let mut lock = Self { file, uuid: None };
+        lock.write()?;
+        Ok(lock)
+    }
+
+    /// Rewrites the lock file when `fat` has another UUID than it names, as
+    /// after `format`.
+    pub fn refresh(&mut self, fat: &FAT) -> io::Result<()> {
+        let uuid = fat.header().map(|header| header.uuid());
+        if uuid == self.uuid {
+            return Ok(());
+        }
+        self.uuid = uuid;
+        self.write()
+    }
+
+    fn write(&mut self) -> io::Result<()> {
+        let mut text = String::new();
+        if let Some(uuid) = self.uuid {
+            text += &format!("uuid={uuid}\n");
+        }
+        text += &format!("pid={}\n", std::process::id());
+
+        self.file.set_len(0)?;
+        self.file.rewind()?;
+        self.file.write_all(text.as_bytes())
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // removing the file would let two processes lock different files of
+        // the same name; emptied, it names no holder
+        let _ = self.file.set_len(0);
+    }
+}
@@ -1,23 +1,105 @@
-use std::{error::Error, io};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs::File,
+    io::{self, BufWriter, IsTerminal, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
 
-use fat::FAT;
+use fat::{alloc::AllocStrategy, disk::LatencyConfig, FAT};
+use ftp::FTP_PORT;
+use http::HTTP_PORT;
+use lock::Lock;
+use nbd::NBD_PORT;
+use ninep::NINEP_PORT;
+use tracing_subscriber::EnvFilter;
+use zos_rs::{fat, json, time, units};
 
 mod cli;
-mod fat;
-mod units;
+mod daemon;
+mod ftp;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod http;
+mod lock;
+mod metrics;
+#[cfg(feature = "fuse")]
+mod mount;
+mod nbd;
+mod ninep;
+#[cfg(feature = "tui")]
+mod tui;
+
+/// Where commands print their results.
+pub enum Output {
+    Stdout(io::Stdout),
+    /// Collected to be sent to a daemon client once the command is done.
+    Buffer(Vec<u8>),
+    /// A host file the command's output is redirected to with `>` or `>>`.
+    File(BufWriter<File>),
+}
+
+impl Output {
+    /// Takes what has been collected so far, leaving an empty buffer.
+    pub fn take(&mut self) -> Vec<u8> {
+        match self {
+            Self::Stdout(_) | Self::File(_) => vec![],
+            Self::Buffer(buffer) => std::mem::take(buffer),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Stdout(stdout) => stdout.write(buf),
+            Self::Buffer(buffer) => buffer.write(buf),
+            Self::File(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Stdout(stdout) => stdout.flush(),
+            Self::Buffer(_) => Ok(()),
+            Self::File(file) => file.flush(),
+        }
+    }
+}
 
 pub struct Application {
     running: bool,
     current_path: String,
+    /// File of the current image, `None` once it has been closed.
+    image: Option<String>,
+    /// Held for as long as `image` is open.
+    lock: Option<Lock>,
     file_system: FAT,
+    /// Further images opened with `mount`, by alias.
+    mounts: BTreeMap<String, FAT>,
+    status: i32,
+    output: Output,
+    /// Commands are typed at a terminal, so destructive ones are confirmed.
+    interactive: bool,
 }
 
 impl Application {
-    pub fn new(filename: String) -> Result<Self, io::Error> {
+    pub fn new(filename: String, latency: Option<LatencyConfig>) -> Result<Self, io::Error> {
+        let mut lock = Lock::acquire(&filename)?;
+        let file_system = FAT::new(filename.clone(), latency)?;
+        lock.refresh(&file_system)?;
+
         Ok(Self {
             running: true,
             current_path: "/".to_string(),
-            file_system: FAT::new(filename)?,
+            image: Some(filename),
+            lock: Some(lock),
+            file_system,
+            mounts: BTreeMap::new(),
+            status: 0,
+            output: Output::Stdout(io::stdout()),
+            interactive: false,
         })
     }
 
@@ -25,33 +107,304 @@ impl Application {
         self.running
     }
 
+    /// Raises the exit status reported when running non-interactively.
+    pub fn set_status(&mut self, status: i32) {
+        self.status = self.status.max(status);
+    }
+
     pub fn quit(&mut self) {
         self.running = false;
     }
+
+    pub fn prompt(&self) -> String {
+        match self.file_system.label() {
+            Some(label) if !label.is_empty() => format!("{label}:{}> ", self.current_path),
+            _ => format!("{}> ", self.current_path),
+        }
+    }
+}
+
+/// Takes the passphrase of an encrypted image from `ZOS_PASSPHRASE`, or
+/// otherwise from the next line of input.
+pub fn read_passphrase(prompt: &str) -> io::Result<String> {
+    if let Some(passphrase) = std::env::var("ZOS_PASSPHRASE")
+        .ok()
+        .filter(|passphrase| !passphrase.is_empty())
+    {
+        return Ok(passphrase);
+    }
+
+    if io::stdin().is_terminal() {
+        print!("{prompt}");
+        io::stdout().flush()?;
+    }
+
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// The port given with `--port`, or `default`.
+fn port(args: &[String], default: u16) -> Result<u16, Box<dyn Error>> {
+    match args.iter().position(|arg| arg == "--port") {
+        Some(position) => Ok(args
+            .get(position + 1)
+            .and_then(|port| port.parse().ok())
+            .ok_or("invalid port")?),
+        None => Ok(default),
+    }
+}
+
+/// Where a server listens: the address given with `--bind`, or only the
+/// local machine, and the port given with `--port`, or `default`.
+fn address(args: &[String], default: u16) -> Result<SocketAddr, Box<dyn Error>> {
+    let ip = match args.iter().position(|arg| arg == "--bind") {
+        Some(position) => args
+            .get(position + 1)
+            .and_then(|ip| ip.parse().ok())
+            .ok_or("invalid bind address")?,
+        None => IpAddr::V4(Ipv4Addr::LOCALHOST),
+    };
+    Ok(SocketAddr::new(ip, port(args, default)?))
+}
+
+#[cfg(feature = "fuse")]
+fn serve(app: &mut Application, mountpoint: &str) -> Result<(), Box<dyn Error>> {
+    Ok(mount::mount(
+        &mut app.file_system,
+        std::path::Path::new(mountpoint),
+    )?)
+}
+
+#[cfg(not(feature = "fuse"))]
+fn serve(_app: &mut Application, _mountpoint: &str) -> Result<(), Box<dyn Error>> {
+    Err("mounting needs a build with the `fuse` feature".into())
+}
+
+#[cfg(feature = "tui")]
+fn manage(app: &mut Application) -> Result<(), Box<dyn Error>> {
+    Ok(tui::run(&mut app.file_system)?)
+}
+
+#[cfg(not(feature = "tui"))]
+fn manage(_app: &mut Application) -> Result<(), Box<dyn Error>> {
+    Err("the file manager needs a build with the `tui` feature".into())
+}
+
+#[cfg(feature = "grpc")]
+fn serve_grpc(app: Application, args: &[String]) -> Result<(), Box<dyn Error>> {
+    grpc::serve(app.file_system, address(args, grpc::GRPC_PORT)?)
+}
+
+#[cfg(not(feature = "grpc"))]
+fn serve_grpc(_app: Application, _args: &[String]) -> Result<(), Box<dyn Error>> {
+    Err("the gRPC service needs a build with the `grpc` feature".into())
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
+    // `--connect <socket> [command...]` hands the command, or each line of
+    // input, to a running daemon instead of opening an image
+    if std::env::args().nth(1).as_deref() == Some("--connect") {
+        let socket = std::env::args().nth(2).ok_or("missing socket path")?;
+        let command = std::env::args().skip(3).collect::<Vec<_>>().join(" ");
+        let status = if command.is_empty() {
+            daemon::connect(&socket, io::stdin().lines().map_while(Result::ok))?
+        } else {
+            daemon::connect(&socket, std::iter::once(command))?
+        };
+        std::process::exit(status);
+    }
+
     let filename = std::env::args().nth(1).expect("Please provide a file!");
-    let mut app = Application::new(filename)?;
+    let args = std::env::args().skip(2).collect::<Vec<_>>();
+
+    // RUST_LOG picks what is traced; otherwise --verbose shows the commands,
+    // allocations and transactions on stderr
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        EnvFilter::new(if args.iter().any(|arg| arg == "--verbose") {
+            "zos_rs=debug"
+        } else {
+            "warn"
+        })
+    });
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .init();
+    let auto_fsck = args.iter().any(|arg| arg == "--auto-fsck");
+
+    // --seek-latency/--io-latency <ms> slow every disk access down for benchmarking
+    let millis = |name: &str| {
+        let position = args.iter().position(|arg| arg == name)?;
+        let value = args.get(position + 1)?.parse().ok()?;
+        Some(Duration::from_millis(value))
+    };
+    let latency = match (millis("--seek-latency"), millis("--io-latency")) {
+        (None, None) => None,
+        (seek, io) => Some(LatencyConfig {
+            seek: seek.unwrap_or_default(),
+            io: io.unwrap_or_default(),
+        }),
+    };
+
+    let mut app = Application::new(filename, latency)?;
+
+    let recovered = app
+        .file_system
+        .replay_journal()
+        .map_err(|_| "cannot replay the journal")?;
+    if recovered > 0 {
+        println!("recovered {recovered} metadata block(s) from the journal");
+    }
+
+    if app.file_system.is_locked() {
+        let passphrase = read_passphrase("Passphrase: ")?;
+        app.file_system.unlock(&passphrase)?;
+    }
+
+    app.file_system
+        .set_discard(args.iter().any(|arg| arg == "--discard"));
+    app.file_system
+        .set_verify(args.iter().any(|arg| arg == "--verify"));
+
+    if let Some(position) = args.iter().position(|arg| arg == "--alloc") {
+        let name = args
+            .get(position + 1)
+            .map(String::as_str)
+            .unwrap_or_default();
+        let strategy = AllocStrategy::from_name(name).ok_or("unknown allocation strategy")?;
+        app.file_system.set_alloc_strategy(strategy);
+    }
+
+    // `--deterministic` makes building the same tree twice yield the same
+    // bytes, overriding `--alloc`
+    if args.iter().any(|arg| arg == "--deterministic") {
+        app.file_system.set_deterministic(true);
+    }
+
+    // `--user <uid>` checks every command against the owner and mode of the
+    // entries it touches; without it, or as 0, everything is allowed
+    if let Some(position) = args.iter().position(|arg| arg == "--user") {
+        let user = args
+            .get(position + 1)
+            .and_then(|user| user.parse().ok())
+            .ok_or("invalid user")?;
+        app.file_system.set_user(Some(user));
+    }
+
+    if app.file_system.is_dirty() {
+        println!("warning: the image was not closed cleanly, run `check` to verify it");
+    }
+
+    if app.file_system.check_due() {
+        if auto_fsck {
+            cli::run(&mut app, "check");
+        } else {
+            println!("warning: the image has not been checked for a while, run `check`");
+        }
+    }
+
+    // `--tui` copies between the host and the image in a two-pane file manager
+    if args.iter().any(|arg| arg == "--tui") {
+        manage(&mut app)?;
+        app.file_system
+            .sync()
+            .map_err(|_| "cannot write the image")?;
+        return Ok(());
+    }
+
+    // `mount <mountpoint>` serves the image through FUSE instead of reading commands
+    if let Some(position) = args.iter().position(|arg| arg == "mount") {
+        let mountpoint = args.get(position + 1).ok_or("missing mountpoint")?;
+        serve(&mut app, mountpoint)?;
+        cli::run(&mut app, "exit");
+        return Ok(());
+    }
+
+    // `serve-nbd [--bind IP] [--port N]` exports the raw image to a single NBD client
+    if args.iter().any(|arg| arg == "serve-nbd") {
+        nbd::Server::new(&mut app.file_system)
+            .listen(address(&args, NBD_PORT)?)
+            .map_err(|_| "cannot serve the image over NBD")?;
+        return Ok(());
+    }
+
+    // `serve-9p [--bind IP] [--port N] | serve-9p --socket PATH` shares the
+    // tree with 9P2000 clients
+    if args.iter().any(|arg| arg == "serve-9p") {
+        let mut server = ninep::Server::new(&mut app.file_system);
+        match args.iter().position(|arg| arg == "--socket") {
+            Some(position) => {
+                server.listen_unix(args.get(position + 1).ok_or("missing socket path")?)?
+            }
+            None => server.listen_tcp(address(&args, NINEP_PORT)?)?,
+        }
+        return Ok(());
+    }
+
+    // `serve-ftp [--bind IP] [--port N]` lets FTP clients push and pull files
+    if args.iter().any(|arg| arg == "serve-ftp") {
+        ftp::Server::new(&mut app.file_system).listen(address(&args, FTP_PORT)?)?;
+        return Ok(());
+    }
+
+    // `serve-http [--bind IP] [--port N] [--max-body BYTES]` answers JSON
+    // requests from web frontends and scripts
+    if args.iter().any(|arg| arg == "serve-http") {
+        let address = address(&args, HTTP_PORT)?;
+        let mut server = http::Server::new(app.file_system);
+        if let Some(position) = args.iter().position(|arg| arg == "--max-body") {
+            server.set_max_body(
+                args.get(position + 1)
+                    .and_then(|bytes| bytes.parse().ok())
+                    .ok_or("invalid body size")?,
+            );
+        }
+        server.listen(address)?;
+        return Ok(());
+    }
+
+    // `serve-grpc [--bind IP] [--port N]` offers the management API to orchestration systems
+    if args.iter().any(|arg| arg == "serve-grpc") {
+        return serve_grpc(app, &args);
+    }
+
+    // `--daemon <socket>` keeps the image open and takes commands from clients
+    if let Some(position) = args.iter().position(|arg| arg == "--daemon") {
+        let socket = args.get(position + 1).ok_or("missing socket path")?;
+        daemon::serve(&mut app, socket)?;
+        return Ok(());
+    }
+
+    let interactive = io::stdin().is_terminal();
+    app.interactive = interactive;
 
     while app.running() {
+        if interactive {
+            print!("{}", app.prompt());
+            io::stdout().flush()?;
+        }
+
         let mut line = String::new();
-        io::stdin().read_line(&mut line)?;
+        if io::stdin().read_line(&mut line)? == 0 {
+            // end of input behaves like `exit`
+            cli::run(&mut app, "exit");
+            break;
+        }
 
         let trimmed = line.trim();
-        if trimmed.len() == 0 {
+        if trimmed.is_empty() {
             continue;
         }
 
-        if let Some(handler) = cli::get(line.trim()) {
-            if let Err(err) = handler.handle(&mut app) {
-                println!("{}", err);
-            } else {
-                println!("OK");
-            }
-        } else {
-            println!("invalid command: {}", trimmed);
-        }
+        cli::run(&mut app, trimmed);
+    }
+
+    if !interactive {
+        // `exit` skips destructors, and the lock must let go of the image
+        let status = app.status;
+        drop(app);
+        std::process::exit(status);
     }
 
     Ok(())
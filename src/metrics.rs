@@ -0,0 +1,103 @@
+use std::{collections::BTreeMap, fmt::Write, time::Duration};
+
+use crate::fat::{FATError, FAT};
+
+/// How long the operations of a server took, by operation.
+#[derive(Default)]
+pub struct Metrics {
+    /// Number of calls and total seconds they took.
+    operations: BTreeMap<String, (u64, f64)>,
+}
+
+impl Metrics {
+    pub fn record(&mut self, operation: &str, elapsed: Duration) {
+        let (count, seconds) = self.operations.entry(operation.to_string()).or_default();
+        *count += 1;
+        *seconds += elapsed.as_secs_f64();
+    }
+
+    /// The IO counters, space left on `fat` and the recorded latencies in the
+    /// Prometheus text format.
+    pub fn render(&self, fat: &mut FAT) -> Result<String, FATError> {
+        let stats = fat.stats();
+        let usage = fat.usage()?;
+        let mut out = String::new();
+
+        let counters = [
+            (
+                "sector_reads",
+                "Sectors read from the image.",
+                stats.sector_reads,
+            ),
+            (
+                "sector_writes",
+                "Sectors written to the image.",
+                stats.sector_writes,
+            ),
+            (
+                "fat_reads",
+                "FAT sectors read, counting every copy.",
+                stats.fat_reads,
+            ),
+            (
+                "fat_writes",
+                "FAT sectors written, counting every copy.",
+                stats.fat_writes,
+            ),
+            (
+                "cache_hits",
+                "FAT lookups served from a sector already read.",
+                stats.cache_hits,
+            ),
+            (
+                "cache_misses",
+                "FAT lookups that had to read a sector.",
+                stats.cache_misses,
+            ),
+            (
+                "clusters_allocated",
+                "Clusters allocated.",
+                stats.clusters_allocated,
+            ),
+            ("clusters_freed", "Clusters freed.", stats.clusters_freed),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP zos_{name}_total {help}");
+            let _ = writeln!(out, "# TYPE zos_{name}_total counter");
+            let _ = writeln!(out, "zos_{name}_total {value}");
+        }
+
+        let gauges = [
+            ("total_bytes", "Size of the data region.", usage.total),
+            (
+                "free_bytes",
+                "Bytes a new file could still be given.",
+                usage.free,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            let _ = writeln!(out, "# HELP zos_{name} {help}");
+            let _ = writeln!(out, "# TYPE zos_{name} gauge");
+            let _ = writeln!(out, "zos_{name} {value}");
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP zos_operation_duration_seconds Time operations took."
+        );
+        let _ = writeln!(out, "# TYPE zos_operation_duration_seconds summary");
+        for (operation, (count, seconds)) in &self.operations {
+            let operation = operation.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = writeln!(
+                out,
+                "zos_operation_duration_seconds_sum{{operation=\"{operation}\"}} {seconds}"
+            );
+            let _ = writeln!(
+                out,
+                "zos_operation_duration_seconds_count{{operation=\"{operation}\"}} {count}"
+            );
+        }
+
+        Ok(out)
+    }
+}
@@ -0,0 +1,381 @@
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    io::{self, Cursor},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyWrite, Request, TimeOrNow, FUSE_ROOT_ID,
+};
+
+use crate::fat::{dirent::Entry, join, FATError, FAT, ROOT};
+
+/// How long the kernel may cache attributes and lookups.
+const TTL: Duration = Duration::from_secs(1);
+
+/// Serves the image through FUSE, one transaction per request.
+///
+/// Inode numbers are handed out as paths are looked up and stay valid until
+/// the path is removed or renamed.
+struct Mount<'a> {
+    fat: &'a mut FAT,
+    paths: HashMap<u64, String>,
+    inodes: HashMap<String, u64>,
+    next_inode: u64,
+}
+
+fn errno(error: FATError) -> i32 {
+    match error {
+        FATError::FileNotFound => libc::ENOENT,
+        FATError::FileExists => libc::EEXIST,
+        FATError::DirNotEmpty => libc::ENOTEMPTY,
+        FATError::NotEnoughSpace => libc::ENOSPC,
+        FATError::FilenameTooLong => libc::ENAMETOOLONG,
+        FATError::InvalidName => libc::EINVAL,
+        FATError::Protected => libc::EPERM,
+        FATError::PermissionDenied => libc::EACCES,
+        FATError::NotADirectory => libc::ENOTDIR,
+        FATError::IsADirectory => libc::EISDIR,
+        FATError::FileTooLarge => libc::EFBIG,
+        FATError::Locked | FATError::BadPassphrase => libc::EACCES,
+        _ => libc::EIO,
+    }
+}
+
+impl<'a> Mount<'a> {
+    fn new(fat: &'a mut FAT) -> Self {
+        Self {
+            fat,
+            paths: HashMap::from([(FUSE_ROOT_ID, ROOT.to_string())]),
+            inodes: HashMap::from([(ROOT.to_string(), FUSE_ROOT_ID)]),
+            next_inode: FUSE_ROOT_ID + 1,
+        }
+    }
+
+    fn inode(&mut self, path: &str) -> u64 {
+        if let Some(inode) = self.inodes.get(path) {
+            return *inode;
+        }
+
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        self.paths.insert(inode, path.to_string());
+        self.inodes.insert(path.to_string(), inode);
+        inode
+    }
+
+    /// Forgets `path` and everything below it.
+    fn forget_path(&mut self, path: &str) {
+        let prefix = format!("{path}/");
+        self.inodes
+            .retain(|other, _| other != path && !other.starts_with(&prefix));
+        self.paths
+            .retain(|_, other| other != path && !other.starts_with(&prefix));
+    }
+
+    fn path(&self, inode: u64) -> Result<String, FATError> {
+        self.paths
+            .get(&inode)
+            .cloned()
+            .ok_or(FATError::FileNotFound)
+    }
+
+    fn child(&self, parent: u64, name: &OsStr) -> Result<String, FATError> {
+        let parent = self.path(parent)?;
+        let name = name.to_str().ok_or(FATError::FilenameTooLong)?;
+        Ok(join(&parent, name))
+    }
+
+    fn attr(&mut self, req: &Request<'_>, path: &str) -> Result<FileAttr, FATError> {
+        let (kind, size, perm) = if path == ROOT {
+            (FileType::Directory, 0, 0o755)
+        } else {
+            let entry = self.fat.find_file(path, FAT::filter_find)?;
+            let mode = entry.permissions().map(|permissions| permissions.mode);
+            if FAT::filter_mkdir(&entry) {
+                (FileType::Directory, 0, mode.unwrap_or(0o755))
+            } else {
+                (FileType::RegularFile, entry.size(), mode.unwrap_or(0o644))
+            }
+        };
+
+        Ok(FileAttr {
+            ino: self.inode(path),
+            size,
+            blocks: size.div_ceil(512),
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind,
+            perm,
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid: req.uid(),
+            gid: req.gid(),
+            rdev: 0,
+            blksize: 512,
+            flags: 0,
+        })
+    }
+
+    fn kind(entry: &Entry) -> FileType {
+        if FAT::filter_mkdir(entry) {
+            FileType::Directory
+        } else {
+            FileType::RegularFile
+        }
+    }
+}
+
+impl Filesystem for Mount<'_> {
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self
+            .child(parent, name)
+            .and_then(|path| self.attr(req, &path))
+        {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(error) => reply.error(errno(error)),
+        }
+    }
+
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.path(ino).and_then(|path| self.attr(req, &path)) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(error) => reply.error(errno(error)),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let result = self.path(ino).and_then(|path| {
+            if let Some(size) = size {
+                self.fat.truncate(&path, size)?;
+            }
+            self.attr(req, &path)
+        });
+
+        match result {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(error) => reply.error(errno(error)),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let path = match self.path(ino) {
+            Ok(path) => path,
+            Err(error) => return reply.error(errno(error)),
+        };
+        let entries = match self.fat.read_dir(&path) {
+            Ok(entries) => entries,
+            Err(error) => return reply.error(errno(error)),
+        };
+
+        let mut listing = vec![
+            (ino, FileType::Directory, ".".to_string()),
+            (ino, FileType::Directory, "..".to_string()),
+        ];
+        for entry in entries {
+            if entry.name() == "." || entry.name() == ".." {
+                continue;
+            }
+            let child = join(&path, entry.name());
+            listing.push((
+                self.inode(&child),
+                Self::kind(&entry),
+                entry.name().to_string(),
+            ));
+        }
+
+        for (index, (inode, kind, name)) in listing.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(inode, index as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let mut data = vec![];
+        match self
+            .path(ino)
+            .and_then(|path| self.fat.cat(&path, &mut data))
+        {
+            Ok(()) => {
+                let start = (offset as usize).min(data.len());
+                let end = (start + size as usize).min(data.len());
+                reply.data(&data[start..end]);
+            }
+            Err(error) => reply.error(errno(error)),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        match self
+            .path(ino)
+            .and_then(|path| self.fat.write_at(&path, offset as u64, data))
+        {
+            Ok(()) => reply.written(data.len() as u32),
+            Err(error) => reply.error(errno(error)),
+        }
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let result = self.child(parent, name).and_then(|path| {
+            self.fat.new_file(&path, Cursor::new(vec![]))?;
+            self.attr(req, &path)
+        });
+
+        match result {
+            Ok(attr) => reply.created(&TTL, &attr, 0, 0, 0),
+            Err(error) => reply.error(errno(error)),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let result = self.child(parent, name).and_then(|path| {
+            self.fat.transaction(|fs| fs.mkdir(&path))?;
+            self.attr(req, &path)
+        });
+
+        match result {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(error) => reply.error(errno(error)),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let result = self.child(parent, name).and_then(|path| {
+            self.fat.transaction(|fs| fs.remove_file(&path))?;
+            self.forget_path(&path);
+            Ok(())
+        });
+
+        match result {
+            Ok(()) => reply.ok(),
+            Err(error) => reply.error(errno(error)),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let result = self.child(parent, name).and_then(|path| {
+            self.fat.transaction(|fs| fs.remove_dir(&path))?;
+            self.forget_path(&path);
+            Ok(())
+        });
+
+        match result {
+            Ok(()) => reply.ok(),
+            Err(error) => reply.error(errno(error)),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        _flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let result = self.child(parent, name).and_then(|source| {
+            let dest = self.child(newparent, newname)?;
+            self.fat.transaction(|fs| {
+                // unlike `mv`, a rename replaces the file it lands on
+                if fs.find_file(&dest, FAT::filter_find_file).is_ok() {
+                    fs.remove_file(&dest)?;
+                }
+                fs.move_file(&source, &dest)
+            })?;
+            self.forget_path(&source);
+            self.forget_path(&dest);
+            Ok(())
+        });
+
+        match result {
+            Ok(()) => reply.ok(),
+            Err(error) => reply.error(errno(error)),
+        }
+    }
+}
+
+/// Exposes the image at `mountpoint` until it is unmounted.
+pub fn mount(fat: &mut FAT, mountpoint: &Path) -> io::Result<()> {
+    let label = fat.label().unwrap_or_default().to_string();
+    let name = if label.is_empty() {
+        "zos_rs".to_string()
+    } else {
+        label
+    };
+
+    fuser::mount2(
+        Mount::new(fat),
+        mountpoint,
+        &[MountOption::FSName(name), MountOption::DefaultPermissions],
+    )
+}
@@ -0,0 +1,230 @@
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+};
+
+use crate::fat::{disk::Disk, FAT};
+
+/// Default port of the NBD protocol.
+pub const NBD_PORT: u16 = 10809;
+
+const NBDMAGIC: u64 = 0x4e42_444d_4147_4943;
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const FLAG_NO_ZEROES: u16 = 1 << 1;
+
+const FLAG_HAS_FLAGS: u16 = 1 << 0;
+const FLAG_SEND_FLUSH: u16 = 1 << 2;
+
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_ABORT: u32 = 2;
+const OPT_LIST: u32 = 3;
+const OPT_INFO: u32 = 6;
+const OPT_GO: u32 = 7;
+
+const REP_ACK: u32 = 1;
+const REP_SERVER: u32 = 2;
+const REP_INFO: u32 = 3;
+const REP_ERR_UNSUP: u32 = (1 << 31) + 1;
+const INFO_EXPORT: u16 = 0;
+
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+
+const EIO: u32 = 5;
+const EINVAL: u32 = 22;
+const ENOSPC: u32 = 28;
+
+/// Largest read or write a client may ask for at once.
+const MAX_REQUEST: u32 = 32 << 20;
+
+fn read_u16(stream: &mut TcpStream) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    stream.read_exact(&mut bytes)?;
+    Ok(u16::from_be_bytes(bytes))
+}
+
+fn read_u32(stream: &mut TcpStream) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    stream.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64(stream: &mut TcpStream) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    stream.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn option_reply(stream: &mut TcpStream, option: u32, kind: u32, data: &[u8]) -> io::Result<()> {
+    let mut reply = REPLY_MAGIC.to_be_bytes().to_vec();
+    reply.extend_from_slice(&option.to_be_bytes());
+    reply.extend_from_slice(&kind.to_be_bytes());
+    reply.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    reply.extend_from_slice(data);
+    stream.write_all(&reply)
+}
+
+fn simple_reply(stream: &mut TcpStream, error: u32, handle: u64, data: &[u8]) -> io::Result<()> {
+    let mut reply = SIMPLE_REPLY_MAGIC.to_be_bytes().to_vec();
+    reply.extend_from_slice(&error.to_be_bytes());
+    reply.extend_from_slice(&handle.to_be_bytes());
+    reply.extend_from_slice(data);
+    stream.write_all(&reply)
+}
+
+/// Exports the raw image to a single NBD client.
+pub struct Server<'a> {
+    fat: &'a mut FAT,
+}
+
+impl<'a> Server<'a> {
+    pub fn new(fat: &'a mut FAT) -> Self {
+        Self { fat }
+    }
+
+    /// Serves the first client to connect on `address` until it disconnects.
+    ///
+    /// Clients see the image byte for byte, so the filesystem must not be
+    /// used afterwards.
+    pub fn listen(&mut self, address: SocketAddr) -> io::Result<()> {
+        let disk = self
+            .fat
+            .export()
+            .map_err(|_| io::Error::from(io::ErrorKind::Other))?;
+
+        let listener = TcpListener::bind(address)?;
+        let (mut stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+
+        if negotiate(disk, &mut stream)? {
+            transmit(disk, &mut stream)?;
+        }
+
+        disk.sync()
+    }
+}
+
+/// Size and transmission flags sent when an export is chosen.
+fn export_info(disk: &Disk) -> io::Result<Vec<u8>> {
+    let mut info = disk.len()?.to_be_bytes().to_vec();
+    info.extend_from_slice(&(FLAG_HAS_FLAGS | FLAG_SEND_FLUSH).to_be_bytes());
+    Ok(info)
+}
+
+/// Runs the fixed newstyle handshake. Returns whether the client chose
+/// the export rather than leaving.
+fn negotiate(disk: &Disk, stream: &mut TcpStream) -> io::Result<bool> {
+    let mut greeting = NBDMAGIC.to_be_bytes().to_vec();
+    greeting.extend_from_slice(&IHAVEOPT.to_be_bytes());
+    greeting.extend_from_slice(&(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES).to_be_bytes());
+    stream.write_all(&greeting)?;
+
+    let client_flags = read_u32(stream)?;
+    let no_zeroes = client_flags & FLAG_NO_ZEROES as u32 != 0;
+
+    loop {
+        if read_u64(stream)? != IHAVEOPT {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let option = read_u32(stream)?;
+        let len = read_u32(stream)?;
+        if len > MAX_REQUEST {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let mut data = vec![0; len as usize];
+        stream.read_exact(&mut data)?;
+
+        match option {
+            OPT_EXPORT_NAME => {
+                // the only export is the image, whatever it is asked for by
+                let mut reply = export_info(disk)?;
+                if !no_zeroes {
+                    reply.resize(reply.len() + 124, 0);
+                }
+                stream.write_all(&reply)?;
+                return Ok(true);
+            }
+            OPT_ABORT => {
+                option_reply(stream, option, REP_ACK, &[])?;
+                return Ok(false);
+            }
+            OPT_LIST => {
+                // one export with an empty name
+                option_reply(stream, option, REP_SERVER, &0u32.to_be_bytes())?;
+                option_reply(stream, option, REP_ACK, &[])?;
+            }
+            OPT_INFO | OPT_GO => {
+                let mut info = INFO_EXPORT.to_be_bytes().to_vec();
+                info.extend(export_info(disk)?);
+                option_reply(stream, option, REP_INFO, &info)?;
+                option_reply(stream, option, REP_ACK, &[])?;
+                if option == OPT_GO {
+                    return Ok(true);
+                }
+            }
+            _ => option_reply(stream, option, REP_ERR_UNSUP, &[])?,
+        }
+    }
+}
+
+/// Answers requests until the client disconnects.
+fn transmit(disk: &mut Disk, stream: &mut TcpStream) -> io::Result<()> {
+    let size = disk.len()?;
+
+    loop {
+        if read_u32(stream)? != REQUEST_MAGIC {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let _flags = read_u16(stream)?;
+        let command = read_u16(stream)?;
+        let handle = read_u64(stream)?;
+        let offset = read_u64(stream)?;
+        let len = read_u32(stream)?;
+
+        if len > MAX_REQUEST {
+            // the data of a write that large cannot be skipped safely
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let in_bounds = offset
+            .checked_add(len as u64)
+            .is_some_and(|end| end <= size);
+
+        match command {
+            CMD_READ => {
+                let mut data = vec![0; len as usize];
+                if !in_bounds {
+                    simple_reply(stream, EINVAL, handle, &[])?;
+                } else if disk.read_at(offset, &mut data).is_err() {
+                    simple_reply(stream, EIO, handle, &[])?;
+                } else {
+                    simple_reply(stream, 0, handle, &data)?;
+                }
+            }
+            CMD_WRITE => {
+                let mut data = vec![0; len as usize];
+                stream.read_exact(&mut data)?;
+                let error = if !in_bounds {
+                    ENOSPC
+                } else if disk.write_at(offset, &data).is_err() {
+                    EIO
+                } else {
+                    0
+                };
+                simple_reply(stream, error, handle, &[])?;
+            }
+            CMD_FLUSH => {
+                let error = if disk.sync().is_err() { EIO } else { 0 };
+                simple_reply(stream, error, handle, &[])?;
+            }
+            CMD_DISC => return Ok(()),
+            _ => simple_reply(stream, EINVAL, handle, &[])?,
+        }
+    }
+}
@@ -0,0 +1,500 @@
+use std::{
+    collections::HashMap,
+    io::{self, Cursor, Read, Write},
+    net::{SocketAddr, TcpListener},
+    os::unix::net::UnixListener,
+};
+
+use tracing::warn;
+
+use crate::fat::{dirent::Entry, join, FATError, FAT, ROOT};
+
+/// Port 9P servers usually listen on.
+pub const NINEP_PORT: u16 = 564;
+
+const VERSION: &str = "9P2000";
+/// Largest message the server agrees to, headers included.
+const MAX_MSIZE: u32 = 64 << 10;
+/// Size, type and tag in front of every message.
+const HEADER: u32 = 7;
+/// Size, count and header of an `Rread`, in front of the data.
+const IOHDRSZ: u32 = 24;
+
+const TVERSION: u8 = 100;
+const TAUTH: u8 = 102;
+const TATTACH: u8 = 104;
+const RERROR: u8 = 107;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TOPEN: u8 = 112;
+const TCREATE: u8 = 114;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+const TSTAT: u8 = 124;
+const TWSTAT: u8 = 126;
+
+const QTDIR: u8 = 0x80;
+const QTFILE: u8 = 0;
+const DMDIR: u32 = 0x8000_0000;
+const OTRUNC: u8 = 0x10;
+
+/// A failed request, answered with `Rerror`.
+struct Error(String);
+
+impl From<FATError> for Error {
+    fn from(error: FATError) -> Self {
+        Self(error.to_string())
+    }
+}
+
+/// Reads the fields of a T-message in order.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < len {
+            return Err(Error("malformed message".to_string()));
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<&'a str, Error> {
+        let len = self.u16()? as usize;
+        std::str::from_utf8(self.take(len)?).map_err(|_| Error("malformed message".to_string()))
+    }
+}
+
+/// Lays out the fields of an R-message.
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn u8(mut self, value: u8) -> Self {
+        self.0.push(value);
+        self
+    }
+
+    fn u16(mut self, value: u16) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn string(self, value: &str) -> Self {
+        self.u16(value.len() as u16).bytes(value.as_bytes())
+    }
+
+    fn bytes(mut self, value: &[u8]) -> Self {
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    fn qid(self, qid: Qid) -> Self {
+        self.u8(qid.kind).u32(0).u64(qid.path)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Qid {
+    kind: u8,
+    path: u64,
+}
+
+/// A file the client refers to by number.
+#[derive(Clone)]
+struct Fid {
+    path: String,
+    /// Directory listing handed out by reads, built when the directory is opened.
+    listing: Option<Vec<u8>>,
+}
+
+fn parent(path: &str) -> &str {
+    match path.rsplit_once('/') {
+        Some((dir, _)) => dir,
+        None => ROOT,
+    }
+}
+
+fn name(path: &str) -> &str {
+    match path {
+        ROOT => "/",
+        _ => path.rsplit('/').next().unwrap_or(path),
+    }
+}
+
+/// Serves the FAT tree to one 9P2000 client at a time, one transaction per
+/// request.
+pub struct Server<'a> {
+    fat: &'a mut FAT,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+    /// Qid paths handed out so far; a file keeps its number for as long as
+    /// the server runs.
+    qids: HashMap<String, u64>,
+}
+
+impl<'a> Server<'a> {
+    pub fn new(fat: &'a mut FAT) -> Self {
+        Self {
+            fat,
+            msize: MAX_MSIZE,
+            fids: HashMap::new(),
+            qids: HashMap::new(),
+        }
+    }
+
+    /// Accepts TCP clients on `address` one after another.
+    pub fn listen_tcp(&mut self, address: SocketAddr) -> io::Result<()> {
+        let listener = TcpListener::bind(address)?;
+        for stream in listener.incoming() {
+            // a client that fails only ends its own session
+            let served = stream.and_then(|stream| {
+                stream.set_nodelay(true)?;
+                self.serve(stream)
+            });
+            if let Err(error) = served {
+                warn!(%error, "9P session failed");
+            }
+        }
+        Ok(())
+    }
+
+    /// Accepts clients on the Unix socket at `path` one after another.
+    pub fn listen_unix(&mut self, path: &str) -> io::Result<()> {
+        let listener = UnixListener::bind(path)?;
+        for stream in listener.incoming() {
+            if let Err(error) = stream.and_then(|stream| self.serve(stream)) {
+                warn!(%error, "9P session failed");
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs a session, then syncs the image even if the session failed.
+    fn serve<S: Read + Write>(&mut self, stream: S) -> io::Result<()> {
+        let session = self.session(stream);
+        self.fat
+            .sync()
+            .map_err(|_| io::Error::from(io::ErrorKind::Other))?;
+        session
+    }
+
+    /// Answers requests until the client hangs up.
+    fn session<S: Read + Write>(&mut self, mut stream: S) -> io::Result<()> {
+        self.fids.clear();
+
+        loop {
+            let mut size = [0; 4];
+            match stream.read_exact(&mut size) {
+                Ok(()) => {}
+                Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(error) => return Err(error),
+            }
+
+            let size = u32::from_le_bytes(size);
+            if !(HEADER..=self.msize).contains(&size) {
+                return Err(io::ErrorKind::InvalidData.into());
+            }
+            let mut message = vec![0; size as usize - 4];
+            stream.read_exact(&mut message)?;
+
+            let kind = message[0];
+            let tag = u16::from_le_bytes([message[1], message[2]]);
+            let (kind, body) = match self.handle(kind, &mut Fields(&message[3..])) {
+                Ok(body) => (kind + 1, body),
+                Err(Error(ename)) => (RERROR, Message::default().string(&ename)),
+            };
+
+            let reply = Message::default()
+                .u32(HEADER + body.0.len() as u32)
+                .u8(kind)
+                .u16(tag)
+                .bytes(&body.0);
+            stream.write_all(&reply.0)?;
+        }
+
+        Ok(())
+    }
+
+    fn qid(&mut self, path: &str, directory: bool) -> Qid {
+        let next = self.qids.len() as u64;
+        Qid {
+            kind: if directory { QTDIR } else { QTFILE },
+            path: *self.qids.entry(path.to_string()).or_insert(next),
+        }
+    }
+
+    /// The directory entry at `path`; `None` for the root, which has none.
+    fn entry(&mut self, path: &str) -> Result<Option<Entry>, Error> {
+        if path == ROOT {
+            return Ok(None);
+        }
+        Ok(Some(self.fat.find_file(path, FAT::filter_find)?))
+    }
+
+    fn is_dir(entry: &Option<Entry>) -> bool {
+        entry.as_ref().is_none_or(FAT::filter_mkdir)
+    }
+
+    fn stat(&mut self, path: &str) -> Result<Vec<u8>, Error> {
+        let entry = self.entry(path)?;
+        let directory = Self::is_dir(&entry);
+        let qid = self.qid(path, directory);
+        let (mode, length) = match entry {
+            Some(entry) if !directory => (0o644, entry.size()),
+            _ => (DMDIR | 0o755, 0),
+        };
+
+        let stat = Message::default()
+            .u16(0)
+            .u32(0)
+            .qid(qid)
+            .u32(mode)
+            .u32(0)
+            .u32(0)
+            .u64(length)
+            .string(name(path))
+            .string("zos")
+            .string("zos")
+            .string("zos");
+        Ok(Message::default().u16(stat.0.len() as u16).bytes(&stat.0).0)
+    }
+
+    fn fid(&self, fid: u32) -> Result<Fid, Error> {
+        self.fids
+            .get(&fid)
+            .cloned()
+            .ok_or(Error("unknown fid".to_string()))
+    }
+
+    fn handle(&mut self, kind: u8, fields: &mut Fields) -> Result<Message, Error> {
+        match kind {
+            TVERSION => {
+                let msize = fields.u32()?;
+                let version = fields.string()?;
+                self.msize = msize.clamp(IOHDRSZ + 1, MAX_MSIZE);
+                self.fids.clear();
+                let version = if version.starts_with(VERSION) {
+                    VERSION
+                } else {
+                    "unknown"
+                };
+                Ok(Message::default().u32(self.msize).string(version))
+            }
+            TAUTH => Err(Error("authentication not required".to_string())),
+            TATTACH => {
+                let fid = fields.u32()?;
+                let qid = self.qid(ROOT, true);
+                self.fids.insert(
+                    fid,
+                    Fid {
+                        path: ROOT.to_string(),
+                        listing: None,
+                    },
+                );
+                Ok(Message::default().qid(qid))
+            }
+            TFLUSH => Ok(Message::default()),
+            TWALK => {
+                let fid = self.fid(fields.u32()?)?;
+                let newfid = fields.u32()?;
+                let count = fields.u16()?;
+
+                let mut path = fid.path;
+                let mut qids = vec![];
+                for index in 0..count {
+                    let name = fields.string()?;
+                    let next = match name {
+                        ".." => parent(&path).to_string(),
+                        _ => join(&path, name),
+                    };
+                    match self.entry(&next) {
+                        Ok(entry) => {
+                            qids.push(self.qid(&next, Self::is_dir(&entry)));
+                            path = next;
+                        }
+                        Err(error) if index == 0 => return Err(error),
+                        Err(_) => break,
+                    }
+                }
+
+                if qids.len() == count as usize {
+                    self.fids.insert(
+                        newfid,
+                        Fid {
+                            path,
+                            listing: None,
+                        },
+                    );
+                }
+                let reply = Message::default().u16(qids.len() as u16);
+                Ok(qids.into_iter().fold(reply, Message::qid))
+            }
+            TOPEN => {
+                let number = fields.u32()?;
+                let mode = fields.u8()?;
+                let mut fid = self.fid(number)?;
+                let entry = self.entry(&fid.path)?;
+                let directory = Self::is_dir(&entry);
+
+                if directory {
+                    fid.listing = Some(self.listing(&fid.path)?);
+                } else if mode & OTRUNC != 0 {
+                    self.fat.truncate(&fid.path, 0)?;
+                }
+
+                let qid = self.qid(&fid.path, directory);
+                self.fids.insert(number, fid);
+                Ok(Message::default().qid(qid).u32(0))
+            }
+            TCREATE => {
+                let number = fields.u32()?;
+                let name = fields.string()?;
+                let perm = fields.u32()?;
+                let fid = self.fid(number)?;
+                let path = join(&fid.path, name);
+
+                let directory = perm & DMDIR != 0;
+                if directory {
+                    self.fat.transaction(|fs| fs.mkdir(&path))?;
+                } else {
+                    self.fat.new_file(&path, Cursor::new(vec![]))?;
+                }
+
+                let listing = if directory {
+                    Some(self.listing(&path)?)
+                } else {
+                    None
+                };
+                let qid = self.qid(&path, directory);
+                self.fids.insert(number, Fid { path, listing });
+                Ok(Message::default().qid(qid).u32(0))
+            }
+            TREAD => {
+                let fid = self.fid(fields.u32()?)?;
+                let offset = fields.u64()? as usize;
+                let count = fields.u32()?.min(self.msize - IOHDRSZ) as usize;
+
+                let data = match &fid.listing {
+                    Some(listing) => Self::whole_stats(listing, offset, count),
+                    None => {
+                        let mut contents = vec![];
+                        self.fat.cat(&fid.path, &mut contents)?;
+                        let start = offset.min(contents.len());
+                        let end = (start + count).min(contents.len());
+                        contents[start..end].to_vec()
+                    }
+                };
+                Ok(Message::default().u32(data.len() as u32).bytes(&data))
+            }
+            TWRITE => {
+                let fid = self.fid(fields.u32()?)?;
+                let offset = fields.u64()?;
+                let count = fields.u32()?;
+                let data = fields.take(count as usize)?;
+                self.fat.write_at(&fid.path, offset, data)?;
+                Ok(Message::default().u32(count))
+            }
+            TCLUNK => {
+                self.fids.remove(&fields.u32()?);
+                Ok(Message::default())
+            }
+            TREMOVE => {
+                // the fid is clunked even when the remove fails
+                let fid = self
+                    .fids
+                    .remove(&fields.u32()?)
+                    .ok_or(Error("unknown fid".to_string()))?;
+                let entry = self.entry(&fid.path)?;
+                if Self::is_dir(&entry) {
+                    self.fat.transaction(|fs| fs.remove_dir(&fid.path))?;
+                } else {
+                    self.fat.transaction(|fs| fs.remove_file(&fid.path))?;
+                }
+                self.qids.remove(&fid.path);
+                Ok(Message::default())
+            }
+            TSTAT => {
+                let fid = self.fid(fields.u32()?)?;
+                Ok(Message::default().bytes(&self.stat(&fid.path)?))
+            }
+            TWSTAT => {
+                let number = fields.u32()?;
+                let mut fid = self.fid(number)?;
+                // stat[n], then the stat's own size, type, dev, qid, mode,
+                // atime and mtime
+                fields.take(2 + 2 + 2 + 4 + 13 + 4 + 4 + 4)?;
+                let length = fields.u64()?;
+                let name = fields.string()?;
+
+                if length != u64::MAX {
+                    self.fat.truncate(&fid.path, length)?;
+                }
+                if !name.is_empty() && name != self::name(&fid.path) {
+                    let dest = join(parent(&fid.path), name);
+                    self.fat.transaction(|fs| fs.move_file(&fid.path, &dest))?;
+                    self.qids.remove(&fid.path);
+                    fid.path = dest;
+                    self.fids.insert(number, fid);
+                }
+                Ok(Message::default())
+            }
+            _ => Err(Error("operation not supported".to_string())),
+        }
+    }
+
+    /// Stats of the entries of the directory at `path`, back to back.
+    fn listing(&mut self, path: &str) -> Result<Vec<u8>, Error> {
+        let mut listing = vec![];
+        for entry in self.fat.read_dir(path)? {
+            if entry.name() == "." || entry.name() == ".." {
+                continue;
+            }
+            listing.extend(self.stat(&join(path, entry.name()))?);
+        }
+        Ok(listing)
+    }
+
+    /// The stats of `listing` that start at `offset` and fit whole in `count`
+    /// bytes.
+    fn whole_stats(listing: &[u8], offset: usize, count: usize) -> Vec<u8> {
+        let mut end = offset.min(listing.len());
+        while let Some(size) = listing.get(end..end + 2) {
+            let next = end + 2 + u16::from_le_bytes([size[0], size[1]]) as usize;
+            if next - offset > count {
+                break;
+            }
+            end = next;
+        }
+        listing[offset.min(end)..end].to_vec()
+    }
+}
@@ -0,0 +1,65 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Current time in seconds since the Unix epoch.
+pub fn now() -> u64 {
+    (now_nanos() / 1_000_000_000) as u64
+}
+
+/// Current time in nanoseconds since the Unix epoch. Targets without a clock,
+/// such as `wasm32-unknown-unknown`, stay at the epoch instead of panicking.
+pub fn now_nanos() -> u128 {
+    if cfg!(all(target_arch = "wasm32", target_os = "unknown")) {
+        return 0;
+    }
+
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos())
+        .unwrap_or_default()
+}
+
+/// Formats a Unix timestamp as `YYYY-MM-DD HH:MM:SS` (UTC).
+pub fn format_timestamp(timestamp: u64) -> String {
+    let [year, month, day, hour, minute, second] = civil(timestamp);
+    format!("{year:04}-{month:02}-{day:02} {hour:02}:{minute:02}:{second:02}")
+}
+
+/// Year, month, day, hour, minute and second (UTC) of a Unix timestamp.
+pub fn civil(timestamp: u64) -> [i64; 6] {
+    let days = (timestamp / 86400) as i64;
+    let seconds = timestamp % 86400;
+
+    // civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let seconds = seconds as i64;
+    [
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+    ]
+}
+
+/// MS-DOS time and date of a Unix timestamp, as FAT directories and zip
+/// archives store them; they cannot go before 1980.
+pub fn dos(timestamp: u64) -> (u16, u16) {
+    let [year, month, day, hour, minute, second] = civil(timestamp);
+    if year < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    (
+        ((hour << 11) | (minute << 5) | (second / 2)) as u16,
+        (((year - 1980).min(127) << 9) | (month << 5) | day) as u16,
+    )
+}
@@ -0,0 +1,487 @@
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{Block, List, ListItem, ListState, Paragraph},
+    DefaultTerminal, Frame,
+};
+
+use crate::fat::{join, FATError, FAT, ROOT};
+
+const HELP: &str =
+    "Tab switch  Enter open  Backspace up  c copy  m move  d delete  n mkdir  r refresh  q quit";
+
+/// Bytes in the largest unit that keeps the number at least one.
+fn size(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < units.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", units[unit])
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Host,
+    Image,
+}
+
+struct Item {
+    name: String,
+    dir: bool,
+    size: u64,
+}
+
+/// One side of the manager: a directory listing and its cursor.
+struct Pane {
+    items: Vec<Item>,
+    state: ListState,
+}
+
+impl Pane {
+    fn new() -> Self {
+        Self {
+            items: vec![],
+            state: ListState::default(),
+        }
+    }
+
+    /// Replaces the listing, keeping the cursor where it was if it still fits.
+    fn set_items(&mut self, mut items: Vec<Item>) {
+        items.sort_by(|a, b| b.dir.cmp(&a.dir).then_with(|| a.name.cmp(&b.name)));
+        let selected = self.state.selected().unwrap_or(0);
+        self.state.select(if items.is_empty() {
+            None
+        } else {
+            Some(selected.min(items.len() - 1))
+        });
+        self.items = items;
+    }
+
+    fn selected(&self) -> Option<&Item> {
+        self.items.get(self.state.selected()?)
+    }
+
+    fn step(&mut self, by: isize) {
+        if let Some(selected) = self.state.selected() {
+            let last = self.items.len().saturating_sub(1);
+            self.state
+                .select(Some(selected.saturating_add_signed(by).min(last)));
+        }
+    }
+}
+
+/// What the status bar is asking the user for.
+enum Prompt {
+    /// Name of the directory `n` creates.
+    Mkdir(String),
+    /// Confirmation of `d`.
+    Delete,
+}
+
+/// Two-pane file manager between a host directory and a directory in the
+/// image.
+struct Manager<'a> {
+    fat: &'a mut FAT,
+    host_dir: PathBuf,
+    image_dir: String,
+    host: Pane,
+    image: Pane,
+    active: Side,
+    prompt: Option<Prompt>,
+    message: String,
+    running: bool,
+}
+
+impl<'a> Manager<'a> {
+    fn new(fat: &'a mut FAT) -> io::Result<Self> {
+        let mut manager = Self {
+            fat,
+            host_dir: std::env::current_dir()?,
+            image_dir: ROOT.to_string(),
+            host: Pane::new(),
+            image: Pane::new(),
+            active: Side::Image,
+            prompt: None,
+            message: String::new(),
+            running: true,
+        };
+        manager.refresh();
+        Ok(manager)
+    }
+
+    fn refresh(&mut self) {
+        match fs::read_dir(&self.host_dir) {
+            Ok(entries) => self.host.set_items(
+                entries
+                    .filter_map(Result::ok)
+                    .filter_map(|entry| {
+                        let metadata = entry.metadata().ok()?;
+                        Some(Item {
+                            name: entry.file_name().to_string_lossy().into_owned(),
+                            dir: metadata.is_dir(),
+                            size: metadata.len(),
+                        })
+                    })
+                    .collect(),
+            ),
+            Err(error) => self.message = error.to_string(),
+        }
+
+        match self.fat.read_dir(&self.image_dir) {
+            Ok(entries) => self.image.set_items(
+                entries
+                    .iter()
+                    .filter(|entry| entry.name() != "." && entry.name() != "..")
+                    .map(|entry| Item {
+                        name: entry.name().to_string(),
+                        dir: FAT::filter_mkdir(entry),
+                        size: entry.size(),
+                    })
+                    .collect(),
+            ),
+            Err(error) => self.message = error.to_string(),
+        }
+    }
+
+    fn pane(&mut self) -> &mut Pane {
+        match self.active {
+            Side::Host => &mut self.host,
+            Side::Image => &mut self.image,
+        }
+    }
+
+    fn open(&mut self) {
+        let Some(item) = self.pane().selected().filter(|item| item.dir) else {
+            return;
+        };
+        let name = item.name.clone();
+
+        match self.active {
+            Side::Host => self.host_dir.push(name),
+            Side::Image => self.image_dir = join(&self.image_dir, &name),
+        }
+        self.pane().state.select(Some(0));
+        self.refresh();
+    }
+
+    fn up(&mut self) {
+        match self.active {
+            Side::Host => {
+                self.host_dir.pop();
+            }
+            Side::Image => {
+                self.image_dir = match self.image_dir.rsplit_once('/') {
+                    Some((parent, _)) => parent.to_string(),
+                    None => ROOT.to_string(),
+                }
+            }
+        }
+        self.pane().state.select(Some(0));
+        self.refresh();
+    }
+
+    /// Copies the selected entry into the other pane's directory, and with
+    /// `remove` set removes it from where it was.
+    fn transfer(&mut self, remove: bool) {
+        let Some(name) = self.pane().selected().map(|item| item.name.clone()) else {
+            return;
+        };
+        let host = self.host_dir.join(&name);
+        let image = join(&self.image_dir, &name);
+
+        let result = match self.active {
+            Side::Host => self
+                .fat
+                .transaction(|fs| copy_in(fs, &host, &image))
+                .map_err(|error| error.to_string())
+                .and_then(|()| {
+                    if remove {
+                        remove_host(&host).map_err(|error| error.to_string())
+                    } else {
+                        Ok(())
+                    }
+                }),
+            Side::Image => copy_out(self.fat, &image, &host).and_then(|()| {
+                if remove {
+                    self.fat
+                        .transaction(|fs| remove_tree(fs, &image))
+                        .map_err(|error| error.to_string())
+                } else {
+                    Ok(())
+                }
+            }),
+        };
+
+        self.message = match result {
+            Ok(()) if remove => format!("moved {name}"),
+            Ok(()) => format!("copied {name}"),
+            Err(error) => format!("{name}: {error}"),
+        };
+        self.refresh();
+    }
+
+    fn delete(&mut self) {
+        let Some(name) = self.pane().selected().map(|item| item.name.clone()) else {
+            return;
+        };
+
+        let result = match self.active {
+            Side::Host => {
+                remove_host(&self.host_dir.join(&name)).map_err(|error| error.to_string())
+            }
+            Side::Image => {
+                let path = join(&self.image_dir, &name);
+                self.fat
+                    .transaction(|fs| remove_tree(fs, &path))
+                    .map_err(|error| error.to_string())
+            }
+        };
+
+        self.message = match result {
+            Ok(()) => format!("deleted {name}"),
+            Err(error) => format!("{name}: {error}"),
+        };
+        self.refresh();
+    }
+
+    fn mkdir(&mut self, name: &str) {
+        let result = match self.active {
+            Side::Host => {
+                fs::create_dir(self.host_dir.join(name)).map_err(|error| error.to_string())
+            }
+            Side::Image => {
+                let path = join(&self.image_dir, name);
+                self.fat
+                    .transaction(|fs| fs.mkdir(&path))
+                    .map_err(|error| error.to_string())
+            }
+        };
+
+        self.message = match result {
+            Ok(()) => format!("created {name}"),
+            Err(error) => format!("{name}: {error}"),
+        };
+        self.refresh();
+    }
+
+    fn key(&mut self, code: KeyCode) {
+        match self.prompt.take() {
+            Some(Prompt::Mkdir(mut name)) => match code {
+                KeyCode::Enter if !name.is_empty() => self.mkdir(&name),
+                KeyCode::Esc | KeyCode::Enter => {}
+                KeyCode::Backspace => {
+                    name.pop();
+                    self.prompt = Some(Prompt::Mkdir(name));
+                }
+                KeyCode::Char(c) => {
+                    name.push(c);
+                    self.prompt = Some(Prompt::Mkdir(name));
+                }
+                _ => self.prompt = Some(Prompt::Mkdir(name)),
+            },
+            Some(Prompt::Delete) => {
+                if code == KeyCode::Char('y') {
+                    self.delete();
+                }
+            }
+            None => match code {
+                KeyCode::Char('q') | KeyCode::Esc => self.running = false,
+                KeyCode::Tab => {
+                    self.active = match self.active {
+                        Side::Host => Side::Image,
+                        Side::Image => Side::Host,
+                    }
+                }
+                KeyCode::Up | KeyCode::Char('k') => self.pane().step(-1),
+                KeyCode::Down | KeyCode::Char('j') => self.pane().step(1),
+                KeyCode::PageUp => self.pane().step(-10),
+                KeyCode::PageDown => self.pane().step(10),
+                KeyCode::Enter | KeyCode::Right => self.open(),
+                KeyCode::Backspace | KeyCode::Left => self.up(),
+                KeyCode::Char('c') | KeyCode::F(5) => self.transfer(false),
+                KeyCode::Char('m') | KeyCode::F(6) => self.transfer(true),
+                KeyCode::Char('n') | KeyCode::F(7) => {
+                    self.prompt = Some(Prompt::Mkdir(String::new()))
+                }
+                KeyCode::Char('d') | KeyCode::F(8) if self.pane().selected().is_some() => {
+                    self.prompt = Some(Prompt::Delete)
+                }
+                KeyCode::Char('r') => {
+                    self.message.clear();
+                    self.refresh();
+                }
+                _ => {}
+            },
+        }
+    }
+
+    fn status(&mut self) -> String {
+        let space = match self.fat.usage() {
+            Ok(usage) => format!("{} free of {}", size(usage.free), size(usage.total)),
+            Err(error) => error.to_string(),
+        };
+
+        match &self.prompt {
+            Some(Prompt::Mkdir(name)) => format!("New directory: {name}_"),
+            Some(Prompt::Delete) => format!(
+                "Delete {}? (y/n)",
+                self.pane()
+                    .selected()
+                    .map(|item| item.name.as_str())
+                    .unwrap_or_default()
+            ),
+            None if self.message.is_empty() => space,
+            None => format!("{space} | {}", self.message),
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [panes, status_area, help] = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [left, right] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+                .areas(panes);
+
+        let label = self.fat.label().unwrap_or_default().to_string();
+        let image_title = if self.image_dir == ROOT {
+            format!("{label}:/")
+        } else {
+            format!("{label}:/{}/", self.image_dir)
+        };
+        let host_title = self.host_dir.display().to_string();
+        let status = Paragraph::new(self.status());
+
+        for (side, area, title) in [
+            (Side::Host, left, host_title),
+            (Side::Image, right, image_title),
+        ] {
+            let active = self.active == side;
+            let pane = match side {
+                Side::Host => &mut self.host,
+                Side::Image => &mut self.image,
+            };
+
+            let items = pane.items.iter().map(|item| {
+                ListItem::new(if item.dir {
+                    format!("{}/", item.name)
+                } else {
+                    format!("{:<32} {:>10}", item.name, size(item.size))
+                })
+            });
+            let highlight = if active {
+                Style::new().add_modifier(Modifier::REVERSED)
+            } else {
+                Style::new().add_modifier(Modifier::UNDERLINED)
+            };
+            let list = List::new(items)
+                .block(Block::bordered().title(Line::from(title)))
+                .highlight_style(highlight);
+            frame.render_stateful_widget(list, area, &mut pane.state);
+        }
+
+        frame.render_widget(status, status_area);
+        frame.render_widget(Paragraph::new(HELP), help);
+    }
+}
+
+/// Copies the host file or directory tree at `host` to `image`.
+fn copy_in(fat: &mut FAT, host: &Path, image: &str) -> Result<(), FATError> {
+    if host.is_dir() {
+        fat.mkdir(image)?;
+        for entry in fs::read_dir(host).map_err(|_| FATError::CannotRead)? {
+            let entry = entry.map_err(|_| FATError::CannotRead)?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            copy_in(fat, &entry.path(), &join(image, &name))?;
+        }
+        Ok(())
+    } else {
+        fat.new_file(image, File::open(host).map_err(|_| FATError::CannotRead)?)
+    }
+}
+
+/// Copies the file or directory tree at `image` to the host path `host`.
+fn copy_out(fat: &mut FAT, image: &str, host: &Path) -> Result<(), String> {
+    if host.exists() {
+        return Err(FATError::FileExists.to_string());
+    }
+
+    let entry = fat
+        .find_file(image, FAT::filter_find)
+        .map_err(|error| error.to_string())?;
+    if FAT::filter_mkdir(&entry) {
+        fs::create_dir(host).map_err(|error| error.to_string())?;
+        for entry in fat.read_dir(image).map_err(|error| error.to_string())? {
+            if entry.name() != "." && entry.name() != ".." {
+                copy_out(fat, &join(image, entry.name()), &host.join(entry.name()))?;
+            }
+        }
+        Ok(())
+    } else {
+        let file = File::create(host).map_err(|error| error.to_string())?;
+        fat.cat(image, file).map_err(|error| error.to_string())
+    }
+}
+
+/// Removes the file or directory tree at `path` in the image.
+fn remove_tree(fat: &mut FAT, path: &str) -> Result<(), FATError> {
+    if !FAT::filter_mkdir(&fat.find_file(path, FAT::filter_find)?) {
+        return fat.remove_file(path);
+    }
+
+    for entry in fat.read_dir(path)? {
+        if entry.name() != "." && entry.name() != ".." {
+            remove_tree(fat, &join(path, entry.name()))?;
+        }
+    }
+    fat.remove_dir(path)
+}
+
+fn remove_host(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+fn event_loop(terminal: &mut DefaultTerminal, manager: &mut Manager) -> io::Result<()> {
+    while manager.running {
+        terminal.draw(|frame| manager.draw(frame))?;
+        if let Event::Key(key) = event::read()? {
+            if key.kind == KeyEventKind::Press {
+                manager.key(key.code);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs the file manager until the user quits, starting in the host's
+/// working directory and the image's root.
+pub fn run(fat: &mut FAT) -> io::Result<()> {
+    let mut manager = Manager::new(fat)?;
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, &mut manager);
+    ratatui::restore();
+    result
+}
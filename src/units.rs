@@ -31,7 +31,7 @@ impl Unit {
             Unit::GB(count) => count * 1024 * 1024 * 1024,
             Unit::MB(count) => count * 1024 * 1024,
             Unit::KB(count) => count * 1024,
-            Unit::B(count) => count * 1,
+            Unit::B(count) => *count,
             Unit::Gb(count) => count * 1000 * 1000 * 1000 / 8,
             Unit::Mb(count) => count * 1000 * 1000 / 8,
             Unit::Kb(count) => count * 1000 / 8,